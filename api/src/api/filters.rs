@@ -1,301 +1,1133 @@
-use crate::models::filter_models::*;
-use actix_web::{HttpResponse, patch, post, web};
+use crate::models::{filter_models::*, response};
+use actix_web::{HttpResponse, delete, get, patch, post, web};
+use boom::filter::{
+    Backtest, BacktestError, BacktestQuery, FILTER_RESULTS_COLLECTION, FilterSchedule,
+    FilterValidator, NotificationPolicy, OBJECT_FILTER_TYPE, build_lsst_filter_prefix,
+    build_object_filter_prefix, build_ztf_filter_prefix, notify_filters_updated,
+};
+use futures::TryStreamExt;
 use mongodb::{
     Client, Collection,
-    bson::{Document, doc},
+    bson::{Bson, Document, doc},
 };
-use std::vec;
+use std::collections::HashMap;
+use tracing::warn;
 use uuid::Uuid;
 
 const DB_NAME: &str = "boom";
 
+// collection of the counters used to assign ids, e.g. to the new filters
+const COUNTERS_COLLECTION: &str = "counters";
+// number of times a new filter id is drawn when it is already taken
+const MAX_FILTER_ID_ATTEMPTS: usize = 10;
+
 struct Filter {
     pub pipeline: Vec<mongodb::bson::Document>,
     pub permissions: Vec<i32>,
     pub catalog: String,
-    pub id: i32,
+    pub group_id: Option<i32>,
+    pub autosave: bool,
+    pub update_annotations: bool,
+    pub notification_policy: NotificationPolicy,
+    pub schedule: Option<FilterSchedule>,
 }
 
+// number of objects an object filter is run on when it is submitted
+const OBJECT_FILTER_TEST_SIZE: i64 = 1000;
+
+// the stages of a filter, after the same prefix as in the filter workers
+// (or the scheduled filter workers, for the object filters), or None if
+// there are no filter workers for the catalog
 fn build_test_pipeline(
-    filter_catalog: String,
-    filter_perms: Vec<i32>,
+    catalog: &str,
+    permissions: &[i32],
+    object_filter: bool,
     mut filter_pipeline: Vec<Document>,
-) -> Vec<Document> {
-    let mut out_pipeline = vec![
-        doc! {
-            "$match": doc! {
-                // during filter::run proper candis are inserted here
-            }
-        },
-        doc! {
-            "$lookup": doc! {
-                "from": format!("{}_aux", filter_catalog),
-                "localField": "objectId",
-                "foreignField": "_id",
-                "as": "aux"
+) -> Option<Vec<Document>> {
+    let mut out_pipeline = match catalog {
+        "ZTF_alerts" | "LSST_alerts" if object_filter => {
+            let now_jd =
+                mongodb::bson::DateTime::now().timestamp_millis() as f64 / 86_400_000.0 + 2440587.5;
+            // the test run only needs some objects, not the whole aux collection
+            let mut prefix = vec![doc! {"$limit": OBJECT_FILTER_TEST_SIZE}];
+            prefix.extend(build_object_filter_prefix(
+                catalog.trim_end_matches("_alerts"),
+                permissions,
+                now_jd,
+            ));
+            prefix
+        }
+        "ZTF_alerts" => build_ztf_filter_prefix(permissions),
+        "LSST_alerts" => build_lsst_filter_prefix(),
+        _ => return None,
+    };
+    out_pipeline.append(&mut filter_pipeline);
+    Some(out_pipeline)
+}
+
+// the alert collection of a catalog, given with or without the _alerts suffix
+fn alert_collection_name(catalog: &str) -> String {
+    if catalog.ends_with("_alerts") {
+        catalog.to_string()
+    } else {
+        format!("{}_alerts", catalog)
+    }
+}
+
+// the catalogs the filters are submitted for
+const FILTER_CATALOGS: [&str; 2] = ["ZTF_alerts", "LSST_alerts"];
+
+/// The validators of the submitted filters of each catalog, which may look up
+/// the catalogs of the `crossmatch` section of the boom config.
+pub struct FilterValidators(HashMap<String, FilterValidator>);
+
+impl FilterValidators {
+    /// Build the validators from the boom config; without it, the filters
+    /// can only look up their catalog and its aux collection.
+    pub fn load(config_path: &str) -> Self {
+        let config = match boom::conf::load_config(config_path) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!("failed to load the config {}: {}", config_path, e);
+                return FilterValidators(HashMap::new());
             }
-        },
-        doc! {
-            "$project": doc! {
-                "objectId": 1,
-                "candidate": 1,
-                "classifications": 1,
-                "coordinates": 1,
-                "cross_matches": doc! {
-                    "$arrayElemAt": [
-                        "$aux.cross_matches",
-                        0
-                    ]
-                },
-                "prv_candidates": doc! {
-                    "$filter": doc! {
-                        "input": doc! {
-                            "$arrayElemAt": [
-                                "$aux.prv_candidates",
-                                0
-                            ]
-                        },
-                        "as": "x",
-                        "cond": doc! {
-                            "$and": [
-                                {
-                                    "$in": [
-                                        "$$x.programid",
-                                        &filter_perms
-                                    ]
-                                },
-                                { // maximum 1 year of past data
-                                    "$lt": [
-                                        {
-                                            "$subtract": [
-                                                "$candidate.jd",
-                                                "$$x.jd"
-                                            ]
-                                        },
-                                        365
-                                    ]
-                                },
-                                { // only datapoints up to (and including) current alert
-                                    "$lte": [
-                                        "$$x.jd",
-                                        "$candidate.jd"
-                                    ]
-                                }
-
-                            ]
-                        }
-                    }
-                },
+        };
+        let mut validators = HashMap::new();
+        for catalog in FILTER_CATALOGS {
+            match FilterValidator::from_config(&config, catalog) {
+                Ok(validator) => {
+                    validators.insert(catalog.to_string(), validator);
+                }
+                Err(e) => {
+                    warn!("invalid crossmatch config for {}: {}", catalog, e);
+                }
             }
-        },
-    ];
-    out_pipeline.append(&mut filter_pipeline);
-    return out_pipeline;
+        }
+        FilterValidators(validators)
+    }
+
+    fn get(&self, catalog: &str) -> FilterValidator {
+        self.0
+            .get(catalog)
+            .cloned()
+            .unwrap_or_else(|| FilterValidator::new(catalog))
+    }
+}
+
+// checks the stages and operators of a submitted filter against the allowlists,
+// and returns the error response (pointing at the offending stage) if not allowed
+fn validate_pipeline(
+    validators: &FilterValidators,
+    catalog: &str,
+    pipeline: &[Document],
+) -> Option<HttpResponse> {
+    let catalog = alert_collection_name(catalog);
+    match validators.get(&catalog).validate(pipeline) {
+        Ok(()) => None,
+        Err(e) => Some(HttpResponse::BadRequest().json(response::ApiResponseBody {
+            status: "error".to_string(),
+            message: format!("Invalid filter submitted: {}", e),
+            data: serde_json::json!({ "stage": e.stage() }),
+        })),
+    }
 }
 
 // tests the functionality of a filter by running it on alerts in database
 async fn run_test_pipeline(
-    client: web::Data<Client>,
-    catalog: String,
+    client: &Client,
+    catalog: &str,
     pipeline: Vec<mongodb::bson::Document>,
 ) -> Result<(), mongodb::error::Error> {
-    let collection: Collection<mongodb::bson::Document> = client
-        .database(DB_NAME)
-        .collection(format!("{}_alerts", catalog).as_str());
+    let collection: Collection<mongodb::bson::Document> =
+        client.database(DB_NAME).collection(catalog);
+    collection.aggregate(pipeline).await?;
+    Ok(())
+}
 
-    let result = collection.aggregate(pipeline).await;
-    match result {
-        Ok(_) => {
-            return Ok(());
-        }
-        Err(e) => {
-            return Err(e);
-        }
+// validates a filter pipeline, and runs it on the alerts of the catalog (or
+// on its aux collection for the object filters), returning the error response
+// if it can't be used
+async fn check_pipeline(
+    client: &Client,
+    validators: &FilterValidators,
+    catalog: &str,
+    permissions: &[i32],
+    object_filter: bool,
+    pipeline: &[Document],
+) -> Option<HttpResponse> {
+    let test_pipeline =
+        match build_test_pipeline(catalog, permissions, object_filter, pipeline.to_vec()) {
+            Some(test_pipeline) => test_pipeline,
+            None => {
+                return Some(response::bad_request(&format!(
+                    "unknown catalog {}",
+                    catalog
+                )));
+            }
+        };
+    if let Some(error_response) = validate_pipeline(validators, catalog, pipeline) {
+        return Some(error_response);
+    }
+    let collection = match object_filter {
+        true => format!("{}_aux", catalog),
+        false => catalog.to_string(),
+    };
+    match run_test_pipeline(client, &collection, test_pipeline).await {
+        Ok(()) => None,
+        Err(e) => Some(response::bad_request(&format!(
+            "Invalid filter submitted, filter test failed with error: {}",
+            e
+        ))),
+    }
+}
+
+// a version of a filter, with its stages stored as a JSON string (as read by the filter workers)
+fn build_filter_version(pipeline: &[Document]) -> Result<(String, Document), serde_json::Error> {
+    let fid = Uuid::new_v4().to_string();
+    let version = doc! {
+        "fid": &fid,
+        "pipeline": serde_json::to_string(pipeline)?,
+        "created_at": mongodb::bson::DateTime::now(),
+    };
+    Ok((fid, version))
+}
+
+// the stages of a version of a filter, stored as a JSON string or as an array of stages
+fn version_pipeline(version: &Document) -> Option<Vec<Document>> {
+    match version.get("pipeline")? {
+        Bson::String(pipeline) => serde_json::from_str(pipeline).ok(),
+        Bson::Array(stages) => stages
+            .iter()
+            .map(|stage| stage.as_document().cloned())
+            .collect(),
+        _ => None,
     }
 }
 
+// the versions of a filter document
+fn filter_versions(filter: &Document) -> Vec<&Document> {
+    filter
+        .get_array("fv")
+        .map(|versions| versions.iter().filter_map(Bson::as_document).collect())
+        .unwrap_or_default()
+}
+
+// a filter document as returned by the API, with the stages of each version as an array
+fn filter_response(mut filter: Document) -> serde_json::Value {
+    filter.remove("_id");
+    let versions: Vec<Document> = filter_versions(&filter)
+        .into_iter()
+        .map(|version| {
+            let mut version = version.clone();
+            if let Some(pipeline) = version_pipeline(&version) {
+                version.insert("pipeline", pipeline);
+            }
+            version
+        })
+        .collect();
+    if filter.contains_key("fv") {
+        filter.insert("fv", versions);
+    }
+    serde_json::json!(filter)
+}
+
 // takes a verified filter and builds the properly formatted bson document for the database
-fn build_filter_bson(filter: Filter) -> Result<mongodb::bson::Document, mongodb::error::Error> {
-    // generate new object id
-    let id = mongodb::bson::oid::ObjectId::new();
+fn build_filter_bson(filter: Filter, filter_id: i32) -> Result<(String, Document), String> {
     let date_time = mongodb::bson::DateTime::now();
-    let pipeline_id = Uuid::new_v4().to_string(); // generate random pipeline id
+    let (fid, version) = build_filter_version(&filter.pipeline).map_err(|e| e.to_string())?;
+    let notification_policy =
+        mongodb::bson::to_bson(&filter.notification_policy).map_err(|e| e.to_string())?;
+    let schedule = mongodb::bson::to_bson(&filter.schedule).map_err(|e| e.to_string())?;
+    let filter_type = match filter.schedule {
+        Some(_) => OBJECT_FILTER_TYPE,
+        None => "alert",
+    };
     let database_filter_bson = doc! {
-        "_id": id,
-        "group_id": 41, // consistent with other test filters
-        "filter_id": filter.id,
+        "_id": mongodb::bson::oid::ObjectId::new(),
+        "group_id": filter.group_id,
+        "filter_id": filter_id,
         "catalog": filter.catalog,
         "permissions": filter.permissions,
         "active": true,
-        "active_fid": pipeline_id.clone(),
-        "fv": [
-            {
-                "fid": pipeline_id,
-                "pipeline": filter.pipeline,
-                "created_at": date_time,
-            }
-        ],
-        "autosave": false,
-        "update_annotations": true,
+        "active_fid": &fid,
+        "fv": [version],
+        "autosave": filter.autosave,
+        "update_annotations": filter.update_annotations,
+        "notification_policy": notification_policy,
+        "type": filter_type,
+        "schedule": schedule,
         "created_at": date_time,
         "last_modified": date_time,
     };
-    Ok(database_filter_bson)
+    Ok((fid, database_filter_bson))
 }
 
-#[patch("/filters/{filter_id}")]
-pub async fn add_filter_version(
+// the next filter id, from a counter in the database
+async fn next_filter_id(client: &Client) -> Result<i32, mongodb::error::Error> {
+    let db = client.database(DB_NAME);
+    let counters = db.collection::<Document>(COUNTERS_COLLECTION);
+    // the counter starts after the largest filter id in use when it is created
+    // (e.g. by the filters added with the add_filter binary before the API was used)
+    if counters
+        .find_one(doc! {"_id": "filter_id"})
+        .await?
+        .is_none()
+    {
+        let max_filter_id = db
+            .collection::<Document>("filters")
+            .find_one(doc! {})
+            .sort(doc! {"filter_id": -1})
+            .projection(doc! {"filter_id": 1})
+            .await?
+            .and_then(|filter| filter.get_i32("filter_id").ok())
+            .unwrap_or(0);
+        if let Err(e) = counters
+            .insert_one(doc! {"_id": "filter_id", "seq": max_filter_id})
+            .await
+        {
+            // unless it was created in the meantime by another request
+            if !is_duplicate_key_error(&e) {
+                return Err(e);
+            }
+        }
+    }
+    let counter = counters
+        .find_one_and_update(doc! {"_id": "filter_id"}, doc! {"$inc": {"seq": 1}})
+        .upsert(true)
+        .return_document(mongodb::options::ReturnDocument::After)
+        .await?;
+    Ok(counter
+        .and_then(|counter| counter.get_i32("seq").ok())
+        .unwrap_or(1))
+}
+
+// tell the filter workers of a catalog to reload their filters; the change is
+// saved either way, and the workers also reload their filters periodically
+async fn notify_filter_workers(redis: &redis::Client, catalog: &str) {
+    let result = match redis.get_multiplexed_async_connection().await {
+        Ok(mut con) => notify_filters_updated(&mut con, catalog).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        warn!("failed to notify the filter workers of {}: {}", catalog, e);
+    }
+}
+
+fn is_duplicate_key_error(error: &mongodb::error::Error) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        mongodb::error::ErrorKind::Write(mongodb::error::WriteFailure::WriteError(e)) if e.code == 11000
+    )
+}
+
+// get a filter by id, or the error response if it doesn't exist
+async fn find_filter(
+    collection: &Collection<Document>,
+    filter_id: i32,
+) -> Result<Document, HttpResponse> {
+    match collection.find_one(doc! {"filter_id": filter_id}).await {
+        Ok(Some(filter)) => Ok(filter),
+        Ok(None) => Err(response::not_found(&format!(
+            "filter with id {} does not exist",
+            filter_id
+        ))),
+        Err(e) => Err(response::internal_error(&format!(
+            "failed to find filter with id {}. error: {}",
+            filter_id, e
+        ))),
+    }
+}
+
+#[post("/filters")]
+pub async fn post_filter(
     client: web::Data<Client>,
-    filter_id: web::Path<i32>,
+    validators: web::Data<FilterValidators>,
+    redis: web::Data<redis::Client>,
     body: web::Json<FilterSubmissionBody>,
 ) -> HttpResponse {
-    let filter_id = filter_id.into_inner();
-    let pipeline = match body.clone().pipeline {
+    let body = body.into_inner();
+    // grab user filter
+    let catalog = match body.catalog {
+        Some(catalog) => alert_collection_name(&catalog),
+        None => {
+            return response::bad_request("catalog not provided");
+        }
+    };
+    let permissions = match body.permissions {
+        Some(permissions) if !permissions.is_empty() => permissions,
+        _ => {
+            return response::bad_request("permissions not provided");
+        }
+    };
+    let pipeline = match body.pipeline {
         Some(pipeline) => pipeline,
         None => {
-            return HttpResponse::BadRequest()
-                .body("pipeline not provided. pipeline required for adding a filter version");
+            return response::bad_request("pipeline not provided");
         }
     };
 
-    let collection: Collection<Document> = client.database(DB_NAME).collection("filters");
-    let owner_filter = match collection.find_one(doc! {"filter_id": filter_id}).await {
-        Ok(Some(filter)) => filter,
-        Ok(None) => {
-            return HttpResponse::BadRequest()
-                .body(format!("filter with id {} does not exist", filter_id));
+    if body
+        .schedule
+        .as_ref()
+        .is_some_and(|schedule| !schedule.is_valid())
+    {
+        return response::bad_request("invalid schedule");
+    }
+
+    // test the filter on the alerts of the catalog, with the prefix of the filter workers
+    let object_filter = body.schedule.is_some();
+    if let Some(error_response) = check_pipeline(
+        &client,
+        &validators,
+        &catalog,
+        &permissions,
+        object_filter,
+        &pipeline,
+    )
+    .await
+    {
+        return error_response;
+    }
+
+    // save original filter to database
+    let filter_collection: Collection<mongodb::bson::Document> =
+        client.database(DB_NAME).collection("filters");
+    let database_filter = Filter {
+        pipeline,
+        permissions,
+        catalog: catalog.clone(),
+        group_id: body.group_id,
+        autosave: body.autosave.unwrap_or(false),
+        update_annotations: body.update_annotations.unwrap_or(true),
+        notification_policy: body.notification_policy.unwrap_or_default(),
+        schedule: body.schedule,
+    };
+
+    // the filter ids are assigned here, skipping the ones already taken
+    // (e.g. by filters added with the add_filter binary)
+    let filter_id = match next_filter_id(&client).await {
+        Ok(filter_id) => filter_id,
+        Err(e) => {
+            return response::internal_error(&format!("failed to assign a filter id: {}", e));
         }
+    };
+    let (fid, mut filter_bson) = match build_filter_bson(database_filter, filter_id) {
+        Ok(bson) => bson,
         Err(e) => {
-            return HttpResponse::InternalServerError().body(format!(
-                "failed to find filter with id {}. error: {}",
-                filter_id, e
-            ));
+            return response::bad_request(&format!("unable to create filter bson: {}", e));
         }
     };
-    let catalog = owner_filter.get_str("catalog").unwrap();
-    let permissions = owner_filter.get_array("permissions").unwrap();
-    let permissions: Vec<i32> = permissions
-        .iter()
-        .map(|perm| perm.as_i32().unwrap())
-        .collect();
-    // create test version of filter and test it
-    let test_pipeline = build_test_pipeline(catalog.to_string(), permissions, pipeline.clone());
+    let mut filter_id = filter_id;
+    for _ in 0..MAX_FILTER_ID_ATTEMPTS {
+        match filter_collection.insert_one(&filter_bson).await {
+            Ok(_) => {
+                notify_filter_workers(&redis, &catalog).await;
+                return response::ok(
+                    &format!("successfully submitted filter {}", filter_id),
+                    serde_json::json!({ "filter_id": filter_id, "fid": fid }),
+                );
+            }
+            Err(e) if is_duplicate_key_error(&e) => {
+                filter_id = match next_filter_id(&client).await {
+                    Ok(filter_id) => filter_id,
+                    Err(e) => {
+                        return response::internal_error(&format!(
+                            "failed to assign a filter id: {}",
+                            e
+                        ));
+                    }
+                };
+                filter_bson.insert("filter_id", filter_id);
+            }
+            Err(e) => {
+                return response::internal_error(&format!(
+                    "failed to insert filter into database: {}",
+                    e
+                ));
+            }
+        }
+    }
+    response::internal_error("failed to assign a filter id, all the ids tried are taken")
+}
 
-    match run_test_pipeline(client.clone(), catalog.to_string(), test_pipeline).await {
-        Ok(()) => {}
+// list the filters (without their versions), optionally those of a group or catalog
+#[get("/filters")]
+pub async fn get_filters(
+    client: web::Data<Client>,
+    query: web::Query<FilterListQuery>,
+) -> HttpResponse {
+    let query = query.into_inner();
+    let mut filter = Document::new();
+    if let Some(group_id) = query.group_id {
+        filter.insert("group_id", group_id);
+    }
+    if let Some(catalog) = &query.catalog {
+        filter.insert("catalog", alert_collection_name(catalog));
+    }
+    if let Some(active) = query.active {
+        filter.insert("active", active);
+    }
+    let find_options = mongodb::options::FindOptions::builder()
+        .sort(doc! {"filter_id": 1})
+        .projection(doc! {"_id": 0, "fv": 0})
+        .build();
+
+    let collection: Collection<Document> = client.database(DB_NAME).collection("filters");
+    let cursor = match collection.find(filter).with_options(find_options).await {
+        Ok(cursor) => cursor,
         Err(e) => {
-            return HttpResponse::BadRequest().body(format!(
-                "Invalid filter submitted, filter test failed with error: {}",
-                e
-            ));
+            return response::internal_error(&format!("error getting filters: {}", e));
         }
+    };
+    let filters: Vec<Document> = match cursor.try_collect().await {
+        Ok(filters) => filters,
+        Err(e) => {
+            return response::internal_error(&format!("error getting filters: {}", e));
+        }
+    };
+    response::ok(
+        &format!("found {} filters", filters.len()),
+        serde_json::json!(filters),
+    )
+}
+
+// get a filter, with the history of its versions
+#[get("/filters/{filter_id}")]
+pub async fn get_filter(client: web::Data<Client>, filter_id: web::Path<i32>) -> HttpResponse {
+    let filter_id = filter_id.into_inner();
+    let collection: Collection<Document> = client.database(DB_NAME).collection("filters");
+    match find_filter(&collection, filter_id).await {
+        Ok(filter) => response::ok(&format!("filter {}", filter_id), filter_response(filter)),
+        Err(error_response) => error_response,
     }
+}
 
-    let new_pipeline_id = Uuid::new_v4().to_string();
-    let date_time = mongodb::bson::DateTime::now();
-    let new_pipeline_bson = doc! {
-        "fid": new_pipeline_id,
-        "pipeline": pipeline,
-        "created_at": date_time,
+// update a filter: add a version, set the active version, (de)activate it,
+// or change its permissions and settings
+#[patch("/filters/{filter_id}")]
+pub async fn patch_filter(
+    client: web::Data<Client>,
+    validators: web::Data<FilterValidators>,
+    redis: web::Data<redis::Client>,
+    filter_id: web::Path<i32>,
+    body: web::Json<FilterUpdateBody>,
+) -> HttpResponse {
+    let filter_id = filter_id.into_inner();
+    let body = body.into_inner();
+
+    let collection: Collection<Document> = client.database(DB_NAME).collection("filters");
+    let owner_filter = match find_filter(&collection, filter_id).await {
+        Ok(filter) => filter,
+        Err(error_response) => return error_response,
     };
-    let update_result = collection
-        .update_one(
-            doc! {"filter_id": filter_id},
-            doc! {
-                "$push": {
-                    "fv": new_pipeline_bson
-                }
-            },
-        )
-        .await;
-    match update_result {
-        Ok(_) => {
-            return HttpResponse::Ok().body(format!(
-                "successfully added new pipeline version to filter id: {}",
-                filter_id
+    let catalog = alert_collection_name(owner_filter.get_str("catalog").unwrap_or_default());
+    let object_filter = owner_filter.get_str("type") == Ok(OBJECT_FILTER_TYPE);
+
+    let mut set = doc! {"last_modified": mongodb::bson::DateTime::now()};
+    let permissions = match body.permissions {
+        Some(permissions) if permissions.is_empty() => {
+            return response::bad_request("permissions can't be empty");
+        }
+        Some(permissions) => {
+            set.insert("permissions", &permissions);
+            permissions
+        }
+        None => owner_filter
+            .get_array("permissions")
+            .map(|permissions| permissions.iter().filter_map(Bson::as_i32).collect())
+            .unwrap_or_default(),
+    };
+    if let Some(active_fid) = &body.active_fid {
+        let exists = filter_versions(&owner_filter)
+            .iter()
+            .any(|version| version.get_str("fid") == Ok(active_fid));
+        if !exists {
+            return response::bad_request(&format!(
+                "filter {} has no version {}",
+                filter_id, active_fid
             ));
         }
-        Err(e) => {
-            return HttpResponse::InternalServerError().body(format!(
-                "failed to add new pipeline to filter. error: {}",
-                e
+        set.insert("active_fid", active_fid);
+    }
+    let mut unset = Document::new();
+    if let Some(active) = body.active {
+        set.insert("active", active);
+        // reactivating a filter disabled by the filter workers clears its flag
+        if active {
+            unset = doc! {"unhealthy": "", "unhealthy_reason": "", "unhealthy_since": ""};
+        }
+    }
+    if let Some(autosave) = body.autosave {
+        set.insert("autosave", autosave);
+    }
+    if let Some(update_annotations) = body.update_annotations {
+        set.insert("update_annotations", update_annotations);
+    }
+    if let Some(schedule) = &body.schedule {
+        if !object_filter {
+            return response::bad_request(&format!(
+                "filter {} is not an object filter, it can't have a schedule",
+                filter_id
             ));
         }
+        if !schedule.is_valid() {
+            return response::bad_request("invalid schedule");
+        }
+        match mongodb::bson::to_bson(schedule) {
+            Ok(schedule) => set.insert("schedule", schedule),
+            Err(e) => {
+                return response::bad_request(&format!("invalid schedule: {}", e));
+            }
+        };
+    }
+    if let Some(notification_policy) = &body.notification_policy {
+        match mongodb::bson::to_bson(notification_policy) {
+            Ok(notification_policy) => set.insert("notification_policy", notification_policy),
+            Err(e) => {
+                return response::bad_request(&format!("invalid notification policy: {}", e));
+            }
+        };
+    }
+
+    let mut update = doc! {"$set": set};
+    if !unset.is_empty() {
+        update.insert("$unset", unset);
+    }
+    let mut new_fid = None;
+    if let Some(pipeline) = &body.pipeline {
+        if let Some(error_response) = check_pipeline(
+            &client,
+            &validators,
+            &catalog,
+            &permissions,
+            object_filter,
+            pipeline,
+        )
+        .await
+        {
+            return error_response;
+        }
+        let (fid, version) = match build_filter_version(pipeline) {
+            Ok(version) => version,
+            Err(e) => {
+                return response::bad_request(&format!("unable to create filter version: {}", e));
+            }
+        };
+        update.insert("$push", doc! {"fv": version});
+        new_fid = Some(fid);
+    }
+
+    match collection
+        .update_one(doc! {"filter_id": filter_id}, update)
+        .await
+    {
+        Ok(_) => {
+            notify_filter_workers(&redis, &catalog).await;
+            response::ok(
+                &format!("successfully updated filter {}", filter_id),
+                serde_json::json!({ "filter_id": filter_id, "fid": new_fid }),
+            )
+        }
+        Err(e) => response::internal_error(&format!("failed to update filter: {}", e)),
     }
 }
 
-#[post("/filters")]
-pub async fn post_filter(
+#[delete("/filters/{filter_id}")]
+pub async fn delete_filter(
     client: web::Data<Client>,
-    body: web::Json<FilterSubmissionBody>,
+    redis: web::Data<redis::Client>,
+    filter_id: web::Path<i32>,
 ) -> HttpResponse {
-    let body = body.clone();
-    // grab user filter
-    let catalog = match body.catalog {
-        Some(catalog) => catalog,
-        None => {
-            return HttpResponse::BadRequest().body("catalog not provided");
+    let filter_id = filter_id.into_inner();
+    let collection: Collection<Document> = client.database(DB_NAME).collection("filters");
+    match collection
+        .find_one_and_delete(doc! {"filter_id": filter_id})
+        .await
+    {
+        Ok(None) => response::not_found(&format!("filter with id {} does not exist", filter_id)),
+        Ok(Some(filter)) => {
+            let catalog = alert_collection_name(filter.get_str("catalog").unwrap_or_default());
+            notify_filter_workers(&redis, &catalog).await;
+            response::ok(
+                &format!("successfully deleted filter {}", filter_id),
+                serde_json::json!({ "filter_id": filter_id }),
+            )
         }
-    };
-    let id = match body.id {
-        Some(id) => id,
-        None => {
-            return HttpResponse::BadRequest().body("filter id not provided");
+        Err(e) => response::internal_error(&format!("failed to delete filter: {}", e)),
+    }
+}
+
+// the stages removed from `from` and added in `to`, from their longest common subsequence
+fn diff_stages(from: &[Document], to: &[Document]) -> Vec<serde_json::Value> {
+    // lengths of the longest common subsequences of the suffixes of the pipelines
+    let mut lengths = vec![vec![0; to.len() + 1]; from.len() + 1];
+    for i in (0..from.len()).rev() {
+        for j in (0..to.len()).rev() {
+            lengths[i][j] = if from[i] == to[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
         }
+    }
+
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < from.len() || j < to.len() {
+        if i < from.len() && j < to.len() && from[i] == to[j] {
+            i += 1;
+            j += 1;
+        } else if j < to.len() && (i == from.len() || lengths[i][j + 1] >= lengths[i + 1][j]) {
+            changes.push(serde_json::json!({"change": "added", "to_stage": j, "stage": to[j]}));
+            j += 1;
+        } else {
+            changes
+                .push(serde_json::json!({"change": "removed", "from_stage": i, "stage": from[i]}));
+            i += 1;
+        }
+    }
+    changes
+}
+
+// compare the stages of two versions of a filter
+#[get("/filters/{filter_id}/diff")]
+pub async fn diff_filter_versions(
+    client: web::Data<Client>,
+    filter_id: web::Path<i32>,
+    query: web::Query<FilterDiffQuery>,
+) -> HttpResponse {
+    let filter_id = filter_id.into_inner();
+    let query = query.into_inner();
+    let collection: Collection<Document> = client.database(DB_NAME).collection("filters");
+    let filter = match find_filter(&collection, filter_id).await {
+        Ok(filter) => filter,
+        Err(error_response) => return error_response,
     };
-    let permissions = match body.permissions {
-        Some(permissions) => permissions,
-        None => {
-            return HttpResponse::BadRequest().body("permissions not provided");
+
+    let versions = filter_versions(&filter);
+    let get_pipeline = |fid: &str| {
+        versions
+            .iter()
+            .find(|version| version.get_str("fid") == Ok(fid))
+            .and_then(|version| version_pipeline(version))
+    };
+    let (Some(from), Some(to)) = (get_pipeline(&query.from), get_pipeline(&query.to)) else {
+        return response::not_found(&format!(
+            "filter {} has no version {} or {}",
+            filter_id, query.from, query.to
+        ));
+    };
+
+    response::ok(
+        &format!(
+            "diff of versions {} and {} of filter {}",
+            query.from, query.to, filter_id
+        ),
+        serde_json::json!({
+            "filter_id": filter_id,
+            "from": query.from,
+            "to": query.to,
+            "changes": diff_stages(&from, &to),
+        }),
+    )
+}
+
+// number of stats windows returned when no limit is given
+const DEFAULT_STATS_LIMIT: i64 = 100;
+
+// get the execution stats of a filter (written by the filter workers), most recent windows first
+#[get("/filters/{filter_id}/stats")]
+pub async fn get_filter_stats(
+    client: web::Data<Client>,
+    filter_id: web::Path<i32>,
+    query: web::Query<FilterStatsQuery>,
+) -> HttpResponse {
+    let filter_id = filter_id.into_inner();
+    let query = query.into_inner();
+
+    let mut filter = doc! {"filter_id": filter_id};
+    if let Some(fid) = &query.fid {
+        filter.insert("fid", fid);
+    }
+    let find_options = mongodb::options::FindOptions::builder()
+        .sort(doc! {"window_start": -1})
+        .projection(doc! {"_id": 0})
+        .limit(query.limit.unwrap_or(DEFAULT_STATS_LIMIT))
+        .build();
+
+    let collection: Collection<Document> = client.database(DB_NAME).collection("filter_stats");
+    let cursor = match collection.find(filter).with_options(find_options).await {
+        Ok(cursor) => cursor,
+        Err(e) => {
+            return response::internal_error(&format!("error getting filter stats: {}", e));
         }
     };
-    let pipeline = match body.pipeline {
-        Some(pipeline) => pipeline,
-        None => {
-            return HttpResponse::BadRequest().body("pipeline not provided");
+    let stats: Vec<Document> = match cursor.try_collect().await {
+        Ok(stats) => stats,
+        Err(e) => {
+            return response::internal_error(&format!("error getting filter stats: {}", e));
         }
     };
 
-    // Test filter received from user
-    // create production version of filter
-    let test_pipeline = build_test_pipeline(catalog.clone(), permissions.clone(), pipeline.clone());
+    // the totals over the returned windows
+    let total = |key: &str| -> i64 {
+        stats
+            .iter()
+            .map(|window| window.get_i64(key).unwrap_or(0))
+            .sum()
+    };
+    let alerts_evaluated = total("alerts_evaluated");
+    let alerts_passed = total("alerts_passed");
+    let pass_rate = if alerts_evaluated > 0 {
+        Some(alerts_passed as f64 / alerts_evaluated as f64)
+    } else {
+        None
+    };
+
+    response::ok(
+        &format!("stats of filter {}", filter_id),
+        serde_json::json!({
+            "filter_id": filter_id,
+            "alerts_evaluated": alerts_evaluated,
+            "alerts_passed": alerts_passed,
+            "alerts_suppressed": total("alerts_suppressed"),
+            "errors": total("errors"),
+            "pass_rate": pass_rate,
+            "windows": stats,
+        }),
+    )
+}
+
+// number of filter results per page when no page size is given, and the maximum
+const DEFAULT_RESULTS_PAGE_SIZE: u64 = 100;
+const MAX_RESULTS_PAGE_SIZE: u64 = 1000;
 
-    // perform test run to ensure no errors
-    match run_test_pipeline(client.clone(), catalog.clone(), test_pipeline).await {
-        Ok(()) => {}
+// get the passes of a filter saved by the filter workers (for the filters with autosave),
+// most recent first, optionally between two dates
+#[get("/filters/{filter_id}/results")]
+pub async fn get_filter_results(
+    client: web::Data<Client>,
+    filter_id: web::Path<i32>,
+    query: web::Query<FilterResultsQuery>,
+) -> HttpResponse {
+    let filter_id = filter_id.into_inner();
+    let query = query.into_inner();
+
+    let mut passed_at = Document::new();
+    for (operator, date) in [("$gte", &query.start), ("$lt", &query.end)] {
+        if let Some(date) = date {
+            match mongodb::bson::DateTime::parse_rfc3339_str(date) {
+                Ok(date) => {
+                    passed_at.insert(operator, date);
+                }
+                Err(e) => {
+                    return response::bad_request(&format!("invalid date {}: {}", date, e));
+                }
+            }
+        }
+    }
+    let mut filter = doc! {"filter_id": filter_id};
+    if !passed_at.is_empty() {
+        filter.insert("passed_at", passed_at);
+    }
+
+    let page = query.page.unwrap_or(1).max(1);
+    let page_size = query
+        .page_size
+        .unwrap_or(DEFAULT_RESULTS_PAGE_SIZE)
+        .clamp(1, MAX_RESULTS_PAGE_SIZE);
+    let find_options = mongodb::options::FindOptions::builder()
+        .sort(doc! {"passed_at": -1, "candid": -1})
+        .projection(doc! {"_id": 0})
+        .skip((page - 1) * page_size)
+        .limit(page_size as i64)
+        .build();
+
+    let collection: Collection<Document> = client
+        .database(DB_NAME)
+        .collection(FILTER_RESULTS_COLLECTION);
+    let total = match collection.count_documents(filter.clone()).await {
+        Ok(total) => total,
         Err(e) => {
-            return HttpResponse::BadRequest().body(format!(
-                "Invalid filter submitted, filter test failed with error: {}",
-                e
-            ));
+            return response::internal_error(&format!("error counting filter results: {}", e));
         }
+    };
+    let cursor = match collection.find(filter).with_options(find_options).await {
+        Ok(cursor) => cursor,
+        Err(e) => {
+            return response::internal_error(&format!("error getting filter results: {}", e));
+        }
+    };
+    let results: Vec<Document> = match cursor.try_collect().await {
+        Ok(results) => results,
+        Err(e) => {
+            return response::internal_error(&format!("error getting filter results: {}", e));
+        }
+    };
+
+    response::ok(
+        &format!("results of filter {}", filter_id),
+        serde_json::json!({
+            "filter_id": filter_id,
+            "page": page,
+            "page_size": page_size,
+            "total": total,
+            "results": results,
+        }),
+    )
+}
+
+// collection of the backtest jobs, with their progress and report
+const BACKTEST_COLLECTION: &str = "filter_backtests";
+
+// run a backtest to completion, updating its job document after each chunk of alerts
+async fn run_backtest(
+    collection: Collection<Document>,
+    backtest_id: String,
+    mut backtest: Backtest,
+) {
+    let (status, error) = loop {
+        match backtest.next_chunk().await {
+            Ok(Some(_)) => {}
+            Ok(None) => break ("completed", None),
+            Err(e) => break ("failed", Some(e.to_string())),
+        }
+        let report = mongodb::bson::to_bson(backtest.report()).unwrap_or_default();
+        let _ = collection
+            .update_one(
+                doc! {"_id": &backtest_id},
+                doc! {"$set": {"report": report, "updated_at": mongodb::bson::DateTime::now()}},
+            )
+            .await;
+    };
+    let report = mongodb::bson::to_bson(backtest.report()).unwrap_or_default();
+    let _ = collection
+        .update_one(
+            doc! {"_id": &backtest_id},
+            doc! {
+                "$set": {
+                    "status": status,
+                    "error": error,
+                    "report": report,
+                    "updated_at": mongodb::bson::DateTime::now(),
+                }
+            },
+        )
+        .await;
+}
+
+// prepare the backtest of the stages of a filter of a catalog
+async fn build_backtest(
+    db: &mongodb::Database,
+    validators: &FilterValidators,
+    catalog: &str,
+    pipeline: Vec<Document>,
+    query: &BacktestQuery,
+    sample_size: Option<usize>,
+) -> Result<Backtest, BacktestError> {
+    let survey = catalog.trim_end_matches("_alerts");
+    let validator = validators.get(catalog);
+    let backtest = Backtest::new(db, survey, pipeline, query, &validator).await?;
+    Ok(match sample_size {
+        Some(sample_size) => backtest.sample_size(sample_size),
+        None => backtest,
+    })
+}
+
+// start the backtest of a filter over the historical alerts of its catalog, which runs in
+// the background: its progress and report are then available at /backtests/{backtest_id}
+#[post("/backtests")]
+pub async fn post_backtest(
+    client: web::Data<Client>,
+    validators: web::Data<FilterValidators>,
+    body: web::Json<BacktestSubmissionBody>,
+) -> HttpResponse {
+    let body = body.into_inner();
+    let db = client.database(DB_NAME);
+
+    // the catalog and permissions are those of the saved filter
+    let filter = match find_filter(&db.collection("filters"), body.filter_id).await {
+        Ok(filter) => filter,
+        Err(error_response) => return error_response,
+    };
+    if filter.get_str("type") == Ok(OBJECT_FILTER_TYPE) {
+        return response::bad_request("object filters can't be backtested over the alerts");
+    }
+    let catalog = alert_collection_name(filter.get_str("catalog").unwrap_or_default());
+    let permissions: Vec<i32> = filter
+        .get_array("permissions")
+        .map(|permissions| permissions.iter().filter_map(Bson::as_i32).collect())
+        .unwrap_or_default();
+    let pipeline = match body.pipeline {
+        Some(pipeline) => pipeline,
+        None => {
+            let active_fid = filter.get_str("active_fid").unwrap_or_default();
+            match filter_versions(&filter)
+                .into_iter()
+                .find(|version| version.get_str("fid") == Ok(active_fid))
+                .and_then(version_pipeline)
+            {
+                Some(pipeline) => pipeline,
+                None => {
+                    return response::internal_error(&format!(
+                        "filter {} has no active version",
+                        body.filter_id
+                    ));
+                }
+            }
+        }
+    };
+    if let Some(error_response) = validate_pipeline(&validators, &catalog, &pipeline) {
+        return error_response;
     }
 
-    // save original filter to database
-    let filter_collection: Collection<mongodb::bson::Document> =
-        client.database(DB_NAME).collection("filters");
-    let database_filter = Filter {
-        pipeline,
+    let query = BacktestQuery {
+        jd_start: body.jd_start,
+        jd_end: body.jd_end,
+        region: body.region,
         permissions,
-        catalog,
-        id,
     };
-    let filter_bson = match build_filter_bson(database_filter) {
-        Ok(bson) => bson,
+    let backtest = match build_backtest(
+        &db,
+        &validators,
+        &catalog,
+        pipeline.clone(),
+        &query,
+        body.sample_size,
+    )
+    .await
+    {
+        Ok(backtest) => backtest,
+        Err(BacktestError::Mongodb(e)) => {
+            return response::internal_error(&format!("error starting backtest: {}", e));
+        }
         Err(e) => {
-            return HttpResponse::BadRequest()
-                .body(format!("unable to create filter bson, got error: {}", e));
+            return response::bad_request(&format!("invalid backtest: {}", e));
         }
     };
-    match filter_collection.insert_one(filter_bson).await {
-        Ok(_) => {
-            return HttpResponse::Ok().body("successfully submitted filter to database");
-        }
+
+    let backtest_id = Uuid::new_v4().to_string();
+    let date_time = mongodb::bson::DateTime::now();
+    let query = match mongodb::bson::to_bson(&query) {
+        Ok(query) => query,
         Err(e) => {
-            return HttpResponse::BadRequest().body(format!(
-                "failed to insert filter into database. error: {}",
-                e
-            ));
+            return response::bad_request(&format!("invalid backtest: {}", e));
         }
+    };
+    let collection: Collection<Document> = db.collection(BACKTEST_COLLECTION);
+    // the stages are kept, so that the backtest can be started over after a restart
+    let backtest_doc = doc! {
+        "_id": &backtest_id,
+        "filter_id": body.filter_id,
+        "catalog": &catalog,
+        "pipeline": pipeline,
+        "query": query,
+        "sample_size": body.sample_size.map(|sample_size| sample_size as i64),
+        "status": "running",
+        "error": null,
+        "report": null,
+        "created_at": date_time,
+        "updated_at": date_time,
+    };
+    if let Err(e) = collection.insert_one(backtest_doc).await {
+        return response::internal_error(&format!("error starting backtest: {}", e));
+    }
+    actix_web::rt::spawn(run_backtest(collection, backtest_id.clone(), backtest));
+
+    response::ok(
+        &format!("started backtest {}", backtest_id),
+        serde_json::json!({ "backtest_id": backtest_id }),
+    )
+}
+
+// the backtest of a job left running, from the stages and query it was started with
+async fn rebuild_backtest(
+    db: &mongodb::Database,
+    validators: &FilterValidators,
+    job: &Document,
+) -> Result<Backtest, String> {
+    let catalog = job.get_str("catalog").map_err(|e| e.to_string())?;
+    let pipeline = job
+        .get_array("pipeline")
+        .map_err(|e| e.to_string())?
+        .iter()
+        .map(|stage| stage.as_document().cloned())
+        .collect::<Option<Vec<Document>>>()
+        .ok_or("invalid pipeline")?;
+    let query: BacktestQuery = job
+        .get("query")
+        .cloned()
+        .map(mongodb::bson::from_bson)
+        .ok_or("missing query")?
+        .map_err(|e| e.to_string())?;
+    let sample_size = job
+        .get_i64("sample_size")
+        .ok()
+        .map(|sample_size| sample_size as usize);
+    build_backtest(db, validators, catalog, pipeline, &query, sample_size)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Start over the backtests that were still running when the API stopped,
+/// as their progress isn't kept; those that can't be are marked as failed.
+pub async fn resume_backtests(
+    client: &Client,
+    validators: &FilterValidators,
+) -> Result<(), mongodb::error::Error> {
+    let db = client.database(DB_NAME);
+    let collection: Collection<Document> = db.collection(BACKTEST_COLLECTION);
+    let jobs: Vec<Document> = collection
+        .find(doc! {"status": "running"})
+        .await?
+        .try_collect()
+        .await?;
+    for job in jobs {
+        let backtest_id = job.get_str("_id").unwrap_or_default().to_string();
+        match rebuild_backtest(&db, validators, &job).await {
+            Ok(backtest) => {
+                collection
+                    .update_one(
+                        doc! {"_id": &backtest_id},
+                        doc! {"$set": {"report": null, "updated_at": mongodb::bson::DateTime::now()}},
+                    )
+                    .await?;
+                actix_web::rt::spawn(run_backtest(collection.clone(), backtest_id, backtest));
+            }
+            Err(e) => {
+                warn!("failed to resume backtest {}: {}", backtest_id, e);
+                collection
+                    .update_one(
+                        doc! {"_id": &backtest_id},
+                        doc! {
+                            "$set": {
+                                "status": "failed",
+                                "error": format!("interrupted by a restart of the API: {}", e),
+                                "updated_at": mongodb::bson::DateTime::now(),
+                            }
+                        },
+                    )
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// get the status of a backtest, with its report so far
+#[get("/backtests/{backtest_id}")]
+pub async fn get_backtest(
+    client: web::Data<Client>,
+    backtest_id: web::Path<String>,
+) -> HttpResponse {
+    let backtest_id = backtest_id.into_inner();
+    let collection: Collection<Document> = client.database(DB_NAME).collection(BACKTEST_COLLECTION);
+    match collection.find_one(doc! {"_id": &backtest_id}).await {
+        Ok(Some(backtest)) => response::ok(
+            &format!("backtest {}", backtest_id),
+            serde_json::json!(backtest),
+        ),
+        Ok(None) => response::not_found(&format!("backtest {} not found", backtest_id)),
+        Err(e) => response::internal_error(&format!("error getting backtest: {}", e)),
     }
 }
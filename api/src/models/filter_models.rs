@@ -1,7 +1,72 @@
+use boom::filter::{FilterSchedule, NotificationPolicy, SkyRegion};
+
+// a new filter, whose id is assigned by the API. With a schedule, it is an
+// object filter, run on that schedule over the aux collection of the catalog
 #[derive(serde::Deserialize, Clone)]
 pub struct FilterSubmissionBody {
     pub pipeline: Option<Vec<mongodb::bson::Document>>,
     pub permissions: Option<Vec<i32>>,
     pub catalog: Option<String>,
-    pub id: Option<i32>,
+    pub group_id: Option<i32>,
+    pub autosave: Option<bool>,
+    pub update_annotations: Option<bool>,
+    pub notification_policy: Option<NotificationPolicy>,
+    pub schedule: Option<FilterSchedule>,
+}
+
+// changes to a filter: a pipeline adds a new version (which isn't active
+// until it is set as the active_fid), the other fields replace the current ones
+#[derive(serde::Deserialize, Clone, Default)]
+pub struct FilterUpdateBody {
+    pub pipeline: Option<Vec<mongodb::bson::Document>>,
+    pub active_fid: Option<String>,
+    pub active: Option<bool>,
+    pub permissions: Option<Vec<i32>>,
+    pub autosave: Option<bool>,
+    pub update_annotations: Option<bool>,
+    pub notification_policy: Option<NotificationPolicy>,
+    pub schedule: Option<FilterSchedule>,
+}
+
+// query parameters of the filter list route
+#[derive(serde::Deserialize, Clone, Default)]
+pub struct FilterListQuery {
+    pub group_id: Option<i32>,
+    pub catalog: Option<String>,
+    pub active: Option<bool>,
+}
+
+// query parameters of the filter diff route, the fids of the two versions
+#[derive(serde::Deserialize, Clone)]
+pub struct FilterDiffQuery {
+    pub from: String,
+    pub to: String,
+}
+
+// query parameters of the filter stats route
+#[derive(serde::Deserialize, Clone, Default)]
+pub struct FilterStatsQuery {
+    pub fid: Option<String>,
+    pub limit: Option<i64>,
+}
+
+// query parameters of the filter results route, with start and end as RFC 3339 dates
+#[derive(serde::Deserialize, Clone, Default)]
+pub struct FilterResultsQuery {
+    pub start: Option<String>,
+    pub end: Option<String>,
+    pub page: Option<u64>,
+    pub page_size: Option<u64>,
+}
+
+// a filter to backtest over the historical alerts of its catalog, with the
+// permissions of the filter: its active version, or new stages to try out
+#[derive(serde::Deserialize, Clone)]
+pub struct BacktestSubmissionBody {
+    pub filter_id: i32,
+    pub pipeline: Option<Vec<mongodb::bson::Document>>,
+    pub jd_start: f64,
+    pub jd_end: f64,
+    pub region: Option<SkyRegion>,
+    pub sample_size: Option<usize>,
 }
@@ -23,6 +23,13 @@ impl ApiResponseBody {
             data: serde_json::Value::Null,
         }
     }
+    pub fn not_found(message: &str) -> Self {
+        Self {
+            status: "error".to_string(),
+            message: message.to_string(),
+            data: serde_json::Value::Null,
+        }
+    }
     pub fn bad_request(message: &str) -> Self {
         Self {
             status: "error".to_string(),
@@ -44,3 +51,7 @@ pub fn internal_error(message: &str) -> HttpResponse {
 pub fn bad_request(message: &str) -> HttpResponse {
     HttpResponse::BadRequest().json(ApiResponseBody::bad_request(message))
 }
+
+pub fn not_found(message: &str) -> HttpResponse {
+    HttpResponse::NotFound().json(ApiResponseBody::not_found(message))
+}
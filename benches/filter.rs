@@ -0,0 +1,93 @@
+//! Compare running each filter in its own aggregation with running all the
+//! filters of a batch in a single aggregation (`run_filters`).
+//!
+//! Needs the test database (see tests/config.test.yaml), run with:
+//! `cargo bench --bench filter`
+
+use boom::{
+    alert::AlertWorker,
+    conf,
+    filter::{group_by_prefix, run_filter, run_filters, Filter, ZtfFilter},
+    utils::testing::{
+        insert_test_ztf_filter, remove_test_ztf_filter, ztf_alert_worker, AlertRandomizerTrait,
+        ZtfAlertRandomizer, TEST_CONFIG_FILE,
+    },
+};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use mongodb::bson::Document;
+
+const N_ALERTS: usize = 100;
+
+fn bench_filters(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    // a batch of alerts, as the filter worker would get from its queue
+    let candids: Vec<i64> = runtime.block_on(async {
+        let mut alert_worker = ztf_alert_worker().await;
+        let mut candids = Vec::new();
+        for _ in 0..N_ALERTS {
+            let (candid, _, _, _, bytes_content) = ZtfAlertRandomizer::default().get().await;
+            alert_worker.process_alert(&bytes_content).await.unwrap();
+            candids.push(candid);
+        }
+        candids
+    });
+
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let db = runtime.block_on(conf::build_db(&config)).unwrap();
+    let alert_collection = db.collection::<Document>("ZTF_alerts");
+    let filter_collection = db.collection::<Document>("filters");
+
+    let mut group = c.benchmark_group("filters");
+    group.sample_size(10);
+    for n_filters in [1, 10, 100] {
+        let filters: Vec<ZtfFilter> = runtime.block_on(async {
+            let mut filters = Vec::new();
+            for _ in 0..n_filters {
+                let filter_id = insert_test_ztf_filter().await.unwrap();
+                let filter = ZtfFilter::build(filter_id, &filter_collection).await;
+                remove_test_ztf_filter(filter_id).await.unwrap();
+                filters.push(filter.unwrap());
+            }
+            filters
+        });
+
+        group.bench_with_input(
+            BenchmarkId::new("one_aggregation_per_filter", n_filters),
+            &filters,
+            |b, filters| {
+                b.iter(|| {
+                    runtime.block_on(async {
+                        for filter in filters {
+                            run_filter(candids.clone(), filter.pipeline.clone(), &alert_collection)
+                                .await
+                                .unwrap();
+                        }
+                    })
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("single_aggregation", n_filters),
+            &filters,
+            |b, filters| {
+                b.iter(|| {
+                    runtime.block_on(async {
+                        let pipelines = filters
+                            .iter()
+                            .map(|filter| (filter.id, filter.pipeline.as_slice()));
+                        for group in group_by_prefix(pipelines) {
+                            run_filters(&candids, &group, &alert_collection, None)
+                                .await
+                                .unwrap();
+                        }
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_filters);
+criterion_main!(benches);
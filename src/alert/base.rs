@@ -1,12 +1,29 @@
-use crate::utils::worker::WorkerCmd;
+use crate::utils::worker::{
+    clear_heartbeat, reclaim_orphaned_packets, send_heartbeat, spawn_heartbeat, temp_queue_name,
+    WorkerCmd, HEARTBEAT_TTL_SECS,
+};
 use crate::{
+    alert::{
+        dead_letter::{get_max_retries, DeadLetterQueue},
+        generic::GenericAlertWorker,
+    },
     conf,
-    utils::{db::CreateIndexError, spatial::XmatchError},
+    utils::{
+        db::CreateIndexError,
+        spatial::{xmatch, XmatchError},
+    },
+};
+use apache_avro::{from_avro_datum, to_avro_datum, types::Value, Schema};
+use flare::Time;
+use futures::stream::StreamExt;
+use mongodb::{
+    bson::{doc, Bson, Document},
+    error::{BulkWriteError, ErrorKind, InsertManyError},
+    options::UpdateOneModel,
 };
-use apache_avro::Schema;
-use mongodb::bson::Document;
 use redis::AsyncCommands;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::error::TryRecvError;
 use tracing::{error, info, trace, warn};
@@ -19,10 +36,6 @@ pub enum SchemaRegistryError {
     Reqwest(#[from] reqwest::Error),
     #[error("error from std::io")]
     Io(#[from] std::io::Error),
-    #[error("invalid version")]
-    InvalidVersion,
-    #[error("invalid subject")]
-    InvalidSubject,
     #[error("could not find expected content in response")]
     InvalidResponse,
     #[error("could not find avro magic bytes")]
@@ -31,6 +44,16 @@ pub enum SchemaRegistryError {
     InvalidRecordCount(usize),
     #[error("integer overflow")]
     IntegerOverflow,
+    #[error("schema {0} not found on disk, and the registry is offline")]
+    SchemaNotCached(String),
+    #[error("the registry is offline")]
+    Offline,
+    #[error("not found in the registry: {0}")]
+    NotFound(String),
+    #[error("not authorized by the registry, check the credentials")]
+    Unauthorized,
+    #[error("error from serde_json")]
+    SerdeJson(#[from] serde_json::Error),
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -65,13 +88,81 @@ pub enum AlertError {
     MissingMagZPSci,
     #[error("could not find avro magic bytes")]
     MagicBytesError,
+    #[error("packet is shorter than the avro header of its schema")]
+    TruncatedPacket,
+    #[error("missing field {0}")]
+    MissingField(String),
+    #[error("no schema registry configured for the stream")]
+    MissingSchemaRegistry,
+}
+
+impl AlertError {
+    /// Name of the error variant, as recorded in the dead-letter store.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AlertError::Avro(_) => "Avro",
+            AlertError::BsonValueAccess(_) => "BsonValueAccess",
+            AlertError::Mongodb(_) => "Mongodb",
+            AlertError::SchemaRegistryError(_) => "SchemaRegistryError",
+            AlertError::Xmatch(_) => "Xmatch",
+            AlertError::AlertExists => "AlertExists",
+            AlertError::AlertAuxExists => "AlertAuxExists",
+            AlertError::MissingObjectId => "MissingObjectId",
+            AlertError::MissingCutout => "MissingCutout",
+            AlertError::MissingFluxPSF => "MissingFluxPSF",
+            AlertError::MissingFluxPSFError => "MissingFluxPSFError",
+            AlertError::MissingFluxAperture => "MissingFluxAperture",
+            AlertError::MissingFluxApertureError => "MissingFluxApertureError",
+            AlertError::MissingMagZPSci => "MissingMagZPSci",
+            AlertError::MagicBytesError => "MagicBytesError",
+            AlertError::TruncatedPacket => "TruncatedPacket",
+            AlertError::MissingField(_) => "MissingField",
+            AlertError::MissingSchemaRegistry => "MissingSchemaRegistry",
+        }
+    }
+
+    /// Whether processing the same packet again could succeed.
+    ///
+    /// Transient errors come from the services we talk to (database timeouts,
+    /// unreachable schema registry, ...), while everything else is a problem
+    /// with the packet itself and will fail the same way on every attempt.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            AlertError::Mongodb(e) => is_transient_mongodb_error(e),
+            AlertError::Xmatch(XmatchError::Mongodb(e)) => is_transient_mongodb_error(e),
+            AlertError::SchemaRegistryError(SchemaRegistryError::Reqwest(_)) => true,
+            _ => false,
+        }
+    }
 }
 
+fn is_transient_mongodb_error(error: &mongodb::error::Error) -> bool {
+    match *error.kind {
+        mongodb::error::ErrorKind::Io(_)
+        | mongodb::error::ErrorKind::ConnectionPoolCleared { .. }
+        | mongodb::error::ErrorKind::ServerSelection { .. } => true,
+        _ => {
+            error.contains_label(mongodb::error::RETRYABLE_WRITE_ERROR)
+                || error.contains_label(mongodb::error::TRANSIENT_TRANSACTION_ERROR)
+        }
+    }
+}
+
+// magic byte of the Confluent wire format, followed by a 4-byte schema id
+const MAGIC_BYTE: u8 = 0;
+
+// pseudo-subject under which the schemas looked up by id are kept on disk
+const SCHEMA_IDS_SUBJECT: &str = "ids";
+
 #[derive(Clone, Debug)]
 pub struct SchemaRegistry {
     client: reqwest::Client,
     cache: HashMap<String, Schema>,
     url: String,
+    cache_dir: Option<PathBuf>,
+    offline: bool,
+    credentials: Option<(String, String)>,
+    reader_schema: Option<Schema>,
 }
 
 impl SchemaRegistry {
@@ -82,68 +173,214 @@ impl SchemaRegistry {
             client,
             cache,
             url: url.to_string(),
+            cache_dir: None,
+            offline: false,
+            credentials: None,
+            reader_schema: None,
         }
     }
 
-    async fn get_subjects(&self) -> Result<Vec<String>, SchemaRegistryError> {
-        let response = self
-            .client
-            .get(&format!("{}/subjects", &self.url))
-            .send()
-            .await?;
+    /// Create a registry backed by schemas on disk.
+    ///
+    /// The schemas of `seed_dir` (bundled with the code) and `cache_dir` are loaded
+    /// on startup, stored as `<subject>/<version>.avsc`, or `ids/<id>.avsc` for the
+    /// schemas looked up by id. Schemas fetched from the registry are saved to
+    /// `cache_dir`, and in offline mode the registry is never queried.
+    pub fn with_cache(
+        url: &str,
+        cache_dir: Option<&Path>,
+        seed_dir: Option<&Path>,
+        offline: bool,
+    ) -> Result<Self, SchemaRegistryError> {
+        let mut registry = SchemaRegistry::new(url);
+        registry.offline = offline;
+        if let Some(seed_dir) = seed_dir {
+            registry.load_dir(seed_dir)?;
+        }
+        if let Some(cache_dir) = cache_dir {
+            std::fs::create_dir_all(cache_dir)?;
+            registry.load_dir(cache_dir)?;
+            registry.cache_dir = Some(cache_dir.to_path_buf());
+        }
+        Ok(registry)
+    }
 
-        let response = response.json::<Vec<String>>().await?;
+    /// Create the registry of a stream from the `schema_registry.<stream>` section
+    /// of the config, using `default_url` if it doesn't set one.
+    pub fn from_config(
+        conf: &config::Config,
+        stream_name: &str,
+        default_url: &str,
+    ) -> Result<Self, SchemaRegistryError> {
+        let key = format!("schema_registry.{}", stream_name);
+        let url = conf
+            .get_string(&format!("{}.url", key))
+            .unwrap_or(default_url.to_string());
+        // relative to the config file, see conf::get_path
+        let cache_dir = conf::get_path(conf, &format!("{}.cache_dir", key));
+        let seed_dir = conf::get_path(conf, &format!("{}.seed_dir", key));
+        let offline = conf.get_bool(&format!("{}.offline", key)).unwrap_or(false);
+        let mut registry =
+            SchemaRegistry::with_cache(&url, cache_dir.as_deref(), seed_dir.as_deref(), offline)?;
 
-        Ok(response)
-    }
+        if let (Ok(username), Ok(password)) = (
+            conf.get_string(&format!("{}.username", key)),
+            conf.get_string(&format!("{}.password", key)),
+        ) {
+            registry.set_credentials(&username, &password);
+        }
 
-    async fn get_versions(&self, subject: &str) -> Result<Vec<u32>, SchemaRegistryError> {
-        // first we check if the subject exists
-        let subjects = self.get_subjects().await?;
-        if !subjects.contains(&subject.to_string()) {
-            return Err(SchemaRegistryError::InvalidSubject);
+        if let Some(reader_schema) = conf::get_path(conf, &format!("{}.reader_schema", key)) {
+            let reader_schema = Schema::parse_str(&std::fs::read_to_string(reader_schema)?)?;
+            registry.set_reader_schema(reader_schema);
         }
+        Ok(registry)
+    }
 
-        let response = self
-            .client
-            .get(&format!("{}/subjects/{}/versions", &self.url, subject))
-            .send()
-            .await?;
+    pub fn url(&self) -> &str {
+        &self.url
+    }
 
-        let response = response.json::<Vec<u32>>().await?;
+    pub fn set_offline(&mut self, offline: bool) {
+        self.offline = offline;
+    }
 
-        Ok(response)
+    /// Authenticate to the registry with HTTP basic auth.
+    pub fn set_credentials(&mut self, username: &str, password: &str) {
+        self.credentials = Some((username.to_string(), password.to_string()));
     }
 
-    async fn _get_schema_by_id(
-        &self,
+    /// Decode the packets against this schema rather than the one they were written with,
+    /// so that the packets written with an older schema get the fields of the newer one.
+    pub fn set_reader_schema(&mut self, reader_schema: Schema) {
+        self.reader_schema = Some(reader_schema);
+    }
+
+    /// Subjects and versions of the schemas held by the registry, sorted.
+    pub fn cached_schemas(&self) -> Vec<(String, u32)> {
+        let mut schemas: Vec<(String, u32)> = self
+            .cache
+            .keys()
+            .filter_map(|key| {
+                let (subject, version) = key.rsplit_once(':')?;
+                Some((subject.to_string(), version.parse().ok()?))
+            })
+            .collect();
+        schemas.sort();
+        schemas
+    }
+
+    // load the <subject>/<version>.avsc files of a directory, returns how many were loaded
+    fn load_dir(&mut self, dir: &Path) -> Result<usize, SchemaRegistryError> {
+        if !dir.is_dir() {
+            warn!("schema directory {} does not exist", dir.display());
+            return Ok(0);
+        }
+        let mut count = 0;
+        for subject_dir in std::fs::read_dir(dir)? {
+            let subject_dir = subject_dir?.path();
+            if !subject_dir.is_dir() {
+                continue;
+            }
+            let Some(subject) = subject_dir.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            for file in std::fs::read_dir(&subject_dir)? {
+                let file = file?.path();
+                if file.extension().and_then(|ext| ext.to_str()) != Some("avsc") {
+                    continue;
+                }
+                let Some(version) = file
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse::<u32>().ok())
+                else {
+                    warn!("skipping schema file {}", file.display());
+                    continue;
+                };
+                let schema = Schema::parse_str(&std::fs::read_to_string(&file)?)?;
+                self.cache
+                    .insert(format!("{}:{}", subject, version), schema);
+                count += 1;
+            }
+        }
+        info!("loaded {} schemas from {}", count, dir.display());
+        Ok(count)
+    }
+
+    /// Add a schema to the registry, saving it to the cache directory if there is one.
+    pub fn add_schema(
+        &mut self,
         subject: &str,
         version: u32,
-    ) -> Result<Schema, SchemaRegistryError> {
-        let versions = self.get_versions(subject).await?;
-        if !versions.contains(&version) {
-            return Err(SchemaRegistryError::InvalidVersion);
+        schema_str: &str,
+    ) -> Result<(), SchemaRegistryError> {
+        let schema = Schema::parse_str(schema_str)?;
+        if let Some(cache_dir) = &self.cache_dir {
+            let subject_dir = cache_dir.join(subject);
+            std::fs::create_dir_all(&subject_dir)?;
+            std::fs::write(subject_dir.join(format!("{}.avsc", version)), schema_str)?;
         }
+        self.cache
+            .insert(format!("{}:{}", subject, version), schema);
+        Ok(())
+    }
 
+    // send a request to the registry, with credentials if we have some
+    async fn send(
+        &self,
+        mut request: reqwest::RequestBuilder,
+        path: &str,
+    ) -> Result<serde_json::Value, SchemaRegistryError> {
+        if self.offline {
+            return Err(SchemaRegistryError::Offline);
+        }
+        if let Some((username, password)) = &self.credentials {
+            request = request.basic_auth(username, Some(password));
+        }
+        let response = request.send().await?;
+        match response.status() {
+            reqwest::StatusCode::NOT_FOUND => Err(SchemaRegistryError::NotFound(path.to_string())),
+            reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => {
+                Err(SchemaRegistryError::Unauthorized)
+            }
+            _ => Ok(response.error_for_status()?.json().await?),
+        }
+    }
+
+    // GET a path of the registry
+    async fn get_json(&self, path: &str) -> Result<serde_json::Value, SchemaRegistryError> {
+        let request = self.client.get(format!("{}{}", &self.url, path));
+        self.send(request, path).await
+    }
+
+    pub async fn get_versions(&self, subject: &str) -> Result<Vec<u32>, SchemaRegistryError> {
         let response = self
-            .client
-            .get(&format!(
-                "{}/subjects/{}/versions/{}",
-                &self.url, subject, version
-            ))
-            .send()
+            .get_json(&format!("/subjects/{}/versions", subject))
             .await?;
+        let versions = serde_json::from_value(response)?;
+        Ok(versions)
+    }
 
-        let response = response.json::<serde_json::Value>().await?;
+    // fetch a version of a subject, returns its schema id and the schema
+    async fn fetch_version(
+        &self,
+        subject: &str,
+        version: u32,
+    ) -> Result<(Option<u32>, String), SchemaRegistryError> {
+        let response = self
+            .get_json(&format!("/subjects/{}/versions/{}", subject, version))
+            .await?;
 
         let schema_str = response["schema"]
             .as_str()
             .ok_or(SchemaRegistryError::InvalidResponse)?;
+        let schema_id = response["id"].as_u64().map(|id| id as u32);
 
-        let schema = Schema::parse_str(schema_str)?;
-        Ok(schema)
+        Ok((schema_id, schema_str.to_string()))
     }
 
+    /// Get a version of a subject.
     pub async fn get_schema(
         &mut self,
         subject: &str,
@@ -151,11 +388,108 @@ impl SchemaRegistry {
     ) -> Result<&Schema, SchemaRegistryError> {
         let key = format!("{}:{}", subject, version);
         if !self.cache.contains_key(&key) {
-            let schema = self._get_schema_by_id(subject, version).await?;
-            self.cache.insert(key.clone(), schema);
+            if self.offline {
+                return Err(SchemaRegistryError::SchemaNotCached(key));
+            }
+            let (schema_id, schema_str) = self.fetch_version(subject, version).await?;
+            self.add_schema(subject, version, &schema_str)?;
+            if let Some(schema_id) = schema_id {
+                self.add_schema(SCHEMA_IDS_SUBJECT, schema_id, &schema_str)?;
+            }
         }
         Ok(self.cache.get(&key).unwrap())
     }
+
+    /// Get a schema by its id, as found in the header of the packets in the wire format.
+    pub async fn get_schema_by_id(
+        &mut self,
+        schema_id: u32,
+    ) -> Result<&Schema, SchemaRegistryError> {
+        let key = format!("{}:{}", SCHEMA_IDS_SUBJECT, schema_id);
+        if !self.cache.contains_key(&key) {
+            if self.offline {
+                return Err(SchemaRegistryError::SchemaNotCached(key));
+            }
+            let response = self
+                .get_json(&format!("/schemas/ids/{}", schema_id))
+                .await?;
+            let schema_str = response["schema"]
+                .as_str()
+                .ok_or(SchemaRegistryError::InvalidResponse)?;
+            self.add_schema(SCHEMA_IDS_SUBJECT, schema_id, schema_str)?;
+        }
+        Ok(self.cache.get(&key).unwrap())
+    }
+
+    /// Decode a packet in the Confluent wire format: a magic byte, the 4-byte
+    /// id of the schema it was written with, then the avro datum. If there is
+    /// a reader schema, the datum is resolved against it.
+    pub async fn decode(&mut self, avro_bytes: &[u8]) -> Result<Value, SchemaRegistryError> {
+        if avro_bytes.len() < 5 || avro_bytes[0] != MAGIC_BYTE {
+            return Err(SchemaRegistryError::MagicBytesError);
+        }
+        let schema_id =
+            u32::from_be_bytes([avro_bytes[1], avro_bytes[2], avro_bytes[3], avro_bytes[4]]);
+        // cloned so that we can borrow the reader schema at the same time
+        let writer_schema = self.get_schema_by_id(schema_id).await?.clone();
+        let value = from_avro_datum(
+            &writer_schema,
+            &mut &avro_bytes[5..],
+            self.reader_schema.as_ref(),
+        )?;
+        Ok(value)
+    }
+
+    /// Register a schema under a subject, returns its schema id. Registering
+    /// a schema that is already registered gives back its existing id.
+    pub async fn register_schema(
+        &mut self,
+        subject: &str,
+        schema_str: &str,
+    ) -> Result<u32, SchemaRegistryError> {
+        let path = format!("/subjects/{}/versions", subject);
+        let request = self
+            .client
+            .post(format!("{}{}", &self.url, path))
+            .header(
+                reqwest::header::CONTENT_TYPE,
+                "application/vnd.schemaregistry.v1+json",
+            )
+            .json(&serde_json::json!({ "schema": schema_str }));
+        let response = self.send(request, &path).await?;
+        let schema_id = response["id"]
+            .as_u64()
+            .ok_or(SchemaRegistryError::InvalidResponse)? as u32;
+        self.add_schema(SCHEMA_IDS_SUBJECT, schema_id, schema_str)?;
+        Ok(schema_id)
+    }
+
+    /// Encode a value in the Confluent wire format, with a schema already held
+    /// by the registry (see `register_schema` and `get_schema_by_id`).
+    pub fn encode(&self, schema_id: u32, value: Value) -> Result<Vec<u8>, SchemaRegistryError> {
+        let key = format!("{}:{}", SCHEMA_IDS_SUBJECT, schema_id);
+        let schema = self
+            .cache
+            .get(&key)
+            .ok_or(SchemaRegistryError::SchemaNotCached(key))?;
+        let mut avro_bytes = vec![MAGIC_BYTE];
+        avro_bytes.extend_from_slice(&schema_id.to_be_bytes());
+        avro_bytes.extend(to_avro_datum(schema, value.resolve(schema)?)?);
+        Ok(avro_bytes)
+    }
+
+    /// Fetch all the versions of a subject not held yet, returns the versions fetched.
+    pub async fn sync(&mut self, subject: &str) -> Result<Vec<u32>, SchemaRegistryError> {
+        let mut fetched = Vec::new();
+        for version in self.get_versions(subject).await? {
+            if self.cache.contains_key(&format!("{}:{}", subject, version)) {
+                continue;
+            }
+            self.get_schema(subject, version).await?;
+            fetched.push(version);
+        }
+        Ok(fetched)
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -168,6 +502,431 @@ pub enum AlertWorkerError {
     Redis(#[from] redis::RedisError),
     #[error("failed to get avro bytes from the alert queue")]
     GetAvroBytesError,
+    #[error("stream {0} not found in config")]
+    UnknownStream(String),
+    #[error("failed to set up the schema registry")]
+    SchemaRegistry(#[from] SchemaRegistryError),
+    #[error("more than one stream in config, a stream name is required")]
+    AmbiguousStream,
+}
+
+/// The documents built from one alert packet, ready to be written to the database.
+pub struct PreparedAlert {
+    pub candid: i64,
+    pub object_id: Bson,
+    pub ra: f64,
+    pub dec: f64,
+    pub alert_doc: Document,
+    pub cutout_doc: Document,
+    pub prv_candidates: Vec<Document>,
+    pub prv_nondetections: Vec<Document>,
+    pub fp_hists: Vec<Document>,
+    pub survey_matches: Option<Document>,
+    pub dia_object: Option<Document>,
+    /// fields set on the aux document, overwritten by each new alert
+    pub aux_fields: Document,
+}
+
+/// The outcome of processing a batch of alert packets.
+#[derive(Debug, Default)]
+pub struct AlertBatchResult {
+    /// candids of the alerts that were not in the database yet, in batch order
+    pub ingested: Vec<i64>,
+    /// packets that could not be prepared, by index in the batch
+    pub failed: Vec<(usize, AlertError)>,
+    /// candids of the new solar system alerts, pushed to their own output queue
+    pub ss_ingested: Vec<i64>,
+    /// candids that were already in the database, with the time (jd) they were
+    /// written at; when retrying a packet, those written since the first attempt
+    /// are forwarded, as the failed attempt may have written them without
+    /// forwarding them
+    pub existing: Vec<(i64, f64)>,
+    /// same as `existing`, for the solar system alerts
+    pub ss_existing: Vec<(i64, f64)>,
+}
+
+impl AlertBatchResult {
+    fn extend(&mut self, other: AlertBatchResult) {
+        self.ingested.extend(other.ingested);
+        self.failed.extend(other.failed);
+        self.ss_ingested.extend(other.ss_ingested);
+        self.existing.extend(other.existing);
+        self.ss_existing.extend(other.ss_existing);
+    }
+
+    // forward the candids written since `since` (jd) along with the new ones, leaving
+    // out those that were in the database before, and have been forwarded already
+    fn forward_existing(&mut self, since: f64) {
+        self.ingested.extend(
+            self.existing
+                .drain(..)
+                .filter(|(_, created_at)| *created_at >= since)
+                .map(|(candid, _)| candid),
+        );
+        self.ss_ingested.extend(
+            self.ss_existing
+                .drain(..)
+                .filter(|(_, created_at)| *created_at >= since)
+                .map(|(candid, _)| candid),
+        );
+    }
+}
+
+/// The candids of a batch of alerts written by [`ingest_alert_batch`].
+#[derive(Debug, Default)]
+pub struct IngestedCandids {
+    /// candids of the alerts written by this batch, in batch order
+    pub new: Vec<i64>,
+    /// candids of the alerts that were already in the database, with their `created_at`
+    pub existing: Vec<(i64, f64)>,
+}
+
+fn is_duplicate_key_error(code: i32) -> bool {
+    code == 11000
+}
+
+// inserts the documents unordered, and returns the indices of those that
+// were already in the collection instead of failing on duplicate keys
+async fn insert_many_skip_existing(
+    collection: &mongodb::Collection<Document>,
+    documents: Vec<Document>,
+) -> Result<HashSet<usize>, mongodb::error::Error> {
+    if documents.is_empty() {
+        return Ok(HashSet::new());
+    }
+    match collection.insert_many(documents).ordered(false).await {
+        Ok(_) => Ok(HashSet::new()),
+        Err(e) => match e.kind.as_ref() {
+            ErrorKind::InsertMany(InsertManyError {
+                write_errors: Some(write_errors),
+                write_concern_error: None,
+                ..
+            }) if write_errors
+                .iter()
+                .all(|write_error| is_duplicate_key_error(write_error.code)) =>
+            {
+                Ok(write_errors
+                    .iter()
+                    .map(|write_error| write_error.index)
+                    .collect())
+            }
+            _ => Err(e),
+        },
+    }
+}
+
+// runs the models unordered, retrying once those that failed on a duplicate key,
+// which happens when two workers upsert the same new document concurrently
+async fn bulk_write_retry_duplicates(
+    client: &mongodb::Client,
+    models: Vec<UpdateOneModel>,
+) -> Result<(), mongodb::error::Error> {
+    if models.is_empty() {
+        return Ok(());
+    }
+    let error = match client.bulk_write(models.clone()).ordered(false).await {
+        Ok(_) => return Ok(()),
+        Err(e) => e,
+    };
+    let retry = match error.kind.as_ref() {
+        ErrorKind::BulkWrite(BulkWriteError {
+            write_errors,
+            write_concern_errors,
+            ..
+        }) if write_concern_errors.is_empty()
+            && write_errors
+                .values()
+                .all(|write_error| is_duplicate_key_error(write_error.code)) =>
+        {
+            write_errors
+                .keys()
+                .map(|index| models[*index].clone())
+                .collect::<Vec<_>>()
+        }
+        _ => return Err(error),
+    };
+    client.bulk_write(retry).ordered(false).await?;
+    Ok(())
+}
+
+// for each object of another survey that matched a new object, add the
+// new object to the aliases of its aux document unless it's already there,
+// keeping only the nearest ones if that survey limits its number of matches
+fn reverse_alias_models(
+    db: &mongodb::Database,
+    stream_name: &str,
+    object_id: &Bson,
+    survey_matches: &Document,
+    alias_limits: &HashMap<String, usize>,
+) -> Vec<UpdateOneModel> {
+    let alias_key = format!("aliases.{}", stream_name);
+    let mut models = Vec::new();
+    for (survey, matches) in survey_matches {
+        let namespace = db
+            .collection::<Document>(&format!("{}_alerts_aux", survey))
+            .namespace();
+        let max_matches = alias_limits.get(survey);
+        for survey_match in matches.as_array().into_iter().flatten() {
+            let Some(survey_match) = survey_match.as_document() else {
+                continue;
+            };
+            let (Some(match_id), Some(angular_separation)) = (
+                survey_match.get("objectId"),
+                survey_match.get("angular_separation"),
+            ) else {
+                continue;
+            };
+            let mut push_doc = doc! {
+                "$each": [{
+                    "objectId": object_id,
+                    "angular_separation": angular_separation,
+                }],
+                "$sort": { "angular_separation": 1 },
+            };
+            if let Some(max_matches) = max_matches {
+                push_doc.insert("$slice", *max_matches as i64);
+            }
+            models.push(
+                UpdateOneModel::builder()
+                    .namespace(namespace.clone())
+                    .filter(doc! {
+                        "_id": match_id,
+                        format!("{}.objectId", alias_key): { "$ne": object_id },
+                    })
+                    .update(doc! { "$push": { &alias_key: push_doc } })
+                    .build(),
+            );
+        }
+    }
+    models
+}
+
+// the latest LSST diaObject is kept on the aux document. It only replaces the stored
+// one if that one is not more recent, going by validityStart
+fn dia_object_filter(object_id: &Bson, dia_object: &Document) -> Document {
+    match dia_object.get("validityStart") {
+        Some(validity_start) => doc! {
+            "_id": object_id,
+            "$or": [
+                { "dia_object.validityStart": { "$lte": validity_start } },
+                { "dia_object.validityStart": null },
+            ],
+        },
+        None => doc! { "_id": object_id, "dia_object.validityStart": null },
+    }
+}
+
+fn validity_start(dia_object: &Document) -> f64 {
+    dia_object
+        .get_f64("validityStart")
+        .unwrap_or(f64::NEG_INFINITY)
+}
+
+/// Write a batch of prepared alerts, skipping the candids already in the database: the
+/// cutouts with an unordered insert, then one upsert per object in the aux collection,
+/// and the alerts last. Objects that are new to the aux collection are cross-matched with
+/// the catalogs first, and the objects of other surveys they were matched with get them
+/// as aliases in return, up to the number of matches with this stream they keep
+/// (`alias_limits`, see [`conf::build_alias_limits`]).
+///
+/// As the alerts are written last, an alert in the database has its cutouts and aux entry
+/// too, and a batch that failed midway is written again as a whole when it is retried
+/// (all the other writes are idempotent). Returns the candids that were newly ingested,
+/// and those that were already in the database.
+pub async fn ingest_alert_batch(
+    stream_name: &str,
+    prepared_alerts: Vec<PreparedAlert>,
+    alert_collection: &mongodb::Collection<Document>,
+    alert_cutout_collection: &mongodb::Collection<Document>,
+    alert_aux_collection: &mongodb::Collection<Document>,
+    xmatch_configs: &Vec<conf::CatalogXmatchConfig>,
+    alias_limits: &HashMap<String, usize>,
+    db: &mongodb::Database,
+    now: f64,
+) -> Result<IngestedCandids, AlertError> {
+    let start = std::time::Instant::now();
+    let candids = prepared_alerts
+        .iter()
+        .map(|alert| alert.candid)
+        .collect::<Vec<_>>();
+    let mut cursor = alert_collection
+        .find(doc! { "_id": { "$in": candids } })
+        .projection(doc! { "_id": 1, "created_at": 1 })
+        .await?;
+    let mut existing = HashMap::new();
+    while let Some(alert) = cursor.next().await {
+        let alert = alert?;
+        if let Some(candid) = alert.get("_id").and_then(Bson::as_i64) {
+            let created_at = alert.get_f64("created_at").unwrap_or(f64::NEG_INFINITY);
+            existing.insert(candid, created_at);
+        }
+    }
+    let nb_alerts = prepared_alerts.len();
+    let new_alerts = prepared_alerts
+        .into_iter()
+        .filter(|alert| !existing.contains_key(&alert.candid))
+        .collect::<Vec<_>>();
+    trace!(
+        "Checking which of {} alerts exist ({} new): {:?}",
+        nb_alerts,
+        new_alerts.len(),
+        start.elapsed()
+    );
+    if new_alerts.is_empty() {
+        return Ok(IngestedCandids {
+            new: vec![],
+            existing: existing.into_iter().collect(),
+        });
+    }
+
+    let start = std::time::Instant::now();
+    let cutout_docs = new_alerts
+        .iter()
+        .map(|alert| alert.cutout_doc.clone())
+        .collect::<Vec<_>>();
+    insert_many_skip_existing(alert_cutout_collection, cutout_docs).await?;
+    trace!("Inserting cutouts: {:?}", start.elapsed());
+
+    // group the alerts by object, so that each aux document gets a single upsert
+    let start = std::time::Instant::now();
+    let mut objects: Vec<(Bson, Vec<&PreparedAlert>)> = Vec::new();
+    let mut object_index: HashMap<String, usize> = HashMap::new();
+    for alert in &new_alerts {
+        match object_index.get(&alert.object_id.to_string()) {
+            Some(i) => objects[*i].1.push(alert),
+            None => {
+                object_index.insert(alert.object_id.to_string(), objects.len());
+                objects.push((alert.object_id.clone(), vec![alert]));
+            }
+        }
+    }
+
+    let object_ids = objects.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>();
+    let mut cursor = alert_aux_collection
+        .find(doc! { "_id": { "$in": object_ids } })
+        .projection(doc! { "_id": 1 })
+        .await?;
+    let mut existing_objects = HashSet::new();
+    while let Some(aux) = cursor.next().await {
+        if let Some(id) = aux?.get("_id") {
+            existing_objects.insert(id.to_string());
+        }
+    }
+    trace!("Checking which alert_aux exist: {:?}", start.elapsed());
+
+    let start = std::time::Instant::now();
+    let namespace = alert_aux_collection.namespace();
+    let mut models = Vec::with_capacity(objects.len());
+    for (object_id, alerts) in objects {
+        let mut prv_candidates = Vec::new();
+        let mut prv_nondetections = Vec::new();
+        let mut fp_hists = Vec::new();
+        let mut survey_matches = None;
+        let mut dia_object: Option<&Document> = None;
+        let mut aux_fields = Document::new();
+        for alert in &alerts {
+            prv_candidates.extend(alert.prv_candidates.iter().cloned());
+            prv_nondetections.extend(alert.prv_nondetections.iter().cloned());
+            fp_hists.extend(alert.fp_hists.iter().cloned());
+            if alert.survey_matches.is_some() {
+                survey_matches = alert.survey_matches.clone();
+            }
+            aux_fields.extend(alert.aux_fields.clone());
+            if let Some(alert_dia_object) = &alert.dia_object {
+                let newer = match dia_object {
+                    Some(d) => validity_start(alert_dia_object) >= validity_start(d),
+                    None => true,
+                };
+                if newer {
+                    dia_object = Some(alert_dia_object);
+                }
+            }
+        }
+
+        let mut set_doc = aux_fields;
+        set_doc.insert("updated_at", now);
+        if let Some(survey_matches) = &survey_matches {
+            // aliases are kept on both sides
+            for (survey, matches) in survey_matches {
+                set_doc.insert(format!("aliases.{}", survey), matches.clone());
+            }
+            models.extend(reverse_alias_models(
+                db,
+                stream_name,
+                &object_id,
+                survey_matches,
+                alias_limits,
+            ));
+        }
+        let mut update_doc = doc! {
+            "$addToSet": {
+                "prv_candidates": { "$each": prv_candidates },
+                "prv_nondetections": { "$each": prv_nondetections },
+                "fp_hists": { "$each": fp_hists },
+            },
+            "$set": set_doc,
+        };
+        if !existing_objects.contains(&object_id.to_string()) {
+            let (ra, dec) = (alerts[0].ra, alerts[0].dec);
+            let xmatches = xmatch(ra, dec, xmatch_configs, db).await?;
+            let mut set_on_insert_doc = doc! {
+                "cross_matches": xmatches,
+                "created_at": now,
+                "coordinates": {
+                    "radec_geojson": {
+                        "type": "Point",
+                        "coordinates": [ra - 180.0, dec],
+                    },
+                },
+            };
+            if let Some(dia_object) = dia_object {
+                set_on_insert_doc.insert("dia_object", dia_object.clone());
+            }
+            update_doc.insert("$setOnInsert", set_on_insert_doc);
+        }
+        // for objects that already exist (or were created by another worker meanwhile)
+        if let Some(dia_object) = dia_object {
+            models.push(
+                UpdateOneModel::builder()
+                    .namespace(namespace.clone())
+                    .filter(dia_object_filter(&object_id, dia_object))
+                    .update(doc! { "$set": { "dia_object": dia_object } })
+                    .build(),
+            );
+        }
+        models.push(
+            UpdateOneModel::builder()
+                .namespace(namespace.clone())
+                .filter(doc! { "_id": object_id })
+                .update(update_doc)
+                .upsert(true)
+                .build(),
+        );
+    }
+    trace!("Xmatching new objects: {:?}", start.elapsed());
+
+    let start = std::time::Instant::now();
+    bulk_write_retry_duplicates(db.client(), models).await?;
+    trace!("Upserting alert_aux & aliases: {:?}", start.elapsed());
+
+    // the alerts inserted by another worker in the meantime are its to forward
+    let start = std::time::Instant::now();
+    let alert_docs = new_alerts
+        .iter()
+        .map(|alert| alert.alert_doc.clone())
+        .collect::<Vec<_>>();
+    let inserted_meanwhile = insert_many_skip_existing(alert_collection, alert_docs).await?;
+    trace!("Inserting alerts: {:?}", start.elapsed());
+
+    Ok(IngestedCandids {
+        new: new_alerts
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !inserted_meanwhile.contains(i))
+            .map(|(_, alert)| alert.candid)
+            .collect(),
+        existing: existing.into_iter().collect(),
+    })
 }
 
 #[async_trait::async_trait]
@@ -178,47 +937,150 @@ pub trait AlertWorker {
         Self: Sized;
     fn stream_name(&self) -> String;
     fn input_queue_name(&self) -> String;
-    fn output_queue_name(&self) -> String;
-    async fn insert_aux(
-        self: &mut Self,
-        object_id: impl Into<Self::ObjectId> + Send,
-        ra: f64,
-        dec: f64,
-        prv_candidates_doc: &Vec<Document>,
-        prv_nondetections_doc: &Vec<Document>,
-        fp_hist_doc: &Vec<Document>,
-        survey_matches: &Option<Document>,
-        now: f64,
-    ) -> Result<(), AlertError>;
-    async fn update_aux(
+    /// The queue the new candids are pushed to, if anything consumes them.
+    fn output_queue_name(&self) -> Option<String>;
+    /// The queue the new solar system candids are pushed to, for the streams that have them.
+    fn ss_output_queue_name(&self) -> Option<String> {
+        None
+    }
+    async fn process_alerts(
         self: &mut Self,
-        object_id: impl Into<Self::ObjectId> + Send,
-        prv_candidates_doc: &Vec<Document>,
-        prv_nondetections_doc: &Vec<Document>,
-        fp_hist_doc: &Vec<Document>,
-        survey_matches: &Option<Document>,
-        now: f64,
-    ) -> Result<(), AlertError>;
-    async fn process_alert(self: &mut Self, avro_bytes: &[u8]) -> Result<i64, AlertError>;
+        avro_bytes: &[Vec<u8>],
+    ) -> Result<AlertBatchResult, AlertError>;
+    /// Process a single packet as a batch of one, returns the candid of the new alert.
+    async fn process_alert(self: &mut Self, avro_bytes: &[u8]) -> Result<i64, AlertError>
+    where
+        Self: Send,
+    {
+        let mut result = self.process_alerts(&[avro_bytes.to_vec()]).await?;
+        if let Some((_, error)) = result.failed.pop() {
+            return Err(error);
+        }
+        result
+            .ingested
+            .into_iter()
+            .chain(result.ss_ingested)
+            .next()
+            .ok_or(AlertError::AlertExists)
+    }
+}
+
+// number of packets popped from the queue and ingested together
+pub const DEFAULT_BATCH_SIZE: usize = 100;
+
+// read workers.<stream>.alert.batch_size from the config, if set
+pub fn get_batch_size(conf: &config::Config, stream_name: &str) -> usize {
+    conf.get_int(&format!("workers.{}.alert.batch_size", stream_name))
+        .map(|batch_size| batch_size.max(1) as usize)
+        .unwrap_or(DEFAULT_BATCH_SIZE)
+}
+
+// process a single packet (a batch of one), retrying transient errors and
+// dead-lettering it if it can't be ingested. It is only called after a failed
+// attempt, so an alert written since the first attempt (`since`, in jd) is forwarded again
+async fn process_packet<T: AlertWorker>(
+    alert_processor: &mut T,
+    packet: &[Vec<u8>],
+    dead_letter_queue: &DeadLetterQueue,
+    max_retries: u32,
+    worker_id: &str,
+    since: f64,
+) -> Result<AlertBatchResult, AlertError> {
+    // retry transient errors (e.g. database timeouts) a bounded number of times
+    let mut attempts = 0;
+    let result = loop {
+        attempts += 1;
+        let result = match alert_processor.process_alerts(packet).await {
+            Ok(mut batch) => match batch.failed.pop() {
+                Some((_, error)) => Err(error),
+                None => Ok(batch),
+            },
+            Err(error) => Err(error),
+        };
+        match result {
+            Err(error) if error.is_transient() && attempts <= max_retries => {
+                warn!(error = %error, attempts, "Transient error processing alert, retrying");
+                tokio::time::sleep(tokio::time::Duration::from_millis(500 * attempts as u64)).await;
+            }
+            result => break result,
+        }
+    };
+    match result {
+        Ok(mut batch) => {
+            batch.forward_existing(since);
+            Ok(batch)
+        }
+        Err(error) => {
+            warn!(error = %error, attempts, "Error processing alert, moving it to the dead letter queue");
+            match dead_letter_queue
+                .push(&packet[0], &error, attempts, worker_id)
+                .await
+            {
+                Ok(_) => Ok(AlertBatchResult::default()),
+                // we keep the packet in the temp queue rather than losing it
+                Err(e) => {
+                    error!(error = %e, "Failed to store alert in the dead letter queue");
+                    Err(error)
+                }
+            }
+        }
+    }
 }
 
 #[tokio::main]
 pub async fn run_alert_worker<T: AlertWorker>(
+    id: String,
+    receiver: mpsc::Receiver<WorkerCmd>,
+    config_path: &str,
+) -> Result<(), AlertWorkerError> {
+    let alert_processor = T::new(config_path).await?;
+    alert_worker_loop(alert_processor, id, receiver, config_path).await
+}
+
+/// Run an alert worker for a stream described in the `streams` section of the config.
+#[tokio::main]
+pub async fn run_generic_alert_worker(
+    id: String,
+    receiver: mpsc::Receiver<WorkerCmd>,
+    config_path: &str,
+    stream_name: &str,
+) -> Result<(), AlertWorkerError> {
+    let alert_processor = GenericAlertWorker::from_config(config_path, stream_name).await?;
+    alert_worker_loop(alert_processor, id, receiver, config_path).await
+}
+
+async fn alert_worker_loop<T: AlertWorker>(
+    mut alert_processor: T,
     id: String,
     mut receiver: mpsc::Receiver<WorkerCmd>,
     config_path: &str,
 ) -> Result<(), AlertWorkerError> {
     let config = conf::load_config(config_path)?;
 
-    let mut alert_processor = T::new(config_path).await?;
     let stream_name = alert_processor.stream_name();
 
     let input_queue_name = alert_processor.input_queue_name();
-    let temp_queue_name = format!("{}_temp", input_queue_name);
+    let temp_queue_name = temp_queue_name(&input_queue_name, &id);
     let output_queue_name = alert_processor.output_queue_name();
+    let ss_output_queue_name = alert_processor.ss_output_queue_name();
 
     let mut con = conf::build_redis(&config).await?;
 
+    let db = conf::build_db(&config).await?;
+    let dead_letter_queue = DeadLetterQueue::new(&db, &stream_name);
+    let max_retries = get_max_retries(&config, &stream_name);
+    let batch_size = get_batch_size(&config, &stream_name);
+
+    // register this worker before taking any packets, then give back
+    // the packets held by workers that died without finishing them
+    send_heartbeat(&mut con, &temp_queue_name).await?;
+    reclaim_orphaned_packets(&mut con, &input_queue_name).await?;
+    // the heartbeat is refreshed in the background, so that it doesn't expire while
+    // a batch is being processed or retried
+    let heartbeat = spawn_heartbeat(con.clone(), temp_queue_name.clone());
+    let reclaim_interval = std::time::Duration::from_secs(HEARTBEAT_TTL_SECS / 4);
+    let mut last_reclaim = std::time::Instant::now();
+
     let command_interval: i64 = 500;
     let mut command_check_countdown = command_interval;
     let mut count = 0;
@@ -226,7 +1088,7 @@ pub async fn run_alert_worker<T: AlertWorker>(
     let start = std::time::Instant::now();
     loop {
         // check for command from threadpool
-        if command_check_countdown == 0 {
+        if command_check_countdown <= 0 {
             match receiver.try_recv() {
                 Ok(WorkerCmd::TERM) => {
                     info!("alert worker {} received termination command", id);
@@ -241,48 +1103,102 @@ pub async fn run_alert_worker<T: AlertWorker>(
                 }
             }
         }
-        // retrieve candids from redis
-        let Some(mut value): Option<Vec<Vec<u8>>> =
-            con.rpoplpush(&input_queue_name, &temp_queue_name).await?
-        else {
+        // look for dead workers' packets
+        if last_reclaim.elapsed() >= reclaim_interval {
+            reclaim_orphaned_packets(&mut con, &input_queue_name).await?;
+            last_reclaim = std::time::Instant::now();
+        }
+        // retrieve a batch of packets from redis
+        let mut packets: Vec<Vec<u8>> = Vec::with_capacity(batch_size);
+        while packets.len() < batch_size {
+            let Some(packet): Option<Vec<u8>> =
+                con.rpoplpush(&input_queue_name, &temp_queue_name).await?
+            else {
+                break;
+            };
+            packets.push(packet);
+        }
+        if packets.is_empty() {
             info!("ALERT WORKER {}: Queue is empty", id);
             tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
             command_check_countdown = 0;
             continue;
+        }
+        let batch_len = packets.len();
+
+        // ingest the batch, retrying transient errors a bounded number of times
+        let batch_start = Time::now().to_jd();
+        let mut attempts = 0;
+        let result = loop {
+            attempts += 1;
+            match alert_processor.process_alerts(&packets).await {
+                Err(error) if error.is_transient() && attempts <= max_retries => {
+                    warn!(error = %error, attempts, "Transient error processing alert batch, retrying");
+                    tokio::time::sleep(tokio::time::Duration::from_millis(500 * attempts as u64))
+                        .await;
+                }
+                result => break result,
+            }
         };
-        let avro_bytes = (!value.is_empty())
-            .then_some(value.remove(0))
-            .ok_or(AlertWorkerError::GetAvroBytesError)?;
 
-        let result = alert_processor.process_alert(&avro_bytes).await;
-        match result {
-            Ok(candid) => {
-                // queue the candid for processing by the classifier
-                con.lpush::<&str, i64, isize>(&output_queue_name, candid)
+        // packets that failed on their own, or all of them if the batch failed,
+        // go through the single alert path which retries and dead-letters them
+        let mut processed = AlertBatchResult::default();
+        let retry_individually: Vec<usize> = match result {
+            Ok(mut batch) => {
+                if attempts > 1 {
+                    batch.forward_existing(batch_start);
+                }
+                processed.ingested = batch.ingested;
+                processed.ss_ingested = batch.ss_ingested;
+                batch.failed.into_iter().map(|(i, _)| i).collect()
+            }
+            Err(error) => {
+                warn!(error = %error, "Error processing alert batch, processing alerts one by one");
+                (0..packets.len()).collect()
+            }
+        };
+        let mut keep = HashSet::new();
+        for i in retry_individually {
+            match process_packet(
+                &mut alert_processor,
+                &packets[i..i + 1],
+                &dead_letter_queue,
+                max_retries,
+                &id,
+                batch_start,
+            )
+            .await
+            {
+                Ok(batch) => processed.extend(batch),
+                Err(_) => {
+                    keep.insert(i);
+                }
+            }
+        }
+
+        // queue the new candids for the next step of the pipeline
+        if let Some(output_queue_name) = &output_queue_name {
+            if !processed.ingested.is_empty() {
+                con.lpush::<&str, Vec<i64>, isize>(output_queue_name, processed.ingested)
                     .await?;
-                con.lrem::<&str, Vec<u8>, isize>(&temp_queue_name, 1, avro_bytes)
+            }
+        }
+        if let Some(ss_output_queue_name) = &ss_output_queue_name {
+            if !processed.ss_ingested.is_empty() {
+                con.lpush::<&str, Vec<i64>, isize>(ss_output_queue_name, processed.ss_ingested)
+                    .await?;
+            }
+        }
+        for (i, packet) in packets.into_iter().enumerate() {
+            if !keep.contains(&i) {
+                con.lrem::<&str, Vec<u8>, isize>(&temp_queue_name, 1, packet)
                     .await?;
             }
-            Err(error) => match error {
-                AlertError::AlertExists => {
-                    trace!("Alert already exists");
-                    con.lrem::<&str, Vec<u8>, isize>(&temp_queue_name, 1, avro_bytes)
-                        .await?;
-                }
-                _ => {
-                    warn!(error = %error, "Error processing alert, skipping");
-                    // TODO: Handle alerts that we could not parse from avro
-                    // so we don't re-push them to the queue
-                    // con.lpush::<&str, Vec<u8>, isize>(&input_queue_name, avro_bytes.clone())
-                    //     .await
-                    //     .map_err(AlertWorkerError::PushAlertError)?;
-                    // con.lrem::<&str, Vec<u8>, isize>(&temp_queue_name, 1, avro_bytes)
-                    //     .await
-                    //     .map_err(AlertWorkerError::RemoveAlertError)?;
-                }
-            },
         }
-        if count % 1000 == 0 {
+        let previous_count = count;
+        count += batch_len;
+        if count / 1000 > previous_count / 1000 {
             let elapsed = start.elapsed().as_secs();
             info!(
                 "\nProcessed {} {} alerts in {} seconds, avg: {:.4} alerts/s\n",
@@ -292,8 +1208,9 @@ pub async fn run_alert_worker<T: AlertWorker>(
                 count as f64 / elapsed as f64
             );
         }
-        count += 1;
-        command_check_countdown -= 1;
+        command_check_countdown -= batch_len as i64;
     }
+    drop(heartbeat);
+    clear_heartbeat(&mut con, &temp_queue_name).await?;
     Ok(())
 }
@@ -0,0 +1,157 @@
+use crate::{alert::base::AlertError, conf};
+use flare::Time;
+use futures::stream::StreamExt;
+use mongodb::bson::{doc, oid::ObjectId, Binary, Document};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+// number of times a packet is retried on transient errors before it is dead-lettered
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+
+#[derive(thiserror::Error, Debug)]
+pub enum DeadLetterError {
+    #[error("error from mongodb")]
+    Mongodb(#[from] mongodb::error::Error),
+    #[error("error from redis")]
+    Redis(#[from] redis::RedisError),
+    #[error("dead letter not found")]
+    NotFound,
+}
+
+/// A packet that could not be ingested, with enough context to debug it and re-inject it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub stream: String,
+    pub packet: Binary,
+    pub error_kind: String,
+    pub error_message: String,
+    pub attempts: u32,
+    pub worker_id: String,
+    pub created_at: f64,
+}
+
+pub fn dead_letter_collection_name(stream_name: &str) -> String {
+    format!("{}_alerts_dead_letter", stream_name)
+}
+
+// read workers.<stream>.alert.max_retries from the config, if set
+pub fn get_max_retries(conf: &config::Config, stream_name: &str) -> u32 {
+    conf.get_int(&format!("workers.{}.alert.max_retries", stream_name))
+        .map(|max_retries| max_retries.max(0) as u32)
+        .unwrap_or(DEFAULT_MAX_RETRIES)
+}
+
+// the display message of an error, followed by the messages of its sources,
+// since our error variants on their own only say where the error came from
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(error) = source {
+        message.push_str(": ");
+        message.push_str(&error.to_string());
+        source = error.source();
+    }
+    message
+}
+
+pub struct DeadLetterQueue {
+    stream_name: String,
+    collection: mongodb::Collection<DeadLetter>,
+}
+
+impl DeadLetterQueue {
+    pub fn new(db: &mongodb::Database, stream_name: &str) -> Self {
+        DeadLetterQueue {
+            stream_name: stream_name.to_string(),
+            collection: db.collection(&dead_letter_collection_name(stream_name)),
+        }
+    }
+
+    pub async fn from_config(
+        config_path: &str,
+        stream_name: &str,
+    ) -> Result<Self, conf::BoomConfigError> {
+        let config = conf::load_config(config_path)?;
+        let db = conf::build_db(&config).await?;
+        Ok(DeadLetterQueue::new(&db, stream_name))
+    }
+
+    /// Store a packet that failed to ingest, returns the id of the dead letter.
+    pub async fn push(
+        &self,
+        avro_bytes: &[u8],
+        error: &AlertError,
+        attempts: u32,
+        worker_id: &str,
+    ) -> Result<ObjectId, DeadLetterError> {
+        let dead_letter = DeadLetter {
+            id: ObjectId::new(),
+            stream: self.stream_name.clone(),
+            packet: Binary {
+                subtype: mongodb::bson::spec::BinarySubtype::Generic,
+                bytes: avro_bytes.to_vec(),
+            },
+            error_kind: error.kind().to_string(),
+            error_message: error_chain(error),
+            attempts,
+            worker_id: worker_id.to_string(),
+            created_at: Time::now().to_jd(),
+        };
+        self.collection.insert_one(&dead_letter).await?;
+        Ok(dead_letter.id)
+    }
+
+    /// List dead letters, most recent first, optionally only those with a given error kind.
+    pub async fn list(
+        &self,
+        error_kind: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<DeadLetter>, DeadLetterError> {
+        let mut cursor = self
+            .collection
+            .find(Self::kind_filter(error_kind))
+            .sort(doc! { "created_at": -1 })
+            .limit(limit)
+            .await?;
+
+        let mut dead_letters = Vec::new();
+        while let Some(dead_letter) = cursor.next().await {
+            dead_letters.push(dead_letter?);
+        }
+        Ok(dead_letters)
+    }
+
+    pub async fn count(&self, error_kind: Option<&str>) -> Result<u64, DeadLetterError> {
+        Ok(self
+            .collection
+            .count_documents(Self::kind_filter(error_kind))
+            .await?)
+    }
+
+    pub async fn get(&self, id: ObjectId) -> Result<Option<DeadLetter>, DeadLetterError> {
+        Ok(self.collection.find_one(doc! { "_id": id }).await?)
+    }
+
+    /// Push the packet of a dead letter back onto `queue_name` and remove it from the store.
+    pub async fn reinject(
+        &self,
+        id: ObjectId,
+        con: &mut redis::aio::MultiplexedConnection,
+        queue_name: &str,
+    ) -> Result<(), DeadLetterError> {
+        let dead_letter = self.get(id).await?.ok_or(DeadLetterError::NotFound)?;
+        con.rpush::<&str, Vec<u8>, usize>(queue_name, dead_letter.packet.bytes)
+            .await?;
+        self.collection.delete_one(doc! { "_id": id }).await?;
+        Ok(())
+    }
+
+    fn kind_filter(error_kind: Option<&str>) -> Document {
+        match error_kind {
+            Some(error_kind) => doc! { "error_kind": error_kind },
+            None => doc! {},
+        }
+    }
+}
@@ -0,0 +1,555 @@
+use apache_avro::{from_avro_datum, types::Value, Schema};
+use flare::Time;
+use mongodb::bson::{doc, Bson, Document};
+use serde::Deserialize;
+use std::collections::HashMap;
+use tracing::{error, trace};
+
+use crate::{
+    alert::{
+        base::{
+            ingest_alert_batch, AlertBatchResult, AlertError, AlertWorker, AlertWorkerError,
+            PreparedAlert, SchemaRegistry,
+        },
+        ztf::get_schema_and_startidx,
+    },
+    conf,
+    utils::{
+        conversions::{flux2mag, fluxerr2diffmaglim},
+        db::{cutout2bsonbinary, get_coordinates},
+        spatial::get_survey_matches,
+    },
+};
+
+/// How to find the schema of the packets of a stream.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "source", rename_all = "lowercase")]
+pub enum SchemaSource {
+    /// Avro object container files, with the schema in the header
+    Ocf,
+    /// Confluent wire format: a magic byte and a schema id, followed by the datum
+    Registry { url: String },
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeFormat {
+    #[default]
+    Jd,
+    Mjd,
+}
+
+/// Where the fields we need live in the packets of a stream.
+///
+/// `candid`, `object_id`, `candidate`, the previous candidates and the cutouts
+/// are paths from the root of the packet, separated by dots. The other fields
+/// are paths inside of a candidate, as they are read from both the candidate
+/// and its previous candidates.
+#[derive(Debug, Clone, Deserialize)]
+pub struct StreamFields {
+    pub candid: String,
+    pub object_id: String,
+    pub candidate: String,
+    pub ra: String,
+    pub dec: String,
+    pub jd: String,
+    pub band: Option<String>,
+    pub prv_candidates: Option<String>,
+    pub prv_nondetections: Option<String>,
+    pub fp_hists: Option<String>,
+    pub cutout_science: Option<String>,
+    pub cutout_template: Option<String>,
+    pub cutout_difference: Option<String>,
+}
+
+fn default_zero_point() -> f64 {
+    8.9 // AB zero point for fluxes in Jy
+}
+
+// AB zero point of the stored fluxes, in µJy like the output photometry of the filter workers
+const STORED_FLUX_ZP: f64 = 23.9;
+
+/// How the brightness of a detection is given, so that we can store both
+/// fluxes (psfFlux, psfFluxErr, in µJy) and magnitudes (magpsf, sigmapsf),
+/// along with isdiffpos, snr and diffmaglim.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "system", rename_all = "lowercase")]
+pub enum PhotometryConvention {
+    Flux {
+        flux: String,
+        flux_err: String,
+        /// AB zero point of the fluxes in the packets
+        #[serde(default = "default_zero_point")]
+        zero_point: f64,
+    },
+    Mag {
+        mag: String,
+        mag_err: String,
+        /// whether the difference is positive, true when not given
+        isdiffpos: Option<String>,
+        /// the limiting magnitude, derived from mag_err when not given
+        diffmaglim: Option<String>,
+    },
+}
+
+/// The `streams.<name>` section of the config, describing a survey
+/// ingested by the `GenericAlertWorker`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct StreamMapping {
+    pub schema: SchemaSource,
+    pub fields: StreamFields,
+    pub photometry: PhotometryConvention,
+    #[serde(default)]
+    pub time_format: TimeFormat,
+}
+
+impl StreamMapping {
+    pub fn from_config(
+        conf: &config::Config,
+        stream_name: &str,
+    ) -> Result<StreamMapping, AlertWorkerError> {
+        if !conf::get_stream_names(conf).contains(&stream_name.to_string()) {
+            return Err(AlertWorkerError::UnknownStream(stream_name.to_string()));
+        }
+        let mapping = conf
+            .get::<StreamMapping>(&format!("streams.{}", stream_name))
+            .map_err(conf::BoomConfigError::from)?;
+        Ok(mapping)
+    }
+}
+
+// convert a decoded avro value to bson, keeping the field names of the schema
+fn avro_to_bson(value: Value) -> Bson {
+    match value {
+        Value::Null => Bson::Null,
+        Value::Boolean(b) => Bson::Boolean(b),
+        Value::Int(i) | Value::Date(i) | Value::TimeMillis(i) => Bson::Int32(i),
+        Value::Long(i)
+        | Value::TimeMicros(i)
+        | Value::TimestampMillis(i)
+        | Value::TimestampMicros(i)
+        | Value::TimestampNanos(i)
+        | Value::LocalTimestampMillis(i)
+        | Value::LocalTimestampMicros(i)
+        | Value::LocalTimestampNanos(i) => Bson::Int64(i),
+        Value::Float(f) => Bson::Double(f as f64),
+        Value::Double(f) => Bson::Double(f),
+        Value::Bytes(bytes) | Value::Fixed(_, bytes) => Bson::Binary(cutout2bsonbinary(bytes)),
+        Value::String(s) | Value::Enum(_, s) => Bson::String(s),
+        Value::Uuid(uuid) => Bson::String(uuid.to_string()),
+        Value::Union(_, value) => avro_to_bson(*value),
+        Value::Array(values) => Bson::Array(values.into_iter().map(avro_to_bson).collect()),
+        Value::Map(map) => Bson::Document(
+            map.into_iter()
+                .map(|(key, value)| (key, avro_to_bson(value)))
+                .collect(),
+        ),
+        Value::Record(fields) => Bson::Document(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key, avro_to_bson(value)))
+                .collect(),
+        ),
+        // decimals and durations don't show up in alert packets
+        _ => Bson::Null,
+    }
+}
+
+// look up a dot-separated path in a document
+fn get_path<'a>(doc: &'a Document, path: &str) -> Option<&'a Bson> {
+    let mut parts = path.split('.');
+    let mut value = doc.get(parts.next()?)?;
+    for part in parts {
+        value = value.as_document()?.get(part)?;
+    }
+    match value {
+        Bson::Null => None,
+        value => Some(value),
+    }
+}
+
+fn get_f64(doc: &Document, path: &str) -> Option<f64> {
+    match get_path(doc, path)? {
+        Bson::Double(f) => Some(*f),
+        Bson::Int32(i) => Some(*i as f64),
+        Bson::Int64(i) => Some(*i as f64),
+        _ => None,
+    }
+}
+
+fn require_f64(doc: &Document, path: &str) -> Result<f64, AlertError> {
+    get_f64(doc, path).ok_or(AlertError::MissingField(path.to_string()))
+}
+
+// a boolean that may be given as a string (e.g. "t" or "1", as in ZTF packets)
+fn get_bool(doc: &Document, path: &str) -> Option<bool> {
+    match get_path(doc, path)? {
+        Bson::Boolean(b) => Some(*b),
+        Bson::String(s) => Some(matches!(s.as_str(), "t" | "T" | "1" | "true" | "True")),
+        Bson::Int32(i) => Some(*i > 0),
+        Bson::Int64(i) => Some(*i > 0),
+        _ => None,
+    }
+}
+
+// store the brightness of a detection given its flux (signed) and flux error in µJy
+fn insert_photometry(candidate: &mut Document, flux: f64, flux_err: f64) {
+    let (magpsf, sigmapsf) = flux2mag(flux.abs() as f32, flux_err as f32, STORED_FLUX_ZP as f32);
+    candidate.insert("psfFlux", flux);
+    candidate.insert("psfFluxErr", flux_err);
+    candidate.insert("magpsf", magpsf as f64);
+    candidate.insert("sigmapsf", sigmapsf as f64);
+    candidate.insert("isdiffpos", flux > 0.0);
+    candidate.insert("snr", flux.abs() / flux_err);
+}
+
+fn get_documents(doc: &Document, path: &Option<String>) -> Vec<Document> {
+    let Some(path) = path else {
+        return vec![];
+    };
+    match get_path(doc, path) {
+        Some(Bson::Array(values)) => values
+            .iter()
+            .filter_map(|value| value.as_document().cloned())
+            .collect(),
+        _ => vec![],
+    }
+}
+
+// cutouts are either raw bytes or, as for ZTF, a record holding the bytes in stampData
+fn get_cutout(doc: &Document, path: &str) -> Result<Bson, AlertError> {
+    match get_path(doc, path) {
+        Some(Bson::Binary(binary)) => Ok(Bson::Binary(binary.clone())),
+        Some(Bson::Document(cutout)) => match cutout.get("stampData") {
+            Some(Bson::Binary(binary)) => Ok(Bson::Binary(binary.clone())),
+            _ => Err(AlertError::MissingCutout),
+        },
+        _ => Err(AlertError::MissingCutout),
+    }
+}
+
+pub struct GenericAlertWorker {
+    stream_name: String,
+    mapping: StreamMapping,
+    schema_registry: Option<SchemaRegistry>,
+    cached_schema: Option<Schema>,
+    cached_start_idx: Option<usize>,
+    xmatch_configs: Vec<conf::CatalogXmatchConfig>,
+    survey_xmatch_configs: Vec<conf::SurveyXmatchConfig>,
+    alias_limits: HashMap<String, usize>,
+    db: mongodb::Database,
+    alert_collection: mongodb::Collection<Document>,
+    alert_aux_collection: mongodb::Collection<Document>,
+    alert_cutout_collection: mongodb::Collection<Document>,
+}
+
+impl GenericAlertWorker {
+    /// Create a worker for a stream described in the `streams` section of the config.
+    pub async fn from_config(
+        config_path: &str,
+        stream_name: &str,
+    ) -> Result<GenericAlertWorker, AlertWorkerError> {
+        let config_file = conf::load_config(config_path)?;
+
+        let mapping = StreamMapping::from_config(&config_file, stream_name)?;
+        let schema_registry = match &mapping.schema {
+            SchemaSource::Registry { url, .. } => {
+                Some(SchemaRegistry::from_config(&config_file, stream_name, url)?)
+            }
+            SchemaSource::Ocf => None,
+        };
+
+        let xmatch_configs = conf::build_xmatch_configs(&config_file, stream_name)?;
+        let survey_xmatch_configs = conf::build_survey_xmatch_configs(&config_file, stream_name)?;
+        let alias_limits = conf::build_alias_limits(&config_file, stream_name)?;
+
+        let db: mongodb::Database = conf::build_db(&config_file).await?;
+
+        let alert_collection = db.collection(&format!("{}_alerts", stream_name));
+        let alert_aux_collection = db.collection(&format!("{}_alerts_aux", stream_name));
+        let alert_cutout_collection = db.collection(&format!("{}_alerts_cutouts", stream_name));
+
+        Ok(GenericAlertWorker {
+            stream_name: stream_name.to_string(),
+            mapping,
+            schema_registry,
+            cached_schema: None,
+            cached_start_idx: None,
+            xmatch_configs,
+            survey_xmatch_configs,
+            alias_limits,
+            db,
+            alert_collection,
+            alert_aux_collection,
+            alert_cutout_collection,
+        })
+    }
+
+    async fn value_from_avro_bytes(&mut self, avro_bytes: &[u8]) -> Result<Value, AlertError> {
+        match &self.mapping.schema {
+            SchemaSource::Ocf => {
+                // same as for ZTF, we keep the schema of the last packet
+                // and only read it again if it no longer works
+                if let (Some(schema), Some(start_idx)) =
+                    (self.cached_schema.as_ref(), self.cached_start_idx)
+                {
+                    let mut datum = avro_bytes
+                        .get(start_idx..)
+                        .ok_or(AlertError::TruncatedPacket)?;
+                    match from_avro_datum(schema, &mut datum, None) {
+                        Ok(value) => return Ok(value),
+                        Err(e) => {
+                            error!("Error deserializing avro message with cached schema: {}", e);
+                        }
+                    }
+                }
+                let (schema, start_idx) = get_schema_and_startidx(avro_bytes)?;
+                let value = from_avro_datum(&schema, &mut &avro_bytes[start_idx..], None)?;
+                self.cached_schema = Some(schema);
+                self.cached_start_idx = Some(start_idx);
+                Ok(value)
+            }
+            SchemaSource::Registry { .. } => {
+                let schema_registry = self
+                    .schema_registry
+                    .as_mut()
+                    .ok_or(AlertError::MissingSchemaRegistry)?;
+                Ok(schema_registry.decode(avro_bytes).await?)
+            }
+        }
+    }
+
+    // read the time and band of a candidate in the stream's conventions,
+    // and add the brightness in the convention it is missing.
+    // Returns false if the candidate has no brightness (a non-detection).
+    fn normalize_candidate(&self, candidate: &mut Document) -> Result<bool, AlertError> {
+        let fields = &self.mapping.fields;
+        let jd = require_f64(candidate, &fields.jd)?;
+        let jd = match self.mapping.time_format {
+            TimeFormat::Jd => jd,
+            TimeFormat::Mjd => jd + 2400000.5,
+        };
+        candidate.insert("jd", jd);
+        if let Some(band_path) = &fields.band {
+            if let Some(band) = get_path(candidate, band_path).cloned() {
+                candidate.insert("band", band);
+            }
+        }
+
+        match &self.mapping.photometry {
+            PhotometryConvention::Flux {
+                flux,
+                flux_err,
+                zero_point,
+            } => {
+                let Some(flux_err) = get_f64(candidate, flux_err) else {
+                    return Ok(false);
+                };
+                // fluxes are converted to µJy
+                let scale = 10.0_f64.powf(0.4 * (STORED_FLUX_ZP - zero_point));
+                let flux_err = flux_err * scale;
+                candidate.insert(
+                    "diffmaglim",
+                    fluxerr2diffmaglim(flux_err as f32, STORED_FLUX_ZP as f32) as f64,
+                );
+                let Some(flux) = get_f64(candidate, flux) else {
+                    return Ok(false);
+                };
+                insert_photometry(candidate, flux * scale, flux_err);
+            }
+            PhotometryConvention::Mag {
+                mag,
+                mag_err,
+                isdiffpos,
+                diffmaglim,
+            } => {
+                let (Some(mag), Some(mag_err)) =
+                    (get_f64(candidate, mag), get_f64(candidate, mag_err))
+                else {
+                    return Ok(false);
+                };
+                let flux = 10.0_f64.powf(-0.4 * (mag - STORED_FLUX_ZP));
+                let flux_err = flux * mag_err * 10.0_f64.ln() / 2.5;
+                let positive = isdiffpos
+                    .as_ref()
+                    .and_then(|path| get_bool(candidate, path))
+                    .unwrap_or(true);
+                let diffmaglim = diffmaglim
+                    .as_ref()
+                    .and_then(|path| get_f64(candidate, path))
+                    .unwrap_or_else(|| {
+                        fluxerr2diffmaglim(flux_err as f32, STORED_FLUX_ZP as f32) as f64
+                    });
+                insert_photometry(candidate, if positive { flux } else { -flux }, flux_err);
+                // keep the magnitudes as given rather than their round trip through fluxes
+                candidate.insert("magpsf", mag);
+                candidate.insert("sigmapsf", mag_err);
+                candidate.insert("diffmaglim", diffmaglim);
+            }
+        }
+        Ok(true)
+    }
+
+    // decode a packet and build the documents to write for it
+    async fn prepare_alert(
+        &mut self,
+        avro_bytes: &[u8],
+        now: f64,
+    ) -> Result<PreparedAlert, AlertError> {
+        let start = std::time::Instant::now();
+
+        let packet = match avro_to_bson(self.value_from_avro_bytes(avro_bytes).await?) {
+            Bson::Document(packet) => packet,
+            _ => return Err(AlertError::MissingField("candidate".to_string())),
+        };
+
+        trace!("Decoding alert: {:?}", start.elapsed());
+
+        let start = std::time::Instant::now();
+
+        let fields = &self.mapping.fields;
+        let candid = match get_path(&packet, &fields.candid) {
+            Some(Bson::Int64(candid)) => *candid,
+            Some(Bson::Int32(candid)) => *candid as i64,
+            _ => return Err(AlertError::MissingField(fields.candid.clone())),
+        };
+        let object_id = get_path(&packet, &fields.object_id)
+            .cloned()
+            .ok_or(AlertError::MissingObjectId)?;
+        let mut candidate_doc = get_path(&packet, &fields.candidate)
+            .and_then(|candidate| candidate.as_document())
+            .cloned()
+            .ok_or(AlertError::MissingField(fields.candidate.clone()))?;
+        let ra = require_f64(&candidate_doc, &fields.ra)?;
+        let dec = require_f64(&candidate_doc, &fields.dec)?;
+
+        let mut cutout_doc = doc! { "_id": &candid };
+        for (key, path) in [
+            ("cutoutScience", &fields.cutout_science),
+            ("cutoutTemplate", &fields.cutout_template),
+            ("cutoutDifference", &fields.cutout_difference),
+        ] {
+            if let Some(path) = path {
+                cutout_doc.insert(key, get_cutout(&packet, path)?);
+            }
+        }
+
+        let mut prv_candidates_doc = vec![];
+        let mut prv_nondetections_doc = vec![];
+        for mut prv_candidate in get_documents(&packet, &fields.prv_candidates) {
+            if self.normalize_candidate(&mut prv_candidate)? {
+                prv_candidates_doc.push(prv_candidate);
+            } else {
+                prv_nondetections_doc.push(prv_candidate);
+            }
+        }
+        for mut prv_nondetection in get_documents(&packet, &fields.prv_nondetections) {
+            self.normalize_candidate(&mut prv_nondetection)?;
+            prv_nondetections_doc.push(prv_nondetection);
+        }
+        let fp_hists_doc = get_documents(&packet, &fields.fp_hists);
+
+        self.normalize_candidate(&mut candidate_doc)?;
+        prv_candidates_doc.push(candidate_doc.clone());
+
+        let alert_doc = doc! {
+            "_id": &candid,
+            "objectId": &object_id,
+            "candidate": &candidate_doc,
+            "coordinates": get_coordinates(ra, dec),
+            "created_at": now,
+            "updated_at": now,
+        };
+
+        trace!("Formatting alert, cutouts & history: {:?}", start.elapsed());
+
+        let start = std::time::Instant::now();
+        let survey_matches =
+            Some(get_survey_matches(ra, dec, &self.survey_xmatch_configs, &self.db).await);
+        trace!(
+            "Xmatching {} alert with other surveys: {:?}",
+            self.stream_name,
+            start.elapsed()
+        );
+
+        Ok(PreparedAlert {
+            candid,
+            object_id,
+            ra,
+            dec,
+            alert_doc,
+            cutout_doc,
+            prv_candidates: prv_candidates_doc,
+            prv_nondetections: prv_nondetections_doc,
+            fp_hists: fp_hists_doc,
+            survey_matches,
+            dia_object: None,
+            aux_fields: Document::new(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl AlertWorker for GenericAlertWorker {
+    type ObjectId = Bson;
+
+    /// Without a stream name, use the only stream of the `streams` section.
+    async fn new(config_path: &str) -> Result<GenericAlertWorker, AlertWorkerError> {
+        let config_file = conf::load_config(config_path)?;
+        let stream_names = conf::get_stream_names(&config_file);
+        match stream_names.as_slice() {
+            [stream_name] => GenericAlertWorker::from_config(config_path, stream_name).await,
+            _ => Err(AlertWorkerError::AmbiguousStream),
+        }
+    }
+
+    fn stream_name(&self) -> String {
+        self.stream_name.clone()
+    }
+
+    fn input_queue_name(&self) -> String {
+        format!("{}_alerts_packets_queue", self.stream_name)
+    }
+
+    // there is no filter or ML worker for the generic streams yet, so their
+    // alerts are only stored: nothing is pushed to an output queue
+    fn output_queue_name(&self) -> Option<String> {
+        None
+    }
+
+    async fn process_alerts(
+        self: &mut Self,
+        avro_bytes: &[Vec<u8>],
+    ) -> Result<AlertBatchResult, AlertError> {
+        let now = Time::now().to_jd();
+
+        let mut prepared_alerts = Vec::with_capacity(avro_bytes.len());
+        let mut failed = Vec::new();
+        for (i, bytes) in avro_bytes.iter().enumerate() {
+            match self.prepare_alert(bytes, now).await {
+                Ok(prepared) => prepared_alerts.push(prepared),
+                Err(e) => failed.push((i, e)),
+            }
+        }
+
+        let candids = ingest_alert_batch(
+            &self.stream_name,
+            prepared_alerts,
+            &self.alert_collection,
+            &self.alert_cutout_collection,
+            &self.alert_aux_collection,
+            &self.xmatch_configs,
+            &self.alias_limits,
+            &self.db,
+            now,
+        )
+        .await?;
+
+        Ok(AlertBatchResult {
+            ingested: candids.new,
+            failed,
+            existing: candids.existing,
+            ..Default::default()
+        })
+    }
+}
@@ -1,18 +1,22 @@
-use apache_avro::{from_avro_datum, from_value};
+use apache_avro::from_value;
 use constcat::concat;
 use flare::Time;
 use mongodb::bson::{doc, Document};
 use serde::{Deserialize, Deserializer, Serialize};
 use serde_with::{serde_as, skip_serializing_none};
+use std::collections::HashMap;
 use tracing::trace;
 
 use crate::{
-    alert::base::{AlertError, AlertWorker, AlertWorkerError, SchemaRegistry, SchemaRegistryError},
+    alert::base::{
+        ingest_alert_batch, AlertBatchResult, AlertError, AlertWorker, AlertWorkerError,
+        PreparedAlert, SchemaRegistry, SchemaRegistryError,
+    },
     conf,
     utils::{
         conversions::{flux2mag, fluxerr2diffmaglim, SNT, ZP_AB},
         db::{cutout2bsonbinary, get_coordinates, mongify},
-        spatial::xmatch,
+        spatial::get_survey_matches,
     },
 };
 
@@ -20,7 +24,8 @@ pub const STREAM_NAME: &str = "LSST";
 pub const ALERT_COLLECTION: &str = concat!(STREAM_NAME, "_alerts");
 pub const ALERT_AUX_COLLECTION: &str = concat!(STREAM_NAME, "_alerts_aux");
 pub const ALERT_CUTOUT_COLLECTION: &str = concat!(STREAM_NAME, "_alerts_cutouts");
-const _MAGIC_BYTE: u8 = 0;
+pub const SS_ALERT_COLLECTION: &str = concat!(STREAM_NAME, "_ss_alerts");
+pub const SS_ALERT_AUX_COLLECTION: &str = concat!(STREAM_NAME, "_ss_aux");
 pub const LSST_SCHEMA_REGISTRY_URL: &str = "https://usdf-alert-schemas-dev.slac.stanford.edu";
 
 #[serde_as]
@@ -228,6 +233,10 @@ pub struct DiaObject {
     /// Unique identifier of this DiaObject.
     #[serde(rename(deserialize = "diaObjectId", serialize = "objectId"))]
     pub object_id: i64,
+    /// Processing time when validity of this diaObject starts, expressed as Julian Date.
+    #[serde(rename = "validityStart")]
+    #[serde(default, deserialize_with = "deserialize_timestamp_micros_option")]
+    pub validity_start: Option<f64>,
     /// Right ascension coordinate of the position of the object at time radecMjdTai.
     pub ra: f64,
     /// Uncertainty of ra.
@@ -554,6 +563,12 @@ pub struct LsstAlert {
     pub prv_nondetections: Option<Vec<NonDetection>>,
     #[serde(rename = "diaObject")]
     pub dia_object: Option<DiaObject>,
+    /// Solar system specific quantities of the diaSource, in schemas that have them.
+    #[serde(rename = "ssSource", default)]
+    pub ss_source: Option<Document>,
+    /// Orbit of the solar system object from the MPC, in schemas that have it.
+    #[serde(rename = "MPCORB", default)]
+    pub mpcorb: Option<Document>,
     #[serde(rename = "cutoutDifference")]
     #[serde(with = "apache_avro::serde_avro_bytes_opt")]
     pub cutout_difference: Option<Vec<u8>>,
@@ -635,6 +650,15 @@ where
     }
 }
 
+fn deserialize_timestamp_micros_option<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    // microseconds since the unix epoch, which is JD 2440587.5
+    let micros = <Option<i64> as Deserialize>::deserialize(deserializer)?;
+    Ok(micros.map(|micros| micros as f64 / 86_400_000_000.0 + 2440587.5))
+}
+
 pub struct LsstAlertWorker {
     stream_name: String,
     schema_registry: SchemaRegistry,
@@ -643,6 +667,18 @@ pub struct LsstAlertWorker {
     alert_collection: mongodb::Collection<Document>,
     alert_aux_collection: mongodb::Collection<Document>,
     alert_cutout_collection: mongodb::Collection<Document>,
+    ss_alert_collection: mongodb::Collection<Document>,
+    ss_alert_aux_collection: mongodb::Collection<Document>,
+    survey_xmatch_configs: Vec<conf::SurveyXmatchConfig>,
+    alias_limits: HashMap<String, usize>,
+}
+
+// solar system alerts have an ssObjectId instead of a diaObjectId,
+// and are kept in their own collections
+#[derive(Debug, PartialEq)]
+enum AlertKind {
+    DiaObject,
+    SolarSystem,
 }
 
 impl LsstAlertWorker {
@@ -650,24 +686,118 @@ impl LsstAlertWorker {
         self: &mut Self,
         avro_bytes: &[u8],
     ) -> Result<LsstAlert, AlertError> {
-        let magic = avro_bytes[0];
-        if magic != _MAGIC_BYTE {
-            Err(AlertError::MagicBytesError)?;
-        }
-        let schema_id =
-            u32::from_be_bytes([avro_bytes[1], avro_bytes[2], avro_bytes[3], avro_bytes[4]]);
-        let schema = self
-            .schema_registry
-            .get_schema("alert-packet", schema_id)
-            .await?;
-
-        let mut slice = &avro_bytes[5..];
-        let value = from_avro_datum(&schema, &mut slice, None)?;
+        let value = self.schema_registry.decode(avro_bytes).await?;
 
         let alert: LsstAlert = from_value::<LsstAlert>(&value)?;
 
         Ok(alert)
     }
+
+    // decode a packet and build the documents to write for it
+    async fn prepare_alert(
+        &mut self,
+        avro_bytes: &[u8],
+        now: f64,
+    ) -> Result<(PreparedAlert, AlertKind), AlertError> {
+        let mut alert = self.alert_from_avro_bytes(avro_bytes).await?;
+
+        let start = std::time::Instant::now();
+
+        let prv_candidates = alert.prv_candidates.take();
+        let fp_hist = alert.fp_hists.take();
+        let prv_nondetections = alert.prv_nondetections.take();
+        let dia_object = alert
+            .dia_object
+            .take()
+            .map(|dia_object| mongify(&dia_object));
+
+        let candid = alert.candid;
+        let (object_id, kind) = match (
+            alert.candidate.dia_source.object_id,
+            alert.candidate.dia_source.ss_object_id,
+        ) {
+            (Some(object_id), _) => (object_id, AlertKind::DiaObject),
+            (None, Some(ss_object_id)) => (ss_object_id, AlertKind::SolarSystem),
+            (None, None) => return Err(AlertError::MissingObjectId),
+        };
+        let ra = alert.candidate.dia_source.ra;
+        let dec = alert.candidate.dia_source.dec;
+
+        let candidate_doc = mongify(&alert.candidate);
+
+        let mut alert_doc = match kind {
+            AlertKind::DiaObject => doc! {
+                "_id": &candid,
+                "objectId": &object_id,
+            },
+            AlertKind::SolarSystem => doc! {
+                "_id": &candid,
+                "ssObjectId": &object_id,
+                "ssSource": alert.ss_source.take(),
+            },
+        };
+        alert_doc.insert("candidate", &candidate_doc);
+        alert_doc.insert("coordinates", get_coordinates(ra, dec));
+        alert_doc.insert("created_at", now);
+        alert_doc.insert("updated_at", now);
+
+        // moving objects don't have counterparts in other surveys
+        let survey_matches = match kind {
+            AlertKind::DiaObject => {
+                Some(get_survey_matches(ra, dec, &self.survey_xmatch_configs, &self.db).await)
+            }
+            AlertKind::SolarSystem => None,
+        };
+
+        let mut aux_fields = Document::new();
+        if let Some(mpcorb) = alert.mpcorb.take() {
+            aux_fields.insert("MPCORB", mpcorb);
+        }
+
+        let cutout_doc = doc! {
+            "_id": &candid,
+            "cutoutScience": cutout2bsonbinary(alert.cutout_science.ok_or(AlertError::MissingCutout)?),
+            "cutoutTemplate": cutout2bsonbinary(alert.cutout_template.ok_or(AlertError::MissingCutout)?),
+            "cutoutDifference": cutout2bsonbinary(alert.cutout_difference.ok_or(AlertError::MissingCutout)?),
+        };
+
+        let mut prv_candidates_doc = prv_candidates
+            .unwrap_or(vec![])
+            .into_iter()
+            .map(|x| mongify(&x))
+            .collect::<Vec<_>>();
+        prv_candidates_doc.push(candidate_doc);
+
+        let fp_hist_doc = fp_hist
+            .unwrap_or(vec![])
+            .into_iter()
+            .map(|x| mongify(&x))
+            .collect::<Vec<_>>();
+
+        let prv_nondetections_doc = prv_nondetections
+            .unwrap_or(vec![])
+            .into_iter()
+            .map(|x| mongify(&x))
+            .collect::<Vec<_>>();
+
+        trace!("Formatting alert, cutouts & history: {:?}", start.elapsed());
+
+        let prepared = PreparedAlert {
+            candid,
+            object_id: object_id.into(),
+            ra,
+            dec,
+            alert_doc,
+            cutout_doc,
+            prv_candidates: prv_candidates_doc,
+            prv_nondetections: prv_nondetections_doc,
+            fp_hists: fp_hist_doc,
+            survey_matches,
+            dia_object,
+            aux_fields,
+        };
+        Ok((prepared, kind))
+    }
 }
 
 #[async_trait::async_trait]
@@ -678,21 +808,31 @@ impl AlertWorker for LsstAlertWorker {
         let config_file = conf::load_config(&config_path)?;
 
         let xmatch_configs = conf::build_xmatch_configs(&config_file, "LSST")?;
+        let survey_xmatch_configs = conf::build_survey_xmatch_configs(&config_file, "LSST")?;
+        let alias_limits = conf::build_alias_limits(&config_file, "LSST")?;
+        let schema_registry =
+            SchemaRegistry::from_config(&config_file, STREAM_NAME, LSST_SCHEMA_REGISTRY_URL)?;
 
         let db: mongodb::Database = conf::build_db(&config_file).await?;
 
         let alert_collection = db.collection(&ALERT_COLLECTION);
         let alert_aux_collection = db.collection(&ALERT_AUX_COLLECTION);
         let alert_cutout_collection = db.collection(&ALERT_CUTOUT_COLLECTION);
+        let ss_alert_collection = db.collection(SS_ALERT_COLLECTION);
+        let ss_alert_aux_collection = db.collection(SS_ALERT_AUX_COLLECTION);
 
         let worker = LsstAlertWorker {
             stream_name: STREAM_NAME.to_string(),
-            schema_registry: SchemaRegistry::new(LSST_SCHEMA_REGISTRY_URL),
+            schema_registry,
             xmatch_configs,
             db,
             alert_collection,
             alert_aux_collection,
             alert_cutout_collection,
+            ss_alert_collection,
+            ss_alert_aux_collection,
+            survey_xmatch_configs,
+            alias_limits,
         };
         Ok(worker)
     }
@@ -705,215 +845,64 @@ impl AlertWorker for LsstAlertWorker {
         format!("{}_alerts_packets_queue", self.stream_name)
     }
 
-    fn output_queue_name(&self) -> String {
-        format!("{}_alerts_filter_queue", self.stream_name)
+    fn output_queue_name(&self) -> Option<String> {
+        Some(format!("{}_alerts_filter_queue", self.stream_name))
     }
 
-    async fn insert_aux(
-        self: &mut Self,
-        object_id: impl Into<Self::ObjectId> + Send,
-        ra: f64,
-        dec: f64,
-        prv_candidates_doc: &Vec<Document>,
-        prv_nondetections_doc: &Vec<Document>,
-        fp_hist_doc: &Vec<Document>,
-        _survey_matches: &Option<Document>,
-        now: f64,
-    ) -> Result<(), AlertError> {
-        let start = std::time::Instant::now();
-        let xmatches = xmatch(ra, dec, &self.xmatch_configs, &self.db).await?;
-        trace!("Xmatch took: {:?}", start.elapsed());
-
-        let start = std::time::Instant::now();
-        let alert_aux_doc = doc! {
-            "_id": object_id.into(),
-            "prv_candidates": prv_candidates_doc,
-            "prv_nondetections": prv_nondetections_doc,
-            "fp_hists": fp_hist_doc,
-            "cross_matches": xmatches,
-            "created_at": now,
-            "updated_at": now,
-            "coordinates": {
-                "radec_geojson": {
-                    "type": "Point",
-                    "coordinates": [ra - 180.0, dec],
-                },
-            },
-        };
-
-        self.alert_aux_collection
-            .insert_one(alert_aux_doc)
-            .await
-            .map_err(|e| match *e.kind {
-                mongodb::error::ErrorKind::Write(mongodb::error::WriteFailure::WriteError(
-                    write_error,
-                )) if write_error.code == 11000 => AlertError::AlertAuxExists,
-                _ => e.into(),
-            })?;
-
-        trace!("Inserting alert_aux: {:?}", start.elapsed());
-
-        Ok(())
+    fn ss_output_queue_name(&self) -> Option<String> {
+        Some(format!("{}_ss_alerts_filter_queue", self.stream_name))
     }
 
-    async fn update_aux(
+    async fn process_alerts(
         self: &mut Self,
-        object_id: impl Into<Self::ObjectId> + Send,
-        prv_candidates_doc: &Vec<Document>,
-        prv_nondetections_doc: &Vec<Document>,
-        fp_hist_doc: &Vec<Document>,
-        _survey_matches: &Option<Document>,
-        now: f64,
-    ) -> Result<(), AlertError> {
-        let start = std::time::Instant::now();
-
-        let update_doc = doc! {
-            "$addToSet": {
-                "prv_candidates": { "$each": prv_candidates_doc },
-                "prv_nondetections": { "$each": prv_nondetections_doc },
-                "fp_hists": { "$each": fp_hist_doc }
-            },
-            "$set": {
-                "updated_at": now,
-            }
-        };
-
-        self.alert_aux_collection
-            .update_one(doc! { "_id": object_id.into() }, update_doc)
-            .await?;
-
-        trace!("Updating alert_aux: {:?}", start.elapsed());
-
-        Ok(())
-    }
-
-    async fn process_alert(self: &mut Self, avro_bytes: &[u8]) -> Result<i64, AlertError> {
+        avro_bytes: &[Vec<u8>],
+    ) -> Result<AlertBatchResult, AlertError> {
         let now = Time::now().to_jd();
 
-        let mut alert = self.alert_from_avro_bytes(avro_bytes).await?;
-
-        let start = std::time::Instant::now();
-
-        let prv_candidates = alert.prv_candidates.take();
-        let fp_hist = alert.fp_hists.take();
-        let prv_nondetections = alert.prv_nondetections.take();
-
-        let candid = alert.candid;
-        let object_id = alert
-            .candidate
-            .dia_source
-            .object_id
-            .ok_or(AlertError::MissingObjectId)?;
-        let ra = alert.candidate.dia_source.ra;
-        let dec = alert.candidate.dia_source.dec;
-
-        let candidate_doc = mongify(&alert.candidate);
-
-        let alert_doc = doc! {
-            "_id": &candid,
-            "objectId": &object_id,
-            "candidate": &candidate_doc,
-            "coordinates": get_coordinates(ra, dec),
-            "created_at": now,
-            "updated_at": now,
-        };
-
-        self.alert_collection
-            .insert_one(alert_doc)
-            .await
-            .map_err(|e| match *e.kind {
-                mongodb::error::ErrorKind::Write(mongodb::error::WriteFailure::WriteError(
-                    write_error,
-                )) if write_error.code == 11000 => AlertError::AlertExists,
-                _ => e.into(),
-            })?;
-
-        trace!("Formatting & Inserting alert: {:?}", start.elapsed());
-
-        let start = std::time::Instant::now();
-
-        let cutout_doc = doc! {
-            "_id": &candid,
-            "cutoutScience": cutout2bsonbinary(alert.cutout_science.ok_or(AlertError::MissingCutout)?),
-            "cutoutTemplate": cutout2bsonbinary(alert.cutout_template.ok_or(AlertError::MissingCutout)?),
-            "cutoutDifference": cutout2bsonbinary(alert.cutout_difference.ok_or(AlertError::MissingCutout)?),
-        };
-
-        self.alert_cutout_collection.insert_one(cutout_doc).await?;
-
-        trace!("Formatting & Inserting cutout: {:?}", start.elapsed());
-
-        let start = std::time::Instant::now();
-
-        let alert_aux_exists = self
-            .alert_aux_collection
-            .count_documents(doc! { "_id": &object_id })
-            .await?
-            > 0;
-
-        trace!("Checking if alert_aux exists: {:?}", start.elapsed());
-
-        let start = std::time::Instant::now();
-
-        let mut prv_candidates_doc = prv_candidates
-            .unwrap_or(vec![])
-            .into_iter()
-            .map(|x| Ok(mongify(&x)))
-            .collect::<Result<Vec<_>, AlertError>>()?;
-        prv_candidates_doc.push(candidate_doc);
-
-        let fp_hist_doc = fp_hist
-            .unwrap_or(vec![])
-            .into_iter()
-            .map(|x| Ok(mongify(&x)))
-            .collect::<Result<Vec<_>, AlertError>>()?;
-
-        let prv_nondetections_doc = prv_nondetections
-            .unwrap_or(vec![])
-            .into_iter()
-            .map(|x| Ok(mongify(&x)))
-            .collect::<Result<Vec<_>, AlertError>>()?;
-
-        trace!("Formatting prv_candidates & fp_hist: {:?}", start.elapsed());
-
-        if !alert_aux_exists {
-            let result = self
-                .insert_aux(
-                    object_id,
-                    ra,
-                    dec,
-                    &prv_candidates_doc,
-                    &prv_nondetections_doc,
-                    &fp_hist_doc,
-                    &None,
-                    now,
-                )
-                .await;
-            if let Err(AlertError::AlertAuxExists) = result {
-                self.update_aux(
-                    object_id,
-                    &prv_candidates_doc,
-                    &prv_nondetections_doc,
-                    &fp_hist_doc,
-                    &None,
-                    now,
-                )
-                .await?;
-            } else {
-                result?;
+        let mut prepared_alerts = Vec::with_capacity(avro_bytes.len());
+        let mut prepared_ss_alerts = Vec::new();
+        let mut failed = Vec::new();
+        for (i, bytes) in avro_bytes.iter().enumerate() {
+            match self.prepare_alert(bytes, now).await {
+                Ok((prepared, AlertKind::DiaObject)) => prepared_alerts.push(prepared),
+                Ok((prepared, AlertKind::SolarSystem)) => prepared_ss_alerts.push(prepared),
+                Err(e) => failed.push((i, e)),
             }
-        } else {
-            self.update_aux(
-                object_id,
-                &prv_candidates_doc,
-                &prv_nondetections_doc,
-                &fp_hist_doc,
-                &None,
-                now,
-            )
-            .await?;
         }
 
-        Ok(candid)
+        let candids = ingest_alert_batch(
+            &self.stream_name,
+            prepared_alerts,
+            &self.alert_collection,
+            &self.alert_cutout_collection,
+            &self.alert_aux_collection,
+            &self.xmatch_configs,
+            &self.alias_limits,
+            &self.db,
+            now,
+        )
+        .await?;
+
+        // moving objects are not cross-matched with the (static) catalogs
+        let ss_candids = ingest_alert_batch(
+            &self.stream_name,
+            prepared_ss_alerts,
+            &self.ss_alert_collection,
+            &self.alert_cutout_collection,
+            &self.ss_alert_aux_collection,
+            &vec![],
+            &HashMap::new(),
+            &self.db,
+            now,
+        )
+        .await?;
+
+        Ok(AlertBatchResult {
+            ingested: candids.new,
+            failed,
+            ss_ingested: ss_candids.new,
+            existing: candids.existing,
+            ss_existing: ss_candids.existing,
+        })
     }
 }
@@ -1,11 +1,21 @@
 mod base;
+mod dead_letter;
+mod generic;
 mod lsst;
 mod ztf;
 pub use base::run_alert_worker;
+pub use base::run_generic_alert_worker;
+pub use base::AlertBatchResult;
 pub use base::AlertError;
 pub use base::AlertWorker;
 pub use base::AlertWorkerError;
 pub use base::SchemaRegistry;
 pub use base::SchemaRegistryError;
+pub use dead_letter::{
+    dead_letter_collection_name, DeadLetter, DeadLetterError, DeadLetterQueue, DEFAULT_MAX_RETRIES,
+};
+pub use generic::{
+    GenericAlertWorker, PhotometryConvention, SchemaSource, StreamFields, StreamMapping, TimeFormat,
+};
 pub use lsst::{LsstAlertWorker, LSST_SCHEMA_REGISTRY_URL};
-pub use ztf::{ZtfAlertWorker, LSST_DEC_LIMIT, LSST_XMATCH_RADIUS};
+pub use ztf::ZtfAlertWorker;
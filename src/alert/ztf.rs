@@ -1,13 +1,13 @@
 use crate::{
-    alert::{
-        base::{AlertError, AlertWorker, AlertWorkerError, SchemaRegistryError},
-        lsst,
+    alert::base::{
+        ingest_alert_batch, AlertBatchResult, AlertError, AlertWorker, AlertWorkerError,
+        PreparedAlert, SchemaRegistryError,
     },
     conf,
     utils::{
         conversions::{flux2mag, fluxerr2diffmaglim, SNT},
         db::{cutout2bsonbinary, get_coordinates, mongify},
-        spatial::xmatch,
+        spatial::get_survey_matches,
     },
 };
 use apache_avro::from_value;
@@ -17,6 +17,7 @@ use flare::Time;
 use mongodb::bson::{doc, Document};
 use serde::{Deserialize, Deserializer, Serialize};
 use serde_with::{serde_as, skip_serializing_none};
+use std::collections::HashMap;
 use std::io::Read;
 use tracing::{error, trace};
 
@@ -25,9 +26,6 @@ pub const ALERT_COLLECTION: &str = concat!(STREAM_NAME, "_alerts");
 pub const ALERT_AUX_COLLECTION: &str = concat!(STREAM_NAME, "_alerts_aux");
 pub const ALERT_CUTOUT_COLLECTION: &str = concat!(STREAM_NAME, "_alerts_cutouts");
 
-pub const LSST_DEC_LIMIT: f64 = 33.5;
-pub const LSST_XMATCH_RADIUS: f64 = (2.0_f64 / 3600.0_f64).to_radians(); // 2 arcseconds in radians
-
 fn decode_variable<R: Read>(reader: &mut R) -> Result<u64, SchemaRegistryError> {
     let mut i = 0u64;
     let mut buf = [0u8; 1];
@@ -455,7 +453,8 @@ pub struct ZtfAlertWorker {
     alert_cutout_collection: mongodb::Collection<Document>,
     cached_schema: Option<Schema>,
     cached_start_idx: Option<usize>,
-    lsst_alert_aux_collection: mongodb::Collection<Document>,
+    survey_xmatch_configs: Vec<conf::SurveyXmatchConfig>,
+    alias_limits: HashMap<String, usize>,
 }
 
 impl ZtfAlertWorker {
@@ -474,7 +473,10 @@ impl ZtfAlertWorker {
             }
         };
 
-        let value = from_avro_datum(schema_ref, &mut &avro_bytes[start_idx..], None);
+        let mut datum = avro_bytes
+            .get(start_idx..)
+            .ok_or(AlertError::TruncatedPacket)?;
+        let value = from_avro_datum(schema_ref, &mut datum, None);
 
         // if value is an error, try recomputing the schema from the avro_bytes
         // as it could be that the schema has changed
@@ -498,173 +500,12 @@ impl ZtfAlertWorker {
         Ok(alert)
     }
 
-    async fn get_lsst_matches(&self, ra: f64, dec: f64) -> Result<Vec<i64>, AlertError> {
-        let lsst_matches = if dec <= LSST_DEC_LIMIT as f64 {
-            let result = self
-                .lsst_alert_aux_collection
-                .find_one(doc! {
-                    "coordinates.radec_geojson": {
-                        "$nearSphere": [ra - 180.0, dec],
-                        "$maxDistance": LSST_XMATCH_RADIUS,
-                    },
-                })
-                .projection(doc! {
-                    "_id": 1
-                })
-                .await;
-            match result {
-                Ok(Some(doc)) => {
-                    let object_id = doc.get_i64("_id")?;
-                    vec![object_id]
-                }
-                Ok(None) => vec![],
-                Err(e) => {
-                    error!("Error cross-matching with LSST: {}", e);
-                    vec![]
-                }
-            }
-        } else {
-            vec![]
-        };
-        Ok(lsst_matches)
-    }
-
-    async fn get_survey_matches(&self, ra: f64, dec: f64) -> Result<Document, AlertError> {
-        let lsst_matches = self.get_lsst_matches(ra, dec).await?;
-
-        Ok(doc! {
-            "LSST": lsst_matches,
-        })
-    }
-}
-
-#[async_trait::async_trait]
-impl AlertWorker for ZtfAlertWorker {
-    type ObjectId = String;
-
-    async fn new(config_path: &str) -> Result<ZtfAlertWorker, AlertWorkerError> {
-        let config_file = conf::load_config(&config_path)?;
-
-        let xmatch_configs = conf::build_xmatch_configs(&config_file, STREAM_NAME)?;
-
-        let db: mongodb::Database = conf::build_db(&config_file).await?;
-
-        let alert_collection = db.collection(&ALERT_COLLECTION);
-        let alert_aux_collection = db.collection(&ALERT_AUX_COLLECTION);
-        let alert_cutout_collection = db.collection(&ALERT_CUTOUT_COLLECTION);
-
-        let lsst_alert_aux_collection: mongodb::Collection<Document> =
-            db.collection(&lsst::ALERT_AUX_COLLECTION);
-
-        let worker = ZtfAlertWorker {
-            stream_name: STREAM_NAME.to_string(),
-            xmatch_configs,
-            db,
-            alert_collection,
-            alert_aux_collection,
-            alert_cutout_collection,
-            cached_schema: None,
-            cached_start_idx: None,
-            lsst_alert_aux_collection,
-        };
-        Ok(worker)
-    }
-
-    fn stream_name(&self) -> String {
-        self.stream_name.clone()
-    }
-
-    fn input_queue_name(&self) -> String {
-        format!("{}_alerts_packets_queue", self.stream_name)
-    }
-
-    fn output_queue_name(&self) -> String {
-        format!("{}_alerts_classifier_queue", self.stream_name)
-    }
-
-    async fn insert_aux(
-        self: &mut Self,
-        object_id: impl Into<Self::ObjectId> + Send,
-        ra: f64,
-        dec: f64,
-        prv_candidates_doc: &Vec<Document>,
-        prv_nondetections_doc: &Vec<Document>,
-        fp_hist_doc: &Vec<Document>,
-        survey_matches: &Option<Document>,
-        now: f64,
-    ) -> Result<(), AlertError> {
-        let start = std::time::Instant::now();
-        let xmatches = xmatch(ra, dec, &self.xmatch_configs, &self.db).await?;
-        trace!("Xmatch took: {:?}", start.elapsed());
-
-        let start = std::time::Instant::now();
-        let alert_aux_doc = doc! {
-            "_id": object_id.into(),
-            "prv_candidates": prv_candidates_doc,
-            "prv_nondetections": prv_nondetections_doc,
-            "fp_hists": fp_hist_doc,
-            "cross_matches": xmatches,
-            "aliases": survey_matches,
-            "created_at": now,
-            "updated_at": now,
-            "coordinates": {
-                "radec_geojson": {
-                    "type": "Point",
-                    "coordinates": [ra - 180.0, dec],
-                },
-            },
-        };
-
-        self.alert_aux_collection
-            .insert_one(alert_aux_doc)
-            .await
-            .map_err(|e| match *e.kind {
-                mongodb::error::ErrorKind::Write(mongodb::error::WriteFailure::WriteError(
-                    write_error,
-                )) if write_error.code == 11000 => AlertError::AlertAuxExists,
-                _ => e.into(),
-            })?;
-
-        trace!("Inserting alert_aux: {:?}", start.elapsed());
-
-        Ok(())
-    }
-
-    async fn update_aux(
-        self: &mut Self,
-        object_id: impl Into<Self::ObjectId> + Send,
-        prv_candidates_doc: &Vec<Document>,
-        prv_nondetections_doc: &Vec<Document>,
-        fp_hist_doc: &Vec<Document>,
-        survey_matches: &Option<Document>,
+    // decode a packet and build the documents to write for it
+    async fn prepare_alert(
+        &mut self,
+        avro_bytes: &[u8],
         now: f64,
-    ) -> Result<(), AlertError> {
-        let start = std::time::Instant::now();
-
-        let update_doc = doc! {
-            "$addToSet": {
-                "prv_candidates": { "$each": prv_candidates_doc },
-                "prv_nondetections": { "$each": prv_nondetections_doc },
-                "fp_hists": { "$each": fp_hist_doc }
-            },
-            "$set": {
-                "updated_at": now,
-                "aliases": survey_matches,
-            }
-        };
-
-        self.alert_aux_collection
-            .update_one(doc! { "_id": object_id.into() }, update_doc)
-            .await?;
-
-        trace!("Updating alert_aux: {:?}", start.elapsed());
-
-        Ok(())
-    }
-
-    async fn process_alert(self: &mut Self, avro_bytes: &[u8]) -> Result<i64, AlertError> {
-        let now = Time::now().to_jd();
-
+    ) -> Result<PreparedAlert, AlertError> {
         let start = std::time::Instant::now();
 
         let mut alert = self.alert_from_avro_bytes(avro_bytes).await?;
@@ -692,20 +533,6 @@ impl AlertWorker for ZtfAlertWorker {
             "updated_at": now,
         };
 
-        self.alert_collection
-            .insert_one(alert_doc)
-            .await
-            .map_err(|e| match *e.kind {
-                mongodb::error::ErrorKind::Write(mongodb::error::WriteFailure::WriteError(
-                    write_error,
-                )) if write_error.code == 11000 => AlertError::AlertExists,
-                _ => e.into(),
-            })?;
-
-        trace!("Formatting & Inserting alert: {:?}", start.elapsed());
-
-        let start = std::time::Instant::now();
-
         let cutout_doc = doc! {
             "_id": &candid,
             "cutoutScience": cutout2bsonbinary(alert.cutout_science.ok_or(AlertError::MissingCutout)?),
@@ -713,22 +540,6 @@ impl AlertWorker for ZtfAlertWorker {
             "cutoutDifference": cutout2bsonbinary(alert.cutout_difference.ok_or(AlertError::MissingCutout)?),
         };
 
-        self.alert_cutout_collection.insert_one(cutout_doc).await?;
-
-        trace!("Formatting & Inserting cutout: {:?}", start.elapsed());
-
-        let start = std::time::Instant::now();
-
-        let alert_aux_exists = self
-            .alert_aux_collection
-            .count_documents(doc! { "_id": &object_id })
-            .await?
-            > 0;
-
-        trace!("Checking if alert_aux exists: {:?}", start.elapsed());
-
-        let start = std::time::Instant::now();
-
         // we split the prv_candidates into detections and non-detections
         let mut prv_candidates_doc = vec![];
         let mut prv_nondetections_doc = vec![];
@@ -748,53 +559,110 @@ impl AlertWorker for ZtfAlertWorker {
             .map(|x| mongify(&x))
             .collect::<Vec<_>>();
 
-        trace!("Formatting prv_candidates & fp_hist: {:?}", start.elapsed());
+        trace!("Formatting alert, cutouts & history: {:?}", start.elapsed());
 
         let start = std::time::Instant::now();
-        let survey_matches = Some(self.get_survey_matches(ra, dec).await?);
+        let survey_matches =
+            Some(get_survey_matches(ra, dec, &self.survey_xmatch_configs, &self.db).await);
         trace!(
             "Xmatching ZTF alert with other surveys: {:?}",
             start.elapsed()
         );
 
-        if !alert_aux_exists {
-            let result = self
-                .insert_aux(
-                    &object_id,
-                    ra,
-                    dec,
-                    &prv_candidates_doc,
-                    &prv_nondetections_doc,
-                    &fp_hist_doc,
-                    &survey_matches,
-                    now,
-                )
-                .await;
-            if let Err(AlertError::AlertAuxExists) = result {
-                self.update_aux(
-                    &object_id,
-                    &prv_candidates_doc,
-                    &prv_nondetections_doc,
-                    &fp_hist_doc,
-                    &survey_matches,
-                    now,
-                )
-                .await?;
-            } else {
-                result?;
+        Ok(PreparedAlert {
+            candid,
+            object_id: object_id.into(),
+            ra,
+            dec,
+            alert_doc,
+            cutout_doc,
+            prv_candidates: prv_candidates_doc,
+            prv_nondetections: prv_nondetections_doc,
+            fp_hists: fp_hist_doc,
+            survey_matches,
+            dia_object: None,
+            aux_fields: Document::new(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl AlertWorker for ZtfAlertWorker {
+    type ObjectId = String;
+
+    async fn new(config_path: &str) -> Result<ZtfAlertWorker, AlertWorkerError> {
+        let config_file = conf::load_config(&config_path)?;
+
+        let xmatch_configs = conf::build_xmatch_configs(&config_file, STREAM_NAME)?;
+        let survey_xmatch_configs = conf::build_survey_xmatch_configs(&config_file, STREAM_NAME)?;
+        let alias_limits = conf::build_alias_limits(&config_file, STREAM_NAME)?;
+
+        let db: mongodb::Database = conf::build_db(&config_file).await?;
+
+        let alert_collection = db.collection(&ALERT_COLLECTION);
+        let alert_aux_collection = db.collection(&ALERT_AUX_COLLECTION);
+        let alert_cutout_collection = db.collection(&ALERT_CUTOUT_COLLECTION);
+
+        let worker = ZtfAlertWorker {
+            stream_name: STREAM_NAME.to_string(),
+            xmatch_configs,
+            db,
+            alert_collection,
+            alert_aux_collection,
+            alert_cutout_collection,
+            cached_schema: None,
+            cached_start_idx: None,
+            survey_xmatch_configs,
+            alias_limits,
+        };
+        Ok(worker)
+    }
+
+    fn stream_name(&self) -> String {
+        self.stream_name.clone()
+    }
+
+    fn input_queue_name(&self) -> String {
+        format!("{}_alerts_packets_queue", self.stream_name)
+    }
+
+    fn output_queue_name(&self) -> Option<String> {
+        Some(format!("{}_alerts_classifier_queue", self.stream_name))
+    }
+
+    async fn process_alerts(
+        self: &mut Self,
+        avro_bytes: &[Vec<u8>],
+    ) -> Result<AlertBatchResult, AlertError> {
+        let now = Time::now().to_jd();
+
+        let mut prepared_alerts = Vec::with_capacity(avro_bytes.len());
+        let mut failed = Vec::new();
+        for (i, bytes) in avro_bytes.iter().enumerate() {
+            match self.prepare_alert(bytes, now).await {
+                Ok(prepared) => prepared_alerts.push(prepared),
+                Err(e) => failed.push((i, e)),
             }
-        } else {
-            self.update_aux(
-                &object_id,
-                &prv_candidates_doc,
-                &prv_nondetections_doc,
-                &fp_hist_doc,
-                &survey_matches,
-                now,
-            )
-            .await?;
         }
 
-        Ok(candid)
+        let candids = ingest_alert_batch(
+            &self.stream_name,
+            prepared_alerts,
+            &self.alert_collection,
+            &self.alert_cutout_collection,
+            &self.alert_aux_collection,
+            &self.xmatch_configs,
+            &self.alias_limits,
+            &self.db,
+            now,
+        )
+        .await?;
+
+        Ok(AlertBatchResult {
+            ingested: candids.new,
+            failed,
+            existing: candids.existing,
+            ..Default::default()
+        })
     }
 }
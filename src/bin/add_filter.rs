@@ -3,7 +3,11 @@ use mongodb::bson::doc;
 use tracing::{error, Level};
 use tracing_subscriber::FmtSubscriber;
 
-use boom::{conf, utils::db::create_index};
+use boom::{
+    conf,
+    filter::{notify_filters_updated, FilterValidator},
+    utils::db::create_index,
+};
 
 #[derive(Parser)]
 struct Cli {
@@ -37,6 +41,34 @@ async fn main() {
         }
     };
 
+    // check the stages of the filter before saving it
+    let stages = match serde_json::from_str::<Vec<mongodb::bson::Document>>(&filter_pipeline) {
+        Ok(stages) => stages,
+        Err(e) => {
+            error!("filter file is not an array of stages: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let config_file = match conf::load_config("config.yaml") {
+        Ok(config) => config,
+        Err(e) => {
+            error!("error loading config file: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let validator = match FilterValidator::from_config(&config_file, &format!("{}_alerts", survey))
+    {
+        Ok(validator) => validator,
+        Err(e) => {
+            error!("error loading the crossmatch config: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = validator.validate(&stages) {
+        error!("invalid filter: {}", e);
+        std::process::exit(1);
+    }
+
     // create a bson document with filter_id, active, catalog, permissions
     // group_id, and a fv array with one doc that has a fid field and a pipeline field
 
@@ -56,14 +88,6 @@ async fn main() {
     };
 
     // insert the filter into the database
-    let config_file = match conf::load_config("config.yaml") {
-        Ok(config) => config,
-        Err(e) => {
-            error!("error loading config file: {}", e);
-            std::process::exit(1);
-        }
-    };
-
     let db = match conf::build_db(&config_file).await {
         Ok(db) => db,
         Err(e) => {
@@ -85,6 +109,18 @@ async fn main() {
     match collection.insert_one(filter).await {
         Ok(_) => {
             println!("Filter added successfully");
+            // let the running filter workers pick up the new filter
+            match conf::build_redis(&config_file).await {
+                Ok(mut con) => {
+                    let catalog = format!("{}_alerts", survey);
+                    if let Err(e) = notify_filters_updated(&mut con, &catalog).await {
+                        error!("error notifying filter workers: {}", e);
+                    }
+                }
+                Err(e) => {
+                    error!("error connecting to redis: {}", e);
+                }
+            }
         }
         Err(e) => {
             error!("error inserting filter obj: {}", e);
@@ -0,0 +1,162 @@
+use clap::Parser;
+use tracing::{error, info, Level};
+use tracing_subscriber::FmtSubscriber;
+
+use boom::{
+    conf,
+    filter::{
+        Backtest, BacktestQuery, FilterValidator, SkyRegion, DEFAULT_BACKTEST_CHUNK_SIZE,
+        DEFAULT_BACKTEST_SAMPLE_SIZE,
+    },
+};
+
+#[derive(Parser)]
+struct Cli {
+    #[arg(help = "Survey of the filter. Options are 'ZTF' or 'LSST'")]
+    survey: String,
+    #[arg(help = "Path to the JSON file containing the filter")]
+    filter_file: String,
+    #[arg(long, help = "Start of the time range of the alerts (JD, included)")]
+    jd_start: f64,
+    #[arg(long, help = "End of the time range of the alerts (JD, excluded)")]
+    jd_end: f64,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "Programids the filter has access to (ZTF only), e.g. 1,2"
+    )]
+    permissions: Vec<i32>,
+    #[arg(
+        long,
+        requires_all = ["dec", "radius"],
+        help = "RA of the center of the sky region (degrees)"
+    )]
+    ra: Option<f64>,
+    #[arg(
+        long,
+        requires_all = ["ra", "radius"],
+        help = "Dec of the center of the sky region (degrees)"
+    )]
+    dec: Option<f64>,
+    #[arg(
+        long,
+        requires_all = ["ra", "dec"],
+        help = "Radius of the sky region (degrees)"
+    )]
+    radius: Option<f64>,
+    #[arg(
+        long,
+        default_value_t = DEFAULT_BACKTEST_CHUNK_SIZE,
+        help = "Number of alerts filtered at once"
+    )]
+    chunk_size: usize,
+    #[arg(
+        long,
+        default_value_t = DEFAULT_BACKTEST_SAMPLE_SIZE,
+        help = "Number of passing candids in the report"
+    )]
+    sample_size: usize,
+    #[arg(long, value_name = "FILE", help = "Path to the configuration file")]
+    config: Option<String>,
+}
+
+#[tokio::main]
+async fn main() {
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(Level::INFO)
+        .with_writer(std::io::stderr)
+        .finish();
+
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+
+    let args = Cli::parse();
+
+    // read the filter stages
+    let filter_pipeline = match std::fs::read_to_string(&args.filter_file) {
+        Ok(filter) => filter,
+        Err(e) => {
+            error!("error reading filter file: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let stages = match serde_json::from_str::<Vec<mongodb::bson::Document>>(&filter_pipeline) {
+        Ok(stages) => stages,
+        Err(e) => {
+            error!("filter file is not an array of stages: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let config_path = args.config.as_deref().unwrap_or("config.yaml");
+    let config_file = match conf::load_config(config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("error loading config file: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let db = match conf::build_db(&config_file).await {
+        Ok(db) => db,
+        Err(e) => {
+            error!("error building db: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let region = match (args.ra, args.dec, args.radius) {
+        (Some(ra), Some(dec), Some(radius)) => Some(SkyRegion { ra, dec, radius }),
+        _ => None,
+    };
+    let query = BacktestQuery {
+        jd_start: args.jd_start,
+        jd_end: args.jd_end,
+        region,
+        permissions: args.permissions,
+    };
+    let validator =
+        match FilterValidator::from_config(&config_file, &format!("{}_alerts", args.survey)) {
+            Ok(validator) => validator,
+            Err(e) => {
+                error!("error loading the crossmatch config: {}", e);
+                std::process::exit(1);
+            }
+        };
+    let mut backtest = match Backtest::new(&db, &args.survey, stages, &query, &validator).await {
+        Ok(backtest) => backtest
+            .chunk_size(args.chunk_size)
+            .sample_size(args.sample_size),
+        Err(e) => {
+            error!("error preparing the backtest: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    loop {
+        match backtest.next_chunk().await {
+            Ok(Some(chunk)) => {
+                let report = backtest.report();
+                info!(
+                    "{} alerts passed out of {} ({} passed out of {} so far)",
+                    chunk.passed.len(),
+                    chunk.alerts_evaluated,
+                    report.alerts_passed,
+                    report.alerts_evaluated
+                );
+            }
+            Ok(None) => break,
+            Err(e) => {
+                error!("error running the backtest: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // the report goes to stdout, the progress to the logs
+    match serde_json::to_string_pretty(backtest.report()) {
+        Ok(report) => println!("{}", report),
+        Err(e) => {
+            error!("error serializing the report: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
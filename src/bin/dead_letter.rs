@@ -0,0 +1,146 @@
+use boom::{alert::DeadLetterQueue, conf};
+use clap::{Parser, Subcommand};
+use mongodb::bson::oid::ObjectId;
+use tracing::{error, info, Level};
+use tracing_subscriber::FmtSubscriber;
+
+#[derive(Parser)]
+struct Cli {
+    #[arg(help = "Name of the stream the dead letters belong to, e.g. 'ZTF' or 'LSST'")]
+    stream: String,
+    #[arg(long, value_name = "FILE", help = "Path to the configuration file")]
+    config: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List the most recent dead letters
+    List {
+        #[arg(
+            long,
+            help = "Only list dead letters with this error kind, e.g. 'MissingCutout'"
+        )]
+        kind: Option<String>,
+        #[arg(
+            long,
+            default_value_t = 20,
+            help = "Maximum number of dead letters to list"
+        )]
+        limit: i64,
+    },
+    /// Show a single dead letter, optionally writing its packet to a file
+    Inspect {
+        #[arg(help = "Id of the dead letter")]
+        id: String,
+        #[arg(
+            long,
+            value_name = "FILE",
+            help = "Write the raw avro packet to this file"
+        )]
+        output: Option<String>,
+    },
+    /// Push dead letters back onto the stream's alert packets queue
+    Reinject {
+        #[arg(help = "Id of the dead letter to re-inject")]
+        id: Option<String>,
+        #[arg(
+            long,
+            help = "Re-inject all dead letters (with --kind, only those of that kind)"
+        )]
+        all: bool,
+        #[arg(long, help = "Only re-inject dead letters with this error kind")]
+        kind: Option<String>,
+    },
+}
+
+fn parse_id(id: &str) -> ObjectId {
+    match ObjectId::parse_str(id) {
+        Ok(id) => id,
+        Err(e) => {
+            error!("invalid dead letter id {}: {}", id, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(Level::INFO)
+        .finish();
+
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+
+    let args = Cli::parse();
+    let config_path = args.config.unwrap_or("config.yaml".to_string());
+    let stream_name = args.stream;
+
+    let dead_letter_queue = DeadLetterQueue::from_config(&config_path, &stream_name).await?;
+
+    match args.command {
+        Command::List { kind, limit } => {
+            let total = dead_letter_queue.count(kind.as_deref()).await?;
+            let dead_letters = dead_letter_queue.list(kind.as_deref(), limit).await?;
+            println!("{} dead letters for {}", total, stream_name);
+            for dead_letter in dead_letters {
+                println!(
+                    "{}\t{}\tattempts={}\tworker={}\tcreated_at={}\t{}",
+                    dead_letter.id,
+                    dead_letter.error_kind,
+                    dead_letter.attempts,
+                    dead_letter.worker_id,
+                    dead_letter.created_at,
+                    dead_letter.error_message
+                );
+            }
+        }
+        Command::Inspect { id, output } => {
+            let Some(dead_letter) = dead_letter_queue.get(parse_id(&id)).await? else {
+                error!("no dead letter found with id {}", id);
+                std::process::exit(1);
+            };
+            println!("id:            {}", dead_letter.id);
+            println!("stream:        {}", dead_letter.stream);
+            println!("error kind:    {}", dead_letter.error_kind);
+            println!("error message: {}", dead_letter.error_message);
+            println!("attempts:      {}", dead_letter.attempts);
+            println!("worker id:     {}", dead_letter.worker_id);
+            println!("created at:    {}", dead_letter.created_at);
+            println!("packet size:   {} bytes", dead_letter.packet.bytes.len());
+            if let Some(output) = output {
+                std::fs::write(&output, &dead_letter.packet.bytes)?;
+                info!("wrote packet to {}", output);
+            }
+        }
+        Command::Reinject { id, all, kind } => {
+            let config = conf::load_config(&config_path)?;
+            let mut con = conf::build_redis(&config).await?;
+            let queue_name = format!("{}_alerts_packets_queue", stream_name);
+
+            let ids = match (id, all) {
+                (Some(id), false) => vec![parse_id(&id)],
+                (None, true) => dead_letter_queue
+                    .list(kind.as_deref(), 0)
+                    .await?
+                    .into_iter()
+                    .map(|dead_letter| dead_letter.id)
+                    .collect(),
+                _ => {
+                    error!("provide either a dead letter id or --all");
+                    std::process::exit(1);
+                }
+            };
+
+            for id in &ids {
+                dead_letter_queue
+                    .reinject(*id, &mut con, &queue_name)
+                    .await?;
+            }
+            info!("re-injected {} dead letters into {}", ids.len(), queue_name);
+        }
+    }
+
+    Ok(())
+}
@@ -3,11 +3,16 @@ use clap::Parser;
 use tracing::Level;
 use tracing_subscriber::FmtSubscriber;
 
-use boom::kafka::{AlertConsumer, LsstAlertConsumer, ZtfAlertConsumer};
+use boom::{
+    conf,
+    kafka::{AlertConsumer, GenericAlertConsumer, LsstAlertConsumer, ZtfAlertConsumer},
+};
 
 #[derive(Parser)]
 struct Cli {
-    #[arg(help = "Survey to consume alerts from. Options are 'ZTF' or 'LSST'")]
+    #[arg(
+        help = "Survey to consume alerts from. Options are 'ZTF', 'LSST' or any stream defined in the config"
+    )]
     survey: String,
     #[arg(help = "UTC date for which we want to consume alerts, with format YYYYMMDD")]
     date: Option<String>,
@@ -81,7 +86,22 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             consumer.consume(timestamp).await?;
         }
         _ => {
-            panic!("Invalid survey provided. Options are 'ZTF' or 'LSST'");
+            let config = conf::load_config(&config_path)?;
+            if !conf::get_stream_names(&config).contains(&survey) {
+                panic!(
+                    "Invalid survey provided. Options are 'ZTF', 'LSST' or any stream defined in the config"
+                );
+            }
+            let consumer = GenericAlertConsumer::from_config(
+                &survey,
+                processes,
+                Some(max_in_queue),
+                &config_path,
+            )?;
+            if clear {
+                let _ = consumer.clear_output_queue().await;
+            }
+            consumer.consume(timestamp).await?;
         }
     }
 
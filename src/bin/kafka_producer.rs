@@ -14,6 +14,8 @@ struct Cli {
         help = "Limit the number of alerts produced"
     )]
     limit: Option<i64>,
+    #[arg(long, value_name = "FILE", help = "Path to the configuration file")]
+    config: Option<String>,
 }
 
 #[tokio::main]
@@ -41,7 +43,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         limit = l;
     }
 
-    produce_from_archive(&date, limit, None).await?;
+    let config_path = args.config.unwrap_or_else(|| "config.yaml".to_string());
+
+    produce_from_archive(&date, limit, None, &config_path).await?;
 
     Ok(())
 }
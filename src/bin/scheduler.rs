@@ -1,6 +1,6 @@
 use boom::{
     conf,
-    scheduler::{get_num_workers, ThreadPool},
+    scheduler::{get_num_workers, SchedulerError, ThreadPool},
     utils::{
         db::initialize_survey_indexes,
         worker::{check_flag, sig_int_handler, WorkerType},
@@ -57,6 +57,18 @@ async fn main() {
         }
     };
 
+    // besides ZTF and LSST, any stream described in the config can be ingested
+    if stream_name != "ZTF"
+        && stream_name != "LSST"
+        && !conf::get_stream_names(&config_file).contains(&stream_name)
+    {
+        warn!(
+            "unknown stream {}, not found in the streams config",
+            stream_name
+        );
+        std::process::exit(1);
+    }
+
     // get num workers from config file
     let n_alert = match get_num_workers(config_file.to_owned(), &stream_name, "alert") {
         Ok(n) => n,
@@ -80,6 +92,20 @@ async fn main() {
         }
     };
 
+    // the scheduled (object) filter workers are optional
+    let n_scheduled_filter =
+        match get_num_workers(config_file.to_owned(), &stream_name, "scheduled_filter") {
+            Ok(n) => n,
+            Err(SchedulerError::Config(config::ConfigError::NotFound(_))) => 0,
+            Err(e) => {
+                warn!(
+                    "could not retrieve number of scheduled filter workers: {}",
+                    e
+                );
+                std::process::exit(1);
+            }
+        };
+
     // initialize the indexes for the survey
     let db: mongodb::Database = match conf::build_db(&config_file).await {
         Ok(db) => db,
@@ -100,7 +126,7 @@ async fn main() {
     let interrupt = Arc::new(Mutex::new(false));
     sig_int_handler(Arc::clone(&interrupt)).await;
 
-    info!("creating alert, ml, and filter workers...");
+    info!("creating alert, ml, filter, and scheduled filter workers...");
     let alert_pool = ThreadPool::new(
         WorkerType::Alert,
         n_alert as usize,
@@ -119,6 +145,12 @@ async fn main() {
         stream_name.clone(),
         config_path.clone(),
     );
+    let scheduled_filter_pool = ThreadPool::new(
+        WorkerType::ScheduledFilter,
+        n_scheduled_filter as usize,
+        stream_name.clone(),
+        config_path.clone(),
+    );
     info!("created workers");
 
     loop {
@@ -129,6 +161,7 @@ async fn main() {
             drop(alert_pool);
             drop(ml_pool);
             drop(filter_pool);
+            drop(scheduled_filter_pool);
             break;
         }
         thread::sleep(std::time::Duration::from_secs(1));
@@ -0,0 +1,97 @@
+use boom::{
+    alert::{SchemaRegistry, SchemaSource, StreamMapping, LSST_SCHEMA_REGISTRY_URL},
+    conf,
+};
+use clap::{Parser, Subcommand};
+use std::path::Path;
+use tracing::{error, info, Level};
+use tracing_subscriber::FmtSubscriber;
+
+#[derive(Parser)]
+struct Cli {
+    #[arg(help = "Name of the stream whose schemas to manage, e.g. 'LSST'")]
+    stream: String,
+    #[arg(long, value_name = "FILE", help = "Path to the configuration file")]
+    config: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Fetch the schemas missing on disk from the registry, even in offline mode
+    Sync {
+        #[arg(
+            long,
+            default_value = "alert-packet",
+            help = "Subject whose versions to fetch"
+        )]
+        subject: String,
+        #[arg(
+            long,
+            value_name = "DIR",
+            help = "Save the schemas to this directory instead of the cache directory, e.g. to bundle them"
+        )]
+        output: Option<String>,
+    },
+    /// List the schemas available on disk
+    List,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(Level::INFO)
+        .finish();
+
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+
+    let args = Cli::parse();
+    let config_path = args.config.unwrap_or("config.yaml".to_string());
+    let stream_name = args.stream;
+    let config = conf::load_config(&config_path)?;
+
+    // LSST has a default registry, the generic streams set theirs in the stream mapping
+    let default_url = match stream_name.as_str() {
+        "LSST" => LSST_SCHEMA_REGISTRY_URL.to_string(),
+        _ => match StreamMapping::from_config(&config, &stream_name).map(|m| m.schema) {
+            Ok(SchemaSource::Registry { url, .. }) => url,
+            _ => {
+                error!("no schema registry configured for stream {}", stream_name);
+                std::process::exit(1);
+            }
+        },
+    };
+    let mut registry = SchemaRegistry::from_config(&config, &stream_name, &default_url)?;
+
+    match args.command {
+        Command::Sync { subject, output } => {
+            if let Some(output) = output {
+                registry = SchemaRegistry::with_cache(
+                    registry.url(),
+                    Some(Path::new(&output)),
+                    None,
+                    false,
+                )?;
+            }
+            registry.set_offline(false);
+            let fetched = registry.sync(&subject).await?;
+            info!(
+                "fetched {} new versions of {} from {}: {:?}",
+                fetched.len(),
+                subject,
+                registry.url(),
+                fetched
+            );
+        }
+        Command::List => {
+            let schemas = registry.cached_schemas();
+            println!("{} schemas on disk for {}", schemas.len(), stream_name);
+            for (subject, version) in schemas {
+                println!("{}\t{}", subject, version);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -2,7 +2,8 @@ use config::{Config, Value};
 // TODO: we do not want to get in the habit of making 3rd party types part of
 // our public API. It's almost always asking for trouble.
 use config::File;
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use tracing::error;
 
 #[derive(thiserror::Error, Debug)]
@@ -17,6 +18,8 @@ pub enum BoomConfigError {
     ConfigFileNotFound,
     #[error("missing key in config")]
     MissingKeyError,
+    #[error("environment variable {0} not set")]
+    MissingEnvVar(String),
 }
 
 pub fn load_config(filepath: &str) -> Result<Config, BoomConfigError> {
@@ -32,6 +35,40 @@ pub fn load_config(filepath: &str) -> Result<Config, BoomConfigError> {
     Ok(conf)
 }
 
+/// Set `username` and `password` from the environment variables named by
+/// `username_env` and `password_env`, for those that are given.
+pub fn read_env_credentials(
+    username_env: &Option<String>,
+    password_env: &Option<String>,
+    username: &mut Option<String>,
+    password: &mut Option<String>,
+) -> Result<(), BoomConfigError> {
+    for (var, value) in [(username_env, username), (password_env, password)] {
+        if let Some(var) = var {
+            *value =
+                Some(std::env::var(var).map_err(|_| BoomConfigError::MissingEnvVar(var.clone()))?);
+        }
+    }
+    Ok(())
+}
+
+/// Read a path from the config. Relative paths are resolved against the directory
+/// of the config file that sets them rather than the working directory, so that
+/// a config file can point to the files next to it wherever it is run from.
+pub fn get_path(conf: &Config, key: &str) -> Option<PathBuf> {
+    let (table, name) = key.rsplit_once('.').unwrap_or(("", key));
+    let value = match table {
+        "" => conf.get::<Value>(name).ok()?,
+        table => conf.get_table(table).ok()?.remove(name)?,
+    };
+    let origin = value.origin().map(PathBuf::from);
+    let path = PathBuf::from(value.into_string().ok()?);
+    match origin.as_deref().and_then(Path::parent) {
+        Some(config_dir) if path.is_relative() => Some(config_dir.join(path)),
+        _ => Some(path),
+    }
+}
+
 pub fn build_xmatch_configs(
     conf: &Config,
     stream_name: &str,
@@ -54,6 +91,64 @@ pub fn build_xmatch_configs(
     Ok(catalog_xmatch_configs)
 }
 
+pub fn build_survey_xmatch_configs(
+    conf: &Config,
+    stream_name: &str,
+) -> Result<Vec<SurveyXmatchConfig>, BoomConfigError> {
+    // the section is optional, without it streams don't alias each other
+    let Ok(survey_crossmatches) = conf.get_table("survey_crossmatch") else {
+        return Ok(Vec::new());
+    };
+
+    let survey_crossmatches_stream = match survey_crossmatches.get(stream_name).cloned() {
+        Some(x) => x,
+        None => {
+            return Ok(Vec::new());
+        }
+    };
+    let mut survey_xmatch_configs = Vec::new();
+
+    for survey_crossmatch in survey_crossmatches_stream.into_array()? {
+        let survey_xmatch_config = SurveyXmatchConfig::from_config(survey_crossmatch)?;
+        survey_xmatch_configs.push(survey_xmatch_config);
+    }
+
+    Ok(survey_xmatch_configs)
+}
+
+// the number of aliases of stream_name kept on the aux documents of each other stream,
+// i.e. the max_matches of the other streams' survey_crossmatch entries for stream_name
+pub fn build_alias_limits(
+    conf: &Config,
+    stream_name: &str,
+) -> Result<HashMap<String, usize>, BoomConfigError> {
+    let Ok(survey_crossmatches) = conf.get_table("survey_crossmatch") else {
+        return Ok(HashMap::new());
+    };
+    let mut alias_limits = HashMap::new();
+    for other_stream_name in survey_crossmatches.keys() {
+        for survey_xmatch_config in build_survey_xmatch_configs(conf, other_stream_name)? {
+            if let (true, Some(max_matches)) = (
+                survey_xmatch_config.survey == stream_name,
+                survey_xmatch_config.max_matches,
+            ) {
+                alias_limits.insert(other_stream_name.clone(), max_matches);
+            }
+        }
+    }
+    Ok(alias_limits)
+}
+
+// names of the streams described in the `streams` section, ingested by the generic alert worker
+pub fn get_stream_names(conf: &Config) -> Vec<String> {
+    let mut stream_names: Vec<String> = match conf.get_table("streams") {
+        Ok(streams) => streams.into_keys().collect(),
+        Err(_) => Vec::new(),
+    };
+    stream_names.sort();
+    stream_names
+}
+
 pub async fn build_db(conf: &Config) -> Result<mongodb::Database, BoomConfigError> {
     let db_conf = conf.get_table("database")?;
 
@@ -170,6 +265,16 @@ pub async fn build_db(conf: &Config) -> Result<mongodb::Database, BoomConfigErro
 pub async fn build_redis(
     conf: &Config,
 ) -> Result<redis::aio::MultiplexedConnection, BoomConfigError> {
+    let client_redis = build_redis_client(conf)?;
+
+    let con = client_redis.get_multiplexed_async_connection().await?;
+
+    Ok(con)
+}
+
+/// Create a redis client from the `redis` section of the config, for
+/// the connections (like pub/sub) that can't be multiplexed.
+pub fn build_redis_client(conf: &Config) -> Result<redis::Client, BoomConfigError> {
     let redis_conf = conf.get_table("redis")?;
 
     let host = match redis_conf.get("host") {
@@ -186,9 +291,116 @@ pub async fn build_redis(
 
     let client_redis = redis::Client::open(uri)?;
 
-    let con = client_redis.get_multiplexed_async_connection().await?;
+    Ok(client_redis)
+}
 
-    Ok(con)
+fn default_bootstrap_servers() -> String {
+    "localhost:9092".to_string()
+}
+
+fn default_security_protocol() -> String {
+    "PLAINTEXT".to_string()
+}
+
+fn default_message_timeout_ms() -> u32 {
+    5000
+}
+
+/// The `kafka.producer` section of the config, used by everything that writes to Kafka.
+///
+/// The credentials can be given directly, or read from the environment variables
+/// named by `username_env` and `password_env`. The optional settings that are not
+/// given are left to the librdkafka defaults.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct KafkaProducerConfig {
+    #[serde(default = "default_bootstrap_servers")]
+    pub bootstrap_servers: String,
+    #[serde(default = "default_security_protocol")]
+    pub security_protocol: String,
+    pub sasl_mechanism: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub username_env: Option<String>,
+    pub password_env: Option<String>,
+    pub ssl_ca_location: Option<String>,
+    pub compression: Option<String>,
+    pub linger_ms: Option<u32>,
+    pub acks: Option<String>,
+    pub enable_idempotence: Option<bool>,
+    #[serde(default = "default_message_timeout_ms")]
+    pub message_timeout_ms: u32,
+}
+
+impl Default for KafkaProducerConfig {
+    fn default() -> Self {
+        KafkaProducerConfig {
+            bootstrap_servers: default_bootstrap_servers(),
+            security_protocol: default_security_protocol(),
+            sasl_mechanism: None,
+            username: None,
+            password: None,
+            username_env: None,
+            password_env: None,
+            ssl_ca_location: None,
+            compression: None,
+            linger_ms: None,
+            acks: None,
+            enable_idempotence: None,
+            message_timeout_ms: default_message_timeout_ms(),
+        }
+    }
+}
+
+impl KafkaProducerConfig {
+    /// Read the `kafka.producer` section, with the defaults (a local
+    /// broker without authentication) if there is none.
+    pub fn from_config(conf: &Config) -> Result<KafkaProducerConfig, BoomConfigError> {
+        let mut producer_config = match conf.get::<KafkaProducerConfig>("kafka.producer") {
+            Ok(producer_config) => producer_config,
+            Err(config::ConfigError::NotFound(_)) => KafkaProducerConfig::default(),
+            Err(e) => return Err(e.into()),
+        };
+        read_env_credentials(
+            &producer_config.username_env,
+            &producer_config.password_env,
+            &mut producer_config.username,
+            &mut producer_config.password,
+        )?;
+        Ok(producer_config)
+    }
+
+    /// The librdkafka settings of the producer, to create producers (or admin clients) from.
+    pub fn client_config(&self) -> rdkafka::config::ClientConfig {
+        let mut client_config = rdkafka::config::ClientConfig::new();
+        client_config
+            .set("bootstrap.servers", &self.bootstrap_servers)
+            .set("security.protocol", &self.security_protocol)
+            .set("message.timeout.ms", self.message_timeout_ms.to_string());
+        if let Some(sasl_mechanism) = &self.sasl_mechanism {
+            client_config.set("sasl.mechanisms", sasl_mechanism);
+        }
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            client_config
+                .set("sasl.username", username)
+                .set("sasl.password", password);
+        }
+        if let Some(ssl_ca_location) = &self.ssl_ca_location {
+            client_config.set("ssl.ca.location", ssl_ca_location);
+        }
+        if let Some(compression) = &self.compression {
+            client_config.set("compression.type", compression);
+        }
+        if let Some(linger_ms) = self.linger_ms {
+            client_config.set("linger.ms", linger_ms.to_string());
+        }
+        if let Some(acks) = &self.acks {
+            client_config.set("acks", acks);
+        }
+        if let Some(enable_idempotence) = self.enable_idempotence {
+            client_config.set("enable.idempotence", enable_idempotence.to_string());
+        }
+        client_config
+    }
 }
 
 #[derive(Debug)]
@@ -298,3 +510,75 @@ impl CatalogXmatchConfig {
         ))
     }
 }
+
+#[derive(Debug, Clone)]
+pub struct SurveyXmatchConfig {
+    pub survey: String,             // name of the other stream, e.g. LSST
+    pub radius: f64,                // radius in radians
+    pub min_dec: Option<f64>,       // only match alerts above this declination, in degrees
+    pub max_dec: Option<f64>,       // only match alerts below this declination, in degrees
+    pub max_matches: Option<usize>, // maximum number of matches to keep, nearest first
+}
+
+impl SurveyXmatchConfig {
+    pub fn new(
+        survey: &str,
+        radius: f64,
+        min_dec: Option<f64>,
+        max_dec: Option<f64>,
+        max_matches: Option<usize>,
+    ) -> SurveyXmatchConfig {
+        SurveyXmatchConfig {
+            survey: survey.to_string(),
+            radius: radius * std::f64::consts::PI / 180.0 / 3600.0, // convert arcsec to radians
+            min_dec,
+            max_dec,
+            max_matches,
+        }
+    }
+
+    pub fn from_config(config_value: Value) -> Result<SurveyXmatchConfig, BoomConfigError> {
+        let hashmap_xmatch = config_value.into_table()?;
+
+        let survey = hashmap_xmatch
+            .get("survey")
+            .ok_or(BoomConfigError::MissingKeyError)?
+            .clone()
+            .into_string()?;
+
+        let radius = hashmap_xmatch
+            .get("radius")
+            .ok_or(BoomConfigError::MissingKeyError)?
+            .clone()
+            .into_float()?;
+
+        let min_dec = match hashmap_xmatch.get("min_dec") {
+            Some(min_dec) => Some(min_dec.clone().into_float()?),
+            None => None,
+        };
+
+        let max_dec = match hashmap_xmatch.get("max_dec") {
+            Some(max_dec) => Some(max_dec.clone().into_float()?),
+            None => None,
+        };
+
+        let max_matches = match hashmap_xmatch.get("max_matches") {
+            Some(max_matches) => Some(max_matches.clone().into_uint()? as usize),
+            None => None,
+        };
+
+        Ok(SurveyXmatchConfig::new(
+            &survey,
+            radius,
+            min_dec,
+            max_dec,
+            max_matches,
+        ))
+    }
+
+    // whether an alert at this declination can have a counterpart in the other survey
+    pub fn in_footprint(&self, dec: f64) -> bool {
+        self.min_dec.is_none_or(|min_dec| dec >= min_dec)
+            && self.max_dec.is_none_or(|max_dec| dec <= max_dec)
+    }
+}
@@ -0,0 +1,245 @@
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Document};
+use std::collections::{BTreeMap, HashSet};
+
+use crate::filter::{
+    build_lsst_filter_prefix, build_ztf_filter_prefix, run_filter, FilterError, FilterValidator,
+};
+
+// number of alerts run through the filter at once
+pub const DEFAULT_BACKTEST_CHUNK_SIZE: usize = 1000;
+// number of passing candids kept in the report
+pub const DEFAULT_BACKTEST_SAMPLE_SIZE: usize = 20;
+
+// longitudes (in degrees, east positive) of the observatories, used to tell the nights apart
+pub const ZTF_LONGITUDE: f64 = -116.8597;
+pub const LSST_LONGITUDE: f64 = -70.7494;
+
+#[derive(thiserror::Error, Debug)]
+pub enum BacktestError {
+    #[error("unknown survey {0}")]
+    UnknownSurvey(String),
+    #[error("invalid jd range, jd_start must be before jd_end")]
+    InvalidJdRange,
+    #[error("invalid sky region")]
+    InvalidRegion,
+    #[error("filter error")]
+    Filter(#[from] FilterError),
+    #[error("error from mongodb")]
+    Mongodb(#[from] mongodb::error::Error),
+    #[error("value access error from bson")]
+    BsonValueAccess(#[from] mongodb::bson::document::ValueAccessError),
+}
+
+/// A cone on the sky, with its center and radius in degrees.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SkyRegion {
+    pub ra: f64,
+    pub dec: f64,
+    pub radius: f64,
+}
+
+/// The historical alerts a backtest runs on: those between `jd_start` (included)
+/// and `jd_end`, in the sky region if any, and for ZTF only those of the
+/// programids of the `permissions` (as with the filter workers).
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct BacktestQuery {
+    pub jd_start: f64,
+    pub jd_end: f64,
+    #[serde(default)]
+    pub region: Option<SkyRegion>,
+    #[serde(default)]
+    pub permissions: Vec<i32>,
+}
+
+/// The date of the (local) evening of the night of an observation made at
+/// `jd`, at an observatory at `longitude`, e.g. "2024-03-01".
+pub fn observing_night(jd: f64, longitude: f64) -> String {
+    // days since the unix epoch in local solar time, shifted by half a day
+    // so that a night doesn't straddle two dates
+    let days = (jd - 2440587.5 + longitude / 360.0 - 0.5).floor() as i64;
+    chrono::DateTime::from_timestamp(days * 86400, 0)
+        .map(|date| date.date_naive().to_string())
+        .unwrap_or_default()
+}
+
+/// Number of alerts of a night evaluated by a backtest, and of those that passed.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct NightCounts {
+    pub alerts_evaluated: u64,
+    pub alerts_passed: u64,
+}
+
+/// What a filter would have passed: the counts overall and per night,
+/// with a random sample of the passing candids.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct BacktestReport {
+    pub alerts_evaluated: u64,
+    pub alerts_passed: u64,
+    pub nights: BTreeMap<String, NightCounts>,
+    pub sample: Vec<i64>,
+}
+
+impl BacktestReport {
+    /// Record an evaluated alert, keeping at most `sample_size` passing candids
+    /// (with reservoir sampling, so that all the passes have the same odds).
+    pub fn record(&mut self, night: String, candid: i64, passed: bool, sample_size: usize) {
+        let night_counts = self.nights.entry(night).or_default();
+        night_counts.alerts_evaluated += 1;
+        self.alerts_evaluated += 1;
+        if !passed {
+            return;
+        }
+        night_counts.alerts_passed += 1;
+        self.alerts_passed += 1;
+        if self.sample.len() < sample_size {
+            self.sample.push(candid);
+        } else {
+            let index = rand::random_range(0..self.alerts_passed) as usize;
+            if index < sample_size {
+                self.sample[index] = candid;
+            }
+        }
+    }
+}
+
+/// The alerts of a chunk of a backtest.
+#[derive(Debug, Clone, Default)]
+pub struct BacktestChunk {
+    pub alerts_evaluated: usize,
+    pub passed: Vec<i64>,
+}
+
+/// Runs a filter (that isn't saved yet) on the historical alerts of a survey,
+/// with the same prefix as the filter workers, to see what it would have passed.
+///
+/// The alerts are filtered in chunks of `chunk_size`, in jd order, with `next_chunk`,
+/// so that the progress can be reported as the backtest goes.
+pub struct Backtest {
+    alert_collection: mongodb::Collection<Document>,
+    pipeline: Vec<Document>,
+    alerts: mongodb::Cursor<Document>,
+    longitude: f64,
+    chunk_size: usize,
+    sample_size: usize,
+    report: BacktestReport,
+}
+
+impl Backtest {
+    /// Prepare the backtest of the stages of a filter of a survey (ZTF or LSST),
+    /// which are checked like those of a submitted filter, with the validator
+    /// of the survey's catalog.
+    pub async fn new(
+        db: &mongodb::Database,
+        survey: &str,
+        stages: Vec<Document>,
+        query: &BacktestQuery,
+        validator: &FilterValidator,
+    ) -> Result<Self, BacktestError> {
+        let catalog = format!("{}_alerts", survey);
+        validator.validate(&stages).map_err(FilterError::from)?;
+        if query.jd_start >= query.jd_end {
+            return Err(BacktestError::InvalidJdRange);
+        }
+
+        let mut alert_filter = doc! {
+            "candidate.jd": {"$gte": query.jd_start, "$lt": query.jd_end},
+        };
+        if let Some(region) = &query.region {
+            if region.radius <= 0.0 || region.dec.abs() > 90.0 {
+                return Err(BacktestError::InvalidRegion);
+            }
+            alert_filter.insert(
+                "coordinates.radec_geojson",
+                doc! {
+                    "$geoWithin": {
+                        "$centerSphere": [[region.ra - 180.0, region.dec], region.radius.to_radians()]
+                    }
+                },
+            );
+        }
+        let (mut pipeline, longitude) = match survey {
+            "ZTF" => {
+                if query.permissions.is_empty() {
+                    return Err(FilterError::InvalidFilterPermissions.into());
+                }
+                alert_filter.insert("candidate.programid", doc! {"$in": &query.permissions});
+                (build_ztf_filter_prefix(&query.permissions), ZTF_LONGITUDE)
+            }
+            "LSST" => (build_lsst_filter_prefix(), LSST_LONGITUDE),
+            _ => return Err(BacktestError::UnknownSurvey(survey.to_string())),
+        };
+        pipeline.extend(stages);
+
+        let alert_collection: mongodb::Collection<Document> = db.collection(&catalog);
+        let alerts = alert_collection
+            .find(alert_filter)
+            .projection(doc! {"candidate.jd": 1})
+            .sort(doc! {"candidate.jd": 1})
+            .await?;
+
+        Ok(Backtest {
+            alert_collection,
+            pipeline,
+            alerts,
+            longitude,
+            chunk_size: DEFAULT_BACKTEST_CHUNK_SIZE,
+            sample_size: DEFAULT_BACKTEST_SAMPLE_SIZE,
+            report: BacktestReport::default(),
+        })
+    }
+
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    pub fn sample_size(mut self, sample_size: usize) -> Self {
+        self.sample_size = sample_size;
+        self
+    }
+
+    /// The report of the chunks run so far.
+    pub fn report(&self) -> &BacktestReport {
+        &self.report
+    }
+
+    /// Run the filter on the next chunk of alerts, and add it to the report.
+    /// Returns None once all the alerts have been evaluated.
+    pub async fn next_chunk(&mut self) -> Result<Option<BacktestChunk>, BacktestError> {
+        let mut alerts = Vec::new();
+        while alerts.len() < self.chunk_size {
+            let Some(alert) = self.alerts.try_next().await? else {
+                break;
+            };
+            let jd = alert.get_document("candidate")?.get_f64("jd")?;
+            alerts.push((alert.get_i64("_id")?, jd));
+        }
+        if alerts.is_empty() {
+            return Ok(None);
+        }
+
+        let candids = alerts.iter().map(|(candid, _)| *candid).collect();
+        let out_documents =
+            run_filter(candids, self.pipeline.clone(), &self.alert_collection).await?;
+        let passed = out_documents
+            .iter()
+            .map(|doc| doc.get_i64("_id"))
+            .collect::<Result<HashSet<i64>, _>>()?;
+
+        let mut chunk = BacktestChunk {
+            alerts_evaluated: alerts.len(),
+            passed: Vec::new(),
+        };
+        for (candid, jd) in alerts {
+            let alert_passed = passed.contains(&candid);
+            let night = observing_night(jd, self.longitude);
+            self.report
+                .record(night, candid, alert_passed, self.sample_size);
+            if alert_passed {
+                chunk.passed.push(candid);
+            }
+        }
+        Ok(Some(chunk))
+    }
+}
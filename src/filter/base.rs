@@ -1,24 +1,46 @@
 use apache_avro::Schema;
 use apache_avro::{serde_avro_bytes, Writer};
+use flare::spatial::great_circle_distance;
 use futures::stream::StreamExt;
-use mongodb::bson::{doc, Document};
+use mongodb::bson::{doc, Bson, Document};
+use rdkafka::admin::{AdminClient, AdminOptions, NewTopic, TopicReplication};
+use rdkafka::client::DefaultClientContext;
+use rdkafka::error::RDKafkaErrorCode;
 use rdkafka::producer::FutureProducer;
 use rdkafka::{config::ClientConfig, producer::FutureRecord};
 use redis::AsyncCommands;
+use std::collections::{HashMap, HashSet};
 use std::num::NonZero;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::error::TryRecvError;
 use tracing::{error, info, trace, warn};
 
-use crate::{conf, utils::worker::WorkerCmd};
+use crate::{
+    alert::{SchemaRegistry, SchemaRegistryError},
+    conf,
+    filter::{validation::FilterValidationError, NotificationPolicy, OBJECT_FILTER_TYPE},
+    utils::{
+        db::{create_index, CreateIndexError},
+        worker::WorkerCmd,
+    },
+};
+
+/// Version of `ALERT_SCHEMA`, sent with every alert as `schemavsn`.
+/// Bump it whenever the schema changes, adding defaults to new fields
+/// so that consumers can keep reading the alerts with an older schema.
+/// The alerts sent before it was added are read with a `schemavsn` of "0.0".
+pub const ALERT_SCHEMA_VERSION: &str = "1.0";
 
 // This is the schema of the avro object that we will send to kafka
-// that includes the alert data and filter results
+// that includes the alert data, filter results, ML scores and cross-matches
 const ALERT_SCHEMA: &str = r#"
 {
     "type": "record",
     "name": "Alert",
     "fields": [
+        {"name": "schemavsn", "type": "string", "default": "0.0"},
         {"name": "candid", "type": "long"},
         {"name": "objectId", "type": "string"},
         {"name": "jd", "type": "double"},
@@ -55,6 +77,33 @@ const ALERT_SCHEMA: &str = r#"
                 ]
             }
         }},
+        {"name": "classifications", "type": {
+            "type": "map",
+            "values": {
+                "type": "record",
+                "name": "Classification",
+                "fields": [
+                    {"name": "score", "type": "double"},
+                    {"name": "version", "type": ["null", "string"], "default": null}
+                ]
+            }
+        }, "default": {}},
+        {"name": "cross_matches", "type": {
+            "type": "map",
+            "values": {
+                "type": "array",
+                "items": {
+                    "type": "record",
+                    "name": "CrossMatch",
+                    "fields": [
+                        {"name": "id", "type": ["null", "string"], "default": null},
+                        {"name": "ra", "type": "double"},
+                        {"name": "dec", "type": "double"},
+                        {"name": "distance_arcsec", "type": "double"}
+                    ]
+                }
+            }
+        }, "default": {}},
         {"name":"cutoutScience","type":{"type":"bytes"}},
         {"name":"cutoutTemplate","type":{"type":"bytes"}},
         {"name":"cutoutDifference","type":{"type":"bytes"}}
@@ -80,6 +129,14 @@ pub enum FilterError {
     InvalidFilterPipeline,
     #[error("invalid filter id")]
     InvalidFilterId,
+    #[error("invalid filter notification policy")]
+    InvalidNotificationPolicy,
+    #[error("invalid filter schedule")]
+    InvalidFilterSchedule,
+    #[error("filter pipeline not allowed")]
+    FilterValidation(#[from] FilterValidationError),
+    #[error("filter output is missing the candid (_id) of the alerts")]
+    InvalidFilterOutput,
 }
 
 pub fn parse_programid_candid_tuple(tuple_str: &str) -> Option<(i32, i64)> {
@@ -98,19 +155,22 @@ pub fn parse_programid_candid_tuple(tuple_str: &str) -> Option<(i32, i64)> {
     None
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum Origin {
     Alert,
     ForcedPhot,
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub enum Survey {
     ZTF,
     LSST,
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+/// Zero point of the fluxes in the output photometry, for fluxes in µJy
+pub const PHOTOMETRY_ZP: f64 = 23.9;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Photometry {
     pub jd: f64,
     pub flux: Option<f64>,
@@ -131,8 +191,25 @@ pub struct FilterResults {
     pub annotations: String,
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+/// Score of an ML model, with the version of the model when it is known
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Classification {
+    pub score: f64,
+    pub version: Option<String>,
+}
+
+/// A compact cross-match with an archival catalog
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CrossMatch {
+    pub id: Option<String>,
+    pub ra: f64,
+    pub dec: f64,
+    pub distance_arcsec: f64,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Alert {
+    pub schemavsn: String,
     pub candid: i64,
     #[serde(rename = "objectId")]
     pub object_id: String,
@@ -141,6 +218,8 @@ pub struct Alert {
     pub dec: f64,
     pub filters: Vec<FilterResults>,
     pub photometry: Vec<Photometry>,
+    pub classifications: HashMap<String, Classification>,
+    pub cross_matches: HashMap<String, Vec<CrossMatch>>,
     #[serde(with = "serde_avro_bytes", rename = "cutoutScience")]
     pub cutout_science: Vec<u8>,
     #[serde(with = "serde_avro_bytes", rename = "cutoutTemplate")]
@@ -149,6 +228,74 @@ pub struct Alert {
     pub cutout_difference: Vec<u8>,
 }
 
+/// Get the ML scores from the `classifications` of an alert document,
+/// with the version of each model from its `model_versions` when present.
+pub fn get_classifications(alert_document: &Document) -> HashMap<String, Classification> {
+    let Ok(scores) = alert_document.get_document("classifications") else {
+        return HashMap::new();
+    };
+    let versions = alert_document.get_document("model_versions").ok();
+    scores
+        .iter()
+        .filter_map(|(model, score)| {
+            let score = match score {
+                Bson::Double(score) => *score,
+                Bson::Int32(score) => *score as f64,
+                Bson::Int64(score) => *score as f64,
+                _ => return None,
+            };
+            let version = versions
+                .and_then(|versions| versions.get_str(model).ok())
+                .map(|version| version.to_string());
+            Some((model.clone(), Classification { score, version }))
+        })
+        .collect()
+}
+
+/// Compact the `cross_matches` of an alert document (catalog -> matches)
+/// to the id, position and distance to the alert of each match, nearest first.
+/// Matches without a position are left out.
+pub fn get_cross_matches(
+    alert_document: &Document,
+    ra: f64,
+    dec: f64,
+) -> HashMap<String, Vec<CrossMatch>> {
+    let Ok(cross_matches) = alert_document.get_document("cross_matches") else {
+        return HashMap::new();
+    };
+    cross_matches
+        .iter()
+        .map(|(catalog, matches)| {
+            let mut compact_matches = matches
+                .as_array()
+                .map(|matches| matches.as_slice())
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|xmatch| {
+                    let xmatch = xmatch.as_document()?;
+                    let xmatch_ra = xmatch.get_f64("ra").ok()?;
+                    let xmatch_dec = xmatch.get_f64("dec").ok()?;
+                    let id = match xmatch.get("_id") {
+                        Some(Bson::String(id)) => Some(id.clone()),
+                        Some(Bson::ObjectId(id)) => Some(id.to_hex()),
+                        Some(Bson::Null) | None => None,
+                        Some(id) => Some(id.to_string()),
+                    };
+                    Some(CrossMatch {
+                        id,
+                        ra: xmatch_ra,
+                        dec: xmatch_dec,
+                        distance_arcsec: great_circle_distance(ra, dec, xmatch_ra, xmatch_dec)
+                            * 3600.0,
+                    })
+                })
+                .collect::<Vec<_>>();
+            compact_matches.sort_by(|a, b| a.distance_arcsec.total_cmp(&b.distance_arcsec));
+            (catalog.clone(), compact_matches)
+        })
+        .collect()
+}
+
 pub fn load_alert_schema() -> Result<Schema, FilterWorkerError> {
     let schema = Schema::parse_str(ALERT_SCHEMA)
         .inspect_err(|e| error!("Failed to parse alert schema: {}", e))?;
@@ -168,24 +315,363 @@ pub fn alert_to_avro_bytes(alert: &Alert, schema: &Schema) -> Result<Vec<u8>, Fi
     Ok(encoded)
 }
 
-// TODO, use the config file to get the kafka server
-pub async fn create_producer() -> Result<FutureProducer, FilterWorkerError> {
-    let producer: FutureProducer = ClientConfig::new()
-        .set("bootstrap.servers", "localhost:9092")
-        .set("message.timeout.ms", "5000")
-        .create()?;
+/// How the alerts are written to an output topic, from the `filter_output.<topic>`
+/// section of the config, or `filter_output.default` for topics without a section
+/// (such as the topics of the filters). Defaults to `ocf`.
+#[derive(Debug, Clone, PartialEq, Default, serde::Deserialize)]
+#[serde(tag = "format", rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// An Avro object container file per message, with the schema in every message
+    #[default]
+    Ocf,
+    /// The Confluent wire format: a magic byte, the id of the schema registered
+    /// in the registry (under `<topic>-value` unless a subject is given) and the Avro datum.
+    /// The credentials of the registry can be given directly, or read from the
+    /// environment variables named by `username_env` and `password_env`.
+    Confluent {
+        registry_url: String,
+        subject: Option<String>,
+        username: Option<String>,
+        password: Option<String>,
+        username_env: Option<String>,
+        password_env: Option<String>,
+    },
+}
+
+impl OutputFormat {
+    pub fn from_config(conf: &config::Config, topic: &str) -> Result<Self, conf::BoomConfigError> {
+        for key in [topic, "default"] {
+            match conf.get::<OutputFormat>(&format!("filter_output.{}", key)) {
+                Ok(output_format) => return output_format.with_env_credentials(),
+                Err(config::ConfigError::NotFound(_)) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(OutputFormat::default())
+    }
+
+    /// Read the credentials of the registry from the environment variables
+    /// named by `username_env` and `password_env`, if any.
+    fn with_env_credentials(mut self) -> Result<Self, conf::BoomConfigError> {
+        if let OutputFormat::Confluent {
+            username,
+            password,
+            username_env,
+            password_env,
+            ..
+        } = &mut self
+        {
+            conf::read_env_credentials(username_env, password_env, username, password)?;
+        }
+        Ok(self)
+    }
+}
+
+/// Encodes the alerts sent to an output topic, in the format of that topic.
+pub enum AlertEncoder {
+    Ocf(Schema),
+    Confluent {
+        registry: SchemaRegistry,
+        schema_id: u32,
+    },
+}
+
+impl AlertEncoder {
+    /// Create the encoder of a topic, registering `ALERT_SCHEMA` for the Confluent format.
+    pub async fn new(output_format: &OutputFormat, topic: &str) -> Result<Self, FilterWorkerError> {
+        let schema = load_alert_schema()?;
+        match output_format {
+            OutputFormat::Ocf => Ok(AlertEncoder::Ocf(schema)),
+            OutputFormat::Confluent {
+                registry_url,
+                subject,
+                username,
+                password,
+                ..
+            } => {
+                let mut registry = SchemaRegistry::new(registry_url);
+                if let (Some(username), Some(password)) = (username, password) {
+                    registry.set_credentials(username, password);
+                }
+                let subject = subject.clone().unwrap_or(format!("{}-value", topic));
+                let schema_id = registry.register_schema(&subject, ALERT_SCHEMA).await?;
+                info!(
+                    "registered the alert schema for topic {} under subject {} with id {}",
+                    topic, subject, schema_id
+                );
+                Ok(AlertEncoder::Confluent {
+                    registry,
+                    schema_id,
+                })
+            }
+        }
+    }
+
+    pub async fn from_config(
+        conf: &config::Config,
+        topic: &str,
+    ) -> Result<Self, FilterWorkerError> {
+        AlertEncoder::new(&OutputFormat::from_config(conf, topic)?, topic).await
+    }
+
+    pub fn encode(&self, alert: &Alert) -> Result<Vec<u8>, FilterWorkerError> {
+        match self {
+            AlertEncoder::Ocf(schema) => alert_to_avro_bytes(alert, schema),
+            AlertEncoder::Confluent {
+                registry,
+                schema_id,
+            } => {
+                let value = apache_avro::to_value(alert)?;
+                Ok(registry.encode(*schema_id, value)?)
+            }
+        }
+    }
+}
+
+fn default_combined() -> bool {
+    true
+}
+
+fn default_partitions() -> i32 {
+    1
+}
+
+fn default_replication_factor() -> i32 {
+    1
+}
+
+/// The `filter_topics` section of the config.
+///
+/// The topic of each filter is derived from the `template`, where `{survey}`,
+/// `{filter_id}` and `{group_id}` are replaced by the values of the filter, e.g.
+/// `boom_{survey}_filter_{filter_id}` or `boom_group_{group_id}`. With `combined`,
+/// every alert also goes to the `<survey>_alerts_results` topic with all its filter results.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct FilterTopicsConfig {
+    pub template: Option<String>,
+    #[serde(default = "default_combined")]
+    pub combined: bool,
+    #[serde(default = "default_partitions")]
+    pub partitions: i32,
+    #[serde(default = "default_replication_factor")]
+    pub replication_factor: i32,
+    pub retention_ms: Option<i64>,
+}
+
+impl Default for FilterTopicsConfig {
+    fn default() -> Self {
+        FilterTopicsConfig {
+            template: None,
+            combined: default_combined(),
+            partitions: default_partitions(),
+            replication_factor: default_replication_factor(),
+            retention_ms: None,
+        }
+    }
+}
+
+impl FilterTopicsConfig {
+    pub fn from_config(conf: &config::Config) -> Result<Self, conf::BoomConfigError> {
+        match conf.get::<FilterTopicsConfig>("filter_topics") {
+            Ok(topics_config) => {
+                if topics_config.template.is_none() && !topics_config.combined {
+                    warn!(
+                        "filter_topics has no template and combined is false, the results of all the filters are sent to the combined topic"
+                    );
+                }
+                Ok(topics_config)
+            }
+            Err(config::ConfigError::NotFound(_)) => Ok(FilterTopicsConfig::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Routes the alerts that passed the filters to their output topics, creating
+/// the topics and their encoders the first time they are used.
+pub struct OutputTopics {
+    topics_config: FilterTopicsConfig,
+    survey: String,
+    combined_topic: String,
+    admin: Option<AdminClient<DefaultClientContext>>,
+    encoders: HashMap<String, AlertEncoder>,
+}
+
+impl OutputTopics {
+    /// Create the router of a survey. Without an admin client, topics are not
+    /// created and are left to the broker (if it creates topics automatically).
+    pub fn new(
+        topics_config: FilterTopicsConfig,
+        survey: &str,
+        combined_topic: &str,
+        admin: Option<AdminClient<DefaultClientContext>>,
+    ) -> Self {
+        OutputTopics {
+            topics_config,
+            survey: survey.to_string(),
+            combined_topic: combined_topic.to_string(),
+            admin,
+            encoders: HashMap::new(),
+        }
+    }
+
+    pub fn from_config(
+        conf: &config::Config,
+        survey: &str,
+        combined_topic: &str,
+    ) -> Result<Self, FilterWorkerError> {
+        let topics_config = FilterTopicsConfig::from_config(conf)?;
+        let admin = create_admin_client(conf)?;
+        Ok(OutputTopics::new(
+            topics_config,
+            survey,
+            combined_topic,
+            Some(admin),
+        ))
+    }
+
+    /// The topic of a filter, None if there is no template, or if it
+    /// needs the group of a filter that doesn't have one.
+    pub fn filter_topic(&self, filter_id: i32, group_id: Option<i32>) -> Option<String> {
+        let template = self.topics_config.template.as_ref()?;
+        let mut topic = template
+            .replace("{survey}", &self.survey)
+            .replace("{filter_id}", &filter_id.to_string());
+        if topic.contains("{group_id}") {
+            topic = topic.replace("{group_id}", &group_id?.to_string());
+        }
+        Some(topic)
+    }
+
+    /// Split an alert between the topics of its filters, each getting the alert
+    /// with the results of its own filters only, plus the combined topic.
+    /// Filters without a topic (when there is no template, or when the template
+    /// needs the group of a filter that doesn't have one) go to the combined topic,
+    /// even if it is disabled, so that their results are not lost.
+    pub fn route(&self, alert: &Alert, group_ids: &HashMap<i32, i32>) -> Vec<(String, Alert)> {
+        let mut routed: Vec<(String, Alert)> = Vec::new();
+        for filter_result in &alert.filters {
+            let group_id = group_ids.get(&filter_result.filter_id).copied();
+            let topic = match self.filter_topic(filter_result.filter_id, group_id) {
+                Some(topic) => topic,
+                // the combined topic has the results of all the filters already
+                None if self.topics_config.combined => continue,
+                None => {
+                    if let Some(template) = &self.topics_config.template {
+                        warn!(
+                            "filter {} has no group for topic template {}, sending its results to {}",
+                            filter_result.filter_id, template, self.combined_topic
+                        );
+                    }
+                    self.combined_topic.clone()
+                }
+            };
+            match routed.iter_mut().find(|(t, _)| *t == topic) {
+                Some((_, routed_alert)) => routed_alert.filters.push(filter_result.clone()),
+                None => {
+                    let routed_alert = Alert {
+                        filters: vec![filter_result.clone()],
+                        ..alert.clone()
+                    };
+                    routed.push((topic, routed_alert));
+                }
+            }
+        }
+        if self.topics_config.combined {
+            routed.push((self.combined_topic.clone(), alert.clone()));
+        }
+        routed
+    }
+
+    /// Get the encoder of a topic, creating the topic first if we haven't used it yet.
+    pub async fn encoder(
+        &mut self,
+        conf: &config::Config,
+        topic: &str,
+    ) -> Result<&AlertEncoder, FilterWorkerError> {
+        if !self.encoders.contains_key(topic) {
+            if let Some(admin) = &self.admin {
+                create_topic(admin, topic, &self.topics_config).await?;
+            }
+            let encoder = AlertEncoder::from_config(conf, topic).await?;
+            self.encoders.insert(topic.to_string(), encoder);
+        }
+        Ok(self.encoders.get(topic).unwrap())
+    }
+}
+
+/// Create an admin client for the cluster of the `kafka.producer` section of the config.
+pub fn create_admin_client(
+    conf: &config::Config,
+) -> Result<AdminClient<DefaultClientContext>, FilterWorkerError> {
+    let producer_config = conf::KafkaProducerConfig::from_config(conf)?;
+    let mut client_config = ClientConfig::new();
+    // the admin client only needs the connection settings of the producer
+    for key in [
+        "bootstrap.servers",
+        "security.protocol",
+        "sasl.mechanisms",
+        "sasl.username",
+        "sasl.password",
+        "ssl.ca.location",
+    ] {
+        if let Some(value) = producer_config.client_config().get(key) {
+            client_config.set(key, value);
+        }
+    }
+    let admin: AdminClient<DefaultClientContext> = client_config.create()?;
+
+    Ok(admin)
+}
+
+/// Create a topic with the partitions, replication and retention of the config,
+/// if it doesn't exist yet.
+pub async fn create_topic(
+    admin: &AdminClient<DefaultClientContext>,
+    topic: &str,
+    topics_config: &FilterTopicsConfig,
+) -> Result<(), FilterWorkerError> {
+    let retention_ms = topics_config.retention_ms.map(|ms| ms.to_string());
+    let mut new_topic = NewTopic::new(
+        topic,
+        topics_config.partitions,
+        TopicReplication::Fixed(topics_config.replication_factor),
+    );
+    if let Some(retention_ms) = &retention_ms {
+        new_topic = new_topic.set("retention.ms", retention_ms);
+    }
+    let results = admin
+        .create_topics(&[new_topic], &AdminOptions::new())
+        .await?;
+    for result in results {
+        match result {
+            Ok(topic) => info!("created Kafka topic {}", topic),
+            Err((_, RDKafkaErrorCode::TopicAlreadyExists)) => {}
+            Err((topic, e)) => {
+                error!("failed to create Kafka topic {}: {}", topic, e);
+                return Err(rdkafka::error::KafkaError::AdminOp(e).into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Create a producer from the `kafka.producer` section of the config.
+pub async fn create_producer(conf: &config::Config) -> Result<FutureProducer, FilterWorkerError> {
+    let producer_config = conf::KafkaProducerConfig::from_config(conf)?;
+    let producer: FutureProducer = producer_config.client_config().create()?;
 
     Ok(producer)
 }
 
 pub async fn send_alert_to_kafka(
     alert: &Alert,
-    schema: &Schema,
+    encoder: &AlertEncoder,
     producer: &FutureProducer,
     topic: &str,
     id: &str,
 ) -> Result<(), FilterWorkerError> {
-    let encoded = alert_to_avro_bytes(alert, schema)?;
+    let encoded = encoder.encode(alert)?;
 
     let record = FutureRecord::to(&topic).key(id).payload(&encoded);
 
@@ -200,6 +686,26 @@ pub async fn send_alert_to_kafka(
     Ok(())
 }
 
+/// The ids of the active filters of a catalog (e.g. ZTF_alerts), run on each
+/// new alert (the object filters are run on a schedule instead).
+pub async fn get_active_filter_ids(
+    catalog: &str,
+    filter_collection: &mongodb::Collection<mongodb::bson::Document>,
+) -> Result<Vec<i32>, FilterError> {
+    let filter_ids: Vec<i32> = filter_collection
+        .distinct(
+            "filter_id",
+            doc! {"active": true, "catalog": catalog, "type": {"$ne": OBJECT_FILTER_TYPE}},
+        )
+        .await?
+        .into_iter()
+        .map(|x| x.as_i32().ok_or(FilterError::InvalidFilterId))
+        .filter_map(Result::ok)
+        .collect();
+
+    Ok(filter_ids)
+}
+
 pub async fn get_filter_object(
     filter_id: i32,
     catalog: &str,
@@ -228,9 +734,15 @@ pub async fn get_filter_object(
                             }
                         }
                     },
+                    "fid": "$active_fid",
                     "group_id": 1,
                     "permissions": 1,
-                    "catalog": 1
+                    "catalog": 1,
+                    "autosave": 1,
+                    "update_annotations": 1,
+                    "notification_policy": 1,
+                    "schedule": 1,
+                    "last_run_at": 1
                 }
             },
             doc! {
@@ -241,9 +753,15 @@ pub async fn get_filter_object(
                             0
                         ]
                     },
+                    "fid": 1,
                     "group_id": 1,
                     "permissions": 1,
-                    "catalog": 1
+                    "catalog": 1,
+                    "autosave": 1,
+                    "update_annotations": 1,
+                    "notification_policy": 1,
+                    "schedule": 1,
+                    "last_run_at": 1
                 }
             },
         ])
@@ -291,6 +809,606 @@ pub async fn run_filter(
     Ok(out_documents)
 }
 
+// number of stages ($match on the candids, $lookup of the aux collection and
+// $project) that every filter of a survey prepends to its own stages
+pub const FILTER_PREFIX_LEN: usize = 3;
+
+/// Group filter pipelines (by filter id) by their prefix, i.e. the first
+/// `FILTER_PREFIX_LEN` stages, so that each group can be run with `run_filters`.
+pub fn group_by_prefix<'a>(
+    pipelines: impl IntoIterator<Item = (i32, &'a [Document])>,
+) -> Vec<Vec<(i32, &'a [Document])>> {
+    let mut groups: Vec<Vec<(i32, &'a [Document])>> = Vec::new();
+    for (filter_id, pipeline) in pipelines {
+        let prefix = &pipeline[..FILTER_PREFIX_LEN.min(pipeline.len())];
+        match groups
+            .iter_mut()
+            .find(|group| &group[0].1[..FILTER_PREFIX_LEN.min(group[0].1.len())] == prefix)
+        {
+            Some(group) => group.push((filter_id, pipeline)),
+            None => groups.push(vec![(filter_id, pipeline)]),
+        }
+    }
+    groups
+}
+
+/// Run filters that share the same prefix on a batch of candids, in a single aggregation.
+///
+/// The prefix (and its $lookup of the aux collection) is computed once, and the
+/// stages of each filter are run on its output as the sub-pipelines of a $facet.
+/// Only the `_id` and `annotations` of the documents that passed are returned,
+/// by filter id, to keep the output of the $facet under the document size limit.
+/// A filter run alone is not wrapped in a $facet, and its output is read from the
+/// cursor instead, so that it isn't limited by the size of a single document.
+/// With a `max_time`, the aggregation is aborted by the server when it runs longer.
+pub async fn run_filters(
+    candids: &[i64],
+    pipelines: &[(i32, &[Document])],
+    alert_collection: &mongodb::Collection<Document>,
+    max_time: Option<std::time::Duration>,
+) -> Result<HashMap<i32, Vec<Document>>, FilterError> {
+    if candids.is_empty() || pipelines.is_empty() {
+        return Ok(HashMap::new());
+    }
+    if pipelines
+        .iter()
+        .any(|(_, pipeline)| pipeline.len() < FILTER_PREFIX_LEN)
+    {
+        panic!(
+            "filter pipeline is missing its prefix, ensure filter has been built before running"
+        );
+    }
+
+    let mut pipeline = pipelines[0].1[..FILTER_PREFIX_LEN].to_vec();
+    // insert candids into the prefix
+    pipeline[0].get_document_mut("$match")?.insert(
+        "_id",
+        doc! {
+            "$in": candids
+        },
+    );
+
+    if let [(_, filter_pipeline)] = pipelines {
+        pipeline.extend_from_slice(&filter_pipeline[FILTER_PREFIX_LEN..]);
+        pipeline.push(doc! { "$project": { "annotations": 1 } });
+    } else {
+        let mut facet = Document::new();
+        for (filter_id, filter_pipeline) in pipelines {
+            let mut stages: Vec<Document> = filter_pipeline[FILTER_PREFIX_LEN..].to_vec();
+            stages.push(doc! { "$project": { "annotations": 1 } });
+            facet.insert(filter_id.to_string(), stages);
+        }
+        pipeline.push(doc! { "$facet": facet });
+    }
+
+    // run filters
+    let mut result = match max_time {
+        Some(max_time) => {
+            alert_collection
+                .aggregate(pipeline)
+                .max_time(max_time)
+                .await?
+        }
+        None => alert_collection.aggregate(pipeline).await?,
+    };
+
+    let mut out_documents: HashMap<i32, Vec<Document>> = HashMap::new();
+    if let [(filter_id, _)] = pipelines {
+        let mut passed = Vec::new();
+        while let Some(doc) = result.next().await {
+            passed.push(doc?);
+        }
+        out_documents.insert(*filter_id, passed);
+    } else if let Some(doc) = result.next().await {
+        let doc = doc?;
+        for (filter_id, _) in pipelines {
+            let passed = doc
+                .get_array(filter_id.to_string())?
+                .iter()
+                .filter_map(|x| x.as_document().cloned())
+                .collect();
+            out_documents.insert(*filter_id, passed);
+        }
+    }
+
+    Ok(out_documents)
+}
+
+// length (in seconds) of the time windows over which the filter stats are aggregated
+pub const DEFAULT_FILTER_STATS_WINDOW: i64 = 300;
+
+// the statistics of a filter version over a time window
+#[derive(Debug, Default, Clone)]
+struct FilterStatsEntry {
+    alerts_evaluated: u64,
+    alerts_passed: u64,
+    alerts_suppressed: u64,
+    errors: u64,
+    latencies_ms: Vec<f64>,
+}
+
+// nearest-rank percentile of sorted values
+fn percentile(sorted: &[f64], p: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+/// Execution statistics of the filters of a catalog, by filter id and version (fid),
+/// aggregated over time windows aligned on multiples of the window length.
+#[derive(Debug)]
+pub struct FilterStats {
+    catalog: String,
+    window: i64,
+    window_start: i64,
+    entries: HashMap<(i32, String), FilterStatsEntry>,
+}
+
+impl FilterStats {
+    pub fn new(catalog: &str, window: i64) -> Self {
+        FilterStats {
+            catalog: catalog.to_string(),
+            window,
+            window_start: Self::current_window_start(window),
+            entries: HashMap::new(),
+        }
+    }
+
+    fn current_window_start(window: i64) -> i64 {
+        let now = chrono::Utc::now().timestamp();
+        now - now.rem_euclid(window)
+    }
+
+    /// Whether the current time window is over, and the stats should be flushed.
+    pub fn window_ended(&self) -> bool {
+        chrono::Utc::now().timestamp() >= self.window_start + self.window
+    }
+
+    /// Record a run of a filter version on `evaluated` alerts, of which `passed` passed.
+    /// The `latency` is that of the aggregation the filter ran in, which the filters
+    /// of a group (sharing a prefix, see `run_filters`) have in common.
+    pub fn record(
+        &mut self,
+        filter_id: i32,
+        fid: String,
+        evaluated: usize,
+        passed: usize,
+        error: bool,
+        latency: std::time::Duration,
+    ) {
+        let entry = self.entries.entry((filter_id, fid)).or_default();
+        entry.alerts_evaluated += evaluated as u64;
+        entry.alerts_passed += passed as u64;
+        if error {
+            entry.errors += 1;
+        }
+        entry.latencies_ms.push(latency.as_secs_f64() * 1000.0);
+    }
+
+    /// Record a pass of a filter version that its notification policy suppressed.
+    pub fn record_suppressed(&mut self, filter_id: i32, fid: String) {
+        self.entries
+            .entry((filter_id, fid))
+            .or_default()
+            .alerts_suppressed += 1;
+    }
+
+    /// The documents of the current time window, one per filter version.
+    pub fn to_documents(&self) -> Vec<Document> {
+        let window_start = mongodb::bson::DateTime::from_millis(self.window_start * 1000);
+        let window_end =
+            mongodb::bson::DateTime::from_millis((self.window_start + self.window) * 1000);
+        self.entries
+            .iter()
+            .map(|((filter_id, fid), entry)| {
+                let mut latencies_ms = entry.latencies_ms.clone();
+                latencies_ms.sort_by(|a, b| a.total_cmp(b));
+                doc! {
+                    "filter_id": filter_id,
+                    "fid": fid,
+                    "catalog": &self.catalog,
+                    "window_start": window_start,
+                    "window_end": window_end,
+                    "alerts_evaluated": entry.alerts_evaluated as i64,
+                    "alerts_passed": entry.alerts_passed as i64,
+                    "alerts_suppressed": entry.alerts_suppressed as i64,
+                    "errors": entry.errors as i64,
+                    "runs": latencies_ms.len() as i64,
+                    "latency_p50_ms": percentile(&latencies_ms, 0.5),
+                    "latency_p95_ms": percentile(&latencies_ms, 0.95),
+                }
+            })
+            .collect()
+    }
+
+    /// Write the stats of the current time window, and start a new one.
+    pub async fn flush(
+        &mut self,
+        stats_collection: &mongodb::Collection<Document>,
+    ) -> Result<(), mongodb::error::Error> {
+        let documents = self.to_documents();
+        self.entries.clear();
+        self.window_start = Self::current_window_start(self.window);
+        if !documents.is_empty() {
+            stats_collection.insert_many(documents).await?;
+        }
+        Ok(())
+    }
+}
+
+// maximum number of filter aggregations a filter worker runs at the same time
+pub const DEFAULT_FILTER_MAX_CONCURRENCY: usize = 4;
+// time after which a filter aggregation is aborted
+pub const DEFAULT_FILTER_MAX_TIME_MS: u64 = 10000;
+// consecutive failures (errors or timeouts) after which a filter is disabled
+pub const DEFAULT_FILTER_MAX_FAILURES: u32 = 3;
+
+/// Runs the filters of a filter worker, concurrently and with a timeout.
+///
+/// The filters that share a prefix are run together (see `run_filters`), with
+/// `max_time` per filter of the aggregation. When such an aggregation fails, its
+/// filters are run again one by one, so that only the offending filter is left
+/// without results, and a filter that failed is then run on its own until it
+/// succeeds again. A filter that fails `max_failures` times in a row is
+/// deactivated (and flagged as unhealthy) in the database.
+///
+/// The runner also keeps the execution statistics of each filter version,
+/// flushed to the `filter_stats` collection at the end of each time window.
+/// The latencies are those of the aggregations: the filters run in the same
+/// group all get the latency of the group, not their own.
+pub struct FilterRunner {
+    alert_collection: mongodb::Collection<Document>,
+    filter_collection: mongodb::Collection<Document>,
+    stats_collection: mongodb::Collection<Document>,
+    catalog: String,
+    max_concurrency: usize,
+    max_time: std::time::Duration,
+    max_failures: u32,
+    failures: HashMap<i32, u32>,
+    disabled: HashSet<i32>,
+    versions: HashMap<i32, String>,
+    stats: FilterStats,
+}
+
+impl FilterRunner {
+    /// Read the `workers.<survey>.filter` settings (max_concurrency, max_time_ms,
+    /// max_failures and stats_window), with the defaults for the missing ones.
+    pub fn from_config(conf: &config::Config, survey: &str, db: &mongodb::Database) -> Self {
+        let get = |key: &str| conf.get_int(&format!("workers.{}.filter.{}", survey, key));
+        let catalog = format!("{}_alerts", survey);
+        let stats_window = get("stats_window")
+            .map(|seconds| seconds.max(1))
+            .unwrap_or(DEFAULT_FILTER_STATS_WINDOW);
+        FilterRunner {
+            alert_collection: db.collection(&catalog),
+            filter_collection: db.collection("filters"),
+            stats_collection: db.collection("filter_stats"),
+            stats: FilterStats::new(&catalog, stats_window),
+            catalog,
+            max_concurrency: get("max_concurrency")
+                .map(|n| n.max(1) as usize)
+                .unwrap_or(DEFAULT_FILTER_MAX_CONCURRENCY),
+            max_time: std::time::Duration::from_millis(
+                get("max_time_ms")
+                    .map(|ms| ms.max(1) as u64)
+                    .unwrap_or(DEFAULT_FILTER_MAX_TIME_MS),
+            ),
+            max_failures: get("max_failures")
+                .map(|n| n.max(1) as u32)
+                .unwrap_or(DEFAULT_FILTER_MAX_FAILURES),
+            failures: HashMap::new(),
+            disabled: HashSet::new(),
+            versions: HashMap::new(),
+        }
+    }
+
+    pub fn max_concurrency(&self) -> usize {
+        self.max_concurrency
+    }
+
+    pub fn max_time(&self) -> std::time::Duration {
+        self.max_time
+    }
+
+    pub fn max_failures(&self) -> u32 {
+        self.max_failures
+    }
+
+    pub fn stats(&self) -> &FilterStats {
+        &self.stats
+    }
+
+    /// Set the active version (fid) of each filter after the filters have been
+    /// (re)loaded, and forget the filters disabled since the last reload, which
+    /// only contain the active filters anymore.
+    pub fn set_versions(&mut self, versions: HashMap<i32, String>) {
+        self.versions = versions;
+        self.disabled.clear();
+    }
+
+    /// Record a pass of a filter that was not sent out, because of its notification policy.
+    pub fn record_suppressed(&mut self, filter_id: i32) {
+        self.stats.record_suppressed(filter_id, self.fid(filter_id));
+    }
+
+    /// Write the statistics of the current time window to the database, and start a new one.
+    pub async fn flush_stats(&mut self) -> Result<(), mongodb::error::Error> {
+        self.stats.flush(&self.stats_collection).await
+    }
+
+    /// Run the filters on a batch of candids, and return the output documents
+    /// of each filter that ran successfully, by filter id.
+    pub async fn run<'a>(
+        &mut self,
+        candids: &[i64],
+        pipelines: impl IntoIterator<Item = (i32, &'a [Document])>,
+    ) -> HashMap<i32, Vec<Document>> {
+        if self.stats.window_ended() {
+            if let Err(e) = self.flush_stats().await {
+                warn!("could not write the {} filter stats: {}", self.catalog, e);
+            }
+        }
+
+        // the filters that failed last time are kept out of the groups,
+        // so that they don't fail (or slow down) the other filters
+        let (isolated, pipelines): (Vec<_>, Vec<_>) = pipelines
+            .into_iter()
+            .filter(|(filter_id, _)| !self.disabled.contains(filter_id))
+            .partition(|(filter_id, _)| self.failures.contains_key(filter_id));
+        let mut groups = group_by_prefix(pipelines);
+        groups.extend(isolated.into_iter().map(|filter| vec![filter]));
+
+        let mut out_documents = HashMap::new();
+        let mut failed = Vec::new();
+        let mut retried = Vec::new();
+        let mut latencies = HashMap::new();
+        for (group, result, latency) in self.run_groups(candids, groups).await {
+            match result {
+                Ok(group_out_documents) => {
+                    // the filters of a group run in the same aggregation, and are
+                    // recorded with its latency (the group latency)
+                    latencies.extend(group_out_documents.keys().map(|id| (*id, latency)));
+                    out_documents.extend(group_out_documents);
+                }
+                Err(e) if group.len() == 1 => failed.push((group[0].0, e, latency)),
+                Err(e) => {
+                    warn!(
+                        "{} filters failed together, running them one by one: {}",
+                        group.len(),
+                        e
+                    );
+                    retried.extend(group.into_iter().map(|filter| vec![filter]));
+                }
+            }
+        }
+        for (group, result, latency) in self.run_groups(candids, retried).await {
+            match result {
+                Ok(filter_out_documents) => {
+                    latencies.extend(filter_out_documents.keys().map(|id| (*id, latency)));
+                    out_documents.extend(filter_out_documents);
+                }
+                Err(e) => failed.push((group[0].0, e, latency)),
+            }
+        }
+
+        // the passes are sent out by candid, so a filter whose output
+        // doesn't keep the candid of the alerts (its _id) has failed
+        let invalid: Vec<i32> = out_documents
+            .iter()
+            .filter(|(_, filter_out_documents)| {
+                filter_out_documents
+                    .iter()
+                    .any(|doc| doc.get_i64("_id").is_err())
+            })
+            .map(|(filter_id, _)| *filter_id)
+            .collect();
+        for filter_id in invalid {
+            out_documents.remove(&filter_id);
+            failed.push((
+                filter_id,
+                FilterError::InvalidFilterOutput,
+                latencies[&filter_id],
+            ));
+        }
+
+        for (filter_id, filter_out_documents) in &out_documents {
+            self.failures.remove(filter_id);
+            self.stats.record(
+                *filter_id,
+                self.fid(*filter_id),
+                candids.len(),
+                filter_out_documents.len(),
+                false,
+                latencies[filter_id],
+            );
+        }
+        for (filter_id, e, latency) in failed {
+            self.stats.record(
+                filter_id,
+                self.fid(filter_id),
+                candids.len(),
+                0,
+                true,
+                latency,
+            );
+            let failures = self.failures.entry(filter_id).or_insert(0);
+            *failures += 1;
+            warn!(
+                "{} filter {} failed ({} in a row): {}",
+                self.catalog, filter_id, failures, e
+            );
+            if *failures >= self.max_failures {
+                self.failures.remove(&filter_id);
+                self.disable(filter_id, &e.to_string()).await;
+            }
+        }
+
+        out_documents
+    }
+
+    fn fid(&self, filter_id: i32) -> String {
+        self.versions.get(&filter_id).cloned().unwrap_or_default()
+    }
+
+    // run groups of filters with run_filters, max_concurrency at a time,
+    // each with max_time per filter of the group
+    async fn run_groups<'a>(
+        &self,
+        candids: &[i64],
+        groups: Vec<Vec<(i32, &'a [Document])>>,
+    ) -> Vec<(
+        Vec<(i32, &'a [Document])>,
+        Result<HashMap<i32, Vec<Document>>, FilterError>,
+        std::time::Duration,
+    )> {
+        let mut runs = Vec::new();
+        for group in groups {
+            let alert_collection = &self.alert_collection;
+            let max_time = Some(self.max_time * group.len() as u32);
+            runs.push(async move {
+                let start = std::time::Instant::now();
+                let result = run_filters(candids, &group, alert_collection, max_time).await;
+                (group, result, start.elapsed())
+            });
+        }
+        futures::stream::iter(runs)
+            .buffer_unordered(self.max_concurrency)
+            .collect()
+            .await
+    }
+
+    // deactivate a filter that keeps failing, so it isn't picked up again on reload
+    async fn disable(&mut self, filter_id: i32, reason: &str) {
+        error!(
+            "{} filter {} failed {} times in a row, disabling it",
+            self.catalog, filter_id, self.max_failures
+        );
+        self.disabled.insert(filter_id);
+        let result = self
+            .filter_collection
+            .update_one(
+                doc! {"filter_id": filter_id, "catalog": &self.catalog},
+                doc! {
+                    "$set": {
+                        "active": false,
+                        "unhealthy": true,
+                        "unhealthy_reason": reason,
+                        "unhealthy_since": mongodb::bson::DateTime::now(),
+                    }
+                },
+            )
+            .await;
+        if let Err(e) = result {
+            error!("could not disable filter {}: {}", filter_id, e);
+        }
+    }
+}
+
+/// Collection in which the passes of the filters with `autosave` are written.
+pub const FILTER_RESULTS_COLLECTION: &str = "filter_results";
+
+/// A filter whose passes are saved, with its active version (fid) and
+/// whether the annotations of its prior passes are kept up to date.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AutosavedFilter {
+    pub fid: String,
+    pub update_annotations: bool,
+}
+
+/// Writes the passes of the filters with `autosave: true` to the `filter_results`
+/// collection, one document per filter and candid, so that the groups can go
+/// over what their filters found.
+///
+/// For the filters with `update_annotations: true`, the annotations of the prior
+/// passes of the same object are replaced by those of the latest pass.
+pub struct FilterResultsWriter {
+    collection: mongodb::Collection<Document>,
+    catalog: String,
+    filters: HashMap<i32, AutosavedFilter>,
+}
+
+impl FilterResultsWriter {
+    pub fn new(db: &mongodb::Database, catalog: &str) -> Self {
+        FilterResultsWriter {
+            collection: db.collection(FILTER_RESULTS_COLLECTION),
+            catalog: catalog.to_string(),
+            filters: HashMap::new(),
+        }
+    }
+
+    /// Create the indexes used to save the passes and query them by filter.
+    pub async fn create_indexes(&self) -> Result<(), CreateIndexError> {
+        create_index(&self.collection, doc! {"filter_id": 1, "candid": 1}, true).await?;
+        create_index(
+            &self.collection,
+            doc! {"filter_id": 1, "objectId": 1},
+            false,
+        )
+        .await?;
+        create_index(
+            &self.collection,
+            doc! {"filter_id": 1, "passed_at": -1},
+            false,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Set the filters whose passes are saved, after the filters have been (re)loaded.
+    pub fn set_filters(&mut self, filters: HashMap<i32, AutosavedFilter>) {
+        self.filters = filters;
+    }
+
+    /// Save the passes of an alert through the filters set with `set_filters`.
+    pub async fn save(&self, alert: &Alert) -> Result<(), FilterWorkerError> {
+        for filter_result in &alert.filters {
+            let Some(filter) = self.filters.get(&filter_result.filter_id) else {
+                continue;
+            };
+            let annotations: Document = serde_json::from_str(&filter_result.annotations)?;
+            let passed_at = mongodb::bson::DateTime::from_millis(filter_result.passed_at as i64);
+
+            if filter.update_annotations {
+                self.collection
+                    .update_many(
+                        doc! {
+                            "filter_id": filter_result.filter_id,
+                            "objectId": &alert.object_id,
+                            "candid": {"$ne": alert.candid},
+                        },
+                        doc! {
+                            "$set": {
+                                "annotations": &annotations,
+                                "annotations_updated_at": passed_at,
+                            }
+                        },
+                    )
+                    .await?;
+            }
+
+            // upsert, so that the same alert filtered again doesn't fail on the unique index
+            self.collection
+                .update_one(
+                    doc! {"filter_id": filter_result.filter_id, "candid": alert.candid},
+                    doc! {
+                        "$set": {
+                            "fid": &filter.fid,
+                            "catalog": &self.catalog,
+                            "objectId": &alert.object_id,
+                            "annotations": annotations,
+                            "passed_at": passed_at,
+                        }
+                    },
+                )
+                .upsert(true)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
 #[async_trait::async_trait]
 pub trait Filter {
     async fn build(
@@ -299,6 +1417,77 @@ pub trait Filter {
     ) -> Result<Self, FilterError>
     where
         Self: Sized;
+    fn id(&self) -> i32;
+    /// The active version of the filter.
+    fn fid(&self) -> &str;
+    /// Whether the passes of the filter are saved to `filter_results`.
+    fn autosave(&self) -> bool;
+    /// Whether the annotations of the prior passes of an object are replaced
+    /// by those of its latest pass, for the filters with `autosave`.
+    fn update_annotations(&self) -> bool;
+    fn notification_policy(&self) -> &NotificationPolicy;
+}
+
+/// The active version of each filter, by filter id.
+pub fn filter_versions<F: Filter>(filters: &[F]) -> HashMap<i32, String> {
+    filters
+        .iter()
+        .map(|filter| (filter.id(), filter.fid().to_string()))
+        .collect()
+}
+
+/// The filters with autosave, by filter id.
+pub fn autosaved_filters<F: Filter>(filters: &[F]) -> HashMap<i32, AutosavedFilter> {
+    filters
+        .iter()
+        .filter(|filter| filter.autosave())
+        .map(|filter| {
+            let autosaved_filter = AutosavedFilter {
+                fid: filter.fid().to_string(),
+                update_annotations: filter.update_annotations(),
+            };
+            (filter.id(), autosaved_filter)
+        })
+        .collect()
+}
+
+/// The notification policy of each filter, by filter id.
+pub fn notification_policies<F: Filter>(filters: &[F]) -> HashMap<i32, NotificationPolicy> {
+    filters
+        .iter()
+        .map(|filter| (filter.id(), filter.notification_policy().clone()))
+        .collect()
+}
+
+/// Build the active filters of a catalog (e.g. ZTF_alerts), see `build_filters`.
+pub async fn load_filters<F: Filter>(
+    catalog: &str,
+    filter_collection: &mongodb::Collection<mongodb::bson::Document>,
+) -> Result<Vec<F>, FilterWorkerError> {
+    let filter_ids = get_active_filter_ids(catalog, filter_collection).await?;
+    build_filters(catalog, filter_ids, filter_collection).await
+}
+
+/// Build the filters of a catalog with the given ids.
+///
+/// The filters are all built before they replace those of a worker, so a database
+/// error while loading them leaves the worker as it was. A filter that can't be built
+/// (e.g. a stored pipeline that isn't valid JSON) is left out until it is fixed,
+/// so that it doesn't hold back the others.
+pub async fn build_filters<F: Filter>(
+    catalog: &str,
+    filter_ids: Vec<i32>,
+    filter_collection: &mongodb::Collection<mongodb::bson::Document>,
+) -> Result<Vec<F>, FilterWorkerError> {
+    let mut filters = Vec::new();
+    for filter_id in filter_ids {
+        match F::build(filter_id, filter_collection).await {
+            Ok(filter) => filters.push(filter),
+            Err(FilterError::Mongodb(e)) => return Err(FilterError::Mongodb(e).into()),
+            Err(e) => warn!("could not build {} filter {}: {}", catalog, filter_id, e),
+        }
+    }
+    Ok(filters)
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -317,14 +1506,31 @@ pub enum FilterWorkerError {
     SerdeJson(#[from] serde_json::Error),
     #[error("failed to load config")]
     LoadConfigError(#[from] crate::conf::BoomConfigError),
+    #[error("error from the schema registry")]
+    SchemaRegistry(#[from] SchemaRegistryError),
     #[error("filter error")]
     FilterError(#[from] FilterError),
+    #[error("failed to create index")]
+    CreateIndex(#[from] CreateIndexError),
     #[error("failed to get filter by queue")]
     GetFilterByQueueError,
     #[error("could not find alert")]
     AlertNotFound,
 }
 
+/// Builds the output alert of a candid of a survey (with its photometry, cross-matches
+/// and cutouts), for the filter workers of the survey and its scheduled filter worker.
+#[async_trait::async_trait]
+pub trait AlertBuilder {
+    /// The survey of the alerts, e.g. ZTF.
+    const SURVEY: &'static str;
+    async fn build_output_alert(
+        alert_collection: &mongodb::Collection<Document>,
+        candid: i64,
+        filter_results: Vec<FilterResults>,
+    ) -> Result<Alert, FilterWorkerError>;
+}
+
 #[async_trait::async_trait]
 pub trait FilterWorker {
     async fn new(config_path: &str) -> Result<Self, FilterWorkerError>
@@ -332,13 +1538,74 @@ pub trait FilterWorker {
         Self: Sized;
     fn input_queue_name(&self) -> String;
     fn output_topic_name(&self) -> String;
+    fn survey_name(&self) -> String;
+    /// The group of each filter that has one, by filter id.
+    fn filter_group_ids(&self) -> HashMap<i32, i32>;
     fn has_filters(&self) -> bool;
+    /// Rebuild the filters from the active filters (and their active version)
+    /// in the database. On error, the worker keeps its current filters.
+    async fn reload_filters(&mut self) -> Result<(), FilterWorkerError>;
+    /// Write the execution statistics of the filters gathered so far to the database.
+    async fn flush_stats(&mut self) -> Result<(), FilterWorkerError>;
     async fn build_alert(
         &self,
         candid: i64,
         filter_results: Vec<FilterResults>,
     ) -> Result<Alert, FilterWorkerError>;
     async fn process_alerts(&mut self, alerts: &[String]) -> Result<Vec<Alert>, FilterWorkerError>;
+    /// Undo the notifications recorded for the passes of an alert that could not be sent.
+    async fn release_notifications(&self, alert: &Alert) -> Result<(), FilterWorkerError>;
+}
+
+/// Channel on which the filter workers are told to reload their filters, with
+/// the catalog of the filters that changed (e.g. ZTF_alerts) as the message.
+pub const FILTERS_UPDATED_CHANNEL: &str = "filters_updated";
+
+// seconds between two reloads of the filters of a filter worker, when
+// they haven't been reloaded on a notification in the meantime
+pub const DEFAULT_FILTER_RELOAD_INTERVAL: u64 = 60;
+
+// read workers.<survey>.filter.reload_interval (in seconds) from the config, if set
+pub fn get_filter_reload_interval(conf: &config::Config, survey: &str) -> std::time::Duration {
+    let seconds = conf
+        .get_int(&format!("workers.{}.filter.reload_interval", survey))
+        .map(|seconds| seconds.max(1) as u64)
+        .unwrap_or(DEFAULT_FILTER_RELOAD_INTERVAL);
+    std::time::Duration::from_secs(seconds)
+}
+
+/// Tell the filter workers of a catalog to reload their filters.
+pub async fn notify_filters_updated(
+    con: &mut redis::aio::MultiplexedConnection,
+    catalog: &str,
+) -> Result<(), redis::RedisError> {
+    con.publish::<&str, &str, ()>(FILTERS_UPDATED_CHANNEL, catalog)
+        .await
+}
+
+// flag the filters for a reload whenever the filters of the catalog are updated
+async fn listen_for_filter_updates(
+    client: redis::Client,
+    catalog: String,
+    reload_requested: Arc<AtomicBool>,
+) {
+    let mut pubsub = match client.get_async_pubsub().await {
+        Ok(pubsub) => pubsub,
+        Err(e) => {
+            warn!("could not listen for filter updates: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = pubsub.subscribe(FILTERS_UPDATED_CHANNEL).await {
+        warn!("could not listen for filter updates: {}", e);
+        return;
+    }
+    let mut messages = pubsub.on_message();
+    while let Some(msg) = messages.next().await {
+        if msg.get_payload::<String>().ok().as_deref() == Some(&catalog) {
+            reload_requested.store(true, Ordering::Relaxed);
+        }
+    }
 }
 
 #[tokio::main]
@@ -351,12 +1618,22 @@ pub async fn run_filter_worker<T: FilterWorker>(
 
     let mut filter_worker = T::new(config_path).await?;
 
+    // the filters are reloaded periodically, and when notified of an update
+    let survey = filter_worker.survey_name();
+    let reload_interval = get_filter_reload_interval(&config, &survey);
+    let reload_requested = Arc::new(AtomicBool::new(false));
+    let listener = tokio::spawn(listen_for_filter_updates(
+        conf::build_redis_client(&config)?,
+        format!("{}_alerts", survey),
+        reload_requested.clone(),
+    ));
+    let mut last_reload = std::time::Instant::now();
+
     if !filter_worker.has_filters() {
         info!(
-            "No filters available for filter worker {} to process alerts",
+            "No filters available for filter worker {}, waiting for filters to be added",
             &id
         );
-        return Ok(());
     }
 
     // in a never ending loop, loop over the queues
@@ -364,15 +1641,16 @@ pub async fn run_filter_worker<T: FilterWorker>(
 
     let input_queue = filter_worker.input_queue_name();
     let output_topic = filter_worker.output_topic_name();
+    let mut group_ids = filter_worker.filter_group_ids();
 
-    let producer = create_producer().await?;
-    let schema = load_alert_schema()?;
+    let producer = create_producer(&config).await?;
+    let mut output_topics = OutputTopics::from_config(&config, &survey, &output_topic)?;
 
     let command_interval: i64 = 500;
     let mut command_check_countdown = command_interval;
 
     loop {
-        if command_check_countdown == 0 {
+        if command_check_countdown <= 0 {
             match receiver.try_recv() {
                 Ok(WorkerCmd::TERM) => {
                     info!("filterworker {} received termination command", &id);
@@ -387,6 +1665,29 @@ pub async fn run_filter_worker<T: FilterWorker>(
                 }
             }
         }
+        if reload_requested.swap(false, Ordering::Relaxed)
+            || last_reload.elapsed() >= reload_interval
+        {
+            match filter_worker.reload_filters().await {
+                Ok(()) => {
+                    group_ids = filter_worker.filter_group_ids();
+                    trace!("filter worker {} reloaded its filters", &id);
+                }
+                Err(e) => {
+                    warn!(
+                        "filter worker {} could not reload its filters, keeping the current ones: {}",
+                        &id, e
+                    );
+                }
+            }
+            last_reload = std::time::Instant::now();
+        }
+        // without filters, leave the alerts in the queue until some are added
+        if !filter_worker.has_filters() {
+            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+            command_check_countdown = 0;
+            continue;
+        }
         // if the queue is empty, wait for a bit and continue the loop
         let queue_len: i64 = con.llen(&input_queue).await?;
         if queue_len == 0 {
@@ -406,17 +1707,79 @@ pub async fn run_filter_worker<T: FilterWorker>(
             continue;
         }
 
-        let alerts_output = filter_worker.process_alerts(&alerts).await?;
-        for alert in alerts_output {
-            send_alert_to_kafka(&alert, &schema, &producer, &output_topic, &id).await?;
-            trace!(
-                "Sent alert with candid {} to Kafka topic {}",
-                &alert.candid,
-                &output_topic
-            );
+        let mut alerts_output = filter_worker.process_alerts(&alerts).await?.into_iter();
+        while let Some(alert) = alerts_output.next() {
+            // the filters whose results were sent to at least one of their topics
+            let mut sent_filter_ids = HashSet::new();
+            let mut send_error = None;
+            for (topic, routed_alert) in output_topics.route(&alert, &group_ids) {
+                let encoder = output_topics.encoder(&config, &topic).await?;
+                match send_alert_to_kafka(&routed_alert, encoder, &producer, &topic, &id).await {
+                    Ok(()) => {
+                        sent_filter_ids.extend(routed_alert.filters.iter().map(|f| f.filter_id));
+                        trace!(
+                            "Sent alert with candid {} to Kafka topic {}",
+                            &alert.candid,
+                            &topic
+                        );
+                    }
+                    Err(e) => {
+                        error!(
+                            "could not send alert with candid {} to Kafka topic {}: {}",
+                            &alert.candid, &topic, e
+                        );
+                        send_error = Some(e);
+                        break;
+                    }
+                }
+            }
+            if let Some(e) = send_error {
+                // the worker stops, and the alerts that weren't sent (this one and
+                // the rest of the batch) go back to the queue to be filtered again,
+                // with the notifications of the passes that weren't sent released
+                let unsent = Alert {
+                    filters: alert
+                        .filters
+                        .iter()
+                        .filter(|f| !sent_filter_ids.contains(&f.filter_id))
+                        .cloned()
+                        .collect(),
+                    ..alert
+                };
+                let mut unsent_candids = HashSet::new();
+                for alert in std::iter::once(unsent).chain(alerts_output) {
+                    unsent_candids.insert(alert.candid);
+                    if let Err(e) = filter_worker.release_notifications(&alert).await {
+                        warn!(
+                            "could not release the notifications of {}: {}",
+                            &alert.candid, e
+                        );
+                    }
+                }
+                let unsent_entries: Vec<&String> = alerts
+                    .iter()
+                    .filter(|entry| {
+                        entry
+                            .rsplit(',')
+                            .next()
+                            .and_then(|candid| candid.parse::<i64>().ok())
+                            .is_some_and(|candid| unsent_candids.contains(&candid))
+                    })
+                    .collect();
+                if !unsent_entries.is_empty() {
+                    con.rpush::<&str, Vec<&String>, usize>(&input_queue, unsent_entries)
+                        .await?;
+                }
+                listener.abort();
+                filter_worker.flush_stats().await?;
+                return Err(e);
+            }
         }
         command_check_countdown -= nb_alerts as i64;
     }
 
+    listener.abort();
+    filter_worker.flush_stats().await?;
+
     Ok(())
 }
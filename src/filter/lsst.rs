@@ -1,16 +1,100 @@
 use futures::stream::StreamExt;
 use mongodb::bson::{doc, Document};
 use std::collections::HashMap;
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::filter::{
-    get_filter_object, run_filter, Alert, Filter, FilterError, FilterResults, FilterWorker,
-    FilterWorkerError, Origin, Photometry, Survey,
+    autosaved_filters, filter_versions, get_classifications, get_cross_matches, get_filter_object,
+    load_filters, notification_policies, Alert, AlertBuilder, Filter, FilterError, FilterResults,
+    FilterResultsWriter, FilterRunner, FilterWorker, FilterWorkerError, NotificationGate,
+    NotificationPolicy, Origin, Photometry, Survey, ALERT_SCHEMA_VERSION, PHOTOMETRY_ZP,
 };
 
+// LSST fluxes are in nJy, the output photometry in µJy
+const NJY_TO_UJY: f64 = 1e-3;
+
 pub struct LsstFilter {
     id: i32,
+    fid: String,
     pipeline: Vec<Document>,
+    group_id: Option<i32>,
+    autosave: bool,
+    update_annotations: bool,
+    notification_policy: NotificationPolicy,
+}
+
+/// The stages run before those of every LSST filter: the alerts (candids) to filter,
+/// joined with their aux entry.
+pub fn build_lsst_filter_prefix() -> Vec<Document> {
+    vec![
+        doc! {
+            "$match": doc! {
+                "_id": doc! {
+                    "$in": [] // candids will be inserted here
+                }
+            }
+        },
+        doc! {
+            "$lookup": doc! {
+                "from": format!("LSST_alerts_aux"),
+                "localField": "objectId",
+                "foreignField": "_id",
+                "as": "aux"
+            }
+        },
+        doc! {
+            "$project": doc! {
+                "objectId": 1,
+                "candidate": 1,
+                "classifications": 1,
+                "coordinates": 1,
+                "cross_matches": doc! {
+                    "$arrayElemAt": [
+                        "$aux.cross_matches",
+                        0
+                    ]
+                },
+                "dia_object": doc! {
+                    "$arrayElemAt": [
+                        "$aux.dia_object",
+                        0
+                    ]
+                },
+                "prv_candidates": doc! {
+                    "$filter": doc! {
+                        "input": doc! {
+                            "$arrayElemAt": [
+                                "$aux.prv_candidates",
+                                0
+                            ]
+                        },
+                        "as": "x",
+                        "cond": doc! {
+                            "$and": [
+                                { // maximum 1 year of past data
+                                    "$lt": [
+                                        {
+                                            "$subtract": [
+                                                "$candidate.mjd",
+                                                "$$x.mjd"
+                                            ]
+                                        },
+                                        365
+                                    ]
+                                },
+                                { // only datapoints up to (and including) current alert
+                                    "$lte": [
+                                        "$$x.mjd",
+                                        "$candidate.mjd"
+                                    ]
+                                }
+                            ]
+                        }
+                    }
+                },
+            }
+        },
+    ]
 }
 
 #[async_trait::async_trait]
@@ -23,69 +107,7 @@ impl Filter for LsstFilter {
         let filter_obj = get_filter_object(filter_id, "LSST_alerts", filter_collection).await?;
 
         // filter prefix (with permissions)
-        let mut pipeline = vec![
-            doc! {
-                "$match": doc! {
-                    "_id": doc! {
-                        "$in": [] // candids will be inserted here
-                    }
-                }
-            },
-            doc! {
-                "$lookup": doc! {
-                    "from": format!("LSST_alerts_aux"),
-                    "localField": "objectId",
-                    "foreignField": "_id",
-                    "as": "aux"
-                }
-            },
-            doc! {
-                "$project": doc! {
-                    "objectId": 1,
-                    "candidate": 1,
-                    "classifications": 1,
-                    "coordinates": 1,
-                    "cross_matches": doc! {
-                        "$arrayElemAt": [
-                            "$aux.cross_matches",
-                            0
-                        ]
-                    },
-                    "prv_candidates": doc! {
-                        "$filter": doc! {
-                            "input": doc! {
-                                "$arrayElemAt": [
-                                    "$aux.prv_candidates",
-                                    0
-                                ]
-                            },
-                            "as": "x",
-                            "cond": doc! {
-                                "$and": [
-                                    { // maximum 1 year of past data
-                                        "$lt": [
-                                            {
-                                                "$subtract": [
-                                                    "$candidate.mjd",
-                                                    "$$x.mjd"
-                                                ]
-                                            },
-                                            365
-                                        ]
-                                    },
-                                    { // only datapoints up to (and including) current alert
-                                        "$lte": [
-                                            "$$x.mjd",
-                                            "$candidate.mjd"
-                                        ]
-                                    }
-                                ]
-                            }
-                        }
-                    },
-                }
-            },
-        ];
+        let mut pipeline = build_lsst_filter_prefix();
 
         let filter_pipeline = filter_obj
             .get("pipeline")
@@ -105,66 +127,55 @@ impl Filter for LsstFilter {
 
         let filter = LsstFilter {
             id: filter_id,
+            fid: filter_obj.get_str("fid").unwrap_or_default().to_string(),
             pipeline: pipeline,
+            group_id: filter_obj.get_i32("group_id").ok(),
+            autosave: filter_obj.get_bool("autosave").unwrap_or(false),
+            update_annotations: filter_obj.get_bool("update_annotations").unwrap_or(false),
+            notification_policy: NotificationPolicy::from_filter_object(&filter_obj)?,
         };
 
         Ok(filter)
     }
+
+    fn id(&self) -> i32 {
+        self.id
+    }
+
+    fn fid(&self) -> &str {
+        &self.fid
+    }
+
+    fn autosave(&self) -> bool {
+        self.autosave
+    }
+
+    fn update_annotations(&self) -> bool {
+        self.update_annotations
+    }
+
+    fn notification_policy(&self) -> &NotificationPolicy {
+        &self.notification_policy
+    }
 }
 
 pub struct LsstFilterWorker {
     alert_collection: mongodb::Collection<mongodb::bson::Document>,
+    filter_collection: mongodb::Collection<mongodb::bson::Document>,
+    filter_runner: FilterRunner,
+    results_writer: FilterResultsWriter,
+    notification_gate: NotificationGate,
     input_queue: String,
     output_topic: String,
     filters: Vec<LsstFilter>,
 }
 
 #[async_trait::async_trait]
-impl FilterWorker for LsstFilterWorker {
-    async fn new(config_path: &str) -> Result<Self, FilterWorkerError> {
-        let config_file = crate::conf::load_config(&config_path)?;
-        let db: mongodb::Database = crate::conf::build_db(&config_file).await?;
-        let alert_collection = db.collection("LSST_alerts");
-        let filter_collection = db.collection("filters");
-
-        let input_queue = "LSST_alerts_filter_queue".to_string();
-        let output_topic = "LSST_alerts_results".to_string();
-
-        let filter_ids: Vec<i32> = filter_collection
-            .distinct("filter_id", doc! {"active": true, "catalog": "LSST_alerts"})
-            .await?
-            .into_iter()
-            .map(|x| x.as_i32().ok_or(FilterError::InvalidFilterId))
-            .filter_map(Result::ok)
-            .collect();
-
-        let mut filters: Vec<LsstFilter> = Vec::new();
-        for filter_id in filter_ids {
-            filters.push(LsstFilter::build(filter_id, &filter_collection).await?);
-        }
+impl AlertBuilder for LsstFilterWorker {
+    const SURVEY: &'static str = "LSST";
 
-        Ok(LsstFilterWorker {
-            alert_collection,
-            input_queue,
-            output_topic,
-            filters,
-        })
-    }
-
-    fn input_queue_name(&self) -> String {
-        self.input_queue.clone()
-    }
-
-    fn output_topic_name(&self) -> String {
-        self.output_topic.clone()
-    }
-
-    fn has_filters(&self) -> bool {
-        !self.filters.is_empty()
-    }
-
-    async fn build_alert(
-        &self,
+    async fn build_output_alert(
+        alert_collection: &mongodb::Collection<Document>,
         candid: i64,
         filter_results: Vec<FilterResults>,
     ) -> Result<Alert, FilterWorkerError> {
@@ -180,6 +191,8 @@ impl FilterWorker for LsstFilterWorker {
                     "jd": "$candidate.jd",
                     "ra": "$candidate.ra",
                     "dec": "$candidate.dec",
+                    "classifications": 1,
+                    "model_versions": 1,
                     "cutoutScience": 1,
                     "cutoutTemplate": 1,
                     "cutoutDifference": 1
@@ -207,6 +220,14 @@ impl FilterWorker for LsstFilterWorker {
                     "jd": 1,
                     "ra": 1,
                     "dec": 1,
+                    "classifications": 1,
+                    "model_versions": 1,
+                    "cross_matches": {
+                        "$arrayElemAt": [
+                            "$aux.cross_matches",
+                            0
+                        ]
+                    },
                     "prv_candidates": {
                         "$arrayElemAt": [
                             "$aux.prv_candidates",
@@ -219,6 +240,12 @@ impl FilterWorker for LsstFilterWorker {
                             0
                         ]
                     },
+                    "fp_hists": {
+                        "$arrayElemAt": [
+                            "$aux.fp_hists",
+                            0
+                        ]
+                    },
                     "cutoutScience": {
                         "$arrayElemAt": [
                             "$cutouts.cutoutScience",
@@ -242,7 +269,7 @@ impl FilterWorker for LsstFilterWorker {
         ];
 
         // Execute the aggregation pipeline
-        let mut cursor = self.alert_collection.aggregate(pipeline).await?;
+        let mut cursor = alert_collection.aggregate(pipeline).await?;
 
         let alert_document = cursor
             .next()
@@ -261,7 +288,7 @@ impl FilterWorker for LsstFilterWorker {
             .get_binary_generic("cutoutDifference")?
             .to_vec();
 
-        // let's create the array of photometry (non forced phot only for now)
+        // let's create the array of photometry
         let mut photometry = Vec::new();
         for doc in alert_document.get_array("prv_candidates")?.iter() {
             let doc = match doc.as_document() {
@@ -277,10 +304,10 @@ impl FilterWorker for LsstFilterWorker {
 
             photometry.push(Photometry {
                 jd,
-                flux: Some(flux),
-                flux_err,
+                flux: Some(flux * NJY_TO_UJY),
+                flux_err: flux_err * NJY_TO_UJY,
                 band: format!("lsst{}", band),
-                zero_point: 8.9,
+                zero_point: PHOTOMETRY_ZP,
                 origin: Origin::Alert,
                 programid: 1, // only one public stream for LSST
                 survey: Survey::LSST,
@@ -302,9 +329,9 @@ impl FilterWorker for LsstFilterWorker {
             photometry.push(Photometry {
                 jd,
                 flux: None, // for non-detections, flux is None
-                flux_err,
+                flux_err: flux_err * NJY_TO_UJY,
                 band: format!("lsst{}", band),
-                zero_point: 8.9,
+                zero_point: PHOTOMETRY_ZP,
                 origin: Origin::Alert,
                 programid: 1, // only one public stream for LSST
                 survey: Survey::LSST,
@@ -313,9 +340,47 @@ impl FilterWorker for LsstFilterWorker {
             });
         }
 
-        // we ignore the forced photometry for now, but will add it later
+        // and finally the forced photometry, which may be missing from older aux entries
+        let fp_hists = alert_document
+            .get_array("fp_hists")
+            .map_or(&[][..], |a| a.as_slice());
+        for doc in fp_hists.iter() {
+            let doc = match doc.as_document() {
+                Some(doc) => doc,
+                None => continue, // skip if not a document
+            };
+            // skip measurements without a flux, error or band
+            let (Ok(flux), Ok(flux_err), Ok(band)) = (
+                doc.get_f64("psfFlux"),
+                doc.get_f64("psfFluxErr"),
+                doc.get_str("band"),
+            ) else {
+                continue;
+            };
+            if !flux.is_finite() || !flux_err.is_finite() || flux_err <= 0.0 {
+                continue;
+            }
+            let jd = doc.get_f64("jd")?;
+
+            photometry.push(Photometry {
+                jd,
+                flux: Some(flux * NJY_TO_UJY),
+                flux_err: flux_err * NJY_TO_UJY,
+                band: format!("lsst{}", band),
+                zero_point: PHOTOMETRY_ZP,
+                origin: Origin::ForcedPhot,
+                programid: 1, // only one public stream for LSST
+                survey: Survey::LSST,
+                ra: doc.get_f64("ra").ok(),
+                dec: doc.get_f64("dec").ok(),
+            });
+        }
+
+        let classifications = get_classifications(&alert_document);
+        let cross_matches = get_cross_matches(&alert_document, ra, dec);
 
         let alert = Alert {
+            schemavsn: ALERT_SCHEMA_VERSION.to_string(),
             candid,
             object_id: format!("{}", object_id),
             jd,
@@ -323,6 +388,8 @@ impl FilterWorker for LsstFilterWorker {
             dec,
             filters: filter_results, // assuming you have filter results to attach
             photometry,
+            classifications,
+            cross_matches,
             cutout_science,
             cutout_template,
             cutout_difference,
@@ -330,6 +397,91 @@ impl FilterWorker for LsstFilterWorker {
 
         Ok(alert)
     }
+}
+
+#[async_trait::async_trait]
+impl FilterWorker for LsstFilterWorker {
+    async fn new(config_path: &str) -> Result<Self, FilterWorkerError> {
+        let config_file = crate::conf::load_config(&config_path)?;
+        let db: mongodb::Database = crate::conf::build_db(&config_file).await?;
+        let alert_collection = db.collection("LSST_alerts");
+        let filter_collection = db.collection("filters");
+
+        let input_queue = "LSST_alerts_filter_queue".to_string();
+        let output_topic = "LSST_alerts_results".to_string();
+
+        let mut filter_runner = FilterRunner::from_config(&config_file, "LSST", &db);
+        let mut results_writer = FilterResultsWriter::new(&db, "LSST_alerts");
+        results_writer.create_indexes().await?;
+        let mut notification_gate = NotificationGate::new(&db);
+        notification_gate.create_indexes().await?;
+
+        let filters: Vec<LsstFilter> = load_filters("LSST_alerts", &filter_collection).await?;
+        filter_runner.set_versions(filter_versions(&filters));
+        results_writer.set_filters(autosaved_filters(&filters));
+        notification_gate.set_policies(notification_policies(&filters));
+
+        Ok(LsstFilterWorker {
+            alert_collection,
+            filter_collection,
+            filter_runner,
+            results_writer,
+            notification_gate,
+            input_queue,
+            output_topic,
+            filters,
+        })
+    }
+
+    async fn reload_filters(&mut self) -> Result<(), FilterWorkerError> {
+        let filters: Vec<LsstFilter> = load_filters("LSST_alerts", &self.filter_collection).await?;
+        self.filter_runner.set_versions(filter_versions(&filters));
+        self.results_writer.set_filters(autosaved_filters(&filters));
+        self.notification_gate
+            .set_policies(notification_policies(&filters));
+        self.filters = filters;
+        Ok(())
+    }
+
+    async fn flush_stats(&mut self) -> Result<(), FilterWorkerError> {
+        self.filter_runner.flush_stats().await?;
+        Ok(())
+    }
+
+    async fn release_notifications(&self, alert: &Alert) -> Result<(), FilterWorkerError> {
+        self.notification_gate.release(alert).await
+    }
+
+    fn input_queue_name(&self) -> String {
+        self.input_queue.clone()
+    }
+
+    fn output_topic_name(&self) -> String {
+        self.output_topic.clone()
+    }
+
+    fn survey_name(&self) -> String {
+        "LSST".to_string()
+    }
+
+    fn filter_group_ids(&self) -> HashMap<i32, i32> {
+        self.filters
+            .iter()
+            .filter_map(|filter| Some((filter.id, filter.group_id?)))
+            .collect()
+    }
+
+    fn has_filters(&self) -> bool {
+        !self.filters.is_empty()
+    }
+
+    async fn build_alert(
+        &self,
+        candid: i64,
+        filter_results: Vec<FilterResults>,
+    ) -> Result<Alert, FilterWorkerError> {
+        Self::build_output_alert(&self.alert_collection, candid, filter_results).await
+    }
 
     async fn process_alerts(&mut self, alerts: &[String]) -> Result<Vec<Alert>, FilterWorkerError> {
         let mut alerts_output = Vec::new();
@@ -340,14 +492,19 @@ impl FilterWorker for LsstFilterWorker {
         let candids: Vec<i64> = alerts.iter().map(|alert| alert.parse().unwrap()).collect();
 
         // run the filters
+        // all the filters share the same prefix (and its $lookup of
+        // the aux collection), run once for all of them
+        let pipelines = self
+            .filters
+            .iter()
+            .map(|filter| (filter.id, filter.pipeline.as_slice()));
+        let mut out_documents_by_filter = self.filter_runner.run(&candids, pipelines).await;
+
         let mut results_map: HashMap<i64, Vec<FilterResults>> = HashMap::new();
         for filter in &self.filters {
-            let out_documents = run_filter(
-                candids.clone(),
-                filter.pipeline.clone(),
-                &self.alert_collection,
-            )
-            .await?;
+            let out_documents = out_documents_by_filter
+                .remove(&filter.id)
+                .unwrap_or_default();
 
             // if the array is empty, continue
             if out_documents.is_empty() {
@@ -365,7 +522,10 @@ impl FilterWorker for LsstFilterWorker {
             let now_ts = chrono::Utc::now().timestamp_millis() as f64;
 
             for doc in out_documents {
-                let candid = doc.get_i64("_id")?;
+                // the filter runner only returns the output of the filters that keep the candid
+                let Ok(candid) = doc.get_i64("_id") else {
+                    continue;
+                };
                 // might want to have the annotations as an optional field instead of empty
                 let annotations =
                     serde_json::to_string(doc.get_document("annotations").unwrap_or(&doc! {}))?;
@@ -382,7 +542,34 @@ impl FilterWorker for LsstFilterWorker {
         // now we've basically combined the filter results for each candid
         // we build the alert output and send it to Kafka
         for (candid, filter_results) in &results_map {
-            let alert = self.build_alert(*candid, filter_results.clone()).await?;
+            let mut alert = match self.build_alert(*candid, filter_results.clone()).await {
+                Ok(alert) => alert,
+                Err(e) => {
+                    warn!("could not build the output alert of {}: {}", candid, e);
+                    continue;
+                }
+            };
+            // failing to save the passes shouldn't keep the alert from being sent
+            if let Err(e) = self.results_writer.save(&alert).await {
+                warn!("could not save the filter results of {}: {}", candid, e);
+            }
+            // the passes suppressed by the notification policies are still in the stats
+            let suppressed = match self.notification_gate.apply(&mut alert).await {
+                Ok(suppressed) => suppressed,
+                Err(e) => {
+                    warn!(
+                        "could not apply the notification policies to {}: {}",
+                        candid, e
+                    );
+                    continue;
+                }
+            };
+            for filter_id in suppressed {
+                self.filter_runner.record_suppressed(filter_id);
+            }
+            if alert.filters.is_empty() {
+                continue;
+            }
 
             alerts_output.push(alert);
         }
@@ -1,13 +1,38 @@
+mod backtest;
 mod base;
 mod lsst;
+mod notification;
+mod scheduled;
+mod validation;
 mod ztf;
 
-use base::{
-    get_filter_object, parse_programid_candid_tuple, Alert, FilterResults, Origin, Photometry,
-    Survey,
+pub use backtest::{
+    observing_night, Backtest, BacktestChunk, BacktestError, BacktestQuery, BacktestReport,
+    NightCounts, SkyRegion, DEFAULT_BACKTEST_CHUNK_SIZE, DEFAULT_BACKTEST_SAMPLE_SIZE,
+    LSST_LONGITUDE, ZTF_LONGITUDE,
 };
 pub use base::{
-    run_filter, run_filter_worker, Filter, FilterError, FilterWorker, FilterWorkerError,
+    alert_to_avro_bytes, get_active_filter_ids, get_filter_reload_interval, group_by_prefix,
+    load_alert_schema, notify_filters_updated, run_filter, run_filter_worker, run_filters, Alert,
+    AlertBuilder, AlertEncoder, AutosavedFilter, Classification, CrossMatch, Filter, FilterError,
+    FilterResults, FilterResultsWriter, FilterRunner, FilterStats, FilterTopicsConfig,
+    FilterWorker, FilterWorkerError, Origin, OutputFormat, OutputTopics, ALERT_SCHEMA_VERSION,
+    FILTERS_UPDATED_CHANNEL, FILTER_PREFIX_LEN, FILTER_RESULTS_COLLECTION,
+};
+use base::{
+    autosaved_filters, build_filters, create_producer, filter_versions, get_classifications,
+    get_cross_matches, get_filter_object, load_filters, notification_policies,
+    parse_programid_candid_tuple, send_alert_to_kafka, Photometry, Survey, PHOTOMETRY_ZP,
+};
+pub use lsst::{build_lsst_filter_prefix, LsstFilter, LsstFilterWorker};
+pub use notification::{NotificationGate, NotificationPolicy, FILTER_NOTIFICATIONS_COLLECTION};
+pub use scheduled::{
+    build_object_filter_prefix, get_scheduled_filter_check_interval, get_scheduled_filter_ids,
+    run_scheduled_filter_worker, FilterSchedule, ScheduledFilter, ScheduledFilterWorker,
+    DEFAULT_SCHEDULED_FILTER_CHECK_INTERVAL, DEFAULT_SCHEDULED_FILTER_MAX_RESULTS,
+    DEFAULT_SCHEDULED_FILTER_MAX_TIME_MS, OBJECT_FILTER_TYPE,
+};
+pub use validation::{
+    validate_filter_pipeline, FilterValidationError, FilterValidator, MAX_UNWIND_STAGES,
 };
-pub use lsst::{LsstFilter, LsstFilterWorker};
-pub use ztf::{ZtfFilter, ZtfFilterWorker};
+pub use ztf::{build_ztf_filter_prefix, ZtfFilter, ZtfFilterWorker};
@@ -0,0 +1,220 @@
+use mongodb::bson::{doc, Document};
+use std::collections::HashMap;
+
+use crate::{
+    filter::{Alert, FilterError, FilterResults, FilterWorkerError},
+    utils::db::{create_index, CreateIndexError},
+};
+
+/// Collection in which the last notification of each filter and object is kept.
+pub const FILTER_NOTIFICATIONS_COLLECTION: &str = "filter_notifications";
+
+/// When the passes of a filter are sent out, from the `notification_policy`
+/// of the filter document, e.g. `{"type": "throttle", "hours": 6}`.
+/// The policies are enforced per object, so that downstream consumers don't get
+/// the same transient with every new detection.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NotificationPolicy {
+    /// Every pass (the default).
+    #[default]
+    Always,
+    /// Only the first pass of each object.
+    FirstPass,
+    /// At most one pass of each object every `hours` hours.
+    Throttle { hours: f64 },
+    /// Only the passes for which the annotations changed since the last
+    /// notification of the object. With `keys`, only those annotations are
+    /// compared, and `classifications.<model>` compares the score of a model.
+    OnChange {
+        #[serde(default)]
+        keys: Vec<String>,
+    },
+}
+
+impl NotificationPolicy {
+    /// The policy of a filter document, `Always` when it has none.
+    pub fn from_filter_object(filter_obj: &Document) -> Result<Self, FilterError> {
+        match filter_obj.get("notification_policy") {
+            None | Some(mongodb::bson::Bson::Null) => Ok(NotificationPolicy::Always),
+            Some(policy) => mongodb::bson::from_bson(policy.clone())
+                .map_err(|_| FilterError::InvalidNotificationPolicy),
+        }
+    }
+
+    // the value compared between the passes of an object by OnChange
+    fn value(&self, alert: &Alert, filter_result: &FilterResults) -> Option<String> {
+        let NotificationPolicy::OnChange { keys } = self else {
+            return None;
+        };
+        if keys.is_empty() {
+            return Some(filter_result.annotations.clone());
+        }
+        let annotations: serde_json::Value =
+            serde_json::from_str(&filter_result.annotations).unwrap_or_default();
+        let values: Vec<serde_json::Value> = keys
+            .iter()
+            .map(|key| match key.strip_prefix("classifications.") {
+                Some(model) => alert
+                    .classifications
+                    .get(model)
+                    .map(|classification| classification.score.into())
+                    .unwrap_or_default(),
+                None => annotations.get(key).cloned().unwrap_or_default(),
+            })
+            .collect();
+        Some(serde_json::Value::from(values).to_string())
+    }
+}
+
+/// Enforces the notification policies of the filters, with the last notification
+/// of each filter and object kept in the `filter_notifications` collection.
+pub struct NotificationGate {
+    collection: mongodb::Collection<Document>,
+    policies: HashMap<i32, NotificationPolicy>,
+}
+
+impl NotificationGate {
+    pub fn new(db: &mongodb::Database) -> Self {
+        NotificationGate {
+            collection: db.collection(FILTER_NOTIFICATIONS_COLLECTION),
+            policies: HashMap::new(),
+        }
+    }
+
+    pub async fn create_indexes(&self) -> Result<(), CreateIndexError> {
+        create_index(&self.collection, doc! {"filter_id": 1, "objectId": 1}, true).await
+    }
+
+    /// Set the policy of each filter, after the filters have been (re)loaded.
+    /// The filters without one (or with `Always`) are not tracked.
+    pub fn set_policies(&mut self, policies: HashMap<i32, NotificationPolicy>) {
+        self.policies = policies
+            .into_iter()
+            .filter(|(_, policy)| *policy != NotificationPolicy::Always)
+            .collect();
+    }
+
+    /// Remove the passes of an alert that the policies of their filters suppress,
+    /// and return the ids of these filters. The passes that are kept become the
+    /// last notification of their object, until [`NotificationGate::release`]
+    /// is called for the alert if it could not be sent.
+    pub async fn apply(&self, alert: &mut Alert) -> Result<Vec<i32>, FilterWorkerError> {
+        let mut suppressed = Vec::new();
+        let mut notified = Vec::new();
+        for filter_result in std::mem::take(&mut alert.filters) {
+            match self.notify(alert, &filter_result).await? {
+                true => notified.push(filter_result),
+                false => suppressed.push(filter_result.filter_id),
+            }
+        }
+        alert.filters = notified;
+        Ok(suppressed)
+    }
+
+    /// Undo the notifications recorded by [`NotificationGate::apply`] for an alert
+    /// that could not be sent, so that the next pass of its object isn't suppressed.
+    pub async fn release(&self, alert: &Alert) -> Result<(), FilterWorkerError> {
+        for filter_result in &alert.filters {
+            if !self.policies.contains_key(&filter_result.filter_id) {
+                continue;
+            }
+            let key = doc! {
+                "filter_id": filter_result.filter_id,
+                "objectId": &alert.object_id,
+                "candid": alert.candid,
+            };
+            // the first notification of the object: there is nothing to go back to
+            let mut first = key.clone();
+            first.insert("previous.last_notified_at", doc! {"$exists": false});
+            self.collection.delete_one(first).await?;
+            self.collection
+                .update_one(
+                    key,
+                    vec![
+                        doc! {
+                            "$set": {
+                                "candid": "$previous.candid",
+                                "last_notified_at": "$previous.last_notified_at",
+                                "last_value": "$previous.last_value",
+                            }
+                        },
+                        doc! {"$unset": "previous"},
+                    ],
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    // whether a pass is sent out, in which case it becomes the last notification of the object
+    // with a single conditional upsert, so that two workers can't both notify the same pass:
+    // when the last notification suppresses the pass, the upsert conflicts with it on the
+    // unique (filter_id, objectId) index instead of updating it
+    async fn notify(
+        &self,
+        alert: &Alert,
+        filter_result: &FilterResults,
+    ) -> Result<bool, FilterWorkerError> {
+        let Some(policy) = self.policies.get(&filter_result.filter_id) else {
+            return Ok(true);
+        };
+        let mut filter = doc! {"filter_id": filter_result.filter_id, "objectId": &alert.object_id};
+        let passed_at = mongodb::bson::DateTime::from_millis(filter_result.passed_at as i64);
+        let value = policy.value(alert, filter_result);
+        match policy {
+            NotificationPolicy::Always => return Ok(true),
+            NotificationPolicy::FirstPass => {
+                filter.insert("last_notified_at", doc! {"$exists": false});
+            }
+            NotificationPolicy::Throttle { hours } => {
+                let throttled_since = mongodb::bson::DateTime::from_millis(
+                    passed_at.timestamp_millis() - (hours * 3600.0 * 1000.0) as i64,
+                );
+                filter.insert("last_notified_at", doc! {"$lte": throttled_since});
+            }
+            NotificationPolicy::OnChange { .. } => {
+                filter.insert("last_value", doc! {"$ne": value.clone()});
+            }
+        }
+
+        let result = self
+            .collection
+            .update_one(
+                filter,
+                vec![
+                    // kept until the alert is sent, for release to go back to
+                    doc! {
+                        "$set": {
+                            "previous": {
+                                "candid": "$candid",
+                                "last_notified_at": "$last_notified_at",
+                                "last_value": "$last_value",
+                            }
+                        }
+                    },
+                    doc! {
+                        "$set": {
+                            "candid": alert.candid,
+                            "last_notified_at": passed_at,
+                            "last_value": {"$literal": value},
+                        }
+                    },
+                ],
+            )
+            .upsert(true)
+            .await;
+        match result {
+            Ok(_) => Ok(true),
+            Err(e) if is_duplicate_key_error(&e) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+fn is_duplicate_key_error(error: &mongodb::error::Error) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        mongodb::error::ErrorKind::Write(mongodb::error::WriteFailure::WriteError(e)) if e.code == 11000
+    )
+}
@@ -0,0 +1,696 @@
+use chrono::{DateTime, Timelike, Utc};
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use std::collections::{HashMap, HashSet};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TryRecvError;
+use tracing::{info, trace, warn};
+
+use crate::{
+    conf,
+    filter::{
+        autosaved_filters, build_filters, create_producer, get_filter_object,
+        notification_policies, send_alert_to_kafka, Alert, AlertBuilder, Filter, FilterError,
+        FilterResults, FilterResultsWriter, FilterWorkerError, NotificationGate,
+        NotificationPolicy, OutputTopics,
+    },
+    utils::worker::WorkerCmd,
+};
+
+/// The `type` of the filters that run on a schedule over the aux collection
+/// of a catalog (one document per object), instead of on each new alert.
+pub const OBJECT_FILTER_TYPE: &str = "object";
+
+// seconds between two checks for the scheduled filters that are due
+pub const DEFAULT_SCHEDULED_FILTER_CHECK_INTERVAL: u64 = 60;
+
+// read workers.<survey>.scheduled_filter.check_interval (in seconds) from the config, if set
+pub fn get_scheduled_filter_check_interval(
+    conf: &config::Config,
+    survey: &str,
+) -> std::time::Duration {
+    let seconds = conf
+        .get_int(&format!(
+            "workers.{}.scheduled_filter.check_interval",
+            survey
+        ))
+        .map(|seconds| seconds.max(1) as u64)
+        .unwrap_or(DEFAULT_SCHEDULED_FILTER_CHECK_INTERVAL);
+    std::time::Duration::from_secs(seconds)
+}
+
+// time after which the aggregation of an object filter is aborted
+pub const DEFAULT_SCHEDULED_FILTER_MAX_TIME_MS: u64 = 60000;
+// maximum number of objects that pass an object filter in one run
+pub const DEFAULT_SCHEDULED_FILTER_MAX_RESULTS: i64 = 10000;
+// number of objects whose latest alert is looked up in one query
+const LATEST_CANDIDS_BATCH_SIZE: usize = 1000;
+
+/// When an object filter runs, from the `schedule` of the filter document,
+/// e.g. `{"type": "interval", "minutes": 60}` or `{"type": "daily", "hour": 14}`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FilterSchedule {
+    /// Every `minutes` minutes.
+    Interval { minutes: u32 },
+    /// Once a day at `hour` (UTC), e.g. after the end of the night at the observatory.
+    Daily { hour: u32 },
+}
+
+impl FilterSchedule {
+    /// The schedule of a filter document, which object filters must have.
+    pub fn from_filter_object(filter_obj: &Document) -> Result<Self, FilterError> {
+        let schedule: FilterSchedule = filter_obj
+            .get("schedule")
+            .and_then(|schedule| mongodb::bson::from_bson(schedule.clone()).ok())
+            .ok_or(FilterError::InvalidFilterSchedule)?;
+        if !schedule.is_valid() {
+            return Err(FilterError::InvalidFilterSchedule);
+        }
+        Ok(schedule)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        match self {
+            FilterSchedule::Interval { minutes } => *minutes > 0,
+            FilterSchedule::Daily { hour } => *hour < 24,
+        }
+    }
+
+    /// The time of the first run after one at `last_run_at`.
+    pub fn next_run(&self, last_run_at: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            FilterSchedule::Interval { minutes } => {
+                last_run_at + chrono::Duration::minutes(*minutes as i64)
+            }
+            FilterSchedule::Daily { hour } => {
+                let run_at = last_run_at
+                    .date_naive()
+                    .and_hms_opt(*hour, 0, 0)
+                    .unwrap_or_default()
+                    .and_utc();
+                if run_at > last_run_at {
+                    run_at
+                } else {
+                    run_at + chrono::Duration::days(1)
+                }
+            }
+        }
+    }
+
+    /// Whether a filter that last ran at `last_run_at` (if ever) should run at `now`.
+    /// A daily filter that never ran waits for its hour of the day.
+    pub fn is_due(&self, last_run_at: Option<DateTime<Utc>>, now: DateTime<Utc>) -> bool {
+        match (self, last_run_at) {
+            (_, Some(last_run_at)) => self.next_run(last_run_at) <= now,
+            (FilterSchedule::Daily { hour }, None) => now.hour() >= *hour,
+            (FilterSchedule::Interval { .. }, None) => true,
+        }
+    }
+}
+
+/// The stage run before those of every object filter of a survey: the aux
+/// documents, with the photometry restricted to the programids of the filter
+/// `permissions` (for ZTF), and the time of the run as `now_jd`.
+pub fn build_object_filter_prefix(survey: &str, permissions: &[i32], now_jd: f64) -> Vec<Document> {
+    let photometry = |field: &str| -> Bson {
+        match survey {
+            "ZTF" => doc! {
+                "$filter": {
+                    "input": format!("${}", field),
+                    "as": "x",
+                    "cond": {"$in": ["$$x.programid", permissions]}
+                }
+            }
+            .into(),
+            _ => format!("${}", field).into(),
+        }
+    };
+    vec![doc! {
+        "$project": {
+            "coordinates": 1,
+            "cross_matches": 1,
+            "aliases": 1,
+            "dia_object": 1,
+            "created_at": 1,
+            "updated_at": 1,
+            "prv_candidates": photometry("prv_candidates"),
+            "prv_nondetections": photometry("prv_nondetections"),
+            "fp_hists": photometry("fp_hists"),
+            "now_jd": {"$literal": now_jd},
+        }
+    }]
+}
+
+/// The ids of the active object filters of a catalog (e.g. ZTF_alerts).
+pub async fn get_scheduled_filter_ids(
+    catalog: &str,
+    filter_collection: &mongodb::Collection<Document>,
+) -> Result<Vec<i32>, FilterError> {
+    let filter_ids: Vec<i32> = filter_collection
+        .distinct(
+            "filter_id",
+            doc! {"active": true, "catalog": catalog, "type": OBJECT_FILTER_TYPE},
+        )
+        .await?
+        .into_iter()
+        .filter_map(|x| x.as_i32())
+        .collect();
+
+    Ok(filter_ids)
+}
+
+fn to_bson_datetime(date: DateTime<Utc>) -> mongodb::bson::DateTime {
+    mongodb::bson::DateTime::from_millis(date.timestamp_millis())
+}
+
+/// An object filter: a pipeline over the aux collection of a catalog, run on a
+/// schedule. Its stages get the aux documents (with `_id` as the objectId),
+/// and the objects that come out (with their `annotations`) pass the filter.
+#[derive(Debug)]
+pub struct ScheduledFilter {
+    pub id: i32,
+    pub fid: String,
+    pub catalog: String,
+    pub pipeline: Vec<Document>,
+    pub permissions: Vec<i32>,
+    pub group_id: Option<i32>,
+    pub schedule: FilterSchedule,
+    pub last_run_at: Option<DateTime<Utc>>,
+    pub autosave: bool,
+    pub update_annotations: bool,
+    pub notification_policy: NotificationPolicy,
+    // the last run before the filter was claimed, to go back to if the run is undone
+    previous_run_at: Option<DateTime<Utc>>,
+}
+
+#[async_trait::async_trait]
+impl Filter for ScheduledFilter {
+    async fn build(
+        filter_id: i32,
+        filter_collection: &mongodb::Collection<Document>,
+    ) -> Result<Self, FilterError> {
+        // the filter ids are unique across the catalogs, whose object filters
+        // are all built the same way
+        let catalog = filter_collection
+            .find_one(doc! {"filter_id": filter_id})
+            .projection(doc! {"catalog": 1})
+            .await?
+            .and_then(|filter_obj| filter_obj.get_str("catalog").ok().map(String::from))
+            .ok_or(FilterError::FilterNotFound)?;
+        let catalog = catalog.as_str();
+        let filter_obj = get_filter_object(filter_id, catalog, filter_collection).await?;
+
+        let permissions = match filter_obj.get("permissions") {
+            Some(permissions) => permissions
+                .as_array()
+                .ok_or(FilterError::InvalidFilterPermissions)?
+                .iter()
+                .filter_map(|x| x.as_i32())
+                .collect(),
+            None => vec![],
+        };
+        if catalog == "ZTF_alerts" && permissions.is_empty() {
+            return Err(FilterError::InvalidFilterPermissions);
+        }
+
+        let filter_pipeline = filter_obj
+            .get_str("pipeline")
+            .map_err(|_| FilterError::FilterNotFound)?;
+        let filter_pipeline = serde_json::from_str::<serde_json::Value>(filter_pipeline)?;
+        let pipeline = filter_pipeline
+            .as_array()
+            .ok_or(FilterError::InvalidFilterPipeline)?
+            .iter()
+            .map(mongodb::bson::to_document)
+            .collect::<Result<Vec<Document>, _>>()?;
+
+        Ok(ScheduledFilter {
+            id: filter_id,
+            fid: filter_obj.get_str("fid").unwrap_or_default().to_string(),
+            catalog: catalog.to_string(),
+            pipeline,
+            permissions,
+            group_id: filter_obj.get_i32("group_id").ok(),
+            schedule: FilterSchedule::from_filter_object(&filter_obj)?,
+            last_run_at: filter_obj
+                .get_datetime("last_run_at")
+                .ok()
+                .and_then(|last_run_at| {
+                    DateTime::from_timestamp_millis(last_run_at.timestamp_millis())
+                }),
+            autosave: filter_obj.get_bool("autosave").unwrap_or(false),
+            update_annotations: filter_obj.get_bool("update_annotations").unwrap_or(false),
+            notification_policy: NotificationPolicy::from_filter_object(&filter_obj)?,
+            previous_run_at: None,
+        })
+    }
+
+    fn id(&self) -> i32 {
+        self.id
+    }
+
+    fn fid(&self) -> &str {
+        &self.fid
+    }
+
+    fn autosave(&self) -> bool {
+        self.autosave
+    }
+
+    fn update_annotations(&self) -> bool {
+        self.update_annotations
+    }
+
+    fn notification_policy(&self) -> &NotificationPolicy {
+        &self.notification_policy
+    }
+}
+
+impl ScheduledFilter {
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        self.schedule.is_due(self.last_run_at, now)
+    }
+
+    /// Mark the filter as run at `now`, unless another worker did since it was
+    /// loaded, in which case it's not ours to run and false is returned.
+    /// A run that fails isn't retried before the next one on the schedule,
+    /// unless it is undone with `unclaim`.
+    pub async fn claim(
+        &mut self,
+        filter_collection: &mongodb::Collection<Document>,
+        now: DateTime<Utc>,
+    ) -> Result<bool, FilterError> {
+        let result = filter_collection
+            .update_one(
+                doc! {
+                    "filter_id": self.id,
+                    "catalog": &self.catalog,
+                    "last_run_at": self.last_run_at.map(to_bson_datetime),
+                },
+                doc! {"$set": {"last_run_at": to_bson_datetime(now)}},
+            )
+            .await?;
+        if result.modified_count == 0 {
+            return Ok(false);
+        }
+        self.previous_run_at = self.last_run_at;
+        self.last_run_at = Some(now);
+        Ok(true)
+    }
+
+    /// Undo the last claim of the filter, so that it runs again at the next
+    /// check (unless another worker has run it since).
+    pub async fn unclaim(
+        &mut self,
+        filter_collection: &mongodb::Collection<Document>,
+    ) -> Result<(), FilterError> {
+        filter_collection
+            .update_one(
+                doc! {
+                    "filter_id": self.id,
+                    "catalog": &self.catalog,
+                    "last_run_at": self.last_run_at.map(to_bson_datetime),
+                },
+                doc! {"$set": {"last_run_at": self.previous_run_at.map(to_bson_datetime)}},
+            )
+            .await?;
+        self.last_run_at = self.previous_run_at;
+        Ok(())
+    }
+
+    /// Run the filter over the aux collection, returning the `_id` (objectId)
+    /// and `annotations` of (at most `max_results` of) the objects that passed.
+    /// The aggregation is aborted by the server when it runs longer than `max_time`.
+    pub async fn run(
+        &self,
+        aux_collection: &mongodb::Collection<Document>,
+        now_jd: f64,
+        max_time: std::time::Duration,
+        max_results: i64,
+    ) -> Result<Vec<Document>, FilterError> {
+        let survey = self.catalog.trim_end_matches("_alerts");
+        let mut pipeline = build_object_filter_prefix(survey, &self.permissions, now_jd);
+        pipeline.extend(self.pipeline.iter().cloned());
+        pipeline.push(doc! {"$limit": max_results});
+        pipeline.push(doc! {"$project": {"annotations": 1}});
+
+        let out_documents: Vec<Document> = aux_collection
+            .aggregate(pipeline)
+            .max_time(max_time)
+            .await?
+            .try_collect()
+            .await?;
+        if out_documents.len() as i64 >= max_results {
+            warn!(
+                "object filter {} of {} reached max_results ({}), the other objects that passed are left out",
+                self.id, self.catalog, max_results
+            );
+        }
+        Ok(out_documents)
+    }
+
+    /// The candid of the latest alert the filter has access to of each object
+    /// that has one, by objectId (as a string, the objectIds of LSST being integers).
+    pub async fn latest_candids(
+        &self,
+        alert_collection: &mongodb::Collection<Document>,
+        object_ids: &[Bson],
+    ) -> Result<HashMap<String, i64>, FilterError> {
+        let mut latest_candids = HashMap::new();
+        for object_ids in object_ids.chunks(LATEST_CANDIDS_BATCH_SIZE) {
+            let mut alert_filter = doc! {"objectId": {"$in": object_ids}};
+            if !self.permissions.is_empty() && self.catalog == "ZTF_alerts" {
+                alert_filter.insert("candidate.programid", doc! {"$in": &self.permissions});
+            }
+            let pipeline = vec![
+                doc! {"$match": alert_filter},
+                doc! {"$sort": {"objectId": 1, "candidate.jd": -1}},
+                doc! {"$group": {"_id": "$objectId", "candid": {"$first": "$_id"}}},
+            ];
+            let mut cursor = alert_collection.aggregate(pipeline).await?;
+            while let Some(latest) = cursor.try_next().await? {
+                let object_id = latest
+                    .get("_id")
+                    .ok_or(FilterError::InvalidFilterPipeline)?;
+                latest_candids.insert(object_id.to_string(), latest.get_i64("candid")?);
+            }
+        }
+        Ok(latest_candids)
+    }
+}
+
+/// Runs the object filters of a survey when they are due, and builds an alert
+/// for each object that passes, from its latest alert, so that the results go
+/// out in the same envelope as those of the alert filters.
+pub struct ScheduledFilterWorker<T: AlertBuilder> {
+    filter_collection: mongodb::Collection<Document>,
+    alert_collection: mongodb::Collection<Document>,
+    aux_collection: mongodb::Collection<Document>,
+    catalog: String,
+    results_writer: FilterResultsWriter,
+    notification_gate: NotificationGate,
+    max_time: std::time::Duration,
+    max_results: i64,
+    filters: Vec<ScheduledFilter>,
+    // the alerts are built as by the filter workers of the survey
+    alert_builder: std::marker::PhantomData<T>,
+}
+
+impl<T: AlertBuilder> ScheduledFilterWorker<T> {
+    /// Read the `workers.<survey>.scheduled_filter` settings (max_time_ms and
+    /// max_results), with the defaults for the missing ones.
+    pub async fn new(config_path: &str) -> Result<Self, FilterWorkerError> {
+        let config_file = conf::load_config(config_path)?;
+        let db: mongodb::Database = conf::build_db(&config_file).await?;
+        let catalog = format!("{}_alerts", T::SURVEY);
+        let get = |key: &str| {
+            config_file.get_int(&format!("workers.{}.scheduled_filter.{}", T::SURVEY, key))
+        };
+
+        let results_writer = FilterResultsWriter::new(&db, &catalog);
+        results_writer.create_indexes().await?;
+        let notification_gate = NotificationGate::new(&db);
+        notification_gate.create_indexes().await?;
+
+        let mut scheduled_filter_worker = ScheduledFilterWorker {
+            filter_collection: db.collection("filters"),
+            alert_collection: db.collection(&catalog),
+            aux_collection: db.collection(&format!("{}_aux", catalog)),
+            catalog,
+            results_writer,
+            notification_gate,
+            max_time: std::time::Duration::from_millis(
+                get("max_time_ms")
+                    .map(|ms| ms.max(1) as u64)
+                    .unwrap_or(DEFAULT_SCHEDULED_FILTER_MAX_TIME_MS),
+            ),
+            max_results: get("max_results")
+                .map(|n| n.max(1))
+                .unwrap_or(DEFAULT_SCHEDULED_FILTER_MAX_RESULTS),
+            filters: Vec::new(),
+            alert_builder: std::marker::PhantomData,
+        };
+        scheduled_filter_worker.reload_filters().await?;
+        Ok(scheduled_filter_worker)
+    }
+
+    pub fn survey_name(&self) -> String {
+        T::SURVEY.to_string()
+    }
+
+    pub fn output_topic_name(&self) -> String {
+        format!("{}_results", self.catalog)
+    }
+
+    /// The group of each filter that has one, by filter id.
+    pub fn filter_group_ids(&self) -> HashMap<i32, i32> {
+        self.filters
+            .iter()
+            .filter_map(|filter| Some((filter.id, filter.group_id?)))
+            .collect()
+    }
+
+    /// Rebuild the active object filters (see `build_filters`).
+    pub async fn reload_filters(&mut self) -> Result<(), FilterWorkerError> {
+        let filter_ids = get_scheduled_filter_ids(&self.catalog, &self.filter_collection).await?;
+        let filters: Vec<ScheduledFilter> =
+            build_filters(&self.catalog, filter_ids, &self.filter_collection).await?;
+        self.results_writer.set_filters(autosaved_filters(&filters));
+        self.notification_gate
+            .set_policies(notification_policies(&filters));
+        self.filters = filters;
+        Ok(())
+    }
+
+    /// Undo the notifications recorded for the passes of an alert that could not be sent.
+    pub async fn release_notifications(&self, alert: &Alert) -> Result<(), FilterWorkerError> {
+        self.notification_gate.release(alert).await
+    }
+
+    /// Undo the last run of a filter whose results could not all be sent,
+    /// so that it runs again at the next check.
+    pub async fn unclaim(&mut self, filter_id: i32) -> Result<(), FilterWorkerError> {
+        if let Some(filter) = self
+            .filters
+            .iter_mut()
+            .find(|filter| filter.id == filter_id)
+        {
+            filter.unclaim(&self.filter_collection).await?;
+        }
+        Ok(())
+    }
+
+    /// Run the filters that are due at `now` (and that no other worker is running),
+    /// and return the alerts of the objects that passed them. A filter that fails
+    /// is logged and skipped until its next run.
+    pub async fn run_due_filters(
+        &mut self,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<Alert>, FilterWorkerError> {
+        let now_jd = now.timestamp_millis() as f64 / 86_400_000.0 + 2440587.5;
+        let mut alerts = Vec::new();
+        for i in 0..self.filters.len() {
+            if !self.filters[i].is_due(now) {
+                continue;
+            }
+            match self.filters[i].claim(&self.filter_collection, now).await {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(e) => {
+                    warn!(
+                        "could not claim object filter {}: {}",
+                        self.filters[i].id, e
+                    );
+                    continue;
+                }
+            }
+            match self.run_filter(&self.filters[i], now, now_jd).await {
+                Ok(filter_alerts) => alerts.extend(filter_alerts),
+                Err(e) => warn!("object filter {} failed: {}", self.filters[i].id, e),
+            }
+        }
+        Ok(alerts)
+    }
+
+    async fn run_filter(
+        &self,
+        filter: &ScheduledFilter,
+        now: DateTime<Utc>,
+        now_jd: f64,
+    ) -> Result<Vec<Alert>, FilterWorkerError> {
+        let out_documents = filter
+            .run(
+                &self.aux_collection,
+                now_jd,
+                self.max_time,
+                self.max_results,
+            )
+            .await?;
+        info!(
+            "{} objects passed object filter {} of {}",
+            out_documents.len(),
+            filter.id,
+            self.catalog
+        );
+
+        let object_ids = out_documents
+            .iter()
+            .map(|doc| doc.get("_id").cloned())
+            .collect::<Option<Vec<Bson>>>()
+            .ok_or(FilterError::InvalidFilterPipeline)?;
+        let latest_candids = filter
+            .latest_candids(&self.alert_collection, &object_ids)
+            .await?;
+
+        let mut alerts = Vec::new();
+        for (doc, object_id) in out_documents.iter().zip(&object_ids) {
+            let Some(&candid) = latest_candids.get(&object_id.to_string()) else {
+                trace!("no alert of object {} for filter {}", object_id, filter.id);
+                continue;
+            };
+            let filter_result = FilterResults {
+                filter_id: filter.id,
+                passed_at: now.timestamp_millis() as f64,
+                annotations: serde_json::to_string(
+                    doc.get_document("annotations").unwrap_or(&doc! {}),
+                )?,
+            };
+            let mut alert =
+                match T::build_output_alert(&self.alert_collection, candid, vec![filter_result])
+                    .await
+                {
+                    Ok(alert) => alert,
+                    Err(e) => {
+                        warn!("could not build the output alert of {}: {}", candid, e);
+                        continue;
+                    }
+                };
+            // failing to save the passes shouldn't keep the alert from being sent
+            if let Err(e) = self.results_writer.save(&alert).await {
+                warn!("could not save the filter results of {}: {}", candid, e);
+            }
+            if let Err(e) = self.notification_gate.apply(&mut alert).await {
+                warn!(
+                    "could not apply the notification policies to {}: {}",
+                    candid, e
+                );
+                continue;
+            }
+            if !alert.filters.is_empty() {
+                alerts.push(alert);
+            }
+        }
+        Ok(alerts)
+    }
+}
+
+#[tokio::main]
+pub async fn run_scheduled_filter_worker<T: AlertBuilder>(
+    id: String,
+    mut receiver: mpsc::Receiver<WorkerCmd>,
+    config_path: &str,
+) -> Result<(), FilterWorkerError> {
+    let config = conf::load_config(config_path)?;
+
+    let mut scheduled_filter_worker = ScheduledFilterWorker::<T>::new(config_path).await?;
+
+    let survey = scheduled_filter_worker.survey_name();
+    let output_topic = scheduled_filter_worker.output_topic_name();
+    let check_interval = get_scheduled_filter_check_interval(&config, &survey);
+
+    let producer = create_producer(&config).await?;
+    let mut output_topics = OutputTopics::from_config(&config, &survey, &output_topic)?;
+
+    let mut last_check: Option<std::time::Instant> = None;
+    loop {
+        match receiver.try_recv() {
+            Ok(WorkerCmd::TERM) => {
+                info!(
+                    "scheduled filter worker {} received termination command",
+                    &id
+                );
+                break;
+            }
+            Err(TryRecvError::Disconnected) => {
+                warn!(
+                    "scheduled filter worker {} receiver disconnected, terminating",
+                    &id
+                );
+                break;
+            }
+            Err(TryRecvError::Empty) => {}
+        }
+        if last_check.is_some_and(|last_check| last_check.elapsed() < check_interval) {
+            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+            continue;
+        }
+        last_check = Some(std::time::Instant::now());
+
+        // pick up the filters added or updated since the last check
+        if let Err(e) = scheduled_filter_worker.reload_filters().await {
+            warn!(
+                "scheduled filter worker {} could not reload its filters, keeping the current ones: {}",
+                &id, e
+            );
+        }
+        let group_ids = scheduled_filter_worker.filter_group_ids();
+
+        let alerts_output = scheduled_filter_worker
+            .run_due_filters(chrono::Utc::now())
+            .await?;
+        // the filters whose results couldn't all be sent run again at the next check,
+        // with the notifications of all their passes released
+        let mut failed_filter_ids = HashSet::new();
+        for alert in &alerts_output {
+            for (topic, routed_alert) in output_topics.route(alert, &group_ids) {
+                let encoder = output_topics.encoder(&config, &topic).await?;
+                if let Err(e) =
+                    send_alert_to_kafka(&routed_alert, encoder, &producer, &topic, &id).await
+                {
+                    warn!(
+                        "could not send alert with candid {} to Kafka topic {}: {}",
+                        &alert.candid, &topic, e
+                    );
+                    failed_filter_ids.extend(routed_alert.filters.iter().map(|f| f.filter_id));
+                    continue;
+                }
+                trace!(
+                    "Sent alert with candid {} to Kafka topic {}",
+                    &alert.candid,
+                    &topic
+                );
+            }
+        }
+        for alert in alerts_output {
+            let failed = Alert {
+                filters: alert
+                    .filters
+                    .iter()
+                    .filter(|f| failed_filter_ids.contains(&f.filter_id))
+                    .cloned()
+                    .collect(),
+                ..alert
+            };
+            if failed.filters.is_empty() {
+                continue;
+            }
+            if let Err(e) = scheduled_filter_worker.release_notifications(&failed).await {
+                warn!(
+                    "could not release the notifications of {}: {}",
+                    &failed.candid, e
+                );
+            }
+        }
+        for filter_id in failed_filter_ids {
+            if let Err(e) = scheduled_filter_worker.unclaim(filter_id).await {
+                warn!(
+                    "could not undo the run of object filter {}: {}",
+                    filter_id, e
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,349 @@
+use crate::conf::{build_xmatch_configs, BoomConfigError};
+use config::Config;
+use mongodb::bson::{Bson, Document};
+use std::collections::HashSet;
+
+// the stages a filter can use: no writes ($out, $merge), no reads outside of
+// the approved collections ($unionWith, $graphLookup), no $facet, which the
+// filter workers already use to run all the filters of a batch together, and
+// none of the stages that replace the _id (candid) of the documents that pass
+// ($group, $count, $bucket, $bucketAuto, $sortByCount, $replaceRoot, $replaceWith)
+const ALLOWED_STAGES: &[&str] = &[
+    "$match",
+    "$project",
+    "$addFields",
+    "$set",
+    "$unset",
+    "$unwind",
+    "$lookup",
+    "$sort",
+    "$limit",
+    "$skip",
+];
+
+// the query and expression operators a filter can use (no server-side javascript)
+const ALLOWED_OPERATORS: &[&str] = &[
+    // query
+    "$eq",
+    "$ne",
+    "$gt",
+    "$gte",
+    "$lt",
+    "$lte",
+    "$in",
+    "$nin",
+    "$and",
+    "$or",
+    "$nor",
+    "$not",
+    "$exists",
+    "$type",
+    "$all",
+    "$elemMatch",
+    "$size",
+    "$regex",
+    "$options",
+    "$mod",
+    "$expr",
+    "$geoWithin",
+    "$centerSphere",
+    "$box",
+    "$polygon",
+    "$center",
+    "$geometry",
+    // arithmetic
+    "$abs",
+    "$add",
+    "$ceil",
+    "$divide",
+    "$exp",
+    "$floor",
+    "$ln",
+    "$log",
+    "$log10",
+    "$multiply",
+    "$pow",
+    "$round",
+    "$sqrt",
+    "$subtract",
+    "$trunc",
+    // trigonometry
+    "$sin",
+    "$cos",
+    "$tan",
+    "$asin",
+    "$acos",
+    "$atan",
+    "$atan2",
+    "$degreesToRadians",
+    "$radiansToDegrees",
+    // comparison
+    "$cmp",
+    // conditional
+    "$cond",
+    "$ifNull",
+    "$switch",
+    // array
+    "$arrayElemAt",
+    "$concatArrays",
+    "$filter",
+    "$first",
+    "$last",
+    "$indexOfArray",
+    "$isArray",
+    "$map",
+    "$maxN",
+    "$minN",
+    "$firstN",
+    "$lastN",
+    "$range",
+    "$reduce",
+    "$reverseArray",
+    "$slice",
+    "$sortArray",
+    "$zip",
+    "$arrayToObject",
+    "$objectToArray",
+    // set
+    "$setDifference",
+    "$setEquals",
+    "$setIntersection",
+    "$setIsSubset",
+    "$setUnion",
+    "$allElementsTrue",
+    "$anyElementTrue",
+    // string
+    "$concat",
+    "$indexOfBytes",
+    "$indexOfCP",
+    "$ltrim",
+    "$rtrim",
+    "$trim",
+    "$split",
+    "$strLenBytes",
+    "$strLenCP",
+    "$strcasecmp",
+    "$substr",
+    "$substrBytes",
+    "$substrCP",
+    "$toLower",
+    "$toUpper",
+    "$regexMatch",
+    "$regexFind",
+    "$regexFindAll",
+    // type
+    "$convert",
+    "$toBool",
+    "$toDate",
+    "$toDecimal",
+    "$toDouble",
+    "$toInt",
+    "$toLong",
+    "$toString",
+    "$isNumber",
+    // date
+    "$dateFromString",
+    "$dateToString",
+    "$year",
+    "$month",
+    "$dayOfMonth",
+    "$hour",
+    // accumulators
+    "$sum",
+    "$avg",
+    "$min",
+    "$max",
+    "$push",
+    "$addToSet",
+    "$count",
+    "$stdDevPop",
+    "$stdDevSamp",
+    "$median",
+    "$percentile",
+    // object and variables
+    "$getField",
+    "$mergeObjects",
+    "$let",
+    "$literal",
+];
+
+// maximum number of $unwind stages in a pipeline, as each multiplies the
+// number of documents the following stages run on
+pub const MAX_UNWIND_STAGES: usize = 2;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum FilterValidationError {
+    #[error("the pipeline has no stages")]
+    EmptyPipeline,
+    #[error("stage {stage} is not a document with a single stage")]
+    InvalidStage { stage: usize },
+    #[error("stage {stage}: {name} is not allowed")]
+    StageNotAllowed { stage: usize, name: String },
+    #[error("stage {stage}: operator {operator} is not allowed")]
+    OperatorNotAllowed { stage: usize, operator: String },
+    #[error("stage {stage}: $lookup from {collection} is not allowed")]
+    LookupNotAllowed { stage: usize, collection: String },
+    #[error("stage {stage}: more than {max} $unwind stages")]
+    TooManyUnwinds { stage: usize, max: usize },
+}
+
+impl FilterValidationError {
+    /// The index of the offending stage, if any.
+    pub fn stage(&self) -> Option<usize> {
+        match self {
+            FilterValidationError::EmptyPipeline => None,
+            FilterValidationError::InvalidStage { stage }
+            | FilterValidationError::StageNotAllowed { stage, .. }
+            | FilterValidationError::OperatorNotAllowed { stage, .. }
+            | FilterValidationError::LookupNotAllowed { stage, .. }
+            | FilterValidationError::TooManyUnwinds { stage, .. } => Some(*stage),
+        }
+    }
+}
+
+/// Checks the stages of the filters submitted by the users before they are saved:
+/// only the stages and operators of the allowlists, with `$lookup`s into the
+/// approved collections.
+#[derive(Debug, Clone)]
+pub struct FilterValidator {
+    lookup_collections: HashSet<String>,
+}
+
+impl FilterValidator {
+    /// A validator for the filters of a catalog (e.g. ZTF_alerts), which
+    /// may only look up the catalog and its aux collection.
+    pub fn new(catalog: &str) -> Self {
+        FilterValidator {
+            lookup_collections: HashSet::from([catalog.to_string(), format!("{}_aux", catalog)]),
+        }
+    }
+
+    /// A validator for the filters of a catalog, which may also look up the
+    /// catalogs the alerts of its survey are crossmatched with
+    /// (the `crossmatch.<survey>` section of the config).
+    pub fn from_config(conf: &Config, catalog: &str) -> Result<Self, BoomConfigError> {
+        let survey = catalog.trim_end_matches("_alerts");
+        let validator = build_xmatch_configs(conf, survey)?
+            .iter()
+            .fold(FilterValidator::new(catalog), |validator, xmatch_config| {
+                validator.allow_lookup(&xmatch_config.catalog)
+            });
+        Ok(validator)
+    }
+
+    /// Approve another collection as a `$lookup` target.
+    pub fn allow_lookup(mut self, collection: &str) -> Self {
+        self.lookup_collections.insert(collection.to_string());
+        self
+    }
+
+    pub fn validate(&self, pipeline: &[Document]) -> Result<(), FilterValidationError> {
+        if pipeline.is_empty() {
+            return Err(FilterValidationError::EmptyPipeline);
+        }
+        let mut n_unwinds = 0;
+        for (index, stage) in pipeline.iter().enumerate() {
+            self.validate_stage(index, stage, &mut n_unwinds)?;
+        }
+        Ok(())
+    }
+
+    // errors in the sub-pipelines of a $lookup point at the $lookup stage
+    fn validate_stage(
+        &self,
+        index: usize,
+        stage: &Document,
+        n_unwinds: &mut usize,
+    ) -> Result<(), FilterValidationError> {
+        let (name, spec) = match stage.iter().next() {
+            Some((name, spec)) if stage.len() == 1 => (name.as_str(), spec),
+            _ => return Err(FilterValidationError::InvalidStage { stage: index }),
+        };
+        if !ALLOWED_STAGES.contains(&name) {
+            return Err(FilterValidationError::StageNotAllowed {
+                stage: index,
+                name: name.to_string(),
+            });
+        }
+
+        match name {
+            "$unwind" => {
+                *n_unwinds += 1;
+                if *n_unwinds > MAX_UNWIND_STAGES {
+                    return Err(FilterValidationError::TooManyUnwinds {
+                        stage: index,
+                        max: MAX_UNWIND_STAGES,
+                    });
+                }
+            }
+            "$lookup" => {
+                let spec = spec
+                    .as_document()
+                    .ok_or(FilterValidationError::InvalidStage { stage: index })?;
+                let collection = spec.get_str("from").unwrap_or_default();
+                if !self.lookup_collections.contains(collection) {
+                    return Err(FilterValidationError::LookupNotAllowed {
+                        stage: index,
+                        collection: collection.to_string(),
+                    });
+                }
+                if let Some(sub_pipeline) = spec.get("pipeline") {
+                    let sub_pipeline = sub_pipeline
+                        .as_array()
+                        .ok_or(FilterValidationError::InvalidStage { stage: index })?;
+                    for sub_stage in sub_pipeline {
+                        let sub_stage = sub_stage
+                            .as_document()
+                            .ok_or(FilterValidationError::InvalidStage { stage: index })?;
+                        self.validate_stage(index, sub_stage, n_unwinds)?;
+                    }
+                }
+                if let Some(variables) = spec.get("let") {
+                    validate_operators(index, variables)?;
+                }
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        validate_operators(index, spec)
+    }
+}
+
+// check all the operators (the keys starting with $) of a stage
+fn validate_operators(index: usize, value: &Bson) -> Result<(), FilterValidationError> {
+    match value {
+        Bson::Document(document) => {
+            for (key, value) in document {
+                if key.starts_with('$') && !ALLOWED_OPERATORS.contains(&key.as_str()) {
+                    return Err(FilterValidationError::OperatorNotAllowed {
+                        stage: index,
+                        operator: key.clone(),
+                    });
+                }
+                validate_operators(index, value)?;
+            }
+            Ok(())
+        }
+        Bson::Array(values) => values
+            .iter()
+            .try_for_each(|value| validate_operators(index, value)),
+        // server-side javascript
+        Bson::JavaScriptCode(_) | Bson::JavaScriptCodeWithScope(_) => {
+            Err(FilterValidationError::OperatorNotAllowed {
+                stage: index,
+                operator: "javascript".to_string(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Validate the stages of a filter of a catalog, with the default validator
+/// (no `$lookup`s into the crossmatch catalogs).
+pub fn validate_filter_pipeline(
+    catalog: &str,
+    pipeline: &[Document],
+) -> Result<(), FilterValidationError> {
+    FilterValidator::new(catalog).validate(pipeline)
+}
@@ -5,15 +5,120 @@ use std::collections::HashMap;
 use tracing::{info, warn};
 
 use crate::filter::{
-    get_filter_object, parse_programid_candid_tuple, run_filter, Alert, Filter, FilterError,
-    FilterResults, FilterWorker, FilterWorkerError, Origin, Photometry, Survey,
+    autosaved_filters, filter_versions, get_classifications, get_cross_matches, get_filter_object,
+    load_filters, notification_policies, parse_programid_candid_tuple, Alert, AlertBuilder, Filter,
+    FilterError, FilterResults, FilterResultsWriter, FilterRunner, FilterWorker, FilterWorkerError,
+    NotificationGate, NotificationPolicy, Origin, Photometry, Survey, ALERT_SCHEMA_VERSION,
+    PHOTOMETRY_ZP,
 };
 
+// procstatus values of the forced photometry measurements we keep:
+// 0 (success) and 57 (no reference catalog source within 5 arcsec)
+const FP_PROCSTATUS_OK: [&str; 2] = ["0", "57"];
+
+/// Convert a ZTF forced photometry measurement (in DN, at the zero point
+/// of the science image) to a flux and error at `PHOTOMETRY_ZP`.
+/// Returns None for measurements that don't pass the procstatus cut
+/// or are missing their flux, error or zero point.
+fn fp_hist_to_flux(fp_hist: &Document) -> Option<(f64, f64)> {
+    let procstatus = fp_hist.get_str("procstatus").ok()?;
+    if !FP_PROCSTATUS_OK.contains(&procstatus) {
+        return None;
+    }
+    let flux = fp_hist.get_f64("forcediffimflux").ok()?;
+    let flux_err = fp_hist.get_f64("forcediffimfluxunc").ok()?;
+    let magzpsci = fp_hist.get_f64("magzpsci").ok()?;
+    if !flux.is_finite() || !flux_err.is_finite() || flux_err <= 0.0 {
+        return None;
+    }
+    let scale = 10.0_f64.powf(-0.4 * (magzpsci - PHOTOMETRY_ZP));
+    Some((flux * scale, flux_err * scale))
+}
+
 #[derive(Debug)]
 pub struct ZtfFilter {
     pub id: i32,
+    pub fid: String,
     pub pipeline: Vec<Document>,
     pub permissions: Vec<i32>,
+    pub group_id: Option<i32>,
+    pub autosave: bool,
+    pub update_annotations: bool,
+    pub notification_policy: NotificationPolicy,
+}
+
+/// The stages run before those of every ZTF filter: the alerts (candids) to filter,
+/// joined with their aux entry, with the past photometry restricted to the
+/// programids of the filter `permissions`.
+pub fn build_ztf_filter_prefix(permissions: &[i32]) -> Vec<Document> {
+    vec![
+        doc! {
+            "$match": doc! {
+                // during filter::run proper candis are inserted here
+            }
+        },
+        doc! {
+            "$lookup": doc! {
+                "from": format!("ZTF_alerts_aux"),
+                "localField": "objectId",
+                "foreignField": "_id",
+                "as": "aux"
+            }
+        },
+        doc! {
+            "$project": doc! {
+                "objectId": 1,
+                "candidate": 1,
+                "classifications": 1,
+                "coordinates": 1,
+                "cross_matches": doc! {
+                    "$arrayElemAt": [
+                        "$aux.cross_matches",
+                        0
+                    ]
+                },
+                "prv_candidates": doc! {
+                    "$filter": doc! {
+                        "input": doc! {
+                            "$arrayElemAt": [
+                                "$aux.prv_candidates",
+                                0
+                            ]
+                        },
+                        "as": "x",
+                        "cond": doc! {
+                            "$and": [
+                                {
+                                    "$in": [
+                                        "$$x.programid",
+                                        permissions
+                                    ]
+                                },
+                                { // maximum 1 year of past data
+                                    "$lt": [
+                                        {
+                                            "$subtract": [
+                                                "$candidate.jd",
+                                                "$$x.jd"
+                                            ]
+                                        },
+                                        365
+                                    ]
+                                },
+                                { // only datapoints up to (and including) current alert
+                                    "$lte": [
+                                        "$$x.jd",
+                                        "$candidate.jd"
+                                    ]
+                                }
+
+                            ]
+                        }
+                    }
+                },
+            }
+        },
+    ]
 }
 
 #[async_trait::async_trait]
@@ -46,74 +151,7 @@ impl Filter for ZtfFilter {
         }
 
         // filter prefix (with permissions)
-        let mut pipeline = vec![
-            doc! {
-                "$match": doc! {
-                    // during filter::run proper candis are inserted here
-                }
-            },
-            doc! {
-                "$lookup": doc! {
-                    "from": format!("ZTF_alerts_aux"),
-                    "localField": "objectId",
-                    "foreignField": "_id",
-                    "as": "aux"
-                }
-            },
-            doc! {
-                "$project": doc! {
-                    "objectId": 1,
-                    "candidate": 1,
-                    "classifications": 1,
-                    "coordinates": 1,
-                    "cross_matches": doc! {
-                        "$arrayElemAt": [
-                            "$aux.cross_matches",
-                            0
-                        ]
-                    },
-                    "prv_candidates": doc! {
-                        "$filter": doc! {
-                            "input": doc! {
-                                "$arrayElemAt": [
-                                    "$aux.prv_candidates",
-                                    0
-                                ]
-                            },
-                            "as": "x",
-                            "cond": doc! {
-                                "$and": [
-                                    {
-                                        "$in": [
-                                            "$$x.programid",
-                                            &permissions
-                                        ]
-                                    },
-                                    { // maximum 1 year of past data
-                                        "$lt": [
-                                            {
-                                                "$subtract": [
-                                                    "$candidate.jd",
-                                                    "$$x.jd"
-                                                ]
-                                            },
-                                            365
-                                        ]
-                                    },
-                                    { // only datapoints up to (and including) current alert
-                                        "$lte": [
-                                            "$$x.jd",
-                                            "$candidate.jd"
-                                        ]
-                                    }
-
-                                ]
-                            }
-                        }
-                    },
-                }
-            },
-        ];
+        let mut pipeline = build_ztf_filter_prefix(&permissions);
 
         // get filter pipeline as str and convert to Vec<Bson>
         let filter_pipeline = filter_obj
@@ -135,82 +173,73 @@ impl Filter for ZtfFilter {
 
         let filter = ZtfFilter {
             id: filter_id,
+            fid: filter_obj.get_str("fid").unwrap_or_default().to_string(),
             pipeline: pipeline,
             permissions: permissions,
+            group_id: filter_obj.get_i32("group_id").ok(),
+            autosave: filter_obj.get_bool("autosave").unwrap_or(false),
+            update_annotations: filter_obj.get_bool("update_annotations").unwrap_or(false),
+            notification_policy: NotificationPolicy::from_filter_object(&filter_obj)?,
         };
 
         Ok(filter)
     }
+
+    fn id(&self) -> i32 {
+        self.id
+    }
+
+    fn fid(&self) -> &str {
+        &self.fid
+    }
+
+    fn autosave(&self) -> bool {
+        self.autosave
+    }
+
+    fn update_annotations(&self) -> bool {
+        self.update_annotations
+    }
+
+    fn notification_policy(&self) -> &NotificationPolicy {
+        &self.notification_policy
+    }
 }
 
 pub struct ZtfFilterWorker {
     alert_collection: mongodb::Collection<mongodb::bson::Document>,
+    filter_collection: mongodb::Collection<mongodb::bson::Document>,
+    filter_runner: FilterRunner,
+    results_writer: FilterResultsWriter,
+    notification_gate: NotificationGate,
     input_queue: String,
     output_topic: String,
     filters: Vec<ZtfFilter>,
     filters_by_permission: HashMap<i32, Vec<usize>>,
 }
 
-#[async_trait::async_trait]
-impl FilterWorker for ZtfFilterWorker {
-    async fn new(config_path: &str) -> Result<Self, FilterWorkerError> {
-        let config_file = crate::conf::load_config(&config_path)?;
-        let db: mongodb::Database = crate::conf::build_db(&config_file).await?;
-        let alert_collection = db.collection("ZTF_alerts");
-        let filter_collection = db.collection("filters");
-
-        let input_queue = "ZTF_alerts_filter_queue".to_string();
-        let output_topic = "ZTF_alerts_results".to_string();
-
-        let filter_ids: Vec<i32> = filter_collection
-            .distinct("filter_id", doc! {"active": true, "catalog": "ZTF_alerts"})
-            .await?
-            .into_iter()
-            .map(|x| x.as_i32().ok_or(FilterError::InvalidFilterId))
-            .filter_map(Result::ok)
-            .collect();
-
-        let mut filters: Vec<ZtfFilter> = Vec::new();
-        for filter_id in filter_ids {
-            filters.push(ZtfFilter::build(filter_id, &filter_collection).await?);
-        }
-
-        // create a hashmap of filters per programid (permissions)
-        // basically we'll have the 4 programid (from 0 to 3) as keys
-        // and the idx of the filters that have that programid in their permissions as values
-        let mut filters_by_permission: HashMap<i32, Vec<usize>> = HashMap::new();
-        for (i, filter) in filters.iter().enumerate() {
-            for permission in &filter.permissions {
-                let entry = filters_by_permission
-                    .entry(*permission)
-                    .or_insert(Vec::new());
-                entry.push(i);
-            }
+// index the filters by programid (permissions): basically we'll have the 4 programid
+// (from 0 to 3) as keys, and the idx of the filters that have that programid
+// in their permissions as values
+fn filters_by_permission(filters: &[ZtfFilter]) -> HashMap<i32, Vec<usize>> {
+    let mut filters_by_permission: HashMap<i32, Vec<usize>> = HashMap::new();
+    for (i, filter) in filters.iter().enumerate() {
+        for permission in &filter.permissions {
+            let entry = filters_by_permission
+                .entry(*permission)
+                .or_insert(Vec::new());
+            entry.push(i);
         }
-
-        Ok(ZtfFilterWorker {
-            alert_collection,
-            input_queue,
-            output_topic,
-            filters,
-            filters_by_permission,
-        })
-    }
-
-    fn input_queue_name(&self) -> String {
-        self.input_queue.clone()
-    }
-
-    fn output_topic_name(&self) -> String {
-        self.output_topic.clone()
     }
+    filters_by_permission
+}
 
-    fn has_filters(&self) -> bool {
-        !self.filters.is_empty()
-    }
+#[async_trait::async_trait]
+impl AlertBuilder for ZtfFilterWorker {
+    const SURVEY: &'static str = "ZTF";
 
-    async fn build_alert(
-        &self,
+    async fn build_output_alert(
+        alert_collection: &mongodb::Collection<Document>,
         candid: i64,
         filter_results: Vec<FilterResults>,
     ) -> Result<Alert, FilterWorkerError> {
@@ -226,6 +255,8 @@ impl FilterWorker for ZtfFilterWorker {
                     "jd": "$candidate.jd",
                     "ra": "$candidate.ra",
                     "dec": "$candidate.dec",
+                    "classifications": 1,
+                    "model_versions": 1,
                     "cutoutScience": 1,
                     "cutoutTemplate": 1,
                     "cutoutDifference": 1
@@ -253,6 +284,14 @@ impl FilterWorker for ZtfFilterWorker {
                     "jd": 1,
                     "ra": 1,
                     "dec": 1,
+                    "classifications": 1,
+                    "model_versions": 1,
+                    "cross_matches": {
+                        "$arrayElemAt": [
+                            "$aux.cross_matches",
+                            0
+                        ]
+                    },
                     "prv_candidates": {
                         "$arrayElemAt": [
                             "$aux.prv_candidates",
@@ -265,6 +304,12 @@ impl FilterWorker for ZtfFilterWorker {
                             0
                         ]
                     },
+                    "fp_hists": {
+                        "$arrayElemAt": [
+                            "$aux.fp_hists",
+                            0
+                        ]
+                    },
                     "cutoutScience": {
                         "$arrayElemAt": [
                             "$cutouts.cutoutScience",
@@ -288,7 +333,7 @@ impl FilterWorker for ZtfFilterWorker {
         ];
 
         // Execute the aggregation pipeline
-        let mut cursor = self.alert_collection.aggregate(pipeline).await?;
+        let mut cursor = alert_collection.aggregate(pipeline).await?;
 
         let alert_document = cursor
             .next()
@@ -307,7 +352,7 @@ impl FilterWorker for ZtfFilterWorker {
             .get_binary_generic("cutoutDifference")?
             .to_vec();
 
-        // let's create the array of photometry (non forced phot only for now)
+        // let's create the array of photometry
         let mut photometry = Vec::new();
         for doc in alert_document.get_array("prv_candidates")?.iter() {
             let doc = match doc.as_document() {
@@ -320,7 +365,7 @@ impl FilterWorker for ZtfFilterWorker {
             let isdiffpos = doc.get_bool("isdiffpos")?;
             let band = doc.get_str("band")?.to_string();
             let programid = doc.get_i32("programid")?;
-            let zero_point = 23.9;
+            let zero_point = PHOTOMETRY_ZP;
             let ra = doc.get_f64("ra").ok(); // optional, might not be present
             let dec = doc.get_f64("dec").ok(); // optional, might not be present
 
@@ -352,7 +397,7 @@ impl FilterWorker for ZtfFilterWorker {
             let mag_limit = doc.get_f64("diffmaglim")?;
             let band = doc.get_str("band")?.to_string();
             let programid = doc.get_i32("programid")?;
-            let zero_point = 23.9;
+            let zero_point = PHOTOMETRY_ZP;
 
             let flux_err = limmag_to_fluxerr(mag_limit, zero_point, 5.0);
 
@@ -370,9 +415,42 @@ impl FilterWorker for ZtfFilterWorker {
             });
         }
 
-        // we ignore the forced photometry for now, but will add it later
+        // and finally the forced photometry, which may be missing from older aux entries
+        let fp_hists = alert_document
+            .get_array("fp_hists")
+            .map_or(&[][..], |a| a.as_slice());
+        for doc in fp_hists.iter() {
+            let doc = match doc.as_document() {
+                Some(doc) => doc,
+                None => continue, // skip if not a document
+            };
+            let (flux, flux_err) = match fp_hist_to_flux(doc) {
+                Some(flux) => flux,
+                None => continue, // skip measurements that don't pass the quality cuts
+            };
+            let jd = doc.get_f64("jd")?;
+            let band = doc.get_str("band")?.to_string();
+            let programid = doc.get_i32("programid")?;
+
+            photometry.push(Photometry {
+                jd,
+                flux: Some(flux),
+                flux_err,
+                band: format!("ztf{}", band),
+                zero_point: PHOTOMETRY_ZP,
+                origin: Origin::ForcedPhot,
+                programid,
+                survey: Survey::ZTF,
+                ra: None,
+                dec: None,
+            });
+        }
+
+        let classifications = get_classifications(&alert_document);
+        let cross_matches = get_cross_matches(&alert_document, ra, dec);
 
         let alert = Alert {
+            schemavsn: ALERT_SCHEMA_VERSION.to_string(),
             candid,
             object_id,
             jd,
@@ -380,6 +458,8 @@ impl FilterWorker for ZtfFilterWorker {
             dec,
             filters: filter_results, // assuming you have filter results to attach
             photometry,
+            classifications,
+            cross_matches,
             cutout_science,
             cutout_template,
             cutout_difference,
@@ -387,6 +467,94 @@ impl FilterWorker for ZtfFilterWorker {
 
         Ok(alert)
     }
+}
+
+#[async_trait::async_trait]
+impl FilterWorker for ZtfFilterWorker {
+    async fn new(config_path: &str) -> Result<Self, FilterWorkerError> {
+        let config_file = crate::conf::load_config(&config_path)?;
+        let db: mongodb::Database = crate::conf::build_db(&config_file).await?;
+        let alert_collection = db.collection("ZTF_alerts");
+        let filter_collection = db.collection("filters");
+
+        let input_queue = "ZTF_alerts_filter_queue".to_string();
+        let output_topic = "ZTF_alerts_results".to_string();
+
+        let mut filter_runner = FilterRunner::from_config(&config_file, "ZTF", &db);
+        let mut results_writer = FilterResultsWriter::new(&db, "ZTF_alerts");
+        results_writer.create_indexes().await?;
+        let mut notification_gate = NotificationGate::new(&db);
+        notification_gate.create_indexes().await?;
+
+        let filters: Vec<ZtfFilter> = load_filters("ZTF_alerts", &filter_collection).await?;
+        let filters_by_permission = filters_by_permission(&filters);
+        filter_runner.set_versions(filter_versions(&filters));
+        results_writer.set_filters(autosaved_filters(&filters));
+        notification_gate.set_policies(notification_policies(&filters));
+
+        Ok(ZtfFilterWorker {
+            alert_collection,
+            filter_collection,
+            filter_runner,
+            results_writer,
+            notification_gate,
+            input_queue,
+            output_topic,
+            filters,
+            filters_by_permission,
+        })
+    }
+
+    async fn reload_filters(&mut self) -> Result<(), FilterWorkerError> {
+        let filters: Vec<ZtfFilter> = load_filters("ZTF_alerts", &self.filter_collection).await?;
+        self.filters_by_permission = filters_by_permission(&filters);
+        self.filter_runner.set_versions(filter_versions(&filters));
+        self.results_writer.set_filters(autosaved_filters(&filters));
+        self.notification_gate
+            .set_policies(notification_policies(&filters));
+        self.filters = filters;
+        Ok(())
+    }
+
+    async fn flush_stats(&mut self) -> Result<(), FilterWorkerError> {
+        self.filter_runner.flush_stats().await?;
+        Ok(())
+    }
+
+    async fn release_notifications(&self, alert: &Alert) -> Result<(), FilterWorkerError> {
+        self.notification_gate.release(alert).await
+    }
+
+    fn input_queue_name(&self) -> String {
+        self.input_queue.clone()
+    }
+
+    fn output_topic_name(&self) -> String {
+        self.output_topic.clone()
+    }
+
+    fn survey_name(&self) -> String {
+        "ZTF".to_string()
+    }
+
+    fn filter_group_ids(&self) -> HashMap<i32, i32> {
+        self.filters
+            .iter()
+            .filter_map(|filter| Some((filter.id, filter.group_id?)))
+            .collect()
+    }
+
+    fn has_filters(&self) -> bool {
+        !self.filters.is_empty()
+    }
+
+    async fn build_alert(
+        &self,
+        candid: i64,
+        filter_results: Vec<FilterResults>,
+    ) -> Result<Alert, FilterWorkerError> {
+        Self::build_output_alert(&self.alert_collection, candid, filter_results).await
+    }
 
     async fn process_alerts(&mut self, alerts: &[String]) -> Result<Vec<Alert>, FilterWorkerError> {
         let mut alerts_output = Vec::new();
@@ -407,19 +575,23 @@ impl FilterWorker for ZtfFilterWorker {
         for (programid, candids) in alerts_by_programid {
             let mut results_map: HashMap<i64, Vec<FilterResults>> = HashMap::new();
 
-            let filter_indices = self
-                .filters_by_permission
-                .get(&programid)
-                .ok_or(FilterWorkerError::GetFilterByQueueError)?;
+            // without filters for this programid, there is nothing to run
+            let Some(filter_indices) = self.filters_by_permission.get(&programid) else {
+                continue;
+            };
+
+            // the filters with the same permissions share their prefix (and its
+            // $lookup of the aux collection), run once for all of them
+            let pipelines = filter_indices
+                .iter()
+                .map(|i| (self.filters[*i].id, self.filters[*i].pipeline.as_slice()));
+            let mut out_documents_by_filter = self.filter_runner.run(&candids, pipelines).await;
 
             for i in filter_indices {
                 let filter = &self.filters[*i];
-                let out_documents = run_filter(
-                    candids.clone(),
-                    filter.pipeline.clone(),
-                    &self.alert_collection,
-                )
-                .await?;
+                let out_documents = out_documents_by_filter
+                    .remove(&filter.id)
+                    .unwrap_or_default();
 
                 // if the array is empty, continue
                 if out_documents.is_empty() {
@@ -438,7 +610,10 @@ impl FilterWorker for ZtfFilterWorker {
                 let now_ts = chrono::Utc::now().timestamp_millis() as f64;
 
                 for doc in out_documents {
-                    let candid = doc.get_i64("_id")?;
+                    // the filter runner only returns the output of the filters that keep the candid
+                    let Ok(candid) = doc.get_i64("_id") else {
+                        continue;
+                    };
                     // might want to have the annotations as an optional field instead of empty
                     let annotations =
                         serde_json::to_string(doc.get_document("annotations").unwrap_or(&doc! {}))?;
@@ -454,7 +629,34 @@ impl FilterWorker for ZtfFilterWorker {
 
             // now we've basically combined the filter results for each candid
             for (candid, filter_results) in &results_map {
-                let alert = self.build_alert(*candid, filter_results.clone()).await?;
+                let mut alert = match self.build_alert(*candid, filter_results.clone()).await {
+                    Ok(alert) => alert,
+                    Err(e) => {
+                        warn!("could not build the output alert of {}: {}", candid, e);
+                        continue;
+                    }
+                };
+                // failing to save the passes shouldn't keep the alert from being sent
+                if let Err(e) = self.results_writer.save(&alert).await {
+                    warn!("could not save the filter results of {}: {}", candid, e);
+                }
+                // the passes suppressed by the notification policies are still in the stats
+                let suppressed = match self.notification_gate.apply(&mut alert).await {
+                    Ok(suppressed) => suppressed,
+                    Err(e) => {
+                        warn!(
+                            "could not apply the notification policies to {}: {}",
+                            candid, e
+                        );
+                        continue;
+                    }
+                };
+                for filter_id in suppressed {
+                    self.filter_runner.record_suppressed(filter_id);
+                }
+                if alert.filters.is_empty() {
+                    continue;
+                }
                 alerts_output.push(alert);
             }
         }
@@ -0,0 +1,197 @@
+use crate::{
+    conf,
+    kafka::base::{consume_partitions, AlertConsumer},
+};
+use redis::AsyncCommands;
+use serde::Deserialize;
+use tracing::{error, info};
+
+fn default_group_id() -> String {
+    "boom".to_string()
+}
+
+fn default_n_partitions() -> usize {
+    1
+}
+
+/// The `streams.<name>.kafka` section of the config.
+///
+/// The topic may contain a `{date}` placeholder, replaced by the date
+/// being consumed (YYYYMMDD) for surveys with nightly topics. Credentials
+/// are read from the environment variables named by `username_env` and `password_env`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct StreamKafkaConfig {
+    pub server: String,
+    pub topic: String,
+    #[serde(default = "default_group_id")]
+    pub group_id: String,
+    #[serde(default = "default_n_partitions")]
+    pub n_partitions: usize,
+    pub username_env: Option<String>,
+    pub password_env: Option<String>,
+}
+
+pub struct GenericAlertConsumer {
+    stream_name: String,
+    topic: String,
+    output_queue: String,
+    n_threads: usize,
+    n_partitions: usize,
+    max_in_queue: usize,
+    group_id: String,
+    username: Option<String>,
+    password: Option<String>,
+    server: String,
+    config_path: String,
+}
+
+impl GenericAlertConsumer {
+    /// Create a consumer for a stream described in the `streams` section of the config.
+    pub fn from_config(
+        stream_name: &str,
+        n_threads: usize,
+        max_in_queue: Option<usize>,
+        config_path: &str,
+    ) -> Result<Self, conf::BoomConfigError> {
+        let config = conf::load_config(config_path)?;
+        let kafka_config =
+            config.get::<StreamKafkaConfig>(&format!("streams.{}.kafka", stream_name))?;
+
+        if kafka_config.n_partitions % n_threads != 0 {
+            panic!(
+                "Number of threads should be a factor of {}",
+                kafka_config.n_partitions
+            );
+        }
+        let max_in_queue = max_in_queue.unwrap_or(15000);
+        let output_queue = format!("{}_alerts_packets_queue", stream_name);
+
+        let username = kafka_config.username_env.as_ref().map(|var| {
+            std::env::var(var).unwrap_or_else(|_| panic!("{} environment variable not set", var))
+        });
+        let password = kafka_config.password_env.as_ref().map(|var| {
+            std::env::var(var).unwrap_or_else(|_| panic!("{} environment variable not set", var))
+        });
+
+        info!(
+            "Creating AlertConsumer for {} with {} threads, topic: {}, output_queue: {}, group_id: {}, server: {}",
+            stream_name, n_threads, kafka_config.topic, output_queue, kafka_config.group_id, kafka_config.server
+        );
+
+        Ok(GenericAlertConsumer {
+            stream_name: stream_name.to_string(),
+            topic: kafka_config.topic,
+            output_queue,
+            n_threads,
+            n_partitions: kafka_config.n_partitions,
+            max_in_queue,
+            group_id: kafka_config.group_id,
+            username,
+            password,
+            server: kafka_config.server,
+            config_path: config_path.to_string(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl AlertConsumer for GenericAlertConsumer {
+    /// Without a stream name, use the only stream of the `streams` section.
+    fn new(
+        n_threads: usize,
+        max_in_queue: Option<usize>,
+        topic: Option<&str>,
+        output_queue: Option<&str>,
+        group_id: Option<&str>,
+        server_url: Option<&str>,
+        config_path: &str,
+    ) -> Self {
+        let config = conf::load_config(config_path).expect("could not load config");
+        let stream_names = conf::get_stream_names(&config);
+        let [stream_name] = stream_names.as_slice() else {
+            panic!(
+                "Expected exactly one stream in config, found {:?}",
+                stream_names
+            );
+        };
+        let mut consumer =
+            GenericAlertConsumer::from_config(stream_name, n_threads, max_in_queue, config_path)
+                .expect("invalid stream config");
+        if let Some(topic) = topic {
+            consumer.topic = topic.to_string();
+        }
+        if let Some(output_queue) = output_queue {
+            consumer.output_queue = output_queue.to_string();
+        }
+        if let Some(group_id) = group_id {
+            consumer.group_id = group_id.to_string();
+        }
+        if let Some(server_url) = server_url {
+            consumer.server = server_url.to_string();
+        }
+        consumer
+    }
+
+    async fn consume(&self, timestamp: i64) -> Result<(), Box<dyn std::error::Error>> {
+        let partitions_per_thread = self.n_partitions / self.n_threads;
+        let mut partitions = vec![vec![]; self.n_threads];
+        for i in 0..self.n_partitions {
+            partitions[i / partitions_per_thread].push(i as i32);
+        }
+
+        let date = chrono::DateTime::from_timestamp(timestamp, 0).unwrap();
+        let topic = self
+            .topic
+            .replace("{date}", &date.format("%Y%m%d").to_string());
+
+        let mut handles = vec![];
+        for i in 0..self.n_threads {
+            let topic = topic.clone();
+            let partitions = partitions[i].clone();
+            let max_in_queue = self.max_in_queue;
+            let output_queue = self.output_queue.clone();
+            let group_id = self.group_id.clone();
+            let username = self.username.clone();
+            let password = self.password.clone();
+            let server = self.server.clone();
+            let config_path = self.config_path.clone();
+            let handle = tokio::spawn(async move {
+                let result = consume_partitions(
+                    &i.to_string(),
+                    &topic,
+                    &group_id,
+                    partitions,
+                    &output_queue,
+                    max_in_queue,
+                    timestamp,
+                    &server,
+                    username.as_deref(),
+                    password.as_deref(),
+                    &config_path,
+                )
+                .await;
+                if let Err(e) = result {
+                    error!("Error consuming partitions: {:?}", e);
+                }
+            });
+            handles.push(handle);
+        }
+
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        Ok(())
+    }
+
+    async fn clear_output_queue(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let config = conf::load_config(&self.config_path)?;
+        let mut con = conf::build_redis(&config).await?;
+        let _: () = con.del(&self.output_queue).await.unwrap();
+        info!(
+            "Cleared redis queued for {} Kafka consumer",
+            self.stream_name
+        );
+        Ok(())
+    }
+}
@@ -1,7 +1,9 @@
 mod base;
+mod generic;
 mod lsst;
 mod ztf;
 
 pub use base::AlertConsumer;
+pub use generic::{GenericAlertConsumer, StreamKafkaConfig};
 pub use lsst::LsstAlertConsumer;
 pub use ztf::{download_alerts_from_archive, produce_from_archive, ZtfAlertConsumer};
@@ -2,9 +2,8 @@ use crate::{
     conf,
     kafka::base::{consume_partitions, AlertConsumer},
 };
-use rdkafka::config::ClientConfig;
 use redis::AsyncCommands;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
 
@@ -179,7 +178,21 @@ pub async fn produce_from_archive(
     date: &str,
     limit: i64,
     topic: Option<String>,
+    config_path: &str,
 ) -> Result<i64, Box<dyn std::error::Error>> {
+    // without a config file, produce to a local broker as before
+    let producer_config = match conf::load_config(config_path) {
+        Ok(config) => conf::KafkaProducerConfig::from_config(&config)?,
+        Err(conf::BoomConfigError::ConfigFileNotFound) => {
+            warn!(
+                "config file {} not found, using the default kafka producer settings",
+                config_path
+            );
+            conf::KafkaProducerConfig::default()
+        }
+        Err(e) => return Err(e.into()),
+    };
+
     match download_alerts_from_archive(&date) {
         Ok(count) => count,
         Err(e) => {
@@ -194,15 +207,20 @@ pub async fn produce_from_archive(
     };
 
     info!("Initializing ZTF alert kafka producer");
-    let producer: FutureProducer = ClientConfig::new()
-        .set("bootstrap.servers", "localhost:9092")
-        .set("message.timeout.ms", "5000")
+    let mut client_config = producer_config.client_config();
+    // linger and acks are tuned for a local cluster, unless set in the config
+    // (idempotence needs acks=all, so acks are left alone when it is enabled)
+    if producer_config.linger_ms.is_none() {
+        client_config.set("linger.ms", "5");
+    }
+    if producer_config.acks.is_none() && producer_config.enable_idempotence != Some(true) {
+        client_config.set("acks", "1");
+    }
+    let producer: FutureProducer = client_config
         // it's best to increase batch.size if the cluster
         // is running on another machine. Locally, lower means less
         // latency, since we are not limited by network speed anyways
         .set("batch.size", "16384")
-        .set("linger.ms", "5")
-        .set("acks", "1")
         .set("max.in.flight.requests.per.connection", "5")
         .set("retries", "3")
         .create()
@@ -5,6 +5,10 @@ use mongodb::bson::{doc, Document};
 use mongodb::options::{UpdateOneModel, WriteModel};
 use tracing::warn;
 
+// versions of the models, saved with their scores in `model_versions`
+const ACAI_VERSION: &str = "d1_dnn_20201130";
+const BTSBOT_VERSION: &str = "v1.0.1";
+
 pub struct ZtfMLWorker {
     input_queue: String,
     output_queue: String,
@@ -230,7 +234,15 @@ impl MLWorker for ZtfMLWorker {
                     "classifications.acai_v": acai_v_scores[0],
                     "classifications.acai_o": acai_o_scores[0],
                     "classifications.acai_b": acai_b_scores[0],
-                    "classifications.btsbot": btsbot_scores[0]
+                    "classifications.btsbot": btsbot_scores[0],
+                    "model_versions": {
+                        "acai_h": ACAI_VERSION,
+                        "acai_n": ACAI_VERSION,
+                        "acai_v": ACAI_VERSION,
+                        "acai_o": ACAI_VERSION,
+                        "acai_b": ACAI_VERSION,
+                        "btsbot": BTSBOT_VERSION
+                    }
                 }
             };
 
@@ -1,6 +1,6 @@
 use crate::{
-    alert::{run_alert_worker, LsstAlertWorker, ZtfAlertWorker},
-    filter::{run_filter_worker, LsstFilterWorker, ZtfFilterWorker},
+    alert::{run_alert_worker, run_generic_alert_worker, LsstAlertWorker, ZtfAlertWorker},
+    filter::{run_filter_worker, run_scheduled_filter_worker, LsstFilterWorker, ZtfFilterWorker},
     ml::{run_ml_worker, ZtfMLWorker},
     utils::worker::{WorkerCmd, WorkerType},
 };
@@ -169,15 +169,13 @@ impl Worker {
         let thread = match worker_type {
             // TODO: Spawn a new worker thread when one dies? (A supervisor or something like that?)
             WorkerType::Alert => thread::spawn(move || {
-                let run = match stream_name.as_str() {
-                    "ZTF" => run_alert_worker::<ZtfAlertWorker>,
-                    "LSST" => run_alert_worker::<LsstAlertWorker>,
-                    _ => {
-                        error!("Unknown stream name: {}", stream_name);
-                        return;
-                    }
+                let result = match stream_name.as_str() {
+                    "ZTF" => run_alert_worker::<ZtfAlertWorker>(id, receiver, &config_path),
+                    "LSST" => run_alert_worker::<LsstAlertWorker>(id, receiver, &config_path),
+                    // other streams are described by a mapping in the config
+                    _ => run_generic_alert_worker(id, receiver, &config_path, &stream_name),
                 };
-                if let Err(error) = run(id, receiver, &config_path) {
+                if let Err(error) = result {
                     error!(error = %error, "failed to run alert worker");
                 }
             }),
@@ -185,8 +183,9 @@ impl Worker {
                 let run = match stream_name.as_str() {
                     "ZTF" => run_filter_worker::<ZtfFilterWorker>,
                     "LSST" => run_filter_worker::<LsstFilterWorker>,
+                    // we don't have a filter worker for the generic streams yet
                     _ => {
-                        error!("Unknown stream name: {}", stream_name);
+                        error!("No filter worker for stream: {}", stream_name);
                         return;
                     }
                 };
@@ -194,6 +193,19 @@ impl Worker {
                     error!(error = %error, "failed to run filter worker");
                 }
             }),
+            WorkerType::ScheduledFilter => thread::spawn(move || {
+                let run = match stream_name.as_str() {
+                    "ZTF" => run_scheduled_filter_worker::<ZtfFilterWorker>,
+                    "LSST" => run_scheduled_filter_worker::<LsstFilterWorker>,
+                    _ => {
+                        error!("No scheduled filter worker for stream: {}", stream_name);
+                        return;
+                    }
+                };
+                if let Err(error) = run(id, receiver, &config_path) {
+                    error!(error = %error, "failed to run scheduled filter worker");
+                }
+            }),
             WorkerType::ML => thread::spawn(move || {
                 let run = match stream_name.as_str() {
                     "ZTF" => run_ml_worker::<ZtfMLWorker>,
@@ -203,7 +215,7 @@ impl Worker {
                         return;
                     }
                     _ => {
-                        error!("Unknown stream name: {}", stream_name);
+                        error!("No ML worker for stream: {}", stream_name);
                         return;
                     }
                 };
@@ -78,5 +78,30 @@ pub async fn initialize_survey_indexes(
     };
     create_index(&alerts_collection, index, false).await?;
 
+    // and one on the jd, to select the alerts of a time range (e.g. for the filter backtests)
+    let index = doc! {
+        "candidate.jd": 1,
+    };
+    create_index(&alerts_collection, index, false).await?;
+
+    // LSST solar system alerts are stored apart, keyed by ssObjectId
+    if survey == "LSST" {
+        let ss_alerts_collection: Collection<Document> =
+            db.collection(&format!("{}_ss_alerts", survey));
+        let ss_aux_collection: Collection<Document> = db.collection(&format!("{}_ss_aux", survey));
+
+        let index = doc! {
+            "coordinates.radec_geojson": "2dsphere",
+            "_id": 1,
+        };
+        create_index(&ss_alerts_collection, index.clone(), false).await?;
+        create_index(&ss_aux_collection, index, false).await?;
+
+        let index = doc! {
+            "ssObjectId": 1,
+        };
+        create_index(&ss_alerts_collection, index, false).await?;
+    }
+
     Ok(())
 }
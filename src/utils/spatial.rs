@@ -186,3 +186,99 @@ pub async fn xmatch(
 
     Ok(xmatch_docs)
 }
+
+/// Find the objects of another survey within `radius` (in radians) of a position, nearest first,
+/// by looking up the aux collection of that survey. Each match is a document with the
+/// `objectId` of the object and its `angular_separation` in arcseconds.
+pub async fn survey_xmatch(
+    ra: f64,
+    dec: f64,
+    aux_collection: &mongodb::Collection<mongodb::bson::Document>,
+    radius: f64,
+    max_matches: Option<usize>,
+) -> Result<Vec<mongodb::bson::Document>, XmatchError> {
+    let mut cursor = aux_collection
+        .find(doc! {
+            "coordinates.radec_geojson": {
+                "$geoWithin": {
+                    "$centerSphere": [[ra - 180.0, dec], radius]
+                }
+            }
+        })
+        .projection(doc! { "_id": 1, "coordinates.radec_geojson.coordinates": 1 })
+        .await?;
+
+    let mut matches = vec![];
+    while let Some(aux) = cursor.next().await {
+        let aux = aux?;
+        let coordinates = aux
+            .get_document("coordinates")?
+            .get_document("radec_geojson")?
+            .get_array("coordinates")?;
+        let (Some(x), Some(y)) = (
+            coordinates.first().and_then(|x| x.as_f64()),
+            coordinates.get(1).and_then(|y| y.as_f64()),
+        ) else {
+            warn!("Invalid coordinates in aux doc");
+            continue;
+        };
+        let angular_separation = great_circle_distance(ra, dec, x + 180.0, y) * 3600.0;
+        let object_id = aux.get("_id").cloned().unwrap_or(mongodb::bson::Bson::Null);
+        matches.push((angular_separation, object_id));
+    }
+    matches.sort_by(|a, b| a.0.total_cmp(&b.0));
+    if let Some(max_matches) = max_matches {
+        matches.truncate(max_matches);
+    }
+
+    Ok(matches
+        .into_iter()
+        .map(|(angular_separation, object_id)| {
+            doc! {
+                "objectId": object_id,
+                "angular_separation": angular_separation,
+            }
+        })
+        .collect())
+}
+
+/// Crossmatch a position with the aux collections of the other surveys listed in
+/// `survey_xmatch_configs`, returning a document keyed by survey name. Positions outside
+/// of a survey's footprint get an empty list. Lookup errors are logged and the survey is
+/// left out, so that a failed crossmatch never blocks ingestion nor replaces the aliases
+/// already found for that survey.
+pub async fn get_survey_matches(
+    ra: f64,
+    dec: f64,
+    survey_xmatch_configs: &[conf::SurveyXmatchConfig],
+    db: &mongodb::Database,
+) -> mongodb::bson::Document {
+    let mut survey_matches = mongodb::bson::Document::new();
+    for survey_xmatch_config in survey_xmatch_configs {
+        let survey = &survey_xmatch_config.survey;
+        let mut matches = vec![];
+        if survey_xmatch_config.in_footprint(dec) {
+            let aux_collection = db.collection(&format!("{}_alerts_aux", survey));
+            match survey_xmatch(
+                ra,
+                dec,
+                &aux_collection,
+                survey_xmatch_config.radius,
+                survey_xmatch_config.max_matches,
+            )
+            .await
+            {
+                Ok(survey_xmatches) => matches = survey_xmatches,
+                Err(e) => {
+                    warn!(
+                        "error crossmatching with {} at {}, {}: {}",
+                        survey, ra, dec, e
+                    );
+                    continue;
+                }
+            }
+        }
+        survey_matches.insert(survey, matches);
+    }
+    survey_matches
+}
@@ -1,6 +1,7 @@
 use crate::{
     alert::{
-        AlertWorker, LsstAlertWorker, SchemaRegistry, ZtfAlertWorker, LSST_SCHEMA_REGISTRY_URL,
+        AlertWorker, GenericAlertWorker, LsstAlertWorker, SchemaRegistry, ZtfAlertWorker,
+        LSST_SCHEMA_REGISTRY_URL,
     },
     conf,
     utils::db::initialize_survey_indexes,
@@ -38,6 +39,23 @@ pub async fn lsst_alert_worker() -> LsstAlertWorker {
     LsstAlertWorker::new(TEST_CONFIG_FILE).await.unwrap()
 }
 
+pub async fn generic_alert_worker(stream_name: &str) -> GenericAlertWorker {
+    // initialize the indexes of the stream
+    let db = conf::build_db(&conf::load_config(TEST_CONFIG_FILE).unwrap())
+        .await
+        .unwrap();
+    initialize_survey_indexes(stream_name, &db).await.unwrap();
+    GenericAlertWorker::from_config(TEST_CONFIG_FILE, stream_name)
+        .await
+        .unwrap()
+}
+
+// the LSST schema registry of the test config, backed by the schemas on disk
+pub fn lsst_schema_registry() -> SchemaRegistry {
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    SchemaRegistry::from_config(&config, "LSST", LSST_SCHEMA_REGISTRY_URL).unwrap()
+}
+
 // drops alert collections from the database
 pub async fn drop_alert_collections(
     alert_collection_name: &str,
@@ -85,22 +103,14 @@ pub async fn drop_alert_from_collections(
             .delete_one(filter.clone())
             .await?;
 
-        // 1. if the stream name is ZTF we read the object id as a string and drop the aux entry
-        // 2. otherwise we consider it an i64 and drop the aux entry
-        match stream_name {
-            "ZTF" => {
-                let object_id = alert.get_str("objectId")?;
-                db.collection::<mongodb::bson::Document>(&alert_aux_collection_name)
-                    .delete_one(doc! {"_id": object_id})
-                    .await?;
-            }
-            _ => {
-                let object_id = alert.get_i64("objectId")?;
-                db.collection::<mongodb::bson::Document>(&alert_aux_collection_name)
-                    .delete_one(doc! {"_id": object_id})
-                    .await?;
-            }
-        }
+        // the object id is a string for ZTF, an i64 for LSST, and either for the generic streams
+        let object_id = alert
+            .get("objectId")
+            .cloned()
+            .ok_or(mongodb::bson::document::ValueAccessError::NotPresent)?;
+        db.collection::<mongodb::bson::Document>(&alert_aux_collection_name)
+            .delete_one(doc! {"_id": object_id})
+            .await?;
     }
 
     Ok(())
@@ -490,6 +500,16 @@ pub struct LsstAlertRandomizer {
     object_id: Option<i64>,
     ra: Option<f64>,
     dec: Option<f64>,
+    solar_system: bool,
+}
+
+impl LsstAlertRandomizer {
+    /// Make the alert a solar system one: the object id is used as
+    /// the ssObjectId, and the diaObjectId is set to null.
+    pub fn solar_system(mut self) -> Self {
+        self.solar_system = true;
+        self
+    }
 }
 
 #[async_trait::async_trait]
@@ -500,11 +520,12 @@ impl AlertRandomizerTrait for LsstAlertRandomizer {
         let payload = fs::read("tests/data/alerts/lsst/25409136044802067.avro").unwrap();
         Self {
             payload: Some(payload),
-            schema_registry: SchemaRegistry::new(LSST_SCHEMA_REGISTRY_URL),
+            schema_registry: lsst_schema_registry(),
             candid: Some(Self::randomize_i64()),
             object_id: Some(Self::randomize_i64()),
             ra: Some(Self::randomize_ra()),
             dec: Some(Self::randomize_dec()),
+            solar_system: false,
         }
     }
 
@@ -512,11 +533,12 @@ impl AlertRandomizerTrait for LsstAlertRandomizer {
         let payload = fs::read("tests/data/alerts/lsst/25409136044802067.avro").unwrap();
         Self {
             payload: Some(payload),
-            schema_registry: SchemaRegistry::new(LSST_SCHEMA_REGISTRY_URL),
+            schema_registry: lsst_schema_registry(),
             candid: None,
             object_id: None,
             ra: None,
             dec: None,
+            solar_system: false,
         }
     }
 
@@ -587,7 +609,7 @@ impl AlertRandomizerTrait for LsstAlertRandomizer {
 
         let schema = self
             .schema_registry
-            .get_schema("alert-packet", schema_id)
+            .get_schema_by_id(schema_id)
             .await
             .unwrap();
 
@@ -619,6 +641,13 @@ impl AlertRandomizerTrait for LsstAlertRandomizer {
                             Some(id) => *value = Value::Long(id),
                             None => object_id = Some(Self::value_to_i64(value)),
                         }
+                    } else if key == "diaObjectId" && self.solar_system {
+                        *value = Value::Union(0_u32, Box::new(Value::Null));
+                    } else if key == "ssObjectId" && self.solar_system {
+                        match object_id {
+                            Some(id) => *value = Value::Union(1_u32, Box::new(Value::Long(id))),
+                            None => panic!("solar system alerts need an object id"),
+                        }
                     } else if key == "diaObjectId" {
                         match object_id {
                             Some(id) => *value = Value::Union(1_u32, Box::new(Value::Long(id))),
@@ -673,3 +702,186 @@ impl AlertRandomizerTrait for LsstAlertRandomizer {
         )
     }
 }
+
+/// A minimal in-process schema registry, serving the subjects, versions and
+/// schema ids of the Confluent API from memory, and registering new schemas,
+/// for tests that shouldn't depend on a real registry.
+pub struct MockSchemaRegistry {
+    url: String,
+    requests: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    handle: tokio::task::JoinHandle<()>,
+}
+
+// answer a request to the mock registry, holding (subject, version, id, schema)
+fn mock_registry_response(
+    schemas: &mut Vec<(String, u32, u32, String)>,
+    method: &str,
+    path: &str,
+    body: &str,
+) -> Option<serde_json::Value> {
+    let parts = path.trim_start_matches('/').split('/').collect::<Vec<_>>();
+    match (method, parts.as_slice()) {
+        ("GET", ["schemas", "ids", id]) => schemas
+            .iter()
+            .find(|s| id.parse() == Ok(s.2))
+            .map(|s| serde_json::json!({ "schema": s.3 })),
+        ("GET", ["subjects", subject, "versions"]) => {
+            let versions = schemas
+                .iter()
+                .filter(|s| s.0 == *subject)
+                .map(|s| s.1)
+                .collect::<Vec<_>>();
+            (!versions.is_empty()).then(|| serde_json::json!(versions))
+        }
+        ("GET", ["subjects", subject, "versions", version]) => schemas
+            .iter()
+            .find(|s| s.0 == *subject && version.parse() == Ok(s.1))
+            .map(|s| {
+                serde_json::json!({
+                    "subject": s.0,
+                    "version": s.1,
+                    "id": s.2,
+                    "schema": s.3,
+                })
+            }),
+        ("POST", ["subjects", subject, "versions"]) => {
+            let body: serde_json::Value = serde_json::from_str(body).ok()?;
+            let schema = body["schema"].as_str()?.to_string();
+            // registering the same schema again gives back the same id
+            if let Some(s) = schemas.iter().find(|s| s.0 == *subject && s.3 == schema) {
+                return Some(serde_json::json!({ "id": s.2 }));
+            }
+            let id = schemas.iter().map(|s| s.2).max().unwrap_or(0) + 1;
+            let version = schemas
+                .iter()
+                .filter(|s| s.0 == *subject)
+                .map(|s| s.1)
+                .max()
+                .unwrap_or(0)
+                + 1;
+            schemas.push((subject.to_string(), version, id, schema));
+            Some(serde_json::json!({ "id": id }))
+        }
+        _ => None,
+    }
+}
+
+impl MockSchemaRegistry {
+    /// Serve `schemas`, given as (subject, version, schema id, schema), on a random
+    /// local port. With `authorization`, the expected value of the Authorization
+    /// header (e.g. `Basic <base64 of username:password>`), requests without it get a 401.
+    pub async fn start(
+        schemas: Vec<(&str, u32, u32, &str)>,
+        authorization: Option<String>,
+    ) -> MockSchemaRegistry {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        let mut schemas = schemas
+            .into_iter()
+            .map(|(subject, version, id, schema)| {
+                (subject.to_string(), version, id, schema.to_string())
+            })
+            .collect::<Vec<_>>();
+        let requests_count = requests.clone();
+        let handle = tokio::spawn(async move {
+            loop {
+                let Ok((mut socket, _)) = listener.accept().await else {
+                    continue;
+                };
+                requests_count.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                // read the headers, then as much of the body as announced
+                let mut buf = Vec::new();
+                let mut chunk = vec![0u8; 8192];
+                let header_end = loop {
+                    let n = socket.read(&mut chunk).await.unwrap_or(0);
+                    if n == 0 {
+                        break None;
+                    }
+                    buf.extend_from_slice(&chunk[..n]);
+                    if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break Some(i + 4);
+                    }
+                };
+                let Some(header_end) = header_end else {
+                    continue;
+                };
+                let headers = String::from_utf8_lossy(&buf[..header_end]).to_string();
+                let content_length = headers
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        (name.eq_ignore_ascii_case("content-length"))
+                            .then(|| value.trim().parse::<usize>().ok())?
+                    })
+                    .unwrap_or(0);
+                while buf.len() < header_end + content_length {
+                    let n = socket.read(&mut chunk).await.unwrap_or(0);
+                    if n == 0 {
+                        break;
+                    }
+                    buf.extend_from_slice(&chunk[..n]);
+                }
+                let body = String::from_utf8_lossy(&buf[header_end..]).to_string();
+
+                let mut request_line = headers
+                    .lines()
+                    .next()
+                    .unwrap_or_default()
+                    .split_whitespace();
+                let method = request_line.next().unwrap_or("GET").to_string();
+                let path = request_line.next().unwrap_or("/").to_string();
+                let authorized = match &authorization {
+                    Some(authorization) => headers.lines().any(|line| {
+                        line.split_once(':').is_some_and(|(name, value)| {
+                            name.eq_ignore_ascii_case("authorization")
+                                && value.trim() == authorization
+                        })
+                    }),
+                    None => true,
+                };
+                let (status, body) = if !authorized {
+                    ("401 Unauthorized", serde_json::json!({"error_code": 401}))
+                } else {
+                    match mock_registry_response(&mut schemas, &method, &path, &body) {
+                        Some(body) => ("200 OK", body),
+                        None => ("404 Not Found", serde_json::json!({"error_code": 404})),
+                    }
+                };
+                let body = body.to_string();
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+                let _ = socket.shutdown().await;
+            }
+        });
+
+        MockSchemaRegistry {
+            url,
+            requests,
+            handle,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Number of requests received so far.
+    pub fn requests(&self) -> usize {
+        self.requests.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+impl Drop for MockSchemaRegistry {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
@@ -1,4 +1,5 @@
 use config::Config;
+use redis::AsyncCommands;
 use std::{
     fmt,
     sync::{Arc, Mutex},
@@ -64,6 +65,7 @@ pub fn get_check_command_interval(conf: Config, stream_name: &str) -> i64 {
 pub enum WorkerType {
     Alert,
     Filter,
+    ScheduledFilter,
     ML,
 }
 
@@ -77,6 +79,7 @@ impl fmt::Display for WorkerType {
                 enum_str = "Alert";
             }
             WorkerType::Filter => enum_str = "Filter",
+            WorkerType::ScheduledFilter => enum_str = "ScheduledFilter",
             WorkerType::ML => enum_str = "ML",
         }
         write!(f, "{}", enum_str)
@@ -99,3 +102,112 @@ impl fmt::Display for WorkerCmd {
         write!(f, "{}", enum_str)
     }
 }
+
+// how long a worker's heartbeat lives in redis without being refreshed
+pub const HEARTBEAT_TTL_SECS: u64 = 60;
+
+// each worker pops packets into its own temp queue, so that when it dies
+// the packets it was holding can be told apart from those of live workers
+pub fn temp_queue_name(input_queue_name: &str, worker_id: &str) -> String {
+    format!("{}_temp_{}", input_queue_name, worker_id)
+}
+
+fn heartbeat_key(temp_queue_name: &str) -> String {
+    format!("{}_heartbeat", temp_queue_name)
+}
+
+// marks the worker owning temp_queue_name as alive for HEARTBEAT_TTL_SECS
+pub async fn send_heartbeat(
+    con: &mut redis::aio::MultiplexedConnection,
+    temp_queue_name: &str,
+) -> Result<(), redis::RedisError> {
+    con.set_ex::<String, u8, ()>(heartbeat_key(temp_queue_name), 1, HEARTBEAT_TTL_SECS)
+        .await
+}
+
+// refreshes the heartbeat of a worker from a background task, so that it stays
+// alive while the worker is busy (e.g. with a large batch, or retries with backoff).
+// The task stops when the returned handle is dropped
+pub fn spawn_heartbeat(
+    mut con: redis::aio::MultiplexedConnection,
+    temp_queue_name: String,
+) -> HeartbeatHandle {
+    let handle = tokio::spawn(async move {
+        let mut interval =
+            tokio::time::interval(std::time::Duration::from_secs(HEARTBEAT_TTL_SECS / 4));
+        loop {
+            interval.tick().await;
+            if let Err(e) = send_heartbeat(&mut con, &temp_queue_name).await {
+                warn!(error = %e, "failed to send heartbeat for {}", temp_queue_name);
+            }
+        }
+    });
+    HeartbeatHandle(handle)
+}
+
+pub struct HeartbeatHandle(tokio::task::JoinHandle<()>);
+
+impl Drop for HeartbeatHandle {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+// removes the heartbeat of a worker that is shutting down cleanly,
+// so that anything left in its temp queue is reclaimed right away
+pub async fn clear_heartbeat(
+    con: &mut redis::aio::MultiplexedConnection,
+    temp_queue_name: &str,
+) -> Result<(), redis::RedisError> {
+    con.del::<String, ()>(heartbeat_key(temp_queue_name)).await
+}
+
+// pushes the packets held in the temp queues of dead workers (no heartbeat)
+// back onto the input queue, returns the number of packets reclaimed.
+// this also picks up the shared "<input>_temp" queue used by older versions
+pub async fn reclaim_orphaned_packets(
+    con: &mut redis::aio::MultiplexedConnection,
+    input_queue_name: &str,
+) -> Result<usize, redis::RedisError> {
+    let pattern = format!("{}_temp*", input_queue_name);
+    let mut temp_queue_names: Vec<String> = Vec::new();
+    {
+        let mut iter = con.scan_match::<&str, String>(&pattern).await?;
+        while let Some(key) = iter.next_item().await {
+            if !key.ends_with("_heartbeat") {
+                temp_queue_names.push(key);
+            }
+        }
+    }
+
+    let mut count = 0;
+    for temp_queue_name in temp_queue_names {
+        let alive: bool = con.exists(heartbeat_key(&temp_queue_name)).await?;
+        if alive {
+            continue;
+        }
+        // move packets one at a time so that none are lost if we die midway,
+        // oldest packets end up at the tail of the input queue, i.e. popped first
+        loop {
+            let packet: Option<Vec<u8>> = con
+                .lmove(
+                    &temp_queue_name,
+                    input_queue_name,
+                    redis::Direction::Left,
+                    redis::Direction::Right,
+                )
+                .await?;
+            if packet.is_none() {
+                break;
+            }
+            count += 1;
+        }
+    }
+    if count > 0 {
+        warn!(
+            "reclaimed {} orphaned packets into {}",
+            count, input_queue_name
+        );
+    }
+    Ok(count)
+}
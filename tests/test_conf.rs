@@ -56,6 +56,44 @@ fn test_build_xmatch_configs() {
     );
 }
 
+#[test]
+fn test_build_survey_xmatch_configs() {
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+
+    let survey_xmatch_configs = conf::build_survey_xmatch_configs(&config, "ZTF").unwrap();
+    assert_eq!(survey_xmatch_configs.len(), 1);
+
+    let lsst = &survey_xmatch_configs[0];
+    assert_eq!(lsst.survey, "LSST");
+    assert_eq!(lsst.radius, 2.0 * std::f64::consts::PI / 180.0 / 3600.0);
+    assert_eq!(lsst.min_dec, None);
+    assert_eq!(lsst.max_dec, Some(33.5));
+    assert_eq!(lsst.max_matches, None);
+    assert!(lsst.in_footprint(0.0));
+    assert!(!lsst.in_footprint(40.0));
+
+    let survey_xmatch_configs = conf::build_survey_xmatch_configs(&config, "LSST").unwrap();
+    assert_eq!(survey_xmatch_configs.len(), 1);
+    assert_eq!(survey_xmatch_configs[0].survey, "ZTF");
+    assert!(survey_xmatch_configs[0].in_footprint(40.0));
+
+    // streams without a section don't alias anything
+    let survey_xmatch_configs = conf::build_survey_xmatch_configs(&config, "DECAM").unwrap();
+    assert!(survey_xmatch_configs.is_empty());
+}
+
+#[test]
+fn test_build_alias_limits() {
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+
+    // LSST keeps at most 5 ZTF aliases, ZTF keeps all of its LSST aliases
+    let alias_limits = conf::build_alias_limits(&config, "ZTF").unwrap();
+    assert_eq!(alias_limits.len(), 1);
+    assert_eq!(alias_limits.get("LSST"), Some(&5));
+    let alias_limits = conf::build_alias_limits(&config, "LSST").unwrap();
+    assert!(alias_limits.is_empty());
+}
+
 #[tokio::test]
 async fn test_build_db() {
     let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
@@ -109,3 +147,75 @@ fn test_catalogxmatchconfig() {
         assert!(catalog_xmatch_config.projection.len() > 0);
     }
 }
+
+#[test]
+fn test_kafka_producer_config() {
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let producer_config = conf::KafkaProducerConfig::from_config(&config).unwrap();
+    assert_eq!(producer_config.bootstrap_servers, "localhost:9092");
+    assert_eq!(producer_config.security_protocol, "PLAINTEXT");
+    let client_config = producer_config.client_config();
+    assert_eq!(client_config.get("message.timeout.ms"), Some("5000"));
+    assert_eq!(client_config.get("sasl.username"), None);
+
+    // without a section, the producer connects to a local broker
+    let config = config::Config::builder().build().unwrap();
+    let producer_config = conf::KafkaProducerConfig::from_config(&config).unwrap();
+    assert_eq!(producer_config, conf::KafkaProducerConfig::default());
+
+    // the credentials are read from the environment
+    let yaml = r#"
+kafka:
+  producer:
+    bootstrap_servers: kafka.example.org:9093
+    security_protocol: SASL_SSL
+    sasl_mechanism: SCRAM-SHA-512
+    username_env: BOOM_TEST_KAFKA_PRODUCER_USERNAME
+    password_env: BOOM_TEST_KAFKA_PRODUCER_PASSWORD
+    compression: zstd
+    linger_ms: 20
+    acks: all
+    enable_idempotence: true
+"#;
+    let config = config::Config::builder()
+        .add_source(config::File::from_str(yaml, config::FileFormat::Yaml))
+        .build()
+        .unwrap();
+    let result = conf::KafkaProducerConfig::from_config(&config);
+    assert!(matches!(
+        result,
+        Err(conf::BoomConfigError::MissingEnvVar(var)) if var == "BOOM_TEST_KAFKA_PRODUCER_USERNAME"
+    ));
+
+    std::env::set_var("BOOM_TEST_KAFKA_PRODUCER_USERNAME", "boom");
+    std::env::set_var("BOOM_TEST_KAFKA_PRODUCER_PASSWORD", "secret");
+    let producer_config = conf::KafkaProducerConfig::from_config(&config).unwrap();
+    let client_config = producer_config.client_config();
+    assert_eq!(
+        client_config.get("bootstrap.servers"),
+        Some("kafka.example.org:9093")
+    );
+    assert_eq!(client_config.get("security.protocol"), Some("SASL_SSL"));
+    assert_eq!(client_config.get("sasl.mechanisms"), Some("SCRAM-SHA-512"));
+    assert_eq!(client_config.get("sasl.username"), Some("boom"));
+    assert_eq!(client_config.get("sasl.password"), Some("secret"));
+    assert_eq!(client_config.get("compression.type"), Some("zstd"));
+    assert_eq!(client_config.get("linger.ms"), Some("20"));
+    assert_eq!(client_config.get("acks"), Some("all"));
+    assert_eq!(client_config.get("enable.idempotence"), Some("true"));
+}
+
+#[test]
+fn test_get_path() {
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+
+    // relative to the directory of the config file, not the working directory
+    let seed_dir = conf::get_path(&config, "schema_registry.LSST.seed_dir").unwrap();
+    assert_eq!(
+        seed_dir.canonicalize().unwrap(),
+        std::path::Path::new("data/schemas/LSST")
+            .canonicalize()
+            .unwrap()
+    );
+    assert!(conf::get_path(&config, "schema_registry.LSST.reader_schema").is_none());
+}
@@ -0,0 +1,65 @@
+use boom::{
+    alert::{AlertError, AlertWorker, DeadLetterQueue},
+    conf,
+    utils::testing::{ztf_alert_worker, TEST_CONFIG_FILE},
+};
+use redis::AsyncCommands;
+
+#[test]
+fn test_alert_error_is_transient() {
+    assert!(!AlertError::MissingCutout.is_transient());
+    assert!(!AlertError::MissingObjectId.is_transient());
+    assert!(!AlertError::AlertExists.is_transient());
+    assert_eq!(AlertError::MissingCutout.kind(), "MissingCutout");
+}
+
+#[tokio::test]
+async fn test_dead_letter_roundtrip() {
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let db = conf::build_db(&config).await.unwrap();
+    let mut con = conf::build_redis(&config).await.unwrap();
+
+    // a packet that is not avro at all can never be ingested
+    let mut alert_worker = ztf_alert_worker().await;
+    let bad_packet = uuid::Uuid::new_v4().as_bytes().to_vec();
+    let error = alert_worker.process_alert(&bad_packet).await.unwrap_err();
+    assert!(!error.is_transient());
+
+    let stream_name = format!("TEST_{}", uuid::Uuid::new_v4().simple());
+    let dead_letter_queue = DeadLetterQueue::new(&db, &stream_name);
+    let id = dead_letter_queue
+        .push(&bad_packet, &error, 1, "test-worker")
+        .await
+        .unwrap();
+
+    let dead_letters = dead_letter_queue.list(None, 10).await.unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    let dead_letter = dead_letter_queue.get(id).await.unwrap().unwrap();
+    assert_eq!(dead_letter.stream, stream_name);
+    assert_eq!(dead_letter.error_kind, error.kind());
+    assert_eq!(dead_letter.attempts, 1);
+    assert_eq!(dead_letter.worker_id, "test-worker");
+    assert_eq!(dead_letter.packet.bytes, bad_packet);
+    assert_eq!(
+        dead_letter_queue
+            .count(Some("MissingCutout"))
+            .await
+            .unwrap(),
+        0
+    );
+
+    // re-injecting moves the packet back to the queue and out of the store
+    let queue_name = format!("{}_alerts_packets_queue", stream_name);
+    dead_letter_queue
+        .reinject(id, &mut con, &queue_name)
+        .await
+        .unwrap();
+    assert!(dead_letter_queue.get(id).await.unwrap().is_none());
+    let packet: Vec<u8> = con.rpop(&queue_name, None).await.unwrap();
+    assert_eq!(packet, bad_packet);
+
+    db.collection::<mongodb::bson::Document>(&format!("{}_alerts_dead_letter", stream_name))
+        .drop()
+        .await
+        .unwrap();
+}
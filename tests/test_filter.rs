@@ -1,9 +1,27 @@
+use apache_avro::types::Value;
 use boom::{
+    alert::{AlertWorker, SchemaRegistry},
     conf,
-    filter::{Filter, ZtfFilter},
-    utils::testing::{insert_test_ztf_filter, remove_test_ztf_filter, TEST_CONFIG_FILE},
+    filter::{
+        get_active_filter_ids, get_filter_reload_interval, get_scheduled_filter_ids,
+        group_by_prefix, load_alert_schema, observing_night, run_filter, run_filters,
+        validate_filter_pipeline, Alert, AlertEncoder, AutosavedFilter, Backtest, BacktestError,
+        BacktestQuery, BacktestReport, Filter, FilterError, FilterResults, FilterResultsWriter,
+        FilterRunner, FilterSchedule, FilterStats, FilterTopicsConfig, FilterValidationError,
+        FilterValidator, FilterWorker, NightCounts, NotificationGate, NotificationPolicy,
+        OutputFormat, OutputTopics, ScheduledFilterWorker, SkyRegion, ZtfFilter, ZtfFilterWorker,
+        ALERT_SCHEMA_VERSION, FILTER_NOTIFICATIONS_COLLECTION, FILTER_PREFIX_LEN,
+        FILTER_RESULTS_COLLECTION, LSST_LONGITUDE, MAX_UNWIND_STAGES, OBJECT_FILTER_TYPE,
+        ZTF_LONGITUDE,
+    },
+    utils::testing::{
+        insert_test_ztf_filter, remove_test_ztf_filter, ztf_alert_worker, AlertRandomizerTrait,
+        MockSchemaRegistry, ZtfAlertRandomizer, TEST_CONFIG_FILE,
+    },
 };
+use futures::TryStreamExt;
 use mongodb::bson::{doc, Document};
+use std::collections::HashMap;
 
 #[tokio::test]
 async fn test_build_filter() {
@@ -43,6 +61,8 @@ async fn test_build_filter() {
     ];
     assert_eq!(pipeline, filter.pipeline);
     assert_eq!(vec![1], filter.permissions);
+    assert!(!filter.autosave);
+    assert!(filter.update_annotations);
 }
 
 #[tokio::test]
@@ -64,3 +84,1214 @@ async fn test_no_filter_found() {
     let filter_result = ZtfFilter::build(-2, &filter_collection).await;
     assert!(filter_result.is_err());
 }
+
+#[tokio::test]
+async fn test_reload_filters() {
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let db = conf::build_db(&config).await.unwrap();
+    let filter_collection = db.collection::<Document>("filters");
+
+    let filter_id = insert_test_ztf_filter().await.unwrap();
+    // a legacy filter, with its pipeline stored as an array, can't be built
+    let broken_filter_id = insert_test_ztf_filter().await.unwrap();
+    filter_collection
+        .update_one(
+            doc! {"filter_id": broken_filter_id},
+            doc! {"$set": {"fv.0.pipeline": [{"$match": {"candidate.drb": {"$gt": 0.5}}}]}},
+        )
+        .await
+        .unwrap();
+    let filter_worker = ZtfFilterWorker::new(TEST_CONFIG_FILE).await;
+    remove_test_ztf_filter(broken_filter_id).await.unwrap();
+
+    // but it doesn't keep the worker from starting with the other filters
+    let mut filter_worker = filter_worker.unwrap();
+    assert!(filter_worker.has_filters());
+    assert!(filter_worker.filter_group_ids().contains_key(&filter_id));
+    assert!(!filter_worker
+        .filter_group_ids()
+        .contains_key(&broken_filter_id));
+
+    // deactivated filters are dropped
+    filter_collection
+        .update_one(
+            doc! {"filter_id": filter_id},
+            doc! {"$set": {"active": false}},
+        )
+        .await
+        .unwrap();
+    filter_worker.reload_filters().await.unwrap();
+    assert!(!filter_worker.filter_group_ids().contains_key(&filter_id));
+
+    // and picked up again once reactivated, with their new active version
+    filter_collection
+        .update_one(
+            doc! {"filter_id": filter_id},
+            doc! {
+                "$set": {"active": true, "active_fid": "v2"},
+                "$push": {"fv": {"fid": "v2", "pipeline": "[{\"$match\": {\"candidate.drb\": {\"$gt\": 0.9}}}]"}},
+            },
+        )
+        .await
+        .unwrap();
+    let reload_result = filter_worker.reload_filters().await;
+    let filter_result = ZtfFilter::build(filter_id, &filter_collection).await;
+    remove_test_ztf_filter(filter_id).await.unwrap();
+
+    reload_result.unwrap();
+    assert!(filter_worker.filter_group_ids().contains_key(&filter_id));
+    let filter = filter_result.unwrap();
+    assert_eq!(
+        filter.pipeline.last().unwrap(),
+        &doc! {"$match": {"candidate.drb": {"$gt": 0.9}}}
+    );
+}
+
+#[test]
+fn test_filter_reload_interval() {
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    assert_eq!(
+        get_filter_reload_interval(&config, "ZTF"),
+        std::time::Duration::from_secs(5)
+    );
+    // streams without a reload interval use the default
+    assert_eq!(
+        get_filter_reload_interval(&config, "DECAM"),
+        std::time::Duration::from_secs(60)
+    );
+}
+
+#[tokio::test]
+async fn test_run_filters() {
+    let mut alert_worker = ztf_alert_worker().await;
+    let (candid, _object_id, _ra, _dec, bytes_content) = ZtfAlertRandomizer::default().get().await;
+    alert_worker.process_alert(&bytes_content).await.unwrap();
+
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let db = conf::build_db(&config).await.unwrap();
+    let alert_collection = db.collection("ZTF_alerts");
+    let filter_collection = db.collection("filters");
+
+    let filter_id = insert_test_ztf_filter().await.unwrap();
+    let filter_result = ZtfFilter::build(filter_id, &filter_collection).await;
+    remove_test_ztf_filter(filter_id).await.unwrap();
+    let filter = filter_result.unwrap();
+
+    // a filter with the same prefix that no alert passes
+    let mut rejecting_pipeline = filter.pipeline.clone();
+    rejecting_pipeline.push(doc! {"$match": {"candidate.drb": {"$gt": 2.0}}});
+
+    let expected = run_filter(vec![candid], filter.pipeline.clone(), &alert_collection)
+        .await
+        .unwrap();
+    assert_eq!(expected.len(), 1);
+
+    let results = run_filters(
+        &[candid],
+        &[(1, &filter.pipeline), (2, &rejecting_pipeline)],
+        &alert_collection,
+        None,
+    )
+    .await
+    .unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[&1], expected);
+    assert!(results[&2].is_empty());
+
+    // a filter run alone gets the same output, without the $facet
+    let results = run_filters(&[candid], &[(1, &filter.pipeline)], &alert_collection, None)
+        .await
+        .unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[&1], expected);
+}
+
+#[tokio::test]
+async fn test_filter_runner_isolates_failures() {
+    let mut alert_worker = ztf_alert_worker().await;
+    let (candid, _object_id, _ra, _dec, bytes_content) = ZtfAlertRandomizer::default().get().await;
+    alert_worker.process_alert(&bytes_content).await.unwrap();
+
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let db = conf::build_db(&config).await.unwrap();
+    let filter_collection = db.collection::<Document>("filters");
+
+    let filter_id = insert_test_ztf_filter().await.unwrap();
+    let broken_filter_id = insert_test_ztf_filter().await.unwrap();
+    let filter = ZtfFilter::build(filter_id, &filter_collection)
+        .await
+        .unwrap();
+    // a filter with the same prefix, with a stage the database rejects
+    let mut broken_pipeline = filter.pipeline.clone();
+    broken_pipeline.push(doc! {"$notAStage": {}});
+
+    let mut filter_runner = FilterRunner::from_config(&config, "ZTF", &db);
+    for _ in 0..filter_runner.max_failures() {
+        let out_documents = filter_runner
+            .run(
+                &[candid],
+                vec![
+                    (filter_id, filter.pipeline.as_slice()),
+                    (broken_filter_id, broken_pipeline.as_slice()),
+                ],
+            )
+            .await;
+        // the broken filter doesn't keep the other one from running
+        assert_eq!(out_documents[&filter_id].len(), 1);
+        assert!(!out_documents.contains_key(&broken_filter_id));
+    }
+
+    // and both runs are in the stats
+    let stats = filter_runner.stats().to_documents();
+    let filter_stats = stats
+        .iter()
+        .find(|doc| doc.get_i32("filter_id").unwrap() == filter_id)
+        .unwrap();
+    assert_eq!(filter_stats.get_str("fid").unwrap(), filter.fid);
+    assert_eq!(
+        filter_stats.get_i64("alerts_passed").unwrap(),
+        filter_runner.max_failures() as i64
+    );
+    assert_eq!(filter_stats.get_i64("errors").unwrap(), 0);
+    let broken_filter_stats = stats
+        .iter()
+        .find(|doc| doc.get_i32("filter_id").unwrap() == broken_filter_id)
+        .unwrap();
+    assert_eq!(
+        broken_filter_stats.get_i64("errors").unwrap(),
+        filter_runner.max_failures() as i64
+    );
+
+    // and it has been disabled after failing max_failures times in a row
+    let broken_filter = filter_collection
+        .find_one(doc! {"filter_id": broken_filter_id})
+        .await
+        .unwrap()
+        .unwrap();
+    let active_filter = filter_collection
+        .find_one(doc! {"filter_id": filter_id})
+        .await
+        .unwrap()
+        .unwrap();
+    remove_test_ztf_filter(filter_id).await.unwrap();
+    remove_test_ztf_filter(broken_filter_id).await.unwrap();
+
+    assert!(!broken_filter.get_bool("active").unwrap());
+    assert!(broken_filter.get_bool("unhealthy").unwrap());
+    assert!(active_filter.get_bool("active").unwrap());
+}
+
+#[tokio::test]
+async fn test_filter_runner_output_without_candid() {
+    let mut alert_worker = ztf_alert_worker().await;
+    let (candid, _object_id, _ra, _dec, bytes_content) = ZtfAlertRandomizer::default().get().await;
+    alert_worker.process_alert(&bytes_content).await.unwrap();
+
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let db = conf::build_db(&config).await.unwrap();
+    let filter_collection = db.collection::<Document>("filters");
+
+    let filter_id = insert_test_ztf_filter().await.unwrap();
+    let filter_result = ZtfFilter::build(filter_id, &filter_collection).await;
+    remove_test_ztf_filter(filter_id).await.unwrap();
+    let filter = filter_result.unwrap();
+    // a filter that passes the alert, but counts them instead of returning their candid
+    let mut counting_pipeline = filter.pipeline.clone();
+    counting_pipeline.push(doc! {"$count": "n"});
+
+    let mut filter_runner = FilterRunner::from_config(&config, "ZTF", &db);
+    let counting_filter_id = filter_id.wrapping_add(1);
+    let out_documents = filter_runner
+        .run(
+            &[candid],
+            vec![
+                (filter_id, filter.pipeline.as_slice()),
+                (counting_filter_id, counting_pipeline.as_slice()),
+            ],
+        )
+        .await;
+    assert_eq!(out_documents[&filter_id].len(), 1);
+    assert!(!out_documents.contains_key(&counting_filter_id));
+
+    // its run is recorded as a failure
+    let stats = filter_runner.stats().to_documents();
+    let counting_filter_stats = stats
+        .iter()
+        .find(|doc| doc.get_i32("filter_id").unwrap() == counting_filter_id)
+        .unwrap();
+    assert_eq!(counting_filter_stats.get_i64("errors").unwrap(), 1);
+}
+
+#[tokio::test]
+async fn test_filter_runner_from_config() {
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let db = mongodb::Client::with_uri_str("mongodb://localhost:27017")
+        .await
+        .unwrap()
+        .database("boom");
+
+    let filter_runner = FilterRunner::from_config(&config, "ZTF", &db);
+    assert_eq!(filter_runner.max_concurrency(), 2);
+    assert_eq!(
+        filter_runner.max_time(),
+        std::time::Duration::from_millis(5000)
+    );
+    assert_eq!(filter_runner.max_failures(), 2);
+
+    // streams without these settings use the defaults
+    let filter_runner = FilterRunner::from_config(&config, "DECAM", &db);
+    assert_eq!(filter_runner.max_concurrency(), 4);
+    assert_eq!(
+        filter_runner.max_time(),
+        std::time::Duration::from_millis(10000)
+    );
+    assert_eq!(filter_runner.max_failures(), 3);
+}
+
+#[test]
+fn test_filter_stats() {
+    let mut stats = FilterStats::new("ZTF_alerts", 300);
+    assert!(!stats.window_ended());
+    for latency_ms in 1..=20 {
+        stats.record(
+            1,
+            "v1".to_string(),
+            100,
+            2,
+            false,
+            std::time::Duration::from_millis(latency_ms),
+        );
+    }
+    stats.record(
+        1,
+        "v2".to_string(),
+        100,
+        0,
+        true,
+        std::time::Duration::from_millis(5),
+    );
+    stats.record_suppressed(1, "v1".to_string());
+
+    let mut documents = stats.to_documents();
+    documents.sort_by_key(|doc| doc.get_str("fid").unwrap().to_string());
+    assert_eq!(documents.len(), 2);
+
+    let v1 = &documents[0];
+    assert_eq!(v1.get_i32("filter_id").unwrap(), 1);
+    assert_eq!(v1.get_str("fid").unwrap(), "v1");
+    assert_eq!(v1.get_str("catalog").unwrap(), "ZTF_alerts");
+    assert_eq!(v1.get_i64("alerts_evaluated").unwrap(), 2000);
+    assert_eq!(v1.get_i64("alerts_passed").unwrap(), 40);
+    assert_eq!(v1.get_i64("alerts_suppressed").unwrap(), 1);
+    assert_eq!(v1.get_i64("errors").unwrap(), 0);
+    assert_eq!(v1.get_i64("runs").unwrap(), 20);
+    assert_eq!(v1.get_f64("latency_p50_ms").unwrap(), 10.0);
+    assert_eq!(v1.get_f64("latency_p95_ms").unwrap(), 19.0);
+    let window_start = v1.get_datetime("window_start").unwrap();
+    let window_end = v1.get_datetime("window_end").unwrap();
+    assert_eq!(
+        window_end.timestamp_millis() - window_start.timestamp_millis(),
+        300_000
+    );
+    assert_eq!(window_start.timestamp_millis() % 300_000, 0);
+
+    let v2 = &documents[1];
+    assert_eq!(v2.get_str("fid").unwrap(), "v2");
+    assert_eq!(v2.get_i64("alerts_passed").unwrap(), 0);
+    assert_eq!(v2.get_i64("errors").unwrap(), 1);
+    assert_eq!(v2.get_f64("latency_p95_ms").unwrap(), 5.0);
+}
+
+#[tokio::test]
+async fn test_filter_results_writer() {
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let db = conf::build_db(&config).await.unwrap();
+    let collection = db.collection::<Document>(FILTER_RESULTS_COLLECTION);
+
+    let mut results_writer = FilterResultsWriter::new(&db, "ZTF_alerts");
+    results_writer.create_indexes().await.unwrap();
+    let filter_id = rand::random::<i32>();
+    let updating_filter_id = filter_id.wrapping_add(1);
+    let not_saved_filter_id = filter_id.wrapping_add(2);
+    let autosaved_filter = |update_annotations| AutosavedFilter {
+        fid: "v1".to_string(),
+        update_annotations,
+    };
+    results_writer.set_filters(HashMap::from([
+        (filter_id, autosaved_filter(false)),
+        (updating_filter_id, autosaved_filter(true)),
+    ]));
+
+    // two alerts of the same object passing the 3 filters
+    let filter_results = |mag_now: f64| {
+        [filter_id, updating_filter_id, not_saved_filter_id]
+            .into_iter()
+            .map(|filter_id| FilterResults {
+                filter_id,
+                passed_at: 1_700_000_000_000.0,
+                annotations: format!("{{\"mag_now\": {}}}", mag_now),
+            })
+            .collect()
+    };
+    let mut alert = test_alert();
+    alert.filters = filter_results(18.5);
+    results_writer.save(&alert).await.unwrap();
+    // saving the same pass again doesn't add a result
+    results_writer.save(&alert).await.unwrap();
+    alert.candid += 1;
+    alert.filters = filter_results(18.0);
+    results_writer.save(&alert).await.unwrap();
+
+    let get_results = |filter_id: i32| {
+        let collection = collection.clone();
+        async move {
+            let results: Vec<Document> = collection
+                .find(doc! {"filter_id": filter_id})
+                .sort(doc! {"candid": 1})
+                .await
+                .unwrap()
+                .try_collect()
+                .await
+                .unwrap();
+            results
+        }
+    };
+    let results = get_results(filter_id).await;
+    let updated_results = get_results(updating_filter_id).await;
+    let not_saved_results = get_results(not_saved_filter_id).await;
+    collection
+        .delete_many(doc! {"filter_id": {"$in": [filter_id, updating_filter_id]}})
+        .await
+        .unwrap();
+
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].get_i64("candid").unwrap(), 42);
+    assert_eq!(results[0].get_str("objectId").unwrap(), "ZTF18abcdefg");
+    assert_eq!(results[0].get_str("fid").unwrap(), "v1");
+    assert_eq!(
+        results[0]
+            .get_datetime("passed_at")
+            .unwrap()
+            .timestamp_millis(),
+        1_700_000_000_000
+    );
+    // without update_annotations, each pass keeps its own annotations
+    let mag_now = |result: &Document| {
+        result
+            .get_document("annotations")
+            .unwrap()
+            .get_f64("mag_now")
+            .unwrap()
+    };
+    assert_eq!(mag_now(&results[0]), 18.5);
+    assert_eq!(mag_now(&results[1]), 18.0);
+    // with it, the prior pass has the annotations of the latest one
+    assert_eq!(updated_results.len(), 2);
+    assert_eq!(mag_now(&updated_results[0]), 18.0);
+    assert_eq!(mag_now(&updated_results[1]), 18.0);
+    assert!(not_saved_results.is_empty());
+}
+
+#[test]
+fn test_notification_policy_from_filter_object() {
+    let policy = |filter_obj: Document| NotificationPolicy::from_filter_object(&filter_obj);
+    assert_eq!(policy(doc! {}).unwrap(), NotificationPolicy::Always);
+    assert_eq!(
+        policy(doc! {"notification_policy": {"type": "first_pass"}}).unwrap(),
+        NotificationPolicy::FirstPass
+    );
+    assert_eq!(
+        policy(doc! {"notification_policy": {"type": "throttle", "hours": 6}}).unwrap(),
+        NotificationPolicy::Throttle { hours: 6.0 }
+    );
+    assert_eq!(
+        policy(doc! {"notification_policy": {"type": "on_change"}}).unwrap(),
+        NotificationPolicy::OnChange { keys: vec![] }
+    );
+    assert!(matches!(
+        policy(doc! {"notification_policy": {"type": "throttle"}}),
+        Err(FilterError::InvalidNotificationPolicy)
+    ));
+}
+
+#[tokio::test]
+async fn test_notification_gate() {
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let db = conf::build_db(&config).await.unwrap();
+
+    let mut notification_gate = NotificationGate::new(&db);
+    notification_gate.create_indexes().await.unwrap();
+    let first_pass_id = rand::random::<i32>();
+    let throttle_id = first_pass_id.wrapping_add(1);
+    let on_change_id = first_pass_id.wrapping_add(2);
+    let always_id = first_pass_id.wrapping_add(3);
+    notification_gate.set_policies(HashMap::from([
+        (first_pass_id, NotificationPolicy::FirstPass),
+        (throttle_id, NotificationPolicy::Throttle { hours: 1.0 }),
+        (
+            on_change_id,
+            NotificationPolicy::OnChange {
+                keys: vec!["mag_now".to_string()],
+            },
+        ),
+        (always_id, NotificationPolicy::Always),
+    ]));
+
+    // passes of the same object through the 4 filters, `minutes` after the first one
+    let mut alert = test_alert();
+    let mut notify = |minutes: i64, mag_now: f64| {
+        alert.candid += 1;
+        alert.filters = [first_pass_id, throttle_id, on_change_id, always_id]
+            .into_iter()
+            .map(|filter_id| FilterResults {
+                filter_id,
+                passed_at: (1_700_000_000_000 + minutes * 60_000) as f64,
+                annotations: format!("{{\"mag_now\": {}, \"age\": {}}}", mag_now, minutes),
+            })
+            .collect();
+        let mut alert = alert.clone();
+        let notification_gate = &notification_gate;
+        async move {
+            let mut suppressed = notification_gate.apply(&mut alert).await.unwrap();
+            suppressed.sort();
+            let notified: Vec<i32> = alert.filters.iter().map(|f| f.filter_id).collect();
+            (notified, suppressed)
+        }
+    };
+    let first = notify(0, 18.5).await;
+    let same_mag = notify(30, 18.5).await;
+    let new_mag = notify(45, 18.0).await;
+    let two_hours_later = notify(120, 18.0).await;
+
+    db.collection::<Document>(FILTER_NOTIFICATIONS_COLLECTION)
+        .delete_many(doc! {"filter_id": {"$in": [first_pass_id, throttle_id, on_change_id]}})
+        .await
+        .unwrap();
+
+    assert_eq!(
+        first,
+        (
+            vec![first_pass_id, throttle_id, on_change_id, always_id],
+            vec![]
+        )
+    );
+    let mut suppressed = vec![first_pass_id, throttle_id, on_change_id];
+    suppressed.sort();
+    assert_eq!(same_mag, (vec![always_id], suppressed));
+    let mut suppressed = vec![first_pass_id, throttle_id];
+    suppressed.sort();
+    assert_eq!(new_mag, (vec![on_change_id, always_id], suppressed));
+    let mut suppressed = vec![first_pass_id, on_change_id];
+    suppressed.sort();
+    assert_eq!(two_hours_later, (vec![throttle_id, always_id], suppressed));
+}
+
+#[tokio::test]
+async fn test_notification_gate_release() {
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let db = conf::build_db(&config).await.unwrap();
+
+    let mut notification_gate = NotificationGate::new(&db);
+    notification_gate.create_indexes().await.unwrap();
+    let first_pass_id = rand::random::<i32>();
+    let throttle_id = first_pass_id.wrapping_add(1);
+    notification_gate.set_policies(HashMap::from([
+        (first_pass_id, NotificationPolicy::FirstPass),
+        (throttle_id, NotificationPolicy::Throttle { hours: 1.0 }),
+    ]));
+
+    let mut alert = test_alert();
+    let pass = |alert: &mut Alert, minutes: i64| {
+        alert.candid += 1;
+        alert.filters = [first_pass_id, throttle_id]
+            .into_iter()
+            .map(|filter_id| FilterResults {
+                filter_id,
+                passed_at: (1_700_000_000_000 + minutes * 60_000) as f64,
+                annotations: "{}".to_string(),
+            })
+            .collect();
+        alert.clone()
+    };
+
+    // the same pass applied twice at once is only notified once
+    let (mut a, mut b) = (pass(&mut alert, 0), alert.clone());
+    let (suppressed_a, suppressed_b) = tokio::join!(
+        notification_gate.apply(&mut a),
+        notification_gate.apply(&mut b)
+    );
+    let nb_notified = a.filters.len() + b.filters.len();
+    let nb_suppressed = suppressed_a.unwrap().len() + suppressed_b.unwrap().len();
+
+    // a first pass that could not be sent is forgotten
+    notification_gate.release(&a).await.unwrap();
+    notification_gate.release(&b).await.unwrap();
+    let mut retried = pass(&mut alert, 30);
+    let suppressed_retried = notification_gate.apply(&mut retried).await.unwrap();
+
+    // and a later one goes back to the previous notification, 30 minutes in
+    let mut unsent = pass(&mut alert, 91);
+    let suppressed_unsent = notification_gate.apply(&mut unsent).await.unwrap();
+    notification_gate.release(&unsent).await.unwrap();
+    let mut next = pass(&mut alert, 92);
+    let suppressed_next = notification_gate.apply(&mut next).await.unwrap();
+
+    db.collection::<Document>(FILTER_NOTIFICATIONS_COLLECTION)
+        .delete_many(doc! {"filter_id": {"$in": [first_pass_id, throttle_id]}})
+        .await
+        .unwrap();
+
+    assert_eq!((nb_notified, nb_suppressed), (2, 2));
+    assert!(suppressed_retried.is_empty());
+    assert_eq!(retried.filters.len(), 2);
+    assert_eq!(suppressed_unsent, vec![first_pass_id]);
+    assert_eq!(suppressed_next, vec![first_pass_id]);
+    assert_eq!(next.filters[0].filter_id, throttle_id);
+}
+
+#[test]
+fn test_observing_night() {
+    // 2024-03-01T04:00 and 10:00 UTC are in the night that started on Feb 29 at Palomar
+    assert_eq!(observing_night(2460370.666667, ZTF_LONGITUDE), "2024-02-29");
+    assert_eq!(observing_night(2460370.916667, ZTF_LONGITUDE), "2024-02-29");
+    // and 2024-03-01T23:30 UTC is in the evening of Mar 1 in Chile
+    assert_eq!(
+        observing_night(2460371.479167, LSST_LONGITUDE),
+        "2024-03-01"
+    );
+}
+
+#[test]
+fn test_backtest_report() {
+    let mut report = BacktestReport::default();
+    for candid in 0..10 {
+        report.record("2024-02-29".to_string(), candid, candid % 2 == 0, 3);
+    }
+    report.record("2024-03-01".to_string(), 10, false, 3);
+
+    assert_eq!(report.alerts_evaluated, 11);
+    assert_eq!(report.alerts_passed, 5);
+    assert_eq!(
+        report.nights["2024-02-29"],
+        NightCounts {
+            alerts_evaluated: 10,
+            alerts_passed: 5
+        }
+    );
+    assert_eq!(report.nights["2024-03-01"].alerts_passed, 0);
+    // a sample of the passing candids
+    assert_eq!(report.sample.len(), 3);
+    assert!(report.sample.iter().all(|candid| candid % 2 == 0));
+}
+
+#[tokio::test]
+async fn test_backtest() {
+    let mut alert_worker = ztf_alert_worker().await;
+    let (candid, _object_id, ra, dec, bytes_content) = ZtfAlertRandomizer::default().get().await;
+    alert_worker.process_alert(&bytes_content).await.unwrap();
+
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let db = conf::build_db(&config).await.unwrap();
+    let alert = db
+        .collection::<Document>("ZTF_alerts")
+        .find_one(doc! {"_id": candid})
+        .await
+        .unwrap()
+        .unwrap();
+    let jd = alert
+        .get_document("candidate")
+        .unwrap()
+        .get_f64("jd")
+        .unwrap();
+
+    // the stages of the test filter, which the alert passes
+    let stages = vec![
+        doc! {"$match": {"candidate.drb": {"$gt": 0.5}, "candidate.ndethist": {"$gt": 1.0}, "candidate.magpsf": {"$lte": 18.5}}},
+        doc! {"$project": {"annotations.mag_now": {"$round": ["$candidate.magpsf", 2]}}},
+    ];
+    let query = BacktestQuery {
+        jd_start: jd - 1e-6,
+        jd_end: jd + 1e-6,
+        region: Some(SkyRegion {
+            ra,
+            dec,
+            radius: 1.0 / 3600.0,
+        }),
+        permissions: vec![1, 2, 3],
+    };
+    let validator = FilterValidator::from_config(&config, "ZTF_alerts").unwrap();
+    let mut backtest = Backtest::new(&db, "ZTF", stages.clone(), &query, &validator)
+        .await
+        .unwrap()
+        .chunk_size(10);
+    let chunk = backtest.next_chunk().await.unwrap().unwrap();
+    assert_eq!(chunk.alerts_evaluated, 1);
+    assert_eq!(chunk.passed, vec![candid]);
+    assert!(backtest.next_chunk().await.unwrap().is_none());
+
+    let report = backtest.report();
+    assert_eq!(report.alerts_evaluated, 1);
+    assert_eq!(report.alerts_passed, 1);
+    assert_eq!(report.sample, vec![candid]);
+    assert_eq!(
+        report.nights[&observing_night(jd, ZTF_LONGITUDE)].alerts_passed,
+        1
+    );
+
+    // the alerts of the programids the filter doesn't have access to are left out
+    let query = BacktestQuery {
+        permissions: vec![99],
+        ..query
+    };
+    let mut backtest = Backtest::new(&db, "ZTF", stages.clone(), &query, &validator)
+        .await
+        .unwrap();
+    assert!(backtest.next_chunk().await.unwrap().is_none());
+
+    let query = BacktestQuery {
+        permissions: vec![],
+        ..query
+    };
+    assert!(matches!(
+        Backtest::new(&db, "ZTF", stages, &query, &validator).await,
+        Err(BacktestError::Filter(FilterError::InvalidFilterPermissions))
+    ));
+}
+
+#[test]
+fn test_filter_schedule() {
+    let date = |s: &str| chrono::DateTime::parse_from_rfc3339(s).unwrap().to_utc();
+
+    let hourly =
+        FilterSchedule::from_filter_object(&doc! {"schedule": {"type": "interval", "minutes": 60}})
+            .unwrap();
+    assert_eq!(hourly, FilterSchedule::Interval { minutes: 60 });
+    assert_eq!(
+        hourly.next_run(date("2024-03-01T10:30:00Z")),
+        date("2024-03-01T11:30:00Z")
+    );
+
+    // once a day, after the end of the night at Palomar
+    let daily = FilterSchedule::Daily { hour: 14 };
+    assert_eq!(
+        daily.next_run(date("2024-03-01T10:30:00Z")),
+        date("2024-03-01T14:00:00Z")
+    );
+    assert_eq!(
+        daily.next_run(date("2024-03-01T14:00:00Z")),
+        date("2024-03-02T14:00:00Z")
+    );
+    // a filter that never ran waits for the hour of the day (if daily)
+    assert!(hourly.is_due(None, date("2024-03-01T10:30:00Z")));
+    assert!(!daily.is_due(None, date("2024-03-01T10:30:00Z")));
+    assert!(daily.is_due(None, date("2024-03-01T14:30:00Z")));
+    assert!(!daily.is_due(
+        Some(date("2024-03-01T14:00:00Z")),
+        date("2024-03-02T13:59:00Z")
+    ));
+    assert!(daily.is_due(
+        Some(date("2024-03-01T14:00:00Z")),
+        date("2024-03-02T14:00:00Z")
+    ));
+
+    for filter_obj in [
+        doc! {},
+        doc! {"schedule": {"type": "interval", "minutes": 0}},
+        doc! {"schedule": {"type": "daily", "hour": 24}},
+        doc! {"schedule": {"type": "weekly"}},
+    ] {
+        assert!(matches!(
+            FilterSchedule::from_filter_object(&filter_obj),
+            Err(FilterError::InvalidFilterSchedule)
+        ));
+    }
+}
+
+#[tokio::test]
+async fn test_scheduled_filter_worker() {
+    let mut alert_worker = ztf_alert_worker().await;
+    let (candid, object_id, _ra, _dec, bytes_content) = ZtfAlertRandomizer::default().get().await;
+    alert_worker.process_alert(&bytes_content).await.unwrap();
+
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let db = conf::build_db(&config).await.unwrap();
+    let filter_collection = db.collection::<Document>("filters");
+
+    // an object filter over the aux collection, which the object of the alert passes
+    let filter_id = rand::random::<i32>();
+    let pipeline = serde_json::json!([
+        {"$match": {"_id": &object_id}},
+        {"$project": {"annotations.n_detections": {"$size": "$prv_candidates"}}},
+    ]);
+    filter_collection
+        .insert_one(doc! {
+            "filter_id": filter_id,
+            "catalog": "ZTF_alerts",
+            "type": OBJECT_FILTER_TYPE,
+            "schedule": {"type": "interval", "minutes": 60},
+            "permissions": [1, 2, 3],
+            "active": true,
+            "active_fid": "v1",
+            "fv": [{"fid": "v1", "pipeline": pipeline.to_string()}],
+        })
+        .await
+        .unwrap();
+
+    // the alert filter workers leave it out
+    let alert_filter_ids = get_active_filter_ids("ZTF_alerts", &filter_collection)
+        .await
+        .unwrap();
+    let scheduled_filter_ids = get_scheduled_filter_ids("ZTF_alerts", &filter_collection)
+        .await
+        .unwrap();
+
+    let mut scheduled_filter_worker =
+        ScheduledFilterWorker::<ZtfFilterWorker>::new(TEST_CONFIG_FILE)
+            .await
+            .unwrap();
+    let now = chrono::Utc::now();
+    let first_run = scheduled_filter_worker.run_due_filters(now).await.unwrap();
+    // not due again before the end of the interval
+    let second_run = scheduled_filter_worker
+        .run_due_filters(now + chrono::Duration::minutes(30))
+        .await
+        .unwrap();
+    // a run whose results couldn't be sent is undone, and the filter runs again
+    scheduled_filter_worker.unclaim(filter_id).await.unwrap();
+    let third_run = scheduled_filter_worker
+        .run_due_filters(now + chrono::Duration::minutes(30))
+        .await
+        .unwrap();
+    let filter = filter_collection
+        .find_one(doc! {"filter_id": filter_id})
+        .await
+        .unwrap()
+        .unwrap();
+    filter_collection
+        .delete_one(doc! {"filter_id": filter_id})
+        .await
+        .unwrap();
+
+    assert!(!alert_filter_ids.contains(&filter_id));
+    assert!(scheduled_filter_ids.contains(&filter_id));
+
+    let passed = |alerts: &[Alert]| -> Vec<Alert> {
+        alerts
+            .iter()
+            .filter(|alert| alert.filters.iter().any(|f| f.filter_id == filter_id))
+            .cloned()
+            .collect()
+    };
+    let first_run = passed(&first_run);
+    assert_eq!(first_run.len(), 1);
+    assert_eq!(first_run[0].candid, candid);
+    assert_eq!(first_run[0].object_id, object_id);
+    let annotations: serde_json::Value =
+        serde_json::from_str(&first_run[0].filters[0].annotations).unwrap();
+    assert!(annotations["n_detections"].as_i64().unwrap() >= 1);
+    assert!(passed(&second_run).is_empty());
+    assert_eq!(passed(&third_run).len(), 1);
+    assert!(filter.get_datetime("last_run_at").is_ok());
+}
+
+#[test]
+fn test_group_by_prefix() {
+    let prefix = |permissions: Vec<i32>| {
+        vec![
+            doc! {"$match": {}},
+            doc! {"$lookup": {"from": "ZTF_alerts_aux", "localField": "objectId", "foreignField": "_id", "as": "aux"}},
+            doc! {"$project": {"permissions": permissions}},
+        ]
+    };
+    let with_stage = |mut pipeline: Vec<Document>, stage: Document| {
+        pipeline.push(stage);
+        pipeline
+    };
+    let pipeline_a = with_stage(prefix(vec![1]), doc! {"$match": {"a": 1}});
+    let pipeline_b = with_stage(prefix(vec![1, 2]), doc! {"$match": {"b": 1}});
+    let pipeline_c = with_stage(prefix(vec![1]), doc! {"$match": {"c": 1}});
+    assert_eq!(pipeline_a.len(), FILTER_PREFIX_LEN + 1);
+
+    let groups = group_by_prefix(vec![
+        (1, pipeline_a.as_slice()),
+        (2, pipeline_b.as_slice()),
+        (3, pipeline_c.as_slice()),
+    ]);
+    let group_ids = groups
+        .iter()
+        .map(|group| group.iter().map(|(id, _)| *id).collect::<Vec<i32>>())
+        .collect::<Vec<_>>();
+    assert_eq!(group_ids, vec![vec![1, 3], vec![2]]);
+}
+
+#[test]
+fn test_validate_filter_pipeline() {
+    let catalog = "ZTF_alerts";
+    let allowed = vec![
+        doc! {"$match": {"candidate.drb": {"$gt": 0.5}, "candidate.ndethist": {"$gt": 1.0}}},
+        doc! {"$lookup": {"from": "ZTF_alerts_aux", "localField": "objectId", "foreignField": "_id", "as": "more_aux"}},
+        doc! {"$unwind": "$prv_candidates"},
+        doc! {"$project": {"annotations.mag_now": {"$round": ["$candidate.magpsf", 2]}}},
+    ];
+    assert_eq!(validate_filter_pipeline(catalog, &allowed), Ok(()));
+
+    assert_eq!(
+        validate_filter_pipeline(catalog, &[]),
+        Err(FilterValidationError::EmptyPipeline)
+    );
+
+    // writes
+    let mut pipeline = allowed.clone();
+    pipeline.push(doc! {"$out": "stolen"});
+    let error = validate_filter_pipeline(catalog, &pipeline).unwrap_err();
+    assert_eq!(
+        error,
+        FilterValidationError::StageNotAllowed {
+            stage: 4,
+            name: "$out".to_string()
+        }
+    );
+    assert_eq!(error.stage(), Some(4));
+
+    // server-side javascript, at any depth
+    let pipeline = vec![doc! {
+        "$match": {"$expr": {"$function": {"body": "function() { return true; }", "args": [], "lang": "js"}}}
+    }];
+    assert_eq!(
+        validate_filter_pipeline(catalog, &pipeline),
+        Err(FilterValidationError::OperatorNotAllowed {
+            stage: 0,
+            operator: "$function".to_string()
+        })
+    );
+    let pipeline = vec![doc! {"$match": {"$where": "this.candid > 0"}}];
+    assert_eq!(
+        validate_filter_pipeline(catalog, &pipeline)
+            .unwrap_err()
+            .stage(),
+        Some(0)
+    );
+
+    // lookups into other collections, and the stages of their sub-pipelines
+    let pipeline = vec![
+        doc! {"$match": {}},
+        doc! {"$lookup": {"from": "LSST_alerts_aux", "localField": "objectId", "foreignField": "_id", "as": "x"}},
+    ];
+    assert_eq!(
+        validate_filter_pipeline(catalog, &pipeline),
+        Err(FilterValidationError::LookupNotAllowed {
+            stage: 1,
+            collection: "LSST_alerts_aux".to_string()
+        })
+    );
+    let pipeline = vec![doc! {
+        "$lookup": {"from": "ZTF_alerts_aux", "pipeline": [{"$merge": {"into": "stolen"}}], "as": "x"}
+    }];
+    assert_eq!(
+        validate_filter_pipeline(catalog, &pipeline),
+        Err(FilterValidationError::StageNotAllowed {
+            stage: 0,
+            name: "$merge".to_string()
+        })
+    );
+    // the crossmatch catalogs of the survey can be looked up
+    let pipeline = vec![doc! {
+        "$lookup": {"from": "PS1_DR1", "localField": "objectId", "foreignField": "_id", "as": "x"}
+    }];
+    assert!(matches!(
+        validate_filter_pipeline(catalog, &pipeline),
+        Err(FilterValidationError::LookupNotAllowed { stage: 0, .. })
+    ));
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let validator = FilterValidator::from_config(&config, catalog).unwrap();
+    assert_eq!(validator.validate(&pipeline), Ok(()));
+    let validator = FilterValidator::from_config(&config, "LSST_alerts").unwrap();
+    assert!(validator.validate(&pipeline).is_err());
+    let validator = FilterValidator::new(catalog).allow_lookup("PS1_DR1");
+    assert_eq!(validator.validate(&pipeline), Ok(()));
+
+    // unbounded unwinds
+    let pipeline = vec![doc! {"$unwind": "$prv_candidates"}; MAX_UNWIND_STAGES + 1];
+    assert_eq!(
+        validate_filter_pipeline(catalog, &pipeline),
+        Err(FilterValidationError::TooManyUnwinds {
+            stage: MAX_UNWIND_STAGES,
+            max: MAX_UNWIND_STAGES
+        })
+    );
+
+    // stages that don't keep the candid of the alerts that pass
+    for stage in [
+        doc! {"$group": {"_id": "$objectId", "n": {"$sum": 1}}},
+        doc! {"$count": "n"},
+        doc! {"$replaceRoot": {"newRoot": "$candidate"}},
+    ] {
+        let mut pipeline = allowed.clone();
+        pipeline.push(stage);
+        assert!(matches!(
+            validate_filter_pipeline(catalog, &pipeline),
+            Err(FilterValidationError::StageNotAllowed { stage: 4, .. })
+        ));
+    }
+
+    // stages with more than one stage name
+    let pipeline = vec![doc! {"$match": {}, "$project": {"_id": 1}}];
+    assert_eq!(
+        validate_filter_pipeline(catalog, &pipeline),
+        Err(FilterValidationError::InvalidStage { stage: 0 })
+    );
+}
+
+#[tokio::test]
+async fn test_filter_worker_keeps_stored_filters() {
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let db = conf::build_db(&config).await.unwrap();
+    let filter_collection = db.collection::<Document>("filters");
+
+    // a filter saved before the pipelines were validated, that no longer passes:
+    // the pipelines are only checked when they are submitted
+    let filter_id = insert_test_ztf_filter().await.unwrap();
+    filter_collection
+        .update_one(
+            doc! {"filter_id": filter_id},
+            doc! {"$set": {"fv.0.pipeline": "[{\"$group\": {\"_id\": \"$objectId\"}}]"}},
+        )
+        .await
+        .unwrap();
+    let filter_result = ZtfFilter::build(filter_id, &filter_collection).await;
+    let filter_worker = ZtfFilterWorker::new(TEST_CONFIG_FILE).await;
+    remove_test_ztf_filter(filter_id).await.unwrap();
+
+    assert!(filter_result.is_ok());
+    let filter_worker = filter_worker.unwrap();
+    assert!(filter_worker.filter_group_ids().contains_key(&filter_id));
+}
+
+fn test_alert() -> Alert {
+    Alert {
+        schemavsn: ALERT_SCHEMA_VERSION.to_string(),
+        candid: 42,
+        object_id: "ZTF18abcdefg".to_string(),
+        jd: 2460000.5,
+        ra: 10.0,
+        dec: 20.0,
+        filters: vec![],
+        photometry: vec![],
+        classifications: HashMap::new(),
+        cross_matches: HashMap::new(),
+        cutout_science: vec![1],
+        cutout_template: vec![2],
+        cutout_difference: vec![3],
+    }
+}
+
+#[test]
+fn test_output_format_from_config() {
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let output_format = OutputFormat::from_config(&config, "ZTF_alerts_results").unwrap();
+    assert_eq!(output_format, OutputFormat::Ocf);
+    let output_format = OutputFormat::from_config(&config, "LSST_alerts_results").unwrap();
+    assert_eq!(output_format, OutputFormat::Ocf);
+    // topics without a section default to ocf
+    let output_format = OutputFormat::from_config(&config, "WINTER_alerts_results").unwrap();
+    assert_eq!(output_format, OutputFormat::Ocf);
+
+    // the credentials of the registry are read from the environment
+    std::env::set_var("TEST_OUTPUT_FORMAT_REGISTRY_PASSWORD", "secret");
+    let config = config::Config::builder()
+        .set_override("filter_output.default.format", "confluent")
+        .unwrap()
+        .set_override(
+            "filter_output.default.registry_url",
+            "http://localhost:8081",
+        )
+        .unwrap()
+        .set_override("filter_output.default.username", "boom")
+        .unwrap()
+        .set_override(
+            "filter_output.default.password_env",
+            "TEST_OUTPUT_FORMAT_REGISTRY_PASSWORD",
+        )
+        .unwrap()
+        .build()
+        .unwrap();
+    let output_format = OutputFormat::from_config(&config, "ZTF_alerts_results").unwrap();
+    assert_eq!(
+        output_format,
+        OutputFormat::Confluent {
+            registry_url: "http://localhost:8081".to_string(),
+            subject: None,
+            username: Some("boom".to_string()),
+            password: Some("secret".to_string()),
+            username_env: None,
+            password_env: Some("TEST_OUTPUT_FORMAT_REGISTRY_PASSWORD".to_string()),
+        }
+    );
+    let config = config::Config::builder()
+        .set_override("filter_output.default.format", "confluent")
+        .unwrap()
+        .set_override(
+            "filter_output.default.registry_url",
+            "http://localhost:8081",
+        )
+        .unwrap()
+        .set_override(
+            "filter_output.default.password_env",
+            "TEST_OUTPUT_FORMAT_UNSET",
+        )
+        .unwrap()
+        .build()
+        .unwrap();
+    assert!(matches!(
+        OutputFormat::from_config(&config, "ZTF_alerts_results"),
+        Err(conf::BoomConfigError::MissingEnvVar(var)) if var == "TEST_OUTPUT_FORMAT_UNSET"
+    ));
+}
+
+#[tokio::test]
+async fn test_alert_encoder_confluent() {
+    let mock = MockSchemaRegistry::start(vec![], None).await;
+    let output_format = OutputFormat::Confluent {
+        registry_url: mock.url().to_string(),
+        subject: None,
+        username: None,
+        password: None,
+        username_env: None,
+        password_env: None,
+    };
+    let encoder = AlertEncoder::new(&output_format, "ZTF_alerts_results")
+        .await
+        .unwrap();
+    let encoded = encoder.encode(&test_alert()).unwrap();
+    // magic byte, then the id the mock gave to the first schema registered
+    assert_eq!(encoded[..5], [0, 0, 0, 0, 1]);
+
+    // the schema is the same, so it is not registered again
+    let encoder = AlertEncoder::new(&output_format, "ZTF_alerts_results")
+        .await
+        .unwrap();
+    assert_eq!(encoder.encode(&test_alert()).unwrap(), encoded);
+
+    // and consumers can decode the alerts with the registry
+    let mut registry = SchemaRegistry::new(mock.url());
+    let Value::Record(fields) = registry.decode(&encoded).await.unwrap() else {
+        panic!("expected a record");
+    };
+    let fields = fields.into_iter().collect::<HashMap<_, _>>();
+    assert_eq!(fields["candid"], Value::Long(42));
+    assert_eq!(fields["cutoutScience"], Value::Bytes(vec![1]));
+}
+
+#[test]
+fn test_alert_schema() {
+    let schema = load_alert_schema().unwrap();
+    let apache_avro::Schema::Record(record) = &schema else {
+        panic!("expected a record");
+    };
+    // consumers look the schema up by its full name, so it must not change
+    assert_eq!(record.name.fullname(None), "Alert");
+    // and alerts written without a version can still be read
+    let schemavsn = record
+        .fields
+        .iter()
+        .find(|f| f.name == "schemavsn")
+        .unwrap();
+    assert!(schemavsn.default.is_some());
+}
+
+#[tokio::test]
+async fn test_alert_encoder_ocf() {
+    let encoder = AlertEncoder::new(&OutputFormat::Ocf, "ZTF_alerts_results")
+        .await
+        .unwrap();
+    let encoded = encoder.encode(&test_alert()).unwrap();
+    // an object container file starts with its own magic bytes
+    assert_eq!(encoded[..4], [b'O', b'b', b'j', 1]);
+}
+
+#[test]
+fn test_filter_topics_from_config() {
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let topics_config = FilterTopicsConfig::from_config(&config).unwrap();
+    assert_eq!(
+        topics_config.template.as_deref(),
+        Some("boom_{survey}_group_{group_id}")
+    );
+    assert!(topics_config.combined);
+    assert_eq!(topics_config.retention_ms, None);
+
+    // without a section, everything goes to the combined topic as before
+    let config = config::Config::builder().build().unwrap();
+    let topics_config = FilterTopicsConfig::from_config(&config).unwrap();
+    assert_eq!(topics_config, FilterTopicsConfig::default());
+    assert_eq!(topics_config.template, None);
+    assert!(topics_config.combined);
+}
+
+#[test]
+fn test_route_alert() {
+    let mut alert = test_alert();
+    for filter_id in [1, 2, 3] {
+        alert.filters.push(FilterResults {
+            filter_id,
+            passed_at: 0.0,
+            annotations: "{}".to_string(),
+        });
+    }
+    // filters 1 and 2 are from the same group, filter 3 has no group
+    let group_ids = HashMap::from([(1, 41), (2, 41)]);
+
+    let topics_config = FilterTopicsConfig {
+        template: Some("boom_{survey}_filter_{filter_id}".to_string()),
+        ..Default::default()
+    };
+    let output_topics = OutputTopics::new(topics_config, "ZTF", "ZTF_alerts_results", None);
+    let routed = output_topics.route(&alert, &group_ids);
+    let topics = routed.iter().map(|(t, _)| t.as_str()).collect::<Vec<_>>();
+    assert_eq!(
+        topics,
+        vec![
+            "boom_ZTF_filter_1",
+            "boom_ZTF_filter_2",
+            "boom_ZTF_filter_3",
+            "ZTF_alerts_results"
+        ]
+    );
+    // each filter only sees its own results, the combined topic sees them all
+    assert_eq!(routed[1].1.filters.len(), 1);
+    assert_eq!(routed[1].1.filters[0].filter_id, 2);
+    assert_eq!(routed[3].1.filters.len(), 3);
+
+    let topics_config = FilterTopicsConfig {
+        template: Some("boom_group_{group_id}".to_string()),
+        combined: false,
+        ..Default::default()
+    };
+    let output_topics = OutputTopics::new(topics_config, "ZTF", "ZTF_alerts_results", None);
+    let routed = output_topics.route(&alert, &group_ids);
+    // filter 3 has no group, so it falls back to the combined topic
+    assert_eq!(routed.len(), 2);
+    assert_eq!(routed[0].0, "boom_group_41");
+    let filter_ids = routed[0]
+        .1
+        .filters
+        .iter()
+        .map(|f| f.filter_id)
+        .collect::<Vec<_>>();
+    assert_eq!(filter_ids, vec![1, 2]);
+    assert_eq!(routed[1].0, "ZTF_alerts_results");
+    assert_eq!(routed[1].1.filters.len(), 1);
+    assert_eq!(routed[1].1.filters[0].filter_id, 3);
+
+    // without a template, the combined topic is used even if it is disabled
+    let topics_config = FilterTopicsConfig {
+        template: None,
+        combined: false,
+        ..Default::default()
+    };
+    let output_topics = OutputTopics::new(topics_config, "ZTF", "ZTF_alerts_results", None);
+    let routed = output_topics.route(&alert, &group_ids);
+    assert_eq!(routed.len(), 1);
+    assert_eq!(routed[0].0, "ZTF_alerts_results");
+    assert_eq!(routed[0].1.filters.len(), 3);
+}
@@ -0,0 +1,153 @@
+use boom::{
+    alert::{AlertWorker, AlertWorkerError, GenericAlertWorker, StreamMapping},
+    conf,
+    utils::testing::{
+        drop_alert_from_collections, generic_alert_worker, AlertRandomizerTrait,
+        ZtfAlertRandomizer, TEST_CONFIG_FILE,
+    },
+};
+use mongodb::bson::doc;
+
+#[test]
+fn test_stream_mapping_from_config() {
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    assert_eq!(conf::get_stream_names(&config), vec!["WINTER".to_string()]);
+
+    let mapping = StreamMapping::from_config(&config, "WINTER").unwrap();
+    assert_eq!(mapping.fields.candid, "candid");
+    assert_eq!(mapping.fields.object_id, "objectId");
+    assert_eq!(mapping.fields.band, Some("fid".to_string()));
+    assert_eq!(mapping.fields.prv_nondetections, None);
+
+    let result = StreamMapping::from_config(&config, "DECAM");
+    assert!(matches!(result, Err(AlertWorkerError::UnknownStream(_))));
+}
+
+#[tokio::test]
+async fn test_generic_alert_worker_new() {
+    // with a single stream in the config, no stream name is needed
+    let alert_worker = GenericAlertWorker::new(TEST_CONFIG_FILE).await.unwrap();
+    assert_eq!(alert_worker.stream_name(), "WINTER");
+    assert_eq!(
+        alert_worker.input_queue_name(),
+        "WINTER_alerts_packets_queue"
+    );
+    assert_eq!(alert_worker.output_queue_name(), None);
+}
+
+#[tokio::test]
+async fn test_process_generic_alert() {
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let db = conf::build_db(&config).await.unwrap();
+
+    // the WINTER stream of the test config has the layout of the ZTF packets
+    let mut alert_worker = generic_alert_worker("WINTER").await;
+    let (candid, object_id, ra, dec, bytes_content) = ZtfAlertRandomizer::default().get().await;
+    let result = alert_worker.process_alert(&bytes_content).await;
+    assert!(result.is_ok());
+    assert_eq!(result.unwrap(), candid);
+
+    // processing it again is a no-op
+    let result = alert_worker.process_alert(&bytes_content).await;
+    assert!(result.is_err());
+
+    let alert = db
+        .collection::<mongodb::bson::Document>("WINTER_alerts")
+        .find_one(doc! {"_id": candid})
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(alert.get_str("objectId").unwrap(), object_id);
+    let candidate = alert.get_document("candidate").unwrap();
+    assert_eq!(candidate.get_f64("ra").unwrap(), ra);
+    assert_eq!(candidate.get_f64("dec").unwrap(), dec);
+    assert_eq!(
+        candidate.get_i32("band").unwrap(),
+        candidate.get_i32("fid").unwrap()
+    );
+    // magnitudes are converted to fluxes in µJy, signed by isdiffpos
+    let magpsf = candidate.get_f64("magpsf").unwrap();
+    let psf_flux = candidate.get_f64("psfFlux").unwrap();
+    assert!((-2.5 * psf_flux.abs().log10() + 23.9 - magpsf).abs() < 1e-6);
+    assert_eq!(candidate.get_bool("isdiffpos").unwrap(), psf_flux > 0.0);
+    let psf_flux_err = candidate.get_f64("psfFluxErr").unwrap();
+    assert!(psf_flux_err > 0.0);
+    assert!((candidate.get_f64("snr").unwrap() - psf_flux.abs() / psf_flux_err).abs() < 1e-6);
+    assert!(candidate.get_f64("diffmaglim").is_ok());
+
+    let cutouts = db
+        .collection::<mongodb::bson::Document>("WINTER_alerts_cutouts")
+        .find_one(doc! {"_id": candid})
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(cutouts.get_binary_generic("cutoutScience").is_ok());
+    assert!(cutouts.get_binary_generic("cutoutTemplate").is_ok());
+    assert!(cutouts.get_binary_generic("cutoutDifference").is_ok());
+
+    // same split of the history as the ZTF worker
+    let aux = db
+        .collection::<mongodb::bson::Document>("WINTER_alerts_aux")
+        .find_one(doc! {"_id": &object_id})
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(aux.get_array("prv_candidates").unwrap().len(), 8);
+    assert_eq!(aux.get_array("prv_nondetections").unwrap().len(), 3);
+    assert_eq!(aux.get_array("fp_hists").unwrap().len(), 10);
+
+    drop_alert_from_collections(candid, "WINTER").await.unwrap();
+}
+
+#[tokio::test]
+async fn test_process_generic_alert_flux_convention() {
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let db = conf::build_db(&config).await.unwrap();
+
+    // the same stream, with its brightness read as fluxes in nJy. Any numeric
+    // fields do to check the conversion, so we use the aperture magnitudes,
+    // which are kept as they are in the stored candidate
+    let config_path = std::env::temp_dir().join(format!("boom_{}.yaml", uuid::Uuid::new_v4()));
+    let config_text = std::fs::read_to_string(TEST_CONFIG_FILE)
+        .unwrap()
+        .replace(
+            "      system: mag\n      mag: magpsf\n      mag_err: sigmapsf\n      isdiffpos: isdiffpos\n      diffmaglim: diffmaglim\n",
+            "      system: flux\n      flux: magap\n      flux_err: sigmagap\n      zero_point: 31.4\n",
+        );
+    assert!(config_text.contains("system: flux"));
+    std::fs::write(&config_path, config_text).unwrap();
+    let mut alert_worker = GenericAlertWorker::from_config(config_path.to_str().unwrap(), "WINTER")
+        .await
+        .unwrap();
+    std::fs::remove_file(&config_path).unwrap();
+
+    let (candid, _, _, _, bytes_content) = ZtfAlertRandomizer::default().get().await;
+    let result = alert_worker.process_alert(&bytes_content).await;
+    assert_eq!(result.unwrap(), candid);
+
+    let alert = db
+        .collection::<mongodb::bson::Document>("WINTER_alerts")
+        .find_one(doc! {"_id": candid})
+        .await
+        .unwrap()
+        .unwrap();
+    let candidate = alert.get_document("candidate").unwrap();
+
+    // fluxes are converted from nJy to µJy
+    let flux = candidate.get_f64("magap").unwrap();
+    let flux_err = candidate.get_f64("sigmagap").unwrap();
+    let psf_flux = candidate.get_f64("psfFlux").unwrap();
+    let psf_flux_err = candidate.get_f64("psfFluxErr").unwrap();
+    assert!((psf_flux - flux * 1e-3).abs() < 1e-9);
+    assert!((psf_flux_err - flux_err * 1e-3).abs() < 1e-9);
+
+    // and magnitudes are derived from them
+    let magpsf = candidate.get_f64("magpsf").unwrap();
+    assert!((-2.5 * psf_flux.abs().log10() + 23.9 - magpsf).abs() < 1e-4);
+    assert!(candidate.get_f64("sigmapsf").unwrap() > 0.0);
+    assert_eq!(candidate.get_bool("isdiffpos").unwrap(), psf_flux > 0.0);
+    assert!((candidate.get_f64("snr").unwrap() - psf_flux.abs() / psf_flux_err).abs() < 1e-6);
+    assert!(candidate.get_f64("diffmaglim").is_ok());
+
+    drop_alert_from_collections(candid, "WINTER").await.unwrap();
+}
@@ -1,4 +1,5 @@
 use boom::kafka::{download_alerts_from_archive, produce_from_archive};
+use boom::utils::testing::TEST_CONFIG_FILE;
 use rdkafka::config::ClientConfig;
 use rdkafka::consumer::{BaseConsumer, Consumer};
 
@@ -24,7 +25,7 @@ async fn test_produce_from_archive() {
 
     let topic = uuid::Uuid::new_v4().to_string();
 
-    let result = produce_from_archive("20240617", 0, Some(topic.clone())).await;
+    let result = produce_from_archive("20240617", 0, Some(topic.clone()), TEST_CONFIG_FILE).await;
     assert!(result.is_ok());
     assert!(result.unwrap() == 710);
     assert!(std::path::Path::new("data/alerts/ztf/20240617").exists());
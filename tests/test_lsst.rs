@@ -1,7 +1,7 @@
 use boom::{
     alert::AlertWorker,
     conf,
-    filter::{FilterWorker, LsstFilterWorker},
+    filter::{FilterWorker, LsstFilterWorker, Origin},
     utils::testing::{
         drop_alert_from_collections, insert_test_lsst_filter, lsst_alert_worker,
         remove_test_lsst_filter, AlertRandomizerTrait, LsstAlertRandomizer, TEST_CONFIG_FILE,
@@ -144,7 +144,54 @@ async fn test_process_lsst_alert() {
     let fp_hists = aux.get_array("fp_hists").unwrap();
     assert_eq!(fp_hists.len(), 3);
 
+    // the diaObject is stored with the aux document
+    let dia_object = aux.get_document("dia_object").unwrap();
+    assert!(dia_object.contains_key("objectId"));
+    assert!(dia_object.contains_key("ra"));
+    assert!(dia_object.contains_key("dec"));
+
+    drop_alert_from_collections(candid, "LSST").await.unwrap();
+}
+
+#[tokio::test]
+async fn test_process_lsst_alert_keeps_latest_dia_object() {
+    let mut alert_worker = lsst_alert_worker().await;
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let db = conf::build_db(&config).await.unwrap();
+    let aux_collection = db.collection::<mongodb::bson::Document>("LSST_alerts_aux");
+
+    let (candid, object_id, _, _, bytes_content) = LsstAlertRandomizer::default().get().await;
+    alert_worker.process_alert(&bytes_content).await.unwrap();
+
+    // pretend the stored diaObject comes from a later processing run
+    aux_collection
+        .update_one(
+            doc! {"_id": object_id},
+            doc! {"$set": {"dia_object.validityStart": 1e9, "dia_object.ra": -1.0}},
+        )
+        .await
+        .unwrap();
+
+    // a new alert with an older diaObject must not replace it
+    let (new_candid, _, _, _, bytes_content) = LsstAlertRandomizer::default()
+        .objectid(object_id)
+        .get()
+        .await;
+    alert_worker.process_alert(&bytes_content).await.unwrap();
+
+    let aux = aux_collection
+        .find_one(doc! {"_id": object_id})
+        .await
+        .unwrap()
+        .unwrap();
+    let dia_object = aux.get_document("dia_object").unwrap();
+    assert_eq!(dia_object.get_f64("validityStart").unwrap(), 1e9);
+    assert_eq!(dia_object.get_f64("ra").unwrap(), -1.0);
+
     drop_alert_from_collections(candid, "LSST").await.unwrap();
+    drop_alert_from_collections(new_candid, "LSST")
+        .await
+        .unwrap();
 }
 
 #[tokio::test]
@@ -152,6 +199,17 @@ async fn test_filter_lsst_alert() {
     let mut alert_worker = lsst_alert_worker().await;
 
     let (candid, object_id, _ra, _dec, bytes_content) = LsstAlertRandomizer::default().get().await;
+    let n_forced = alert_worker
+        .alert_from_avro_bytes(&bytes_content)
+        .await
+        .unwrap()
+        .fp_hists
+        .map_or(0, |fp_hists| {
+            fp_hists
+                .iter()
+                .filter(|fp| fp.dia_forced_source.psf_flux.is_some())
+                .count()
+        });
     let result = alert_worker.process_alert(&bytes_content).await.unwrap();
     assert_eq!(result, candid);
 
@@ -166,7 +224,14 @@ async fn test_filter_lsst_alert() {
     let alert = &alerts_output[0];
     assert_eq!(alert.candid, candid);
     assert_eq!(alert.object_id, format!("{}", object_id));
-    assert_eq!(alert.photometry.len(), 3); // prv_candidates + prv_nondetections
+    // prv_candidates + prv_nondetections, then the forced photometry
+    let (forced, photometry): (Vec<_>, Vec<_>) = alert
+        .photometry
+        .iter()
+        .partition(|p| p.origin == Origin::ForcedPhot);
+    assert_eq!(photometry.len(), 3);
+    assert_eq!(forced.len(), n_forced);
+    assert!(alert.photometry.iter().all(|p| p.zero_point == 23.9));
     let filter_passed = alert
         .filters
         .iter()
@@ -176,3 +241,59 @@ async fn test_filter_lsst_alert() {
 
     remove_test_lsst_filter(filter_id).await.unwrap();
 }
+
+#[tokio::test]
+async fn test_process_lsst_solar_system_alert() {
+    let mut alert_worker = lsst_alert_worker().await;
+
+    let (candid, ss_object_id, _, _, bytes_content) =
+        LsstAlertRandomizer::default().solar_system().get().await;
+    let result = alert_worker.process_alerts(&[bytes_content]).await.unwrap();
+    assert!(result.failed.is_empty());
+    assert!(result.ingested.is_empty());
+    assert_eq!(result.ss_ingested, vec![candid]);
+    assert_eq!(
+        alert_worker.ss_output_queue_name(),
+        Some("LSST_ss_alerts_filter_queue".to_string())
+    );
+
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let db = conf::build_db(&config).await.unwrap();
+    let alert = db
+        .collection::<mongodb::bson::Document>("LSST_ss_alerts")
+        .find_one(doc! {"_id": candid})
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(alert.get_i64("ssObjectId").unwrap(), ss_object_id);
+    assert!(!alert.contains_key("objectId"));
+
+    let aux = db
+        .collection::<mongodb::bson::Document>("LSST_ss_aux")
+        .find_one(doc! {"_id": ss_object_id})
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(aux.get_array("prv_candidates").unwrap().len(), 3);
+
+    // nothing ends up in the regular collections
+    let alert = db
+        .collection::<mongodb::bson::Document>("LSST_alerts")
+        .find_one(doc! {"_id": candid})
+        .await
+        .unwrap();
+    assert!(alert.is_none());
+
+    db.collection::<mongodb::bson::Document>("LSST_ss_alerts")
+        .delete_one(doc! {"_id": candid})
+        .await
+        .unwrap();
+    db.collection::<mongodb::bson::Document>("LSST_ss_aux")
+        .delete_one(doc! {"_id": ss_object_id})
+        .await
+        .unwrap();
+    db.collection::<mongodb::bson::Document>("LSST_alerts_cutouts")
+        .delete_one(doc! {"_id": candid})
+        .await
+        .unwrap();
+}
@@ -0,0 +1,193 @@
+use apache_avro::{
+    to_avro_datum,
+    types::{Record, Value},
+    Schema,
+};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use boom::{
+    alert::{SchemaRegistry, SchemaRegistryError},
+    utils::testing::MockSchemaRegistry,
+};
+use std::path::PathBuf;
+
+const SCHEMA: &str =
+    r#"{"type": "record", "name": "Alert", "fields": [{"name": "candid", "type": "long"}]}"#;
+
+fn temp_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("boom_schemas_{}", uuid::Uuid::new_v4()))
+}
+
+#[tokio::test]
+async fn test_schema_registry_offline_seeds() {
+    let seed_dir = temp_dir();
+    std::fs::create_dir_all(seed_dir.join("alert-packet")).unwrap();
+    std::fs::write(seed_dir.join("alert-packet/701.avsc"), SCHEMA).unwrap();
+    // files that aren't schemas are ignored
+    std::fs::write(seed_dir.join("README.md"), "schemas").unwrap();
+
+    // nothing listens on this port, so any request would fail
+    let mut registry =
+        SchemaRegistry::with_cache("http://localhost:1", None, Some(&seed_dir), true).unwrap();
+    assert_eq!(
+        registry.cached_schemas(),
+        vec![("alert-packet".to_string(), 701)]
+    );
+
+    let schema = registry.get_schema("alert-packet", 701).await;
+    assert!(schema.is_ok());
+
+    let schema = registry.get_schema("alert-packet", 702).await;
+    assert!(matches!(
+        schema,
+        Err(SchemaRegistryError::SchemaNotCached(_))
+    ));
+
+    std::fs::remove_dir_all(&seed_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_schema_registry_cache_dir() {
+    let cache_dir = temp_dir();
+
+    // schemas added to a registry are saved to its cache directory...
+    let mut registry =
+        SchemaRegistry::with_cache("http://localhost:1", Some(&cache_dir), None, true).unwrap();
+    assert!(registry.cached_schemas().is_empty());
+    registry.add_schema("alert-packet", 800, SCHEMA).unwrap();
+    assert!(cache_dir.join("alert-packet/800.avsc").exists());
+
+    // ...and loaded by the next one
+    let mut registry =
+        SchemaRegistry::with_cache("http://localhost:1", Some(&cache_dir), None, true).unwrap();
+    assert_eq!(
+        registry.cached_schemas(),
+        vec![("alert-packet".to_string(), 800)]
+    );
+    assert!(registry.get_schema("alert-packet", 800).await.is_ok());
+
+    // invalid schemas are rejected and not saved
+    let result = registry.add_schema("alert-packet", 801, "not a schema");
+    assert!(matches!(result, Err(SchemaRegistryError::Avro(_))));
+    assert!(!cache_dir.join("alert-packet/801.avsc").exists());
+
+    std::fs::remove_dir_all(&cache_dir).unwrap();
+}
+
+// a newer version of SCHEMA, with a field added
+const SCHEMA_V2: &str = r#"{"type": "record", "name": "Alert", "fields": [{"name": "candid", "type": "long"}, {"name": "band", "type": "string", "default": "r"}]}"#;
+
+// a packet in the wire format, written with SCHEMA
+fn wire_format_packet(schema_id: u32, candid: i64) -> Vec<u8> {
+    let schema = Schema::parse_str(SCHEMA).unwrap();
+    let mut record = Record::new(&schema).unwrap();
+    record.put("candid", Value::Long(candid));
+    let mut packet = vec![0u8];
+    packet.extend(schema_id.to_be_bytes());
+    packet.extend(to_avro_datum(&schema, record).unwrap());
+    packet
+}
+
+#[tokio::test]
+async fn test_schema_registry_get_schema_by_id() {
+    let mock = MockSchemaRegistry::start(vec![("alert-packet", 1, 701, SCHEMA)], None).await;
+    let mut registry = SchemaRegistry::new(mock.url());
+
+    let schema = registry.get_schema_by_id(701).await;
+    assert!(schema.is_ok());
+    assert_eq!(mock.requests(), 1);
+
+    // served from memory the second time
+    let schema = registry.get_schema_by_id(701).await;
+    assert!(schema.is_ok());
+    assert_eq!(mock.requests(), 1);
+
+    let schema = registry.get_schema_by_id(702).await;
+    assert!(matches!(schema, Err(SchemaRegistryError::NotFound(_))));
+
+    // versions are not ids
+    let schema = registry.get_schema("alert-packet", 1).await;
+    assert!(schema.is_ok());
+    let schema = registry.get_schema("alert-packet", 701).await;
+    assert!(matches!(schema, Err(SchemaRegistryError::NotFound(_))));
+
+    let value = registry.decode(&wire_format_packet(701, 42)).await.unwrap();
+    assert_eq!(
+        value,
+        Value::Record(vec![("candid".to_string(), Value::Long(42))])
+    );
+
+    let result = registry.decode(&[1, 0, 0, 2, 189, 84]).await;
+    assert!(matches!(result, Err(SchemaRegistryError::MagicBytesError)));
+}
+
+#[tokio::test]
+async fn test_schema_registry_sync() {
+    let mock = MockSchemaRegistry::start(
+        vec![
+            ("alert-packet", 1, 701, SCHEMA),
+            ("alert-packet", 2, 702, SCHEMA_V2),
+        ],
+        None,
+    )
+    .await;
+    let cache_dir = temp_dir();
+
+    let mut registry =
+        SchemaRegistry::with_cache(mock.url(), Some(&cache_dir), None, false).unwrap();
+    let fetched = registry.sync("alert-packet").await.unwrap();
+    assert_eq!(fetched, vec![1, 2]);
+    assert!(cache_dir.join("alert-packet/2.avsc").exists());
+    assert!(cache_dir.join("ids/702.avsc").exists());
+
+    // the schemas looked up by id are then available offline
+    let mut registry =
+        SchemaRegistry::with_cache(mock.url(), Some(&cache_dir), None, true).unwrap();
+    assert!(registry.get_schema_by_id(702).await.is_ok());
+
+    std::fs::remove_dir_all(&cache_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_schema_registry_basic_auth() {
+    let mock = MockSchemaRegistry::start(
+        vec![("alert-packet", 1, 701, SCHEMA)],
+        Some(format!("Basic {}", BASE64_STANDARD.encode("boom:secret"))),
+    )
+    .await;
+
+    let mut registry = SchemaRegistry::new(mock.url());
+    let schema = registry.get_schema_by_id(701).await;
+    assert!(matches!(schema, Err(SchemaRegistryError::Unauthorized)));
+
+    registry.set_credentials("boom", "wrong");
+    let schema = registry.get_schema_by_id(701).await;
+    assert!(matches!(schema, Err(SchemaRegistryError::Unauthorized)));
+
+    registry.set_credentials("boom", "secret");
+    let schema = registry.get_schema_by_id(701).await;
+    assert!(schema.is_ok());
+}
+
+#[tokio::test]
+async fn test_schema_registry_reader_schema() {
+    let mock = MockSchemaRegistry::start(
+        vec![
+            ("alert-packet", 1, 701, SCHEMA),
+            ("alert-packet", 2, 702, SCHEMA_V2),
+        ],
+        None,
+    )
+    .await;
+
+    // a packet written with the old schema gets the default of the new field
+    let mut registry = SchemaRegistry::new(mock.url());
+    registry.set_reader_schema(Schema::parse_str(SCHEMA_V2).unwrap());
+    let value = registry.decode(&wire_format_packet(701, 42)).await.unwrap();
+    assert_eq!(
+        value,
+        Value::Record(vec![
+            ("candid".to_string(), Value::Long(42)),
+            ("band".to_string(), Value::String("r".to_string())),
+        ])
+    );
+}
@@ -0,0 +1,92 @@
+use boom::{
+    conf,
+    utils::{
+        testing::TEST_CONFIG_FILE,
+        worker::{
+            clear_heartbeat, reclaim_orphaned_packets, send_heartbeat, spawn_heartbeat,
+            temp_queue_name,
+        },
+    },
+};
+use redis::AsyncCommands;
+
+#[tokio::test]
+async fn test_reclaim_orphaned_packets() {
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let mut con = conf::build_redis(&config).await.unwrap();
+
+    let input_queue_name = format!(
+        "TEST_{}_alerts_packets_queue",
+        uuid::Uuid::new_v4().simple()
+    );
+    let live_queue_name = temp_queue_name(&input_queue_name, "live");
+    let dead_queue_name = temp_queue_name(&input_queue_name, "dead");
+
+    // the live worker holds one packet, the dead one (no heartbeat) holds two
+    send_heartbeat(&mut con, &live_queue_name).await.unwrap();
+    con.lpush::<&str, &[u8], usize>(&live_queue_name, b"live")
+        .await
+        .unwrap();
+    con.lpush::<&str, &[u8], usize>(&dead_queue_name, b"first")
+        .await
+        .unwrap();
+    con.lpush::<&str, &[u8], usize>(&dead_queue_name, b"second")
+        .await
+        .unwrap();
+
+    let count = reclaim_orphaned_packets(&mut con, &input_queue_name)
+        .await
+        .unwrap();
+    assert_eq!(count, 2);
+    let dead_len: usize = con.llen(&dead_queue_name).await.unwrap();
+    assert_eq!(dead_len, 0);
+    let live_len: usize = con.llen(&live_queue_name).await.unwrap();
+    assert_eq!(live_len, 1);
+
+    // workers pop from the tail, the oldest packet should come out first
+    let packet: Vec<u8> = con.rpop(&input_queue_name, None).await.unwrap();
+    assert_eq!(packet, b"first");
+    let packet: Vec<u8> = con.rpop(&input_queue_name, None).await.unwrap();
+    assert_eq!(packet, b"second");
+
+    // once the live worker shuts down, what it left behind is reclaimed too
+    clear_heartbeat(&mut con, &live_queue_name).await.unwrap();
+    let count = reclaim_orphaned_packets(&mut con, &input_queue_name)
+        .await
+        .unwrap();
+    assert_eq!(count, 1);
+
+    con.del::<&str, usize>(&input_queue_name).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_spawn_heartbeat() {
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let mut con = conf::build_redis(&config).await.unwrap();
+
+    let input_queue_name = format!(
+        "TEST_{}_alerts_packets_queue",
+        uuid::Uuid::new_v4().simple()
+    );
+    let busy_queue_name = temp_queue_name(&input_queue_name, "busy");
+    con.lpush::<&str, &[u8], usize>(&busy_queue_name, b"busy")
+        .await
+        .unwrap();
+
+    // the heartbeat is sent right away, while the worker itself does something else
+    let heartbeat = spawn_heartbeat(con.clone(), busy_queue_name.clone());
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    let count = reclaim_orphaned_packets(&mut con, &input_queue_name)
+        .await
+        .unwrap();
+    assert_eq!(count, 0);
+
+    drop(heartbeat);
+    clear_heartbeat(&mut con, &busy_queue_name).await.unwrap();
+    let count = reclaim_orphaned_packets(&mut con, &input_queue_name)
+        .await
+        .unwrap();
+    assert_eq!(count, 1);
+
+    con.del::<&str, usize>(&input_queue_name).await.unwrap();
+}
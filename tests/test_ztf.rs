@@ -1,7 +1,11 @@
+use apache_avro::types::Value;
 use boom::{
-    alert::{AlertWorker, LSST_DEC_LIMIT, LSST_XMATCH_RADIUS},
+    alert::AlertWorker,
     conf,
-    filter::{FilterWorker, ZtfFilterWorker},
+    filter::{
+        alert_to_avro_bytes, load_alert_schema, FilterWorker, Origin, ZtfFilterWorker,
+        ALERT_SCHEMA_VERSION,
+    },
     ml::{MLWorker, ZtfMLWorker},
     utils::{
         db::mongify,
@@ -13,6 +17,7 @@ use boom::{
     },
 };
 use mongodb::bson::doc;
+use std::collections::HashMap;
 
 #[tokio::test]
 async fn test_alert_from_avro_bytes() {
@@ -225,18 +230,155 @@ async fn test_process_ztf_alert() {
     drop_alert_from_collections(candid, "ZTF").await.unwrap();
 }
 
+#[tokio::test]
+async fn test_process_ztf_alerts_batch() {
+    let mut alert_worker = ztf_alert_worker().await;
+
+    // two alerts of the same object, a packet that can't be decoded and a duplicate
+    let (candid_1, object_id, _, _, bytes_content_1) = ZtfAlertRandomizer::default().get().await;
+    let (candid_2, _, _, _, bytes_content_2) = ZtfAlertRandomizer::default()
+        .objectid(&object_id)
+        .get()
+        .await;
+    let packets = vec![
+        bytes_content_1.clone(),
+        bytes_content_2,
+        b"not an avro packet".to_vec(),
+        bytes_content_1,
+    ];
+
+    let result = alert_worker.process_alerts(&packets).await.unwrap();
+    assert_eq!(result.ingested, vec![candid_1, candid_2]);
+    assert_eq!(result.failed.len(), 1);
+    assert_eq!(result.failed[0].0, 2);
+
+    // both alerts end up in a single aux document
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let db = conf::build_db(&config).await.unwrap();
+    let aux = db
+        .collection::<mongodb::bson::Document>("ZTF_alerts_aux")
+        .find_one(doc! {"_id": &object_id})
+        .await
+        .unwrap()
+        .unwrap();
+    let candids = aux
+        .get_array("prv_candidates")
+        .unwrap()
+        .iter()
+        .map(|prv_candidate| {
+            prv_candidate
+                .as_document()
+                .unwrap()
+                .get_i64("candid")
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+    assert!(candids.contains(&candid_1));
+    assert!(candids.contains(&candid_2));
+    assert!(aux.contains_key("cross_matches"));
+    assert!(aux.contains_key("aliases"));
+
+    // nothing is new the second time around, but the alerts come back with the time
+    // they were written at, to forward those written by a failed attempt
+    let result = alert_worker.process_alerts(&packets[..2]).await.unwrap();
+    assert!(result.ingested.is_empty());
+    assert!(result.failed.is_empty());
+    assert!(result
+        .existing
+        .iter()
+        .all(|(_, created_at)| created_at.is_finite()));
+    let mut existing = result
+        .existing
+        .iter()
+        .map(|(candid, _)| *candid)
+        .collect::<Vec<_>>();
+    existing.sort();
+    let mut expected = vec![candid_1, candid_2];
+    expected.sort();
+    assert_eq!(existing, expected);
+
+    drop_alert_from_collections(candid_1, "ZTF").await.unwrap();
+    drop_alert_from_collections(candid_2, "ZTF").await.unwrap();
+}
+
+#[tokio::test]
+async fn test_process_ztf_alert_aux_failure() {
+    let mut alert_worker = ztf_alert_worker().await;
+    let (candid, object_id, _, _, bytes_content) = ZtfAlertRandomizer::default().get().await;
+
+    // an aux document the alert can't be added to, so that the aux write fails
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let db = conf::build_db(&config).await.unwrap();
+    let aux_collection = db.collection::<mongodb::bson::Document>("ZTF_alerts_aux");
+    aux_collection
+        .insert_one(doc! {"_id": &object_id, "prv_candidates": "not an array"})
+        .await
+        .unwrap();
+    let result = alert_worker
+        .process_alerts(std::slice::from_ref(&bytes_content))
+        .await;
+    assert!(result.is_err());
+
+    // the alert isn't written before its aux entry, so the retry still ingests it
+    let alert = db
+        .collection::<mongodb::bson::Document>("ZTF_alerts")
+        .find_one(doc! {"_id": candid})
+        .await
+        .unwrap();
+    assert!(alert.is_none());
+    aux_collection
+        .delete_one(doc! {"_id": &object_id})
+        .await
+        .unwrap();
+    let result = alert_worker.process_alerts(&[bytes_content]).await.unwrap();
+    assert_eq!(result.ingested, vec![candid]);
+    let aux = aux_collection
+        .find_one(doc! {"_id": &object_id})
+        .await
+        .unwrap()
+        .unwrap();
+    let prv_candids = aux
+        .get_array("prv_candidates")
+        .unwrap()
+        .iter()
+        .filter_map(|prv| prv.as_document()?.get("candid")?.as_i64())
+        .collect::<Vec<_>>();
+    assert!(prv_candids.contains(&candid));
+
+    drop_alert_from_collections(candid, "ZTF").await.unwrap();
+}
+
+// the object ids of the aliases of an aux document for a given survey, nearest first
+fn get_alias_ids(aux: &mongodb::bson::Document, survey: &str) -> Vec<mongodb::bson::Bson> {
+    aux.get_document("aliases")
+        .unwrap()
+        .get_array(survey)
+        .unwrap()
+        .iter()
+        .map(|x| x.as_document().unwrap().get("objectId").unwrap().clone())
+        .collect()
+}
+
 #[tokio::test]
 async fn test_process_ztf_lsst_xmatch() {
     let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
     let db = conf::build_db(&config).await.unwrap();
+    let lsst_xmatch_config = conf::build_survey_xmatch_configs(&config, "ZTF")
+        .unwrap()
+        .into_iter()
+        .find(|survey_xmatch_config| survey_xmatch_config.survey == "LSST")
+        .unwrap();
+    let lsst_dec_limit = lsst_xmatch_config.max_dec.unwrap();
+    let lsst_xmatch_radius = lsst_xmatch_config.radius;
 
     // ZTF setup: the dec should be *below* the LSST dec limit:
     let mut alert_worker = ztf_alert_worker().await;
-    let ztf_alert_randomizer = ZtfAlertRandomizer::default().dec(LSST_DEC_LIMIT - 10.0);
+    let ztf_alert_randomizer = ZtfAlertRandomizer::default().dec(lsst_dec_limit - 10.0);
 
     let (_, object_id, ra, dec, bytes_content) = ztf_alert_randomizer.clone().get().await;
     let aux_collection_name = "ZTF_alerts_aux";
     let filter_aux = doc! {"_id": &object_id};
+    let lsst_aux_collection = db.collection::<mongodb::bson::Document>("LSST_alerts_aux");
 
     // LSST setup
     let mut lsst_alert_worker = lsst_alert_worker().await;
@@ -244,7 +386,7 @@ async fn test_process_ztf_lsst_xmatch() {
     // 1. LSST alert further than max radius, ZTF alert should not have an LSST alias
     let (_, _, _, _, lsst_bytes_content) = LsstAlertRandomizer::default()
         .ra(ra)
-        .dec(dec + 1.1 * LSST_XMATCH_RADIUS.to_degrees())
+        .dec(dec + 1.1 * lsst_xmatch_radius.to_degrees())
         .get()
         .await;
     lsst_alert_worker
@@ -259,17 +401,12 @@ async fn test_process_ztf_lsst_xmatch() {
         .await
         .unwrap()
         .unwrap();
-    let matches = aux
-        .get_document("aliases")
-        .unwrap()
-        .get_array("LSST")
-        .unwrap();
-    assert_eq!(matches.len(), 0);
+    assert_eq!(get_alias_ids(&aux, "LSST").len(), 0);
 
-    // 2. nearby LSST alert, ZTF alert should have an LSST alias
-    let (_, lsst_object_id, _, _, lsst_bytes_content) = LsstAlertRandomizer::default()
+    // 2. nearby LSST alert, both objects should have each other as alias
+    let (_, lsst_object_id_1, _, _, lsst_bytes_content) = LsstAlertRandomizer::default()
         .ra(ra)
-        .dec(dec + 0.9 * LSST_XMATCH_RADIUS.to_degrees())
+        .dec(dec + 0.9 * lsst_xmatch_radius.to_degrees())
         .get()
         .await;
     lsst_alert_worker
@@ -277,28 +414,39 @@ async fn test_process_ztf_lsst_xmatch() {
         .await
         .unwrap();
 
-    let (_, _, _, _, bytes_content) = ztf_alert_randomizer.clone().rand_candid().get().await;
-    alert_worker.process_alert(&bytes_content).await.unwrap();
+    // the ZTF object learns about the new LSST object right away
     let aux = db
         .collection::<mongodb::bson::Document>(aux_collection_name)
         .find_one(filter_aux.clone())
         .await
         .unwrap()
         .unwrap();
-    let lsst_matches = aux
+    assert_eq!(get_alias_ids(&aux, "LSST"), vec![lsst_object_id_1.into()]);
+
+    let lsst_aux = lsst_aux_collection
+        .find_one(doc! {"_id": lsst_object_id_1})
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        get_alias_ids(&lsst_aux, "ZTF"),
+        vec![object_id.clone().into()]
+    );
+    let separation = lsst_aux
         .get_document("aliases")
         .unwrap()
-        .get_array("LSST")
+        .get_array("ZTF")
+        .unwrap()[0]
+        .as_document()
         .unwrap()
-        .iter()
-        .map(|x| x.as_i64().unwrap())
-        .collect::<Vec<_>>();
-    assert_eq!(lsst_matches, vec![lsst_object_id]);
+        .get_f64("angular_separation")
+        .unwrap();
+    assert!((separation - 0.9 * lsst_xmatch_radius.to_degrees() * 3600.0).abs() < 1e-2);
 
-    // 3. Closer LSST alert, ZTF alert should have a new LSST alias
-    let (_, lsst_object_id, _, _, lsst_bytes_content) = LsstAlertRandomizer::default()
+    // 3. closer LSST alert, ZTF alert should have both LSST aliases, nearest first
+    let (_, lsst_object_id_2, _, _, lsst_bytes_content) = LsstAlertRandomizer::default()
         .ra(ra)
-        .dec(dec + 0.1 * LSST_XMATCH_RADIUS.to_degrees())
+        .dec(dec + 0.1 * lsst_xmatch_radius.to_degrees())
         .get()
         .await;
     lsst_alert_worker
@@ -314,20 +462,15 @@ async fn test_process_ztf_lsst_xmatch() {
         .await
         .unwrap()
         .unwrap();
-    let lsst_matches = aux
-        .get_document("aliases")
-        .unwrap()
-        .get_array("LSST")
-        .unwrap()
-        .iter()
-        .map(|x| x.as_i64().unwrap())
-        .collect::<Vec<_>>();
-    assert_eq!(lsst_matches, vec![lsst_object_id]);
+    assert_eq!(
+        get_alias_ids(&aux, "LSST"),
+        vec![lsst_object_id_2.into(), lsst_object_id_1.into()]
+    );
 
-    // 4. Further LSST alert, ZTF alert should NOT have a new LSST alias
-    let (_, bad_lsst_object_id, _, _, lsst_bytes_content) = LsstAlertRandomizer::default()
+    // 4. LSST alert in between, all three within the radius sorted by separation
+    let (_, lsst_object_id_3, _, _, lsst_bytes_content) = LsstAlertRandomizer::default()
         .ra(ra)
-        .dec(dec + 0.5 * LSST_XMATCH_RADIUS.to_degrees())
+        .dec(dec + 0.5 * lsst_xmatch_radius.to_degrees())
         .get()
         .await;
     lsst_alert_worker
@@ -343,29 +486,27 @@ async fn test_process_ztf_lsst_xmatch() {
         .await
         .unwrap()
         .unwrap();
-    let lsst_matches = aux
-        .get_document("aliases")
-        .unwrap()
-        .get_array("LSST")
-        .unwrap()
-        .iter()
-        .map(|x| x.as_i64().unwrap())
-        .collect::<Vec<_>>();
-    assert_eq!(lsst_matches, vec![lsst_object_id]);
-    assert_ne!(lsst_matches, vec![bad_lsst_object_id]);
+    assert_eq!(
+        get_alias_ids(&aux, "LSST"),
+        vec![
+            lsst_object_id_2.into(),
+            lsst_object_id_3.into(),
+            lsst_object_id_1.into()
+        ]
+    );
 
     // 5. This ZTF alert is above the LSST dec cutoff and therefore should not
     //    even attempt to match. Test this by creating an LSST alert with an
     //    unrealistically high dec that ZTF would otherwise match without this
     //    constraint:
     let (_, object_id, ra, dec, bytes_content) = ZtfAlertRandomizer::default()
-        .dec(LSST_DEC_LIMIT + 10.0)
+        .dec(lsst_dec_limit + 10.0)
         .get()
         .await;
 
     let (_, _, _, _, lsst_bytes_content) = LsstAlertRandomizer::default()
         .ra(ra)
-        .dec(dec + 0.9 * LSST_XMATCH_RADIUS.to_degrees())
+        .dec(dec + 0.9 * lsst_xmatch_radius.to_degrees())
         .get()
         .await;
     lsst_alert_worker
@@ -381,15 +522,7 @@ async fn test_process_ztf_lsst_xmatch() {
         .await
         .unwrap()
         .unwrap();
-    let lsst_matches = aux
-        .get_document("aliases")
-        .unwrap()
-        .get_array("LSST")
-        .unwrap()
-        .iter()
-        .map(|x| x.as_i64().unwrap())
-        .collect::<Vec<_>>();
-    assert_eq!(lsst_matches.len(), 0);
+    assert_eq!(get_alias_ids(&aux, "LSST").len(), 0);
 }
 
 #[tokio::test]
@@ -456,6 +589,9 @@ async fn test_filter_ztf_alert() {
     assert!(result.is_ok());
     assert_eq!(result.unwrap(), candid);
 
+    let ml_worker = ZtfMLWorker::new(TEST_CONFIG_FILE).await.unwrap();
+    ml_worker.process_alerts(&[candid]).await.unwrap();
+
     let filter_id = insert_test_ztf_filter().await.unwrap();
 
     let mut filter_worker = ZtfFilterWorker::new(TEST_CONFIG_FILE).await.unwrap();
@@ -469,7 +605,18 @@ async fn test_filter_ztf_alert() {
     let alert = &alerts_output[0];
     assert_eq!(alert.candid, candid);
     assert_eq!(alert.object_id, object_id);
-    assert_eq!(alert.photometry.len(), 11); // prv_candidates + prv_nondetections
+    // prv_candidates + prv_nondetections, then the 10 fp_hists (all with procstatus 0)
+    assert_eq!(alert.photometry.len(), 21);
+    let forced = alert
+        .photometry
+        .iter()
+        .filter(|p| p.origin == Origin::ForcedPhot)
+        .collect::<Vec<_>>();
+    assert_eq!(forced.len(), 10);
+    // the forced photometry is converted from DN to the common zero point
+    assert!(forced
+        .iter()
+        .all(|p| p.zero_point == 23.9 && p.flux.is_some() && p.flux_err < 100.0));
     let filter_passed = alert
         .filters
         .iter()
@@ -477,5 +624,33 @@ async fn test_filter_ztf_alert() {
         .unwrap();
     assert_eq!(filter_passed.annotations, "{\"mag_now\":14.91}");
 
+    // the ML scores come with the version of their model
+    assert_eq!(alert.schemavsn, ALERT_SCHEMA_VERSION);
+    assert_eq!(alert.classifications.len(), 6);
+    let btsbot = alert.classifications.get("btsbot").unwrap();
+    assert!(btsbot.score >= 0.0 && btsbot.score <= 1.0);
+    assert_eq!(btsbot.version.as_deref(), Some("v1.0.1"));
+    // there is an entry for every catalog, even without matches
+    let config = conf::load_config(TEST_CONFIG_FILE).unwrap();
+    let catalogs = conf::build_xmatch_configs(&config, "ZTF").unwrap();
+    assert_eq!(alert.cross_matches.len(), catalogs.len());
+
+    // and the alert can be read back with the output schema
+    let schema = load_alert_schema().unwrap();
+    let encoded = alert_to_avro_bytes(alert, &schema).unwrap();
+    let mut reader = apache_avro::Reader::new(&encoded[..]).unwrap();
+    let Value::Record(fields) = reader.next().unwrap().unwrap() else {
+        panic!("expected a record");
+    };
+    let fields = fields.into_iter().collect::<HashMap<_, _>>();
+    assert_eq!(
+        fields["schemavsn"],
+        Value::String(ALERT_SCHEMA_VERSION.to_string())
+    );
+    let Value::Map(classifications) = &fields["classifications"] else {
+        panic!("expected a map");
+    };
+    assert_eq!(classifications.len(), 6);
+
     remove_test_ztf_filter(filter_id).await.unwrap();
 }